@@ -1,7 +1,7 @@
 //! Implementation of an ARM instruction decoder.
 
 /// Enumerated type describing different kinds of ARM instruction encodings.
-#[derive(Clone,Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum ArmInst {
     AndRegShiftReg, AdcRegShiftReg, MovRegShiftReg, OrrRegShiftReg,
     EorRegShiftReg, RscRegShiftReg, MvnRegShiftReg, SbcRegShiftReg,
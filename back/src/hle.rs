@@ -0,0 +1,386 @@
+//! Backend for high-level emulation of IOS.
+//!
+//! Instead of running IOS on the emulated ARM core, this backend watches the
+//! IPC mailbox for requests from the PowerPC side, and dispatches them to
+//! resource managers implemented in Rust. Replies are sent back through the
+//! mailbox in the same way that IOS would send them.
+
+pub mod fs;
+pub mod es;
+pub mod stm;
+
+use ironic_core::bus::*;
+use ironic_core::bus::task::CYCLES_PER_US;
+use ironic_core::dev::hlwd::irq::*;
+use ironic_core::dev::hlwd::ipc::ARM_CTRL_Y2;
+use crate::back::*;
+use crate::ipc::*;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use std::convert::{TryFrom, TryInto};
+
+pub const IPC_OK: i32 = 0;
+pub const IPC_EACCES: i32 = -1;
+pub const IPC_EEXIST: i32 = -2;
+pub const IPC_EINVAL: i32 = -4;
+pub const IPC_EMAX: i32 = -5;
+pub const IPC_ENOENT: i32 = -6;
+
+/// The maximum number of file descriptors open at once.
+const MAX_FDS: u32 = 24;
+/// Number of bus cycles which elapse between each poll of the mailbox.
+const POLL_CYCLES: usize = CYCLES_PER_US * 1000;
+/// The most vectors accepted in a single ioctlv request. IOS rejects
+/// requests with more than this.
+const MAX_VECTORS: u32 = 32;
+
+/// The largest piece of a buffer copied to or from guest memory at once.
+pub const COPY_CHUNK_LEN: usize = 0x0001_0000;
+
+/// Copy some buffer out of guest memory, returning false (without reading
+/// anything) if the range isn't backed by memory.
+pub fn guest_read(bus: &mut Bus, addr: u32, buf: &mut [u8]) -> bool {
+    if buf.is_empty() {
+        return true;
+    }
+    match u32::try_from(buf.len()) {
+        Ok(len) if bus.is_mem_range(addr, len) => {
+            bus.dma_read(addr, buf);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Copy some buffer into guest memory, returning false (without writing
+/// anything) if the range isn't backed by memory.
+pub fn guest_write(bus: &mut Bus, addr: u32, buf: &[u8]) -> bool {
+    if buf.is_empty() {
+        return true;
+    }
+    match u32::try_from(buf.len()) {
+        Ok(len) if bus.is_mem_range(addr, len) => {
+            bus.dma_write(addr, buf);
+            true
+        },
+        _ => false,
+    }
+}
+
+/// Read a word from guest memory.
+pub fn guest_read32(bus: &mut Bus, addr: u32) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    guest_read(bus, addr, &mut buf).then(|| u32::from_be_bytes(buf))
+}
+
+/// A resource manager, responsible for requests on some set of paths.
+///
+/// Each successful open returns a handle chosen by the resource manager,
+/// which is passed back in subsequent requests on the same descriptor.
+/// Buffer addresses in requests are physical addresses, and must be checked
+/// (with [guest_read] and [guest_write]) before they are used.
+pub trait ResourceManager: Send {
+    /// Returns true if this resource manager handles some path.
+    fn handles(&self, path: &str) -> bool;
+
+    /// Open some path, returning a handle (or an error code).
+    fn open(&mut self, path: &str, mode: u32) -> Result<u32, i32>;
+
+    /// Close some handle.
+    fn close(&mut self, _handle: u32) -> i32 { IPC_OK }
+
+    fn read(&mut self, _bus: &mut Bus, _handle: u32, _addr: u32, _len: u32)
+        -> i32 { IPC_EINVAL }
+
+    fn write(&mut self, _bus: &mut Bus, _handle: u32, _addr: u32, _len: u32)
+        -> i32 { IPC_EINVAL }
+
+    fn seek(&mut self, _handle: u32, _offset: i32, _whence: u32)
+        -> i32 { IPC_EINVAL }
+
+    /// Handle an ioctl, returning None if the request remains pending until
+    /// it is completed by [ResourceManager::poll].
+    fn ioctl(&mut self, _bus: &mut Bus, _handle: u32, _arg: &IoctlArg)
+        -> Option<i32> { Some(IPC_EINVAL) }
+
+    /// Handle an ioctlv with some input and output vectors.
+    fn ioctlv(&mut self, _bus: &mut Bus, _handle: u32, _cmd: u32,
+        _input: &[IoctlvArgEntry], _output: &[IoctlvArgEntry]) -> i32 { IPC_EINVAL }
+
+    /// Returns the handles and results for any pending requests which have
+    /// been completed.
+    fn poll(&mut self) -> Vec<(u32, i32)> { Vec::new() }
+}
+
+/// A request which has not been replied to yet.
+struct PendingReq {
+    /// Physical address of the request.
+    addr: u32,
+    /// Index of the resource manager.
+    rm: usize,
+    /// Handle associated with the request.
+    handle: u32,
+    /// The original command.
+    cmd: u32,
+}
+
+pub struct IosHleBackend {
+    /// Reference to the system bus.
+    pub bus: Arc<RwLock<Bus>>,
+    /// The set of resource managers.
+    pub managers: Vec<Box<dyn ResourceManager>>,
+    /// Open file descriptors, each mapped to a resource manager and handle.
+    fds: BTreeMap<u32, (usize, u32)>,
+    /// Requests waiting for a reply.
+    pending: Vec<PendingReq>,
+    /// Number of cycles elapsed on the bus.
+    cycle: usize,
+}
+impl IosHleBackend {
+    pub fn new(bus: Arc<RwLock<Bus>>) -> Self {
+        IosHleBackend {
+            bus,
+            managers: Vec::new(),
+            fds: BTreeMap::new(),
+            pending: Vec::new(),
+            cycle: 0,
+        }
+    }
+
+    /// Create a backend with the default set of resource managers, where
+    /// the filesystem is backed by some host directory.
+    pub fn with_host_dir(bus: Arc<RwLock<Bus>>, dir: &str) -> Result<Self, String> {
+        let mut res = Self::new(bus);
+        let fs = fs::FsManager::new(dir)?;
+        res.register(Box::new(es::EsManager::new(fs.titles())));
+        res.register(Box::new(stm::StmManager::new()));
+        res.register(Box::new(fs));
+        Ok(res)
+    }
+
+    /// Add a resource manager. Paths are matched against resource managers
+    /// in the order they were registered.
+    pub fn register(&mut self, rm: Box<dyn ResourceManager>) {
+        self.managers.push(rm);
+    }
+}
+
+impl IosHleBackend {
+    /// Advance the bus, updating the state of any IRQs.
+    fn step_bus(&mut self) {
+        self.bus.write().unwrap().step(self.cycle);
+        self.cycle += POLL_CYCLES;
+    }
+
+    /// Set things up as IOS would before the PowerPC side starts talking to
+    /// us, then send the initial ACK.
+    pub fn boot_ppc(&mut self) {
+        {
+            let mut bus = self.bus.write().unwrap();
+            bus.hlwd.irq.ppc_irq_enable.set(HollywoodIrq::PpcIpc);
+            bus.hlwd.ppc_on = true;
+        }
+        println!("HLE Broadway came online");
+
+        // Wait for the PowerPC side to enable IPC interrupts
+        while !self.bus.read().unwrap().hlwd.ipc.state.ppc_ack_int {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.bus.write().unwrap().hlwd.ipc.arm_ctrl_set(ARM_CTRL_Y2);
+        self.step_bus();
+    }
+
+    /// Take a new request from the mailbox, if there is one.
+    fn recv_request(&mut self) -> Option<u32> {
+        self.bus.write().unwrap().hlwd.ipc.take_request()
+    }
+
+    /// Write the result into some request and send it back to the PowerPC.
+    fn send_reply(&mut self, addr: u32, cmd: u32, res: i32) {
+        println!("HLE reply {:08x} cmd={} res={}", addr, cmd, res);
+        let mut buf = [0u8; 0xc];
+        buf[0x0..0x4].copy_from_slice(&IOS_REPLY.to_be_bytes());
+        buf[0x4..0x8].copy_from_slice(&res.to_be_bytes());
+        buf[0x8..0xc].copy_from_slice(&cmd.to_be_bytes());
+
+        let mut bus = self.bus.write().unwrap();
+        bus.dma_write(addr, &buf);
+
+        // Wait for the PowerPC to take any previous reply
+        while bus.hlwd.ipc.state.ppc_req {
+            drop(bus);
+            thread::sleep(Duration::from_millis(1));
+            bus = self.bus.write().unwrap();
+        }
+        bus.hlwd.ipc.send_reply(addr);
+    }
+
+    /// Returns the lowest unused file descriptor.
+    fn alloc_fd(&self) -> Option<u32> {
+        (0..MAX_FDS).find(|fd| !self.fds.contains_key(fd))
+    }
+
+    /// Read a NUL-terminated path from guest memory.
+    fn read_path(bus: &mut Bus, addr: u32) -> Option<String> {
+        let mut buf = [0u8; 0x40];
+        if !guest_read(bus, addr, &mut buf) {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Some(String::from_utf8_lossy(&buf[..len]).to_string())
+    }
+
+    /// Read the vectors for an ioctlv request from guest memory.
+    fn read_vectors(bus: &mut Bus, arg: &IoctlvArg) -> Result<Vec<IoctlvArgEntry>, i32> {
+        let num = arg.input.checked_add(arg.output)
+            .filter(|&num| num <= MAX_VECTORS)
+            .ok_or(IPC_EINVAL)?;
+        let mut buf = vec![0u8; num as usize * 8];
+        if !guest_read(bus, arg.argv, &mut buf) {
+            return Err(IPC_EINVAL);
+        }
+        Ok(buf.chunks(8).map(|e| IoctlvArgEntry {
+            data: u32::from_be_bytes(e[0..4].try_into().unwrap()),
+            len: u32::from_be_bytes(e[4..8].try_into().unwrap()),
+        }).collect())
+    }
+
+    /// Dispatch a request to a resource manager, returning the result (or
+    /// None if the request is still pending).
+    fn handle_request(&mut self, addr: u32) -> Option<i32> {
+        let mut buf = [0u8; IosReq::LEN];
+        self.bus.write().unwrap().dma_read(addr, &mut buf);
+        let req = match IosReq::from_buf(&buf) {
+            Some(req) => req,
+            None => {
+                println!("HLE unknown IPC command {:08x}",
+                    u32::from_be_bytes(buf[0..4].try_into().unwrap()));
+                return Some(IPC_EINVAL);
+            },
+        };
+
+        // Every variant of the arguments is five words long, so reading any
+        // of them is sound.
+        let mut bus = self.bus.write().unwrap();
+        if req.cmd == IosCmd::Open {
+            let arg = unsafe { req.arg.open };
+            let path = match Self::read_path(&mut bus, arg.name) {
+                Some(path) => path,
+                None => return Some(IPC_EINVAL),
+            };
+            println!("HLE open('{}', {})", path, arg.mode);
+            let rm = match self.managers.iter().position(|rm| rm.handles(&path)) {
+                Some(rm) => rm,
+                None => return Some(IPC_ENOENT),
+            };
+            let fd = match self.alloc_fd() {
+                Some(fd) => fd,
+                None => return Some(IPC_EMAX),
+            };
+            return Some(match self.managers[rm].open(&path, arg.mode) {
+                Ok(handle) => {
+                    self.fds.insert(fd, (rm, handle));
+                    fd as i32
+                },
+                Err(e) => e,
+            });
+        }
+
+        let (rm, handle) = match self.fds.get(&req.fd) {
+            Some(&(rm, handle)) => (rm, handle),
+            None => return Some(IPC_EINVAL),
+        };
+        let mgr = &mut self.managers[rm];
+        match req.cmd {
+            IosCmd::Close => {
+                self.fds.remove(&req.fd);
+                Some(mgr.close(handle))
+            },
+            IosCmd::Read => {
+                let arg = unsafe { req.arg.read };
+                Some(mgr.read(&mut bus, handle, arg.addr, arg.len))
+            },
+            IosCmd::Write => {
+                let arg = unsafe { req.arg.write };
+                Some(mgr.write(&mut bus, handle, arg.addr, arg.len))
+            },
+            IosCmd::Seek => {
+                let arg = unsafe { req.arg.seek };
+                Some(mgr.seek(handle, arg.offset as i32, arg.whence))
+            },
+            IosCmd::Ioctl => {
+                let arg = unsafe { req.arg.ioctl };
+                let res = mgr.ioctl(&mut bus, handle, &arg);
+                if res.is_none() {
+                    self.pending.push(PendingReq { addr, rm, handle, cmd: req.cmd as u32 });
+                }
+                res
+            },
+            IosCmd::Ioctlv => {
+                let arg = unsafe { req.arg.ioctlv };
+                let vecs = match Self::read_vectors(&mut bus, &arg) {
+                    Ok(vecs) => vecs,
+                    Err(e) => {
+                        println!("HLE ioctlv has too many vectors ({} in, {} out)",
+                            arg.input, arg.output);
+                        return Some(e);
+                    },
+                };
+                let (input, output) = vecs.split_at(arg.input as usize);
+                Some(mgr.ioctlv(&mut bus, handle, arg.cmd, input, output))
+            },
+            IosCmd::Open => unreachable!(),
+        }
+    }
+
+    /// Reply to any pending requests which were completed.
+    fn handle_completions(&mut self) {
+        for rm in 0..self.managers.len() {
+            for (handle, res) in self.managers[rm].poll() {
+                let idx = self.pending.iter()
+                    .position(|p| p.rm == rm && p.handle == handle);
+                if let Some(idx) = idx {
+                    let p = self.pending.remove(idx);
+                    self.send_reply(p.addr, p.cmd, res);
+                }
+            }
+        }
+    }
+}
+
+impl IosHleBackend {
+    /// Handle a new request (if there is one) and any completions. This
+    /// never blocks, so it can be used to run IOS in the same thread as the
+    /// PowerPC side.
+    pub fn step(&mut self) {
+        if let Some(addr) = self.recv_request() {
+            // There's nowhere to put the reply to a request outside of memory
+            if !self.bus.read().unwrap().is_mem_range(addr, IosReq::LEN as u32) {
+                println!("HLE request {:08x} isn't in memory", addr);
+                self.step_bus();
+                return;
+            }
+            self.step_bus();
+            let cmd = self.bus.write().unwrap().read32(addr);
+            if let Some(res) = self.handle_request(addr) {
+                self.send_reply(addr, cmd, res);
+            }
+        }
+        self.handle_completions();
+        self.step_bus();
+    }
+}
+
+impl Backend for IosHleBackend {
+    fn run(&mut self) {
+        println!("HLE IOS backend thread started");
+        self.boot_ppc();
+        loop {
+            self.step();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+//! HLE for `/dev/es`, which only reports the set of installed titles.
+
+use ironic_core::bus::*;
+use crate::hle::*;
+
+pub const ES_EINVAL: i32 = -1017;
+
+const IOCTLV_GET_TITLE_COUNT: u32 = 0x0e;
+const IOCTLV_GET_TITLES: u32 = 0x0f;
+const IOCTLV_GET_TITLE_ID: u32 = 0x20;
+
+/// The title ID reported for the running title (the system menu).
+const DEFAULT_TITLE_ID: u64 = 0x0000_0001_0000_0002;
+
+pub struct EsManager {
+    /// The set of installed titles.
+    pub titles: Vec<u64>,
+    /// The ID of the running title.
+    pub title_id: u64,
+}
+impl EsManager {
+    pub fn new(titles: Vec<u64>) -> Self {
+        EsManager { titles, title_id: DEFAULT_TITLE_ID }
+    }
+}
+
+impl ResourceManager for EsManager {
+    fn handles(&self, path: &str) -> bool { path == "/dev/es" }
+
+    fn open(&mut self, _path: &str, _mode: u32) -> Result<u32, i32> { Ok(0) }
+
+    fn ioctlv(&mut self, bus: &mut Bus, _handle: u32, cmd: u32,
+        input: &[IoctlvArgEntry], output: &[IoctlvArgEntry]) -> i32
+    {
+        match (cmd, input, output) {
+            (IOCTLV_GET_TITLE_COUNT, [], [count]) => {
+                let num = self.titles.len() as u32;
+                if !guest_write(bus, count.data, &num.to_be_bytes()) {
+                    return ES_EINVAL;
+                }
+                IPC_OK
+            },
+            (IOCTLV_GET_TITLES, [count], [titles]) => {
+                let num = match guest_read32(bus, count.data) {
+                    Some(num) => num as usize,
+                    None => return ES_EINVAL,
+                };
+                let buf: Vec<u8> = self.titles.iter().take(num)
+                    .flat_map(|t| t.to_be_bytes()).collect();
+                if buf.len() > titles.len as usize || !guest_write(bus, titles.data, &buf) {
+                    return ES_EINVAL;
+                }
+                IPC_OK
+            },
+            (IOCTLV_GET_TITLE_ID, [], [id]) => {
+                if !guest_write(bus, id.data, &self.title_id.to_be_bytes()) {
+                    return ES_EINVAL;
+                }
+                IPC_OK
+            },
+            _ => {
+                println!("HLE ES unimplemented ioctlv {:02x} ({} in, {} out)",
+                    cmd, input.len(), output.len());
+                ES_EINVAL
+            },
+        }
+    }
+}
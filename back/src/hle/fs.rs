@@ -0,0 +1,413 @@
+//! HLE for `/dev/fs` and files on the NAND, backed by a host directory.
+
+use ironic_core::bus::*;
+use crate::hle::*;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf, Component};
+
+pub const FS_EINVAL: i32 = -101;
+pub const FS_EACCESS: i32 = -102;
+pub const FS_EEXIST: i32 = -105;
+pub const FS_ENOENT: i32 = -106;
+
+const IOCTL_CREATE_DIR: u32 = 0x03;
+const IOCTLV_READ_DIR: u32 = 0x04;
+const IOCTL_DELETE: u32 = 0x07;
+const IOCTL_RENAME: u32 = 0x08;
+const IOCTL_CREATE_FILE: u32 = 0x09;
+const IOCTL_GET_FILE_STATS: u32 = 0x0b;
+
+/// Offset of the path in the arguments for creating files/directories.
+const CREATE_PATH_OFF: usize = 0x06;
+/// Length of a path in the arguments to some ioctl.
+const PATH_LEN: usize = 0x40;
+/// Maximum length of a filename (including the NUL terminator).
+const NAME_LEN: usize = 13;
+
+/// Something referred to by an open handle.
+enum FsHandle {
+    /// The `/dev/fs` device itself.
+    Device,
+    /// An open file.
+    File(File),
+}
+
+/// Convert a NUL-terminated buffer into a string.
+fn cstr(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+pub struct FsManager {
+    /// The host directory used as the root of the filesystem.
+    root: PathBuf,
+    /// Open handles.
+    handles: BTreeMap<u32, FsHandle>,
+    /// The next handle number to hand out.
+    next_handle: u32,
+}
+impl FsManager {
+    pub fn new(root: &str) -> Result<Self, String> {
+        let root = Path::new(root).canonicalize()
+            .map_err(|e| format!("Couldn't open {}: {}", root, e))?;
+        if !root.is_dir() {
+            return Err(format!("{} isn't a directory", root.display()));
+        }
+        Ok(FsManager { root, handles: BTreeMap::new(), next_handle: 0 })
+    }
+
+    /// Returns the IDs of all titles installed under `/title`.
+    pub fn titles(&self) -> Vec<u64> {
+        let mut res = Vec::new();
+        let hi_dirs = match fs::read_dir(self.root.join("title")) {
+            Ok(d) => d,
+            Err(_) => return res,
+        };
+        for hi in hi_dirs.flatten() {
+            let hi_name = hi.file_name().to_string_lossy().to_string();
+            let hi_id = match u32::from_str_radix(&hi_name, 16) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            for lo in fs::read_dir(hi.path()).into_iter().flatten().flatten() {
+                let lo_name = lo.file_name().to_string_lossy().to_string();
+                if let Ok(lo_id) = u32::from_str_radix(&lo_name, 16) {
+                    res.push((hi_id as u64) << 32 | lo_id as u64);
+                }
+            }
+        }
+        res.sort_unstable();
+        res
+    }
+
+    /// Resolve a NAND path to a path in the host directory.
+    fn resolve(&self, path: &str) -> Result<PathBuf, i32> {
+        let path = Path::new(path);
+        if !path.has_root() {
+            return Err(FS_EINVAL);
+        }
+        let mut res = self.root.clone();
+        for c in path.components() {
+            match c {
+                Component::RootDir => {},
+                Component::Normal(name) => res.push(name),
+                _ => return Err(FS_EINVAL),
+            }
+        }
+
+        if res == self.root {
+            return Ok(res);
+        }
+
+        // Catch symbolic links which point outside of the directory. The
+        // target may not exist yet, but its parent always has to.
+        let parent = res.parent().and_then(|p| p.canonicalize().ok()).ok_or(FS_ENOENT)?;
+        if !parent.starts_with(&self.root) {
+            return Err(FS_EACCESS);
+        }
+        let res = parent.join(res.file_name().unwrap());
+        if res.symlink_metadata().is_ok() {
+            // Dangling links are rejected too, since creating a file would
+            // follow them
+            match res.canonicalize() {
+                Ok(p) if p.starts_with(&self.root) => {},
+                _ => return Err(FS_EACCESS),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Read the path from the input buffer for some ioctl.
+    fn ioctl_path(bus: &mut Bus, addr: u32) -> Result<String, i32> {
+        let mut buf = [0u8; PATH_LEN];
+        if !guest_read(bus, addr, &mut buf) {
+            return Err(FS_EINVAL);
+        }
+        Ok(cstr(&buf))
+    }
+
+    fn create_dir(&self, path: &str) -> i32 {
+        let dir = match self.resolve(path) {
+            Ok(dir) => dir,
+            Err(e) => return e,
+        };
+        if dir.exists() {
+            return FS_EEXIST;
+        }
+        match fs::create_dir(&dir) {
+            Ok(_) => IPC_OK,
+            Err(_) => FS_ENOENT,
+        }
+    }
+
+    fn create_file(&self, path: &str) -> i32 {
+        let file = match self.resolve(path) {
+            Ok(file) => file,
+            Err(e) => return e,
+        };
+        if file.exists() {
+            return FS_EEXIST;
+        }
+        match File::create(&file) {
+            Ok(_) => IPC_OK,
+            Err(_) => FS_ENOENT,
+        }
+    }
+
+    fn delete(&self, path: &str) -> i32 {
+        let target = match self.resolve(path) {
+            Ok(target) => target,
+            Err(e) => return e,
+        };
+        // Don't let the guest remove the whole host directory
+        if target == self.root {
+            return FS_EACCESS;
+        }
+        let res = if target.is_dir() {
+            fs::remove_dir_all(&target)
+        } else {
+            fs::remove_file(&target)
+        };
+        match res {
+            Ok(_) => IPC_OK,
+            Err(_) => FS_ENOENT,
+        }
+    }
+
+    fn rename(&self, old: &str, new: &str) -> i32 {
+        match (self.resolve(old), self.resolve(new)) {
+            (Ok(old), Ok(new)) if old == self.root || new == self.root => FS_EACCESS,
+            (Ok(old), Ok(new)) => match fs::rename(old, new) {
+                Ok(_) => IPC_OK,
+                Err(_) => FS_ENOENT,
+            },
+            (Err(e), _) | (_, Err(e)) => e,
+        }
+    }
+
+    /// List the names of entries in some directory.
+    fn read_dir(&self, path: &str) -> Result<Vec<String>, i32> {
+        let dir = self.resolve(path)?;
+        let mut names: Vec<String> = fs::read_dir(dir).map_err(|_| FS_ENOENT)?
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn file(&mut self, handle: u32) -> Option<&mut File> {
+        match self.handles.get_mut(&handle)? {
+            FsHandle::File(f) => Some(f),
+            FsHandle::Device => None,
+        }
+    }
+}
+
+impl ResourceManager for FsManager {
+    fn handles(&self, path: &str) -> bool {
+        path == "/dev/fs" || (path.starts_with('/') && !path.starts_with("/dev/"))
+    }
+
+    fn open(&mut self, path: &str, mode: u32) -> Result<u32, i32> {
+        let handle = if path == "/dev/fs" {
+            FsHandle::Device
+        } else {
+            // Modes are a mask of read (1) and write (2) access
+            let file = self.resolve(path)?;
+            if !file.is_file() {
+                return Err(FS_ENOENT);
+            }
+            let f = OpenOptions::new()
+                .read(mode & 1 != 0)
+                .write(mode & 2 != 0)
+                .open(&file)
+                .map_err(|_| FS_EACCESS)?;
+            FsHandle::File(f)
+        };
+        let num = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(num, handle);
+        Ok(num)
+    }
+
+    fn close(&mut self, handle: u32) -> i32 {
+        match self.handles.remove(&handle) {
+            Some(_) => IPC_OK,
+            None => IPC_EINVAL,
+        }
+    }
+
+    // Buffers are checked as a whole before anything is copied. Since they
+    // must be in a single memory device, the length always fits in an i32.
+
+    fn read(&mut self, bus: &mut Bus, handle: u32, addr: u32, len: u32) -> i32 {
+        let f = match self.file(handle) {
+            Some(f) => f,
+            None => return FS_EINVAL,
+        };
+        if len != 0 && !bus.is_mem_range(addr, len) {
+            return FS_EINVAL;
+        }
+        let mut buf = vec![0u8; (len as usize).min(COPY_CHUNK_LEN)];
+        let mut done = 0;
+        while done < len as usize {
+            let chunk = (len as usize - done).min(buf.len());
+            let n = match f.read(&mut buf[..chunk]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(_) => return FS_EACCESS,
+            };
+            bus.dma_write(addr + done as u32, &buf[..n]);
+            done += n;
+        }
+        done as i32
+    }
+
+    fn write(&mut self, bus: &mut Bus, handle: u32, addr: u32, len: u32) -> i32 {
+        let f = match self.file(handle) {
+            Some(f) => f,
+            None => return FS_EINVAL,
+        };
+        if len != 0 && !bus.is_mem_range(addr, len) {
+            return FS_EINVAL;
+        }
+        let mut buf = vec![0u8; (len as usize).min(COPY_CHUNK_LEN)];
+        let mut done = 0;
+        while done < len as usize {
+            let chunk = (len as usize - done).min(buf.len());
+            bus.dma_read(addr + done as u32, &mut buf[..chunk]);
+            if f.write_all(&buf[..chunk]).is_err() {
+                return FS_EACCESS;
+            }
+            done += chunk;
+        }
+        done as i32
+    }
+
+    fn seek(&mut self, handle: u32, offset: i32, whence: u32) -> i32 {
+        let f = match self.file(handle) {
+            Some(f) => f,
+            None => return FS_EINVAL,
+        };
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u32 as u64),
+            1 => SeekFrom::Current(offset as i64),
+            2 => SeekFrom::End(offset as i64),
+            _ => return FS_EINVAL,
+        };
+        match f.seek(pos) {
+            Ok(off) => off as i32,
+            Err(_) => FS_EINVAL,
+        }
+    }
+
+    fn ioctl(&mut self, bus: &mut Bus, handle: u32, arg: &IoctlArg) -> Option<i32> {
+        if arg.cmd == IOCTL_GET_FILE_STATS {
+            // Output is the length of the file and the current offset
+            let f = match self.file(handle) {
+                Some(f) => f,
+                None => return Some(FS_EINVAL),
+            };
+            let len = f.metadata().map(|m| m.len()).unwrap_or(0) as u32;
+            let off = f.stream_position().unwrap_or(0) as u32;
+            let mut buf = [0u8; 8];
+            buf[0..4].copy_from_slice(&len.to_be_bytes());
+            buf[4..8].copy_from_slice(&off.to_be_bytes());
+            if !guest_write(bus, arg.output_addr, &buf) {
+                return Some(FS_EINVAL);
+            }
+            return Some(IPC_OK);
+        }
+
+        let path = match Self::ioctl_path(bus, arg.input_addr) {
+            Ok(path) => path,
+            Err(e) => return Some(e),
+        };
+        Some(match arg.cmd {
+            IOCTL_CREATE_DIR | IOCTL_CREATE_FILE => {
+                // The path follows the owner and group IDs
+                let mut buf = [0u8; CREATE_PATH_OFF + PATH_LEN];
+                if !guest_read(bus, arg.input_addr, &mut buf) {
+                    return Some(FS_EINVAL);
+                }
+                let path = cstr(&buf[CREATE_PATH_OFF..]);
+                println!("HLE FS create {}", path);
+                if arg.cmd == IOCTL_CREATE_DIR {
+                    self.create_dir(&path)
+                } else {
+                    self.create_file(&path)
+                }
+            },
+            IOCTL_DELETE => {
+                println!("HLE FS delete {}", path);
+                self.delete(&path)
+            },
+            IOCTL_RENAME => {
+                let new = match arg.input_addr.checked_add(PATH_LEN as u32) {
+                    Some(addr) => Self::ioctl_path(bus, addr),
+                    None => Err(FS_EINVAL),
+                };
+                let new = match new {
+                    Ok(new) => new,
+                    Err(e) => return Some(e),
+                };
+                println!("HLE FS rename {} to {}", path, new);
+                self.rename(&path, &new)
+            },
+            _ => {
+                println!("HLE FS unimplemented ioctl {:02x}", arg.cmd);
+                IPC_EINVAL
+            },
+        })
+    }
+
+    fn ioctlv(&mut self, bus: &mut Bus, _handle: u32, cmd: u32,
+        input: &[IoctlvArgEntry], output: &[IoctlvArgEntry]) -> i32
+    {
+        if cmd != IOCTLV_READ_DIR || input.is_empty() || output.is_empty() {
+            println!("HLE FS unimplemented ioctlv {:02x}", cmd);
+            return IPC_EINVAL;
+        }
+        let path = match Self::ioctl_path(bus, input[0].data) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+        let names = match self.read_dir(&path) {
+            Ok(names) => names,
+            Err(e) => return e,
+        };
+
+        // With one output, only the number of entries is returned. Otherwise
+        // the NUL-terminated names are packed into the first output, and the
+        // count is written to the second.
+        if output.len() == 1 {
+            let count = (names.len() as u32).to_be_bytes();
+            return if guest_write(bus, output[0].data, &count) { IPC_OK } else { FS_EINVAL };
+        }
+        if input.len() < 2 {
+            return FS_EINVAL;
+        }
+        let max = match guest_read32(bus, input[1].data) {
+            Some(max) => max as usize,
+            None => return FS_EINVAL,
+        };
+        let mut buf = Vec::new();
+        for name in names.iter().take(max) {
+            let len = name.len().min(NAME_LEN - 1);
+            buf.extend_from_slice(&name.as_bytes()[..len]);
+            buf.push(0);
+        }
+        let count = (names.len().min(max) as u32).to_be_bytes();
+        if buf.len() > output[0].len as usize
+            || !guest_write(bus, output[0].data, &buf)
+            || !guest_write(bus, output[1].data, &count)
+        {
+            return FS_EINVAL;
+        }
+        IPC_OK
+    }
+}
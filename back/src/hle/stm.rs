@@ -0,0 +1,113 @@
+//! HLE for `/dev/stm/immediate` and `/dev/stm/eventhook`.
+//!
+//! The eventhook ioctl stays pending until some event occurs, or until the
+//! PowerPC side asks for it to be released.
+
+use ironic_core::bus::*;
+use crate::hle::*;
+
+use std::collections::BTreeMap;
+
+pub const STM_EINVAL: i32 = -6;
+
+const IOCTL_EVENTHOOK: u32 = 0x1000;
+const IOCTL_HOTRESET: u32 = 0x2001;
+const IOCTL_SHUTDOWN: u32 = 0x2003;
+const IOCTL_RELEASE_EH: u32 = 0x3002;
+const IOCTL_VIDIMMING: u32 = 0x5001;
+const IOCTL_LEDMODE: u32 = 0x6002;
+
+/// Something referred to by an open handle.
+#[derive(PartialEq)]
+enum StmHandle { Immediate, EventHook }
+
+#[derive(Default)]
+pub struct StmManager {
+    /// Open handles.
+    handles: BTreeMap<u32, StmHandle>,
+    /// The next handle number to hand out.
+    next_handle: u32,
+    /// Handle with a pending eventhook request, if any.
+    eventhook: Option<u32>,
+    /// Completed requests waiting to be replied to.
+    completed: Vec<(u32, i32)>,
+    /// Set when the PowerPC side asks to shut down or reset.
+    pub power_event: Option<u32>,
+}
+impl StmManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Complete the pending eventhook request (if any) with some event.
+    pub fn send_event(&mut self, event: i32) {
+        if let Some(handle) = self.eventhook.take() {
+            self.completed.push((handle, event));
+        }
+    }
+}
+
+impl ResourceManager for StmManager {
+    fn handles(&self, path: &str) -> bool {
+        path == "/dev/stm/immediate" || path == "/dev/stm/eventhook"
+    }
+
+    fn open(&mut self, path: &str, _mode: u32) -> Result<u32, i32> {
+        let handle = if path == "/dev/stm/immediate" {
+            StmHandle::Immediate
+        } else {
+            StmHandle::EventHook
+        };
+        let num = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(num, handle);
+        Ok(num)
+    }
+
+    fn close(&mut self, handle: u32) -> i32 {
+        if self.eventhook == Some(handle) {
+            self.send_event(0);
+        }
+        self.handles.remove(&handle);
+        IPC_OK
+    }
+
+    fn ioctl(&mut self, _bus: &mut Bus, handle: u32, arg: &IoctlArg) -> Option<i32> {
+        let kind = match self.handles.get(&handle) {
+            Some(kind) => kind,
+            None => return Some(STM_EINVAL),
+        };
+        if *kind == StmHandle::EventHook {
+            if arg.cmd != IOCTL_EVENTHOOK || self.eventhook.is_some() {
+                return Some(STM_EINVAL);
+            }
+            self.eventhook = Some(handle);
+            return None;
+        }
+
+        Some(match arg.cmd {
+            IOCTL_RELEASE_EH => {
+                if self.eventhook.is_none() {
+                    return Some(STM_EINVAL);
+                }
+                self.send_event(0);
+                IPC_OK
+            },
+            IOCTL_HOTRESET | IOCTL_SHUTDOWN => {
+                println!("HLE STM {} requested",
+                    if arg.cmd == IOCTL_HOTRESET { "reset" } else { "shutdown" });
+                self.power_event = Some(arg.cmd);
+                IPC_OK
+            },
+            IOCTL_VIDIMMING | IOCTL_LEDMODE => IPC_OK,
+            _ => {
+                println!("HLE STM unimplemented ioctl {:04x}", arg.cmd);
+                STM_EINVAL
+            },
+        })
+    }
+
+    fn poll(&mut self) -> Vec<(u32, i32)> {
+        std::mem::take(&mut self.completed)
+    }
+}
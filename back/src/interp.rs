@@ -4,19 +4,24 @@ pub mod arm;
 pub mod thumb;
 pub mod dispatch;
 pub mod lut;
+pub mod cycles;
+pub mod semihosting;
 
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::back::*;
 use crate::interp::lut::*;
 use crate::interp::dispatch::DispatchRes;
+use crate::interp::cycles::*;
+use crate::interp::semihosting::Semihosting;
 
 use crate::decode::arm::*;
 use crate::decode::thumb::*;
 
 use ironic_core::bus::*;
 use ironic_core::cpu::{Cpu, CpuRes};
-use ironic_core::cpu::reg::Reg;
+use ironic_core::cpu::reg::{Reg, CpuMode};
 use ironic_core::cpu::excep::ExceptionType;
 
 /// Current stage in the platform's boot process.
@@ -36,6 +41,9 @@ pub enum BootStatus {
     /// Execution in a user-loaded foreign kernel.
     UserKernelStub, 
     UserKernel, 
+
+    /// Execution in a bare-metal program loaded directly into memory.
+    BareMetal,
 }
 
 /// Backend for interpreting-style emulation. 
@@ -44,12 +52,13 @@ pub enum BootStatus {
 ///
 /// - Execute all pending work on the bus
 /// - Update the state of any signals from the bus to the CPU
-/// - Decode/dispatch an instruction, mutating the CPU state
+/// - Decode/dispatch instructions until the next event on the bus is due,
+///   mutating the CPU state
 ///
-/// For now it's sufficient to perfectly interleave bus and CPU cycles, but
-/// maybe at some point it will become more efficient to let dispatched
-/// instructions return some hint to the backend (requesting that a bus cycle
-/// should be completed before the next instruction).
+/// A batch of instructions ends early when some instruction writes to an I/O 
+/// device, since the write may have side-effects which the bus needs to deal
+/// with before the next instruction (i.e. changes to the ROM mapping, or to
+/// the state of the IRQ lines).
 
 pub struct InterpBackend {
     /// Reference to a bus (attached to memories and devices).
@@ -60,28 +69,85 @@ pub struct InterpBackend {
 
     /// Number of CPU cycles elapsed.
     pub cpu_cycle: usize,
-    /// Number of bus cycles elapsed.
+    /// Timing state used to work out the cost of each instruction.
+    cycles: CycleModel,
+    /// Number of times the bus has been stepped.
     pub bus_cycle: usize,
+    /// Number of CPU steps taken. Unlike the cycle count, this isn't moved
+    /// forward when cycles are skipped in realtime mode.
+    pub cpu_steps: usize,
 
-    /// Buffer for semi-hosting debug writes.
-    pub svc_buf: String,
+    /// Semihosting state, if semihosting calls are enabled.
+    pub semihosting: Option<Semihosting>,
     /// Current stage in the platform boot process.
     pub boot_status: BootStatus,
+    /// Exit status reported by the guest with a semihosting call.
+    pub exit_status: Option<i32>,
+
+    /// When set, emulated time is kept in step with host time.
+    pub realtime: bool,
+    /// The host time, CPU cycle, and clock rate that emulated time is
+    /// measured from in realtime mode.
+    realtime_base: Option<(Instant, usize, u64)>,
 }
 impl InterpBackend {
     pub fn new(bus: Arc<RwLock<Bus>>) -> Self {
         InterpBackend {
-            svc_buf: String::new(),
+            semihosting: None,
             cpu: Cpu::new(bus.clone()),
             boot_status: BootStatus::Boot0,
+            exit_status: None,
+            realtime: false,
+            realtime_base: None,
             cpu_cycle: 0,
+            cycles: CycleModel::default(),
             bus_cycle: 0,
+            cpu_steps: 0,
             bus,
         }
     }
 }
 
 impl InterpBackend {
+    /// Start executing a kernel which was loaded directly into memory.
+    pub fn boot_kernel(&mut self, entry: u32) {
+        self.cpu.reset_to_entry(entry);
+        self.boot_status = BootStatus::IOSKernel;
+    }
+
+    /// Start executing a bare-metal program which was loaded directly into
+    /// memory, with the CPU in some mode and with some stack pointer.
+    pub fn boot_program(&mut self, entry: u32, mode: CpuMode, sp: u32) {
+        self.cpu.reset_to_entry(entry);
+        self.cpu.set_mode_and_stack(mode, sp);
+        self.boot_status = BootStatus::BareMetal;
+    }
+
+    /// Keep emulated time in step with host time: sleep while the guest is
+    /// ahead, and skip cycles when it falls behind.
+    fn sync_realtime(&mut self) {
+        let hz = self.bus.read().unwrap().hlwd.pll.starlet_hz();
+        let (start, base_cycle, base_hz) = match self.realtime_base {
+            Some(base) if base.2 == hz => base,
+            _ => {
+                let base = (Instant::now(), self.cpu_cycle, hz);
+                self.realtime_base = Some(base);
+                base
+            },
+        };
+        let elapsed = start.elapsed().as_nanos() * base_hz as u128 / 1_000_000_000;
+        let host_cycle = base_cycle + elapsed as usize;
+        if self.cpu_cycle < host_cycle {
+            self.cpu_cycle = host_cycle;
+        } else {
+            let ahead = (self.cpu_cycle - host_cycle) as u128;
+            let ns = ahead * 1_000_000_000 / base_hz as u128;
+            if ns >= 1_000_000 {
+                std::thread::sleep(Duration::from_nanos(ns as u64));
+            }
+        }
+    }
+
     /// Check if we need to update the current boot stage.
     pub fn update_boot_status(&mut self) {
         match self.boot_status {
@@ -125,33 +191,6 @@ impl InterpBackend {
         }
     }
 
-    /// Write semihosting debug strings to stdout.
-    pub fn svc_read(&mut self) {
-        use ironic_core::cpu::mmu::prim::{TLBReq, Access};
-
-        // On the SVC calls, r1 should contain a pointer to some buffer.
-        // They might be virtual addresses, so we need to do an out-of-band
-        // request to MMU code in order to resolve the actual location.
-        let paddr = self.cpu.translate(
-            TLBReq::new(self.cpu.reg.r[1], Access::Debug)
-        );
-
-        // Pull the buffer out of guest memory
-        let mut line_buf = [0u8; 16];
-        self.bus.write().unwrap().dma_read(paddr, &mut line_buf);
-
-        let s = std::str::from_utf8(&line_buf).unwrap()
-            .trim_matches(char::from(0));
-        self.svc_buf += s;
-
-        if self.svc_buf.find('\n').is_some() {
-            let string: String = self.svc_buf.chars()
-                .take(self.svc_buf.find('\n').unwrap()).collect();
-            println!("SVC {}", string);
-            self.svc_buf.clear();
-        }
-    }
-
     /// Log IOS syscalls to stdout.
     pub fn syscall_log(&mut self, opcd: u32) {
         println!("IOS syscall {:08x}, lr={:08x}", opcd, self.cpu.reg[Reg::Lr]);
@@ -219,33 +258,51 @@ impl InterpBackend {
         }
     }
 
-    /// Do a single step of the CPU.
+    /// Do a single step of the CPU, adding the cost of the instruction to
+    /// the cycle count.
     pub fn cpu_step(&mut self) -> CpuRes {
         assert!((self.cpu.read_fetch_pc() & 1) == 0);
 
-        // Sample the IRQ line. If the IRQ line is high and IRQs are not 
-        // disabled in the CPSR, take an IRQ exception. 
-        if !self.cpu.reg.cpsr.irq_disable() && self.cpu.irq_input {
-            self.cpu.generate_exception(ExceptionType::Irq);
+        // Sample the FIQ and IRQ lines. If a line is high and the interrupt
+        // is not disabled in the CPSR, take an exception (FIQs first).
+        let irq = if !self.cpu.reg.cpsr.fiq_disable() && self.cpu.fiq_input {
+            Some(ExceptionType::Fiq)
+        } else if !self.cpu.reg.cpsr.irq_disable() && self.cpu.irq_input {
+            Some(ExceptionType::Irq)
+        } else {
+            None
+        };
+        // The handler is entered on the next step, so that the refill can't
+        // push the first instruction of the handler past the end of a batch.
+        if let Some(e) = irq {
+            self.cpu.generate_exception(e);
+            self.cycles.flush();
+            self.cpu_cycle += BRANCH_REFILL_CYCLES;
+            self.update_boot_status();
+            return CpuRes::StepOk;
         }
 
         // Fetch/decode/execute an ARM or Thumb instruction depending on
         // the state of the Thumb flag in the CPSR.
-        let disp_res = if self.cpu.reg.cpsr.thumb() {
+        let thumb = self.cpu.reg.cpsr.thumb();
+        let (disp_res, cost, opcd) = if thumb {
             self.dbg_print();
-            let opcd = self.cpu.read16(self.cpu.read_fetch_pc());
+            let opcd = self.cpu.fetch16(self.cpu.read_fetch_pc());
             let func = INTERP_LUT.thumb.lookup(opcd);
-            func.0(&mut self.cpu, opcd)
+            (func.0(&mut self.cpu, opcd), INTERP_LUT.thumb.cost(opcd), opcd as u32)
         } else {
             self.dbg_print();
-            let opcd = self.cpu.read32(self.cpu.read_fetch_pc());
+            let opcd = self.cpu.fetch32(self.cpu.read_fetch_pc());
+            let cost = INTERP_LUT.arm.cost(opcd);
             if self.cpu.reg.cond_pass(opcd) {
                 let func = INTERP_LUT.arm.lookup(opcd);
-                func.0(&mut self.cpu, opcd)
+                (func.0(&mut self.cpu, opcd), cost, opcd)
             } else {
-                DispatchRes::CondFailed
+                (DispatchRes::CondFailed, cost, opcd)
             }
         };
+        self.cpu_cycle += self.cycles.cycles(cost, opcd, thumb, &disp_res);
+        self.cpu_cycle += std::mem::take(&mut self.cpu.stall_cycles);
 
         // Depending on the instruction, adjust the program counter
         let cpu_res = match disp_res {
@@ -256,16 +313,16 @@ impl InterpBackend {
                 CpuRes::StepOk
             },
 
-            // NOTE: Skyeye doesn't take SWI exceptions at all, but I wonder
-            // why this is permissible. What does the hardware actually do?
+            // SVC instructions are only treated as semihosting calls when
+            // semihosting is enabled
+            DispatchRes::Exception(ExceptionType::Swi)
+                if self.is_semihosting_call(opcd, thumb) => {
+                self.cpu.increment_pc();
+                CpuRes::Semihosting
+            },
             DispatchRes::Exception(e) => {
-                if e == ExceptionType::Swi {
-                    self.cpu.increment_pc();
-                    CpuRes::Semihosting
-                } else {
-                    self.cpu.generate_exception(e);
-                    CpuRes::StepException(e)
-                }
+                self.cpu.generate_exception(e);
+                CpuRes::StepException(e)
             },
 
             DispatchRes::FatalErr => {
@@ -281,35 +338,57 @@ impl InterpBackend {
 
 impl Backend for InterpBackend {
     fn run(&mut self) {
-        for _step in 0..0x8000_0000usize {
+        'run: while self.cpu_steps < 0x8000_0000usize {
+            if self.realtime {
+                self.sync_realtime();
+            }
 
-            // Take ownership of the bus to deal with any pending tasks
-            {
+            // Take ownership of the bus to deal with any pending tasks, and
+            // figure out how long we can run until the next event
+            let batch = {
                 let mut bus = self.bus.write().unwrap();
                 bus.step(self.cpu_cycle);
                 self.bus_cycle += 1;
+                if bus.hlwd.gpio.shutdown() {
+                    println!("Guest asserted the shutdown pin");
+                    break 'run;
+                }
                 self.cpu.irq_input = bus.hlwd.irq.arm_irq_output;
-            }
-
-            // Before each CPU step, check if we need to patch any close code
-            self.hotpatch_check();
-
-            let res = self.cpu_step();
-            match res {
-                CpuRes::StepOk => {},
-                CpuRes::HaltEmulation => break,
-                CpuRes::StepException(e) => {
-                    match e {
-                        ExceptionType::Undef(_) => {},
-                        ExceptionType::Irq => {},
-                        _ => panic!("Unimplemented exception type {:?}", e),
+                self.cpu.fiq_input = bus.hlwd.irq.arm_fiq_output;
+                bus.cycles_until_next_event()
+            };
+            self.cpu.bus_sync = false;
+
+            let batch_end = self.cpu_cycle + batch;
+            while self.cpu_cycle < batch_end {
+                // Before each CPU step, check if we need to patch any close code
+                self.hotpatch_check();
+
+                let res = self.cpu_step();
+                self.cpu_steps += 1;
+                match res {
+                    CpuRes::StepOk => {},
+                    CpuRes::HaltEmulation => break 'run,
+                    CpuRes::StepException(e) => {
+                        match e {
+                            ExceptionType::Undef(_) => {},
+                            ExceptionType::Irq => {},
+                            ExceptionType::Swi => {},
+                            _ => panic!("Unimplemented exception type {:?}", e),
+                        }
+                    },
+                    CpuRes::Semihosting => {
+                        if self.semihosting() {
+                            break 'run;
+                        }
                     }
-                },
-                CpuRes::Semihosting => {
-                    self.svc_read();
+                }
+
+                // Some write needs to be handled by the bus before continuing
+                if self.cpu.bus_sync {
+                    break;
                 }
             }
-            self.cpu_cycle += 1;
         }
         println!("CPU stopped at pc={:08x}", self.cpu.read_fetch_pc());
     }
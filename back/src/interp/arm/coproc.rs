@@ -6,7 +6,7 @@ use crate::interp::DispatchRes;
 
 pub fn mcr(cpu: &mut Cpu, op: MoveCoprocBits) -> DispatchRes {
     assert_eq!(op.coproc(), 15);
-    cpu.p15.write(cpu.reg[op.rt()], op.crn(), op.crm(), op.opc2());
+    cpu.p15_write(cpu.reg[op.rt()], op.crn(), op.crm(), op.opc2());
     DispatchRes::RetireOk
 }
 
@@ -16,7 +16,7 @@ pub fn mrc(cpu: &mut Cpu, op: MoveCoprocBits) -> DispatchRes {
         let val = cpu.p15.read(op.crn(), op.crm(), op.opc2());
         cpu.reg[op.rt()] = val;
     } else {
-        let val = cpu.p15.read_alt(op.crn(), op.crm(), op.opc2());
+        let val = cpu.p15_read_alt(op.crn(), op.crm(), op.opc2());
         if val.n.is_some() { cpu.reg.cpsr.set_n(val.n.unwrap()); }
         if val.z.is_some() { cpu.reg.cpsr.set_z(val.z.unwrap()); }
         if val.c.is_some() { cpu.reg.cpsr.set_c(val.c.unwrap()); }
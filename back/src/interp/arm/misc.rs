@@ -0,0 +1,8 @@
+use crate::bits::arm::*;
+use crate::interp::DispatchRes;
+use ironic_core::cpu::Cpu;
+use ironic_core::cpu::excep::ExceptionType;
+
+pub fn svc(_cpu: &mut Cpu, _op: BranchBits) -> DispatchRes {
+    DispatchRes::Exception(ExceptionType::Swi)
+}
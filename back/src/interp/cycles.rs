@@ -0,0 +1,287 @@
+//! Instruction timings for the interpreter backend.
+//!
+//! Costs are taken from the ARM9E-S/ARM926EJ-S cycle timings, assuming that
+//! every access hits in the caches and TCMs. Each entry in the lookup tables
+//! has a [CostClass], and [CycleModel] turns it into a number of cycles after
+//! the instruction has been dispatched.
+
+use crate::decode::arm::ArmInst;
+use crate::decode::thumb::ThumbInst;
+use crate::interp::DispatchRes;
+
+/// Cycles spent refilling the pipeline after a branch or an exception.
+pub const BRANCH_REFILL_CYCLES: usize = 2;
+/// Cycles spent refilling the pipeline after a load to the PC.
+pub const LOAD_PC_REFILL_CYCLES: usize = 4;
+
+/// The timing class of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CostClass {
+    /// Data processing with an immediate or an immediate shift.
+    Alu,
+    /// Data processing with a register-specified shift.
+    AluShiftReg,
+    /// MUL/MLA (two more cycles when setting flags).
+    Mul,
+    /// UMULL/UMLAL/SMULL/SMLAL (two more cycles when setting flags).
+    MulLong,
+    /// The 16-bit DSP multiplies.
+    MulHalf,
+    /// SMLAL<x><y>.
+    MulHalfLong,
+    /// Single loads, with the number of cycles before the result is
+    /// available, and the bit offset of the destination register.
+    Load(u8, u8),
+    /// LDRD.
+    LoadDouble,
+    /// Single stores.
+    Store,
+    /// STRD.
+    StoreDouble,
+    /// Block loads, with the mask of the register list.
+    LoadMulti(u32),
+    /// Block stores, with the mask of the register list.
+    StoreMulti(u32),
+    /// Branches (the refill is added when the branch is taken).
+    Branch,
+    /// MCR/MRC/LDC/STC.
+    Coproc,
+    /// MCRR/MRRC.
+    CoprocDouble,
+    /// MRS.
+    Mrs,
+    /// MSR (two more cycles when writing the control field).
+    Msr,
+    /// Instructions which always cause an exception.
+    Exception,
+}
+
+impl CostClass {
+    pub const fn from_arm(inst: ArmInst) -> Self {
+        use ArmInst::*;
+        match inst {
+            AndRegShiftReg | AdcRegShiftReg | MovRegShiftReg | OrrRegShiftReg |
+            EorRegShiftReg | RscRegShiftReg | MvnRegShiftReg | SbcRegShiftReg |
+            AddRegShiftReg | BicRegShiftReg | RsbRegShiftReg | SubRegShiftReg |
+            TeqRegShiftReg | CmnRegShiftReg | TstRegShiftReg | CmpRegShiftReg
+                => CostClass::AluShiftReg,
+
+            Mul | Mla => CostClass::Mul,
+            Smull | Umlal | Smlal | Umull => CostClass::MulLong,
+            Smulwb | Smlawb | Smlabb | Smulbb => CostClass::MulHalf,
+            Smlalbb => CostClass::MulHalfLong,
+
+            LdrImm | LdrReg | Ldrt | LdrtAlt => CostClass::Load(1, 12),
+            LdrhImm | LdrbImm | LdrsbImm | LdrshImm |
+            LdrbReg | LdrhReg | LdrsbReg | LdrshReg |
+            Ldrbt | LdrbtAlt => CostClass::Load(2, 12),
+            LdrdImm | LdrdReg => CostClass::LoadDouble,
+            StrImm | StrhImm | StrbImm | StrReg | StrbReg | StrhReg |
+            Strbt | Strt | StrbtAlt | StrtAlt => CostClass::Store,
+            StrdImm | StrdReg => CostClass::StoreDouble,
+            Ldmda | Ldmib | Ldmdb | Ldm | LdmRegUser => CostClass::LoadMulti(0xffff),
+            Stm | Stmda | Stmdb | Stmib | StmRegUser => CostClass::StoreMulti(0xffff),
+
+            B | BlImm | Bx | BlxReg | Bxj => CostClass::Branch,
+            Mrc | Mcr | Stc | LdcImm => CostClass::Coproc,
+            Mcrr | Mrrc => CostClass::CoprocDouble,
+            Mrs => CostClass::Mrs,
+            MsrImm | MsrReg => CostClass::Msr,
+            Svc | Bkpt | Undefined => CostClass::Exception,
+            _ => CostClass::Alu,
+        }
+    }
+
+    pub const fn from_thumb(inst: ThumbInst) -> Self {
+        use ThumbInst::*;
+        match inst {
+            MovRegShiftReg => CostClass::AluShiftReg,
+            Mul => CostClass::Mul,
+
+            LdrReg | LdrImm => CostClass::Load(1, 0),
+            LdrImmAlt | LdrLit => CostClass::Load(1, 8),
+            LdrhReg | LdrbReg | LdrsbReg | LdrshReg |
+            LdrhImm | LdrbImm => CostClass::Load(2, 0),
+            StrbReg | StrReg | StrhReg | StrhImm | StrImm | StrbImm |
+            StrImmAlt => CostClass::Store,
+            Ldm => CostClass::LoadMulti(0x00ff),
+            Stm => CostClass::StoreMulti(0x00ff),
+            Pop => CostClass::LoadMulti(0x01ff),
+            Push => CostClass::StoreMulti(0x01ff),
+
+            B | Bx | BlxReg | BAlt | BlImmSuffix | BlxImmSuffix => CostClass::Branch,
+            Svc | Bkpt | Undefined => CostClass::Exception,
+            _ => CostClass::Alu,
+        }
+    }
+
+    /// The number of cycles spent issuing an instruction.
+    fn issue_cycles(self, op: u32, thumb: bool) -> usize {
+        // Thumb instructions which set flags don't have an S bit
+        let sets_flags = thumb || (op & (1 << 20)) != 0;
+        match self {
+            CostClass::Alu | CostClass::Branch | CostClass::Store |
+            CostClass::MulHalf | CostClass::Load(..) => 1,
+            CostClass::AluShiftReg | CostClass::MulHalfLong |
+            CostClass::LoadDouble | CostClass::StoreDouble |
+            CostClass::Coproc | CostClass::Mrs => 2,
+            CostClass::CoprocDouble | CostClass::Exception => 3,
+            CostClass::Mul => if sets_flags { 4 } else { 2 },
+            CostClass::MulLong => if sets_flags { 5 } else { 3 },
+            CostClass::Msr => if (op & (1 << 16)) != 0 { 3 } else { 1 },
+            CostClass::LoadMulti(mask) |
+            CostClass::StoreMulti(mask) => (op & mask).count_ones().max(2) as usize,
+        }
+    }
+}
+
+/// Tracks the state needed to work out the cost of each instruction.
+#[derive(Debug, Default)]
+pub struct CycleModel {
+    /// The destination register of the last instruction (if it was a load),
+    /// and the number of cycles until the result is available.
+    pending_load: Option<(u32, usize)>,
+}
+impl CycleModel {
+    /// Returns true if some instruction (probably) reads a register.
+    ///
+    /// This only looks at the fields where most encodings keep their source
+    /// registers, so it's an approximation.
+    fn reads_reg(op: u32, thumb: bool, reg: u32) -> bool {
+        if thumb {
+            ((op >> 3) & 0x7) == reg || ((op >> 6) & 0x7) == reg
+        } else {
+            ((op >> 16) & 0xf) == reg || (op & 0xf) == reg
+        }
+    }
+
+    /// Returns the number of cycles taken by an instruction.
+    pub fn cycles(&mut self, class: CostClass, op: u32, thumb: bool,
+        res: &DispatchRes) -> usize
+    {
+        let pending = self.pending_load.take();
+        match res {
+            DispatchRes::CondFailed => return 1,
+            DispatchRes::FatalErr => return 0,
+            _ => {},
+        }
+
+        let mut cycles = class.issue_cycles(op, thumb);
+
+        // Stall until the result of the previous load is available
+        if let Some((reg, latency)) = pending {
+            if Self::reads_reg(op, thumb, reg) {
+                cycles += latency;
+            }
+        }
+
+        match (res, class) {
+            (DispatchRes::RetireBranch, CostClass::Load(..)) |
+            (DispatchRes::RetireBranch, CostClass::LoadMulti(_)) => {
+                cycles += LOAD_PC_REFILL_CYCLES;
+            },
+            (DispatchRes::RetireBranch, _) |
+            (DispatchRes::Exception(_), _) => {
+                cycles += BRANCH_REFILL_CYCLES;
+            },
+            (DispatchRes::RetireOk, CostClass::Load(latency, shift)) => {
+                let mask = if thumb { 0x7 } else { 0xf };
+                self.pending_load = Some(((op >> shift) & mask, latency as usize));
+            },
+            _ => {},
+        }
+        cycles
+    }
+
+    /// Forget about the last load (i.e. when an exception is taken).
+    pub fn flush(&mut self) {
+        self.pending_load = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ironic_core::cpu::excep::ExceptionType;
+
+    /// Returns the cost of some ARM instruction.
+    fn arm(model: &mut CycleModel, op: u32, res: DispatchRes) -> usize {
+        model.cycles(CostClass::from_arm(ArmInst::decode(op)), op, false, &res)
+    }
+
+    #[test]
+    fn block_transfer_counts() {
+        let mut model = CycleModel::default();
+        // ldmia r0, {r1-r4}
+        assert_eq!(arm(&mut model, 0xe890_001e, DispatchRes::RetireOk), 4);
+        // ldmia r0, {r1} takes at least two cycles
+        assert_eq!(arm(&mut model, 0xe890_0002, DispatchRes::RetireOk), 2);
+        // stmdb sp!, {r4-r11, lr}
+        assert_eq!(arm(&mut model, 0xe92d_4ff0, DispatchRes::RetireOk), 9);
+
+        // push {r4-r7, lr} counts LR, but ldmia r0!, {r0-r7} only has eight
+        // bits in the register list
+        let push = 0xb5f0;
+        let class = CostClass::from_thumb(ThumbInst::decode(push));
+        assert_eq!(model.cycles(class, push as u32, true, &DispatchRes::RetireOk), 5);
+        let ldm = 0xc8ff;
+        let class = CostClass::from_thumb(ThumbInst::decode(ldm));
+        assert_eq!(model.cycles(class, ldm as u32, true, &DispatchRes::RetireOk), 8);
+    }
+
+    #[test]
+    fn load_use_stalls() {
+        let mut model = CycleModel::default();
+        // ldr r1, [r0]; add r2, r1, #1
+        assert_eq!(arm(&mut model, 0xe590_1000, DispatchRes::RetireOk), 1);
+        assert_eq!(arm(&mut model, 0xe281_2001, DispatchRes::RetireOk), 2);
+
+        // ldr r1, [r0]; add r2, r3, #4
+        assert_eq!(arm(&mut model, 0xe590_1000, DispatchRes::RetireOk), 1);
+        assert_eq!(arm(&mut model, 0xe283_2004, DispatchRes::RetireOk), 1);
+
+        // Byte loads take another cycle: ldrb r1, [r0]; add r2, r1, #1
+        assert_eq!(arm(&mut model, 0xe5d0_1000, DispatchRes::RetireOk), 1);
+        assert_eq!(arm(&mut model, 0xe281_2001, DispatchRes::RetireOk), 3);
+
+        // The stall only applies to the next instruction
+        assert_eq!(arm(&mut model, 0xe590_1000, DispatchRes::RetireOk), 1);
+        assert_eq!(arm(&mut model, 0xe283_2004, DispatchRes::RetireOk), 1);
+        assert_eq!(arm(&mut model, 0xe281_2001, DispatchRes::RetireOk), 1);
+
+        // Taking an exception forgets about the load
+        assert_eq!(arm(&mut model, 0xe590_1000, DispatchRes::RetireOk), 1);
+        model.flush();
+        assert_eq!(arm(&mut model, 0xe281_2001, DispatchRes::RetireOk), 1);
+    }
+
+    #[test]
+    fn pipeline_refills() {
+        let mut model = CycleModel::default();
+        // b <label>, taken and not taken
+        assert_eq!(arm(&mut model, 0xea00_0000, DispatchRes::RetireBranch),
+            1 + BRANCH_REFILL_CYCLES);
+        assert_eq!(arm(&mut model, 0x0a00_0000, DispatchRes::CondFailed), 1);
+
+        // ldr pc, [r0]
+        assert_eq!(arm(&mut model, 0xe590_f000, DispatchRes::RetireBranch),
+            1 + LOAD_PC_REFILL_CYCLES);
+        // ldmia sp!, {r4, pc}
+        assert_eq!(arm(&mut model, 0xe8bd_8010, DispatchRes::RetireBranch),
+            2 + LOAD_PC_REFILL_CYCLES);
+
+        // svc #0
+        assert_eq!(arm(&mut model, 0xef00_0000,
+            DispatchRes::Exception(ExceptionType::Swi)), 3 + BRANCH_REFILL_CYCLES);
+    }
+
+    #[test]
+    fn multiply_flags() {
+        let mut model = CycleModel::default();
+        // mul r1, r2, r3 and muls r1, r2, r3
+        assert_eq!(arm(&mut model, 0xe001_0392, DispatchRes::RetireOk), 2);
+        assert_eq!(arm(&mut model, 0xe011_0392, DispatchRes::RetireOk), 4);
+        // umull r0, r1, r2, r3
+        assert_eq!(arm(&mut model, 0xe081_0392, DispatchRes::RetireOk), 3);
+    }
+}
@@ -93,6 +93,7 @@ impl ArmFn {
             B           => ArmFn(afn!(arm::branch::b)),
             Bx          => ArmFn(afn!(arm::branch::bx)),
             BlImm       => ArmFn(afn!(arm::branch::bl_imm)),
+            Svc         => ArmFn(afn!(arm::misc::svc)),
 
             RsbImm      => ArmFn(afn!(arm::dataproc::rsb_imm)),
             RsbReg      => ArmFn(afn!(arm::dataproc::rsb_reg)),
@@ -2,6 +2,7 @@
 
 use crate::interp::DispatchRes;
 use crate::interp::dispatch;
+use crate::interp::cycles::CostClass;
 use ironic_core::cpu::Cpu;
 use crate::decode::arm::ArmInst;
 use crate::decode::thumb::ThumbInst;
@@ -19,12 +20,16 @@ pub struct ThumbFn(pub fn(&mut Cpu, u16) -> DispatchRes);
 
 /// The ARMv5 lookup table.
 pub struct ArmLut { 
-    pub data: [ArmFn; 0x1000] 
+    pub data: [ArmFn; 0x1000],
+    pub cost: [CostClass; 0x1000],
 }
 impl ArmLut {
     pub fn lookup(&self, opcd: u32) -> ArmFn { 
         self.data[Self::opcd_to_idx(opcd)] 
     }
+    pub fn cost(&self, opcd: u32) -> CostClass { 
+        self.cost[Self::opcd_to_idx(opcd)] 
+    }
 
     const fn idx_to_opcd(idx: usize) -> u32 {
         (((idx & 0x0ff0) << 16) | ((idx & 0x000f) << 4)) as u32
@@ -36,11 +41,14 @@ impl ArmLut {
     const fn create_lut(default_entry: ArmFn) -> Self {
         let mut lut = ArmLut {
             data: [default_entry; 0x1000],
+            cost: [CostClass::Alu; 0x1000],
         };
         let mut i = 0;
         while i < Self::LUT_SIZE {
             let opcd = ArmLut::idx_to_opcd(i);
-            lut.data[i as usize] = ArmFn::from_inst(ArmInst::decode(opcd));
+            let inst = ArmInst::decode(opcd);
+            lut.data[i as usize] = ArmFn::from_inst(inst);
+            lut.cost[i] = CostClass::from_arm(inst);
             i += 1;
         }
         lut
@@ -49,12 +57,16 @@ impl ArmLut {
 
 /// The ARMv5T lookup table.
 pub struct ThumbLut { 
-    pub data: [ThumbFn; 0x400] 
+    pub data: [ThumbFn; 0x400],
+    pub cost: [CostClass; 0x400],
 }
 impl ThumbLut {
     pub fn lookup(&self, opcd: u16) -> ThumbFn { 
         self.data[Self::opcd_to_idx(opcd)] 
     }
+    pub fn cost(&self, opcd: u16) -> CostClass { 
+        self.cost[Self::opcd_to_idx(opcd)] 
+    }
 
     const fn idx_to_opcd(idx: usize) -> u16 {
         (idx << 6) as u16
@@ -66,11 +78,14 @@ impl ThumbLut {
     const fn create_lut(default_entry: ThumbFn) -> Self {
         let mut lut = ThumbLut {
             data: [default_entry; 0x400],
+            cost: [CostClass::Alu; 0x400],
         };
         let mut i = 0;
         while i < Self::LUT_SIZE {
             let opcd = ThumbLut::idx_to_opcd(i);
-            lut.data[i as usize] = ThumbFn::from_inst(ThumbInst::decode(opcd));
+            let inst = ThumbInst::decode(opcd);
+            lut.data[i as usize] = ThumbFn::from_inst(inst);
+            lut.cost[i] = CostClass::from_thumb(inst);
             i += 1;
         }
         lut
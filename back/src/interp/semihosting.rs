@@ -0,0 +1,401 @@
+//! Host-side support for ARM semihosting calls.
+//!
+//! Semihosting calls are SVC instructions with a special immediate value
+//! (0x123456 in ARM state, or 0xab in Thumb state). The operation is passed
+//! in r0, and r1 holds either a single argument or a pointer to a block of
+//! arguments. The result is returned in r0.
+//!
+//! Host files are only accessible from some chosen directory.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf, Component};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ironic_core::cpu::mmu::prim::{TLBReq, Access};
+use crate::interp::InterpBackend;
+
+/// SVC immediate used for semihosting calls in ARM state.
+const ARM_SEMIHOSTING_SVC: u32 = 0x12_3456;
+/// SVC immediate used for semihosting calls in Thumb state.
+const THUMB_SEMIHOSTING_SVC: u32 = 0xab;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_SEEK: u32 = 0x0a;
+const SYS_FLEN: u32 = 0x0c;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_GET_CMDLINE: u32 = 0x15;
+/// Semihosting call used to stop execution.
+const SYS_EXIT: u32 = 0x18;
+/// Semihosting call used to stop execution with an exit status.
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// Reason code for [SYS_EXIT] indicating that the program exited normally.
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x0002_0026;
+
+/// The value returned to the guest when some call fails.
+const SH_ERR: u32 = 0xffff_ffff;
+
+/// The longest filename accepted by [SYS_OPEN].
+const MAX_NAME_LEN: usize = 0x400;
+/// The most bytes moved by a single [SYS_READ] or [SYS_WRITE]. Guests are
+/// told how many bytes were left over, and are expected to try again.
+/// Strings printed with [SYS_WRITE0] are truncated to this length.
+const MAX_TRANSFER_LEN: usize = 0x0010_0000;
+
+/// Something the guest can refer to with a semihosting file handle.
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// State associated with semihosting.
+pub struct Semihosting {
+    /// The directory used to resolve guest filenames.
+    root: PathBuf,
+    /// The command line passed to the guest.
+    cmdline: String,
+    /// Open handles, by handle number.
+    handles: BTreeMap<u32, Handle>,
+    /// The next handle number to hand out.
+    next_handle: u32,
+    /// Time when semihosting started, used for [SYS_CLOCK].
+    start: Instant,
+    /// Buffer for console output, written to stdout one line at a time.
+    con_buf: String,
+}
+impl Semihosting {
+    /// Allow the guest to access files in some host directory.
+    pub fn new(root: &str, cmdline: &str) -> Result<Self, String> {
+        let root = Path::new(root).canonicalize()
+            .map_err(|e| format!("Couldn't open {}: {}", root, e))?;
+        if !root.is_dir() {
+            return Err(format!("{} isn't a directory", root.display()));
+        }
+        Ok(Semihosting {
+            root,
+            cmdline: cmdline.to_string(),
+            handles: BTreeMap::new(),
+            next_handle: 1,
+            start: Instant::now(),
+            con_buf: String::new(),
+        })
+    }
+
+    /// Resolve a guest filename to a path in the host directory.
+    /// Absolute paths and paths outside the directory are rejected.
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        let name_ok = path.components().all(|c| {
+            matches!(c, Component::Normal(_) | Component::CurDir)
+        });
+        if !name_ok || path.file_name().is_none() {
+            return None;
+        }
+
+        // Catch symbolic links which point outside of the directory
+        let full = self.root.join(path);
+        let parent = full.parent()?.canonicalize().ok()?;
+        if !parent.starts_with(&self.root) {
+            return None;
+        }
+        match full.canonicalize() {
+            Ok(p) if !p.starts_with(&self.root) => None,
+            _ => Some(full),
+        }
+    }
+
+    /// Open some file, returning a new handle.
+    fn open(&mut self, name: &str, mode: u32) -> Option<u32> {
+        let handle = if name == ":tt" {
+            match mode / 4 {
+                0 => Handle::Stdin,
+                1 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            // Modes correspond to "r", "r+", "w", "w+", "a", and "a+"
+            let path = self.resolve(name)?;
+            let mut opts = OpenOptions::new();
+            match mode / 2 {
+                0 => opts.read(true),
+                1 => opts.read(true).write(true),
+                2 => opts.write(true).create(true).truncate(true),
+                3 => opts.read(true).write(true).create(true).truncate(true),
+                4 => opts.append(true).create(true),
+                5 => opts.read(true).append(true).create(true),
+                _ => return None,
+            };
+            Handle::File(opts.open(path).ok()?)
+        };
+        let num = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(num, handle);
+        Some(num)
+    }
+
+    /// Write to the console, printing any complete lines.
+    fn console_write(&mut self, buf: &[u8]) {
+        self.con_buf += &String::from_utf8_lossy(buf);
+        while let Some(idx) = self.con_buf.find('\n') {
+            let line: String = self.con_buf.drain(..=idx).collect();
+            println!("SVC {}", line.trim_end_matches('\n'));
+        }
+    }
+
+    /// Print any incomplete line left in the console buffer.
+    fn console_flush(&mut self) {
+        if !self.con_buf.is_empty() {
+            println!("SVC {}", self.con_buf);
+            self.con_buf.clear();
+        }
+    }
+
+    /// Write to some handle, returning the number of bytes written.
+    fn write(&mut self, num: u32, buf: &[u8]) -> Option<usize> {
+        match self.handles.get_mut(&num)? {
+            Handle::Stdin => None,
+            Handle::Stdout | Handle::Stderr => {
+                self.console_write(buf);
+                Some(buf.len())
+            },
+            Handle::File(f) => f.write_all(buf).ok().map(|_| buf.len()),
+        }
+    }
+
+    /// Read from some handle, returning the number of bytes read.
+    fn read(&mut self, num: u32, buf: &mut [u8]) -> Option<usize> {
+        match self.handles.get_mut(&num)? {
+            Handle::Stdin => std::io::stdin().read(buf).ok(),
+            Handle::Stdout | Handle::Stderr => None,
+            Handle::File(f) => {
+                let mut len = 0;
+                while len < buf.len() {
+                    match f.read(&mut buf[len..]) {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        Err(_) => return None,
+                    }
+                }
+                Some(len)
+            },
+        }
+    }
+
+    /// Get the file associated with some handle.
+    fn file(&mut self, num: u32) -> Option<&mut File> {
+        match self.handles.get_mut(&num)? {
+            Handle::File(f) => Some(f),
+            _ => None,
+        }
+    }
+}
+
+impl InterpBackend {
+    /// Returns true if some (already fetched) SVC instruction is used for
+    /// a semihosting call (and semihosting is enabled).
+    pub fn is_semihosting_call(&self, opcd: u32, thumb: bool) -> bool {
+        if self.semihosting.is_none() {
+            return false;
+        }
+        if thumb {
+            (opcd & 0xff) == THUMB_SEMIHOSTING_SVC
+        } else {
+            (opcd & 0x00ff_ffff) == ARM_SEMIHOSTING_SVC
+        }
+    }
+
+    /// Copy some buffer out of guest memory.
+    fn guest_read(&self, vaddr: u32, buf: &mut [u8]) {
+        // The buffer might span multiple pages, so translate each page
+        let mut off = 0;
+        while off < buf.len() {
+            let addr = vaddr.wrapping_add(off as u32);
+            let len = (0x1000 - (addr & 0xfff) as usize).min(buf.len() - off);
+            let paddr = self.cpu.translate(TLBReq::new(addr, Access::Debug));
+            self.bus.write().unwrap().dma_read(paddr, &mut buf[off..off + len]);
+            off += len;
+        }
+    }
+
+    /// Copy some buffer into guest memory.
+    fn guest_write(&self, vaddr: u32, buf: &[u8]) {
+        let mut off = 0;
+        while off < buf.len() {
+            let addr = vaddr.wrapping_add(off as u32);
+            let len = (0x1000 - (addr & 0xfff) as usize).min(buf.len() - off);
+            let paddr = self.cpu.translate(TLBReq::new(addr, Access::Debug));
+            self.bus.write().unwrap().dma_write(paddr, &buf[off..off + len]);
+            off += len;
+        }
+    }
+
+    /// Read a word from guest memory.
+    fn guest_read32(&self, vaddr: u32) -> u32 {
+        let mut buf = [0u8; 4];
+        self.guest_read(vaddr, &mut buf);
+        u32::from_be_bytes(buf)
+    }
+
+    /// Read some number of words from the argument block pointed to by r1.
+    fn semihosting_args<const N: usize>(&self) -> [u32; N] {
+        let mut res = [0u32; N];
+        for (idx, arg) in res.iter_mut().enumerate() {
+            *arg = self.guest_read32(self.cpu.reg.r[1].wrapping_add(idx as u32 * 4));
+        }
+        res
+    }
+
+    /// Read a NUL-terminated string from guest memory, one page at a time.
+    /// Strings longer than [MAX_TRANSFER_LEN] bytes are truncated.
+    fn guest_read_cstr(&self, vaddr: u32) -> Vec<u8> {
+        let mut res = Vec::new();
+        while res.len() < MAX_TRANSFER_LEN {
+            let addr = vaddr.wrapping_add(res.len() as u32);
+            let len = (0x1000 - (addr & 0xfff) as usize).min(MAX_TRANSFER_LEN - res.len());
+            let mut buf = vec![0u8; len];
+            self.guest_read(addr, &mut buf);
+            if let Some(idx) = buf.iter().position(|&b| b == 0) {
+                res.extend_from_slice(&buf[..idx]);
+                return res;
+            }
+            res.extend_from_slice(&buf);
+        }
+        println!("SVC string at {:08x} is too long", vaddr);
+        res
+    }
+
+    /// Handle a semihosting call, returning true if emulation should stop.
+    pub fn semihosting(&mut self) -> bool {
+        let mut sh = self.semihosting.take().unwrap();
+        let op = self.cpu.reg.r[0];
+        let res = match op {
+            SYS_OPEN => {
+                let [name_ptr, mode, len] = self.semihosting_args::<3>();
+                if len as usize > MAX_NAME_LEN {
+                    println!("SVC filename is too long ({:x} bytes)", len);
+                    SH_ERR
+                } else {
+                    let mut name = vec![0u8; len as usize];
+                    self.guest_read(name_ptr, &mut name);
+                    let name = String::from_utf8_lossy(&name).to_string();
+                    let res = sh.open(&name, mode);
+                    if res.is_none() {
+                        println!("SVC couldn't open '{}' (mode {})", name, mode);
+                    }
+                    res.unwrap_or(SH_ERR)
+                }
+            },
+            SYS_CLOSE => {
+                let [num] = self.semihosting_args::<1>();
+                if sh.handles.remove(&num).is_some() { 0 } else { SH_ERR }
+            },
+            SYS_WRITEC => {
+                let mut buf = [0u8; 1];
+                self.guest_read(self.cpu.reg.r[1], &mut buf);
+                sh.console_write(&buf);
+                self.cpu.reg.r[0]
+            },
+            SYS_WRITE0 => {
+                let buf = self.guest_read_cstr(self.cpu.reg.r[1]);
+                sh.console_write(&buf);
+                self.cpu.reg.r[0]
+            },
+            SYS_WRITE => {
+                // Returns the number of bytes which were *not* written
+                let [num, ptr, len] = self.semihosting_args::<3>();
+                let mut buf = vec![0u8; (len as usize).min(MAX_TRANSFER_LEN)];
+                self.guest_read(ptr, &mut buf);
+                match sh.write(num, &buf) {
+                    Some(n) => len - n as u32,
+                    None => len,
+                }
+            },
+            SYS_READ => {
+                // Returns the number of bytes which were *not* read
+                let [num, ptr, len] = self.semihosting_args::<3>();
+                let mut buf = vec![0u8; (len as usize).min(MAX_TRANSFER_LEN)];
+                match sh.read(num, &mut buf) {
+                    Some(n) => {
+                        self.guest_write(ptr, &buf[..n]);
+                        len - n as u32
+                    },
+                    None => len,
+                }
+            },
+            SYS_SEEK => {
+                let [num, pos] = self.semihosting_args::<2>();
+                sh.file(num)
+                    .and_then(|f| f.seek(SeekFrom::Start(pos as u64)).ok())
+                    .map_or(SH_ERR, |_| 0)
+            },
+            SYS_FLEN => {
+                let [num] = self.semihosting_args::<1>();
+                sh.file(num)
+                    .and_then(|f| f.metadata().ok())
+                    .map_or(SH_ERR, |m| m.len() as u32)
+            },
+            SYS_CLOCK => {
+                // Centiseconds since execution started
+                (sh.start.elapsed().as_millis() / 10) as u32
+            },
+            SYS_TIME => {
+                SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs() as u32)
+            },
+            SYS_GET_CMDLINE => {
+                // The length in the argument block is updated
+                let [ptr, len] = self.semihosting_args::<2>();
+                let cmdline = sh.cmdline.as_bytes();
+                if cmdline.len() < len as usize {
+                    let mut buf = cmdline.to_vec();
+                    buf.push(0);
+                    self.guest_write(ptr, &buf);
+                    self.guest_write(self.cpu.reg.r[1].wrapping_add(4),
+                        &(cmdline.len() as u32).to_be_bytes());
+                    0
+                } else {
+                    SH_ERR
+                }
+            },
+            SYS_EXIT => {
+                // On 32-bit targets, r1 only holds a reason code
+                let reason = self.cpu.reg.r[1];
+                let status = if reason == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 };
+                sh.console_flush();
+                self.semihosting = Some(sh);
+                return self.exit(reason, status);
+            },
+            SYS_EXIT_EXTENDED => {
+                // r1 points to a reason code and an exit status
+                let [reason, status] = self.semihosting_args::<2>();
+                sh.console_flush();
+                self.semihosting = Some(sh);
+                return self.exit(reason, status as i32);
+            },
+            _ => {
+                println!("SVC unimplemented semihosting call {:02x}", op);
+                SH_ERR
+            },
+        };
+        self.cpu.reg.r[0] = res;
+        self.semihosting = Some(sh);
+        false
+    }
+
+    /// Record the exit status reported by the guest.
+    fn exit(&mut self, reason: u32, status: i32) -> bool {
+        println!("SVC exit reason={:08x} status={}", reason, status);
+        self.exit_status = Some(status);
+        true
+    }
+}
@@ -1,5 +1,9 @@
 //! Types for emulating inter-processor communication.
 
+pub mod trace;
+
+use std::convert::TryInto;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct OpenArg {
@@ -64,6 +68,7 @@ pub struct IoctlvArg {
     pub pad4: u32,
 }
 
+#[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct IoctlvArgEntry {
     pub data: u32, // ptr
@@ -72,6 +77,7 @@ pub struct IoctlvArgEntry {
 
 
 /// Commands used in IPC requests to IOS58.
+#[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u32)]
 pub enum IosCmd {
     Open    = 0x0000_0001,
@@ -82,17 +88,34 @@ pub enum IosCmd {
     Ioctl   = 0x0000_0006,
     Ioctlv  = 0x0000_0007,
 }
+impl IosCmd {
+    pub fn from_u32(x: u32) -> Option<Self> {
+        match x {
+            1 => Some(Self::Open),
+            2 => Some(Self::Close),
+            3 => Some(Self::Read),
+            4 => Some(Self::Write),
+            5 => Some(Self::Seek),
+            6 => Some(Self::Ioctl),
+            7 => Some(Self::Ioctlv),
+            _ => None,
+        }
+    }
+}
+
+/// Command used by IOS to mark a request as completed.
+pub const IOS_REPLY: u32 = 0x0000_0008;
 
 /// Command arguments used in IPC requests to IOS58.
 #[repr(C)]
 pub union IosArg {
-    open: OpenArg,
-    close: CloseArg,
-    read: ReadArg,
-    write: WriteArg,
-    seek: SeekArg,
-    ioctl: IoctlArg,
-    ioctlv: IoctlvArg,
+    pub open: OpenArg,
+    pub close: CloseArg,
+    pub read: ReadArg,
+    pub write: WriteArg,
+    pub seek: SeekArg,
+    pub ioctl: IoctlArg,
+    pub ioctlv: IoctlvArg,
 }
 
 /// An IOS58 IPC request.
@@ -103,3 +126,37 @@ pub struct IosReq {
     pub fd: u32,
     pub arg: IosArg,
 }
+impl IosReq {
+    /// The length of an IPC request in memory.
+    pub const LEN: usize = 0x20;
+
+    /// Decode a (big-endian) IPC request from some buffer.
+    pub fn from_buf(s: &[u8; Self::LEN]) -> Option<Self> {
+        let mut w = [0u32; 8];
+        for (idx, word) in w.iter_mut().enumerate() {
+            *word = u32::from_be_bytes(s[idx * 4..idx * 4 + 4].try_into().unwrap());
+        }
+        // Every variant is five words long, so any of them will do here
+        let arg = IosArg {
+            ioctl: IoctlArg {
+                cmd: w[3], input_addr: w[4], input_len: w[5],
+                output_addr: w[6], output_len: w[7],
+            }
+        };
+        Some(IosReq { cmd: IosCmd::from_u32(w[0])?, res: w[1], fd: w[2], arg })
+    }
+    /// Encode an IPC request as it would appear in memory (big-endian).
+    pub fn to_buf(&self) -> [u8; Self::LEN] {
+        // Every variant is five words long, so any of them will do here
+        let arg = unsafe { self.arg.ioctl };
+        let w = [
+            self.cmd as u32, self.res, self.fd, arg.cmd, arg.input_addr,
+            arg.input_len, arg.output_addr, arg.output_len,
+        ];
+        let mut res = [0u8; Self::LEN];
+        for (idx, word) in w.iter().enumerate() {
+            res[idx * 4..idx * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        res
+    }
+}
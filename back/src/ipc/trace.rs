@@ -0,0 +1,400 @@
+//! Decoding and logging for IPC requests passed between the PowerPC and ARM.
+//!
+//! Each request sent through the mailbox is decoded (following any pointers
+//! to paths and buffers) and paired with the reply from IOS. Completed
+//! transactions are kept in a log, optionally written to a file (one JSON
+//! object per line), and sent to any subscribers as they happen.
+
+use ironic_core::bus::*;
+use ironic_core::dev::hlwd::ipc::*;
+use crate::ipc::*;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::convert::TryInto;
+
+/// The largest number of bytes captured from some buffer in guest memory.
+const MAX_CAPTURE: usize = 0x100;
+
+/// A decoded IPC request.
+#[derive(Debug, Clone)]
+pub enum IpcCall {
+    Open { path: String, mode: u32 },
+    Close,
+    Read { addr: u32, len: u32 },
+    Write { addr: u32, len: u32, data: Vec<u8> },
+    Seek { offset: i32, whence: u32 },
+    Ioctl { cmd: u32, input: Vec<u8>, output_addr: u32, output_len: u32 },
+    Ioctlv { cmd: u32, input: Vec<Vec<u8>>, output: Vec<IoctlvArgEntry> },
+    /// A request with an unknown command.
+    Unknown(u32),
+}
+
+/// An IPC request, paired with the reply (once it has completed).
+#[derive(Debug, Clone)]
+pub struct IpcTransaction {
+    /// Sequence number, in the order requests were sent.
+    pub id: u64,
+    /// Physical address of the request.
+    pub addr: u32,
+    pub fd: u32,
+    pub call: IpcCall,
+    /// The result returned by IOS.
+    pub result: Option<i32>,
+    /// Contents of any output buffers after the reply.
+    pub output: Vec<Vec<u8>>,
+}
+
+/// An event in the live stream of IPC transactions.
+#[derive(Debug, Clone)]
+pub enum IpcEvent {
+    Request(IpcTransaction),
+    Reply(IpcTransaction),
+}
+
+/// Read some buffer from guest memory, returning false (and leaving the
+/// buffer alone) when it isn't in memory.
+fn guest_read(bus: &mut Bus, addr: u32, buf: &mut [u8]) -> bool {
+    if buf.is_empty() {
+        return true;
+    }
+    if !bus.is_mem_range(addr, buf.len() as u32) {
+        println!("IPC buffer {:08x} ({:x} bytes) isn't in memory", addr, buf.len());
+        return false;
+    }
+    bus.dma_read(addr, buf);
+    true
+}
+
+/// Read some buffer from guest memory, truncated to [MAX_CAPTURE] bytes.
+/// Buffers which aren't in memory are captured as empty.
+fn capture(bus: &mut Bus, addr: u32, len: u32) -> Vec<u8> {
+    let mut buf = vec![0u8; (len as usize).min(MAX_CAPTURE)];
+    if !guest_read(bus, addr, &mut buf) {
+        buf.clear();
+    }
+    buf
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Quote some string for use in JSON.
+fn json_str(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn json_list(items: &[Vec<u8>]) -> String {
+    let items: Vec<String> = items.iter().map(|b| json_str(&hex(b))).collect();
+    format!("[{}]", items.join(","))
+}
+
+impl IpcCall {
+    /// Decode the request at some address, following any pointers. Returns
+    /// [None] when the request itself isn't in memory.
+    fn decode(bus: &mut Bus, addr: u32) -> Option<(u32, Self)> {
+        let mut buf = [0u8; IosReq::LEN];
+        if !guest_read(bus, addr, &mut buf) {
+            return None;
+        }
+        let req = match IosReq::from_buf(&buf) {
+            Some(req) => req,
+            None => {
+                let cmd = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+                return Some((0, IpcCall::Unknown(cmd)));
+            },
+        };
+
+        // Every variant of the arguments is five words long, so reading any
+        // of them is sound.
+        let call = match req.cmd {
+            IosCmd::Open => {
+                let arg = unsafe { req.arg.open };
+                let name = capture(bus, arg.name, 0x40);
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                let path = String::from_utf8_lossy(&name[..len]).to_string();
+                IpcCall::Open { path, mode: arg.mode }
+            },
+            IosCmd::Close => IpcCall::Close,
+            IosCmd::Read => {
+                let arg = unsafe { req.arg.read };
+                IpcCall::Read { addr: arg.addr, len: arg.len }
+            },
+            IosCmd::Write => {
+                let arg = unsafe { req.arg.write };
+                let data = capture(bus, arg.addr, arg.len);
+                IpcCall::Write { addr: arg.addr, len: arg.len, data }
+            },
+            IosCmd::Seek => {
+                let arg = unsafe { req.arg.seek };
+                IpcCall::Seek { offset: arg.offset as i32, whence: arg.whence }
+            },
+            IosCmd::Ioctl => {
+                let arg = unsafe { req.arg.ioctl };
+                IpcCall::Ioctl {
+                    cmd: arg.cmd,
+                    input: capture(bus, arg.input_addr, arg.input_len),
+                    output_addr: arg.output_addr,
+                    output_len: arg.output_len,
+                }
+            },
+            IosCmd::Ioctlv => {
+                let arg = unsafe { req.arg.ioctlv };
+                let num = arg.input.saturating_add(arg.output) as usize;
+                let mut vbuf = vec![0u8; num.min(MAX_CAPTURE) * 8];
+                if !guest_read(bus, arg.argv, &mut vbuf) {
+                    vbuf.clear();
+                }
+                let vecs: Vec<IoctlvArgEntry> = vbuf.chunks(8).map(|e| IoctlvArgEntry {
+                    data: u32::from_be_bytes(e[0..4].try_into().unwrap()),
+                    len: u32::from_be_bytes(e[4..8].try_into().unwrap()),
+                }).collect();
+                let split = (arg.input as usize).min(vecs.len());
+                let input = vecs[..split].iter()
+                    .map(|v| capture(bus, v.data, v.len)).collect();
+                IpcCall::Ioctlv { cmd: arg.cmd, input, output: vecs[split..].to_vec() }
+            },
+        };
+        Some((req.fd, call))
+    }
+
+    /// Capture the contents of any output buffers after some reply.
+    fn capture_output(&self, bus: &mut Bus, result: i32) -> Vec<Vec<u8>> {
+        match self {
+            IpcCall::Read { addr, len } if result > 0 => {
+                vec![capture(bus, *addr, (result as u32).min(*len))]
+            },
+            IpcCall::Ioctl { output_addr, output_len, .. } if *output_len != 0 => {
+                vec![capture(bus, *output_addr, *output_len)]
+            },
+            IpcCall::Ioctlv { output, .. } => {
+                output.iter().map(|v| capture(bus, v.data, v.len)).collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for IpcCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpcCall::Open { path, mode } => write!(f, "open(\"{}\", {})", path, mode),
+            IpcCall::Close => write!(f, "close()"),
+            IpcCall::Read { addr, len } => write!(f, "read({:08x}, {:x})", addr, len),
+            IpcCall::Write { addr, len, .. } => write!(f, "write({:08x}, {:x})", addr, len),
+            IpcCall::Seek { offset, whence } => write!(f, "seek({}, {})", offset, whence),
+            IpcCall::Ioctl { cmd, input, output_addr, output_len } => {
+                write!(f, "ioctl({:x}, in=[{}], out={:08x}:{:x})",
+                    cmd, hex(input), output_addr, output_len)
+            },
+            IpcCall::Ioctlv { cmd, input, output } => {
+                let input: Vec<String> = input.iter().map(|b| hex(b)).collect();
+                let output: Vec<String> = output.iter()
+                    .map(|v| format!("{:08x}:{:x}", v.data, v.len)).collect();
+                write!(f, "ioctlv({:x}, in=[{}], out=[{}])",
+                    cmd, input.join(", "), output.join(", "))
+            },
+            IpcCall::Unknown(cmd) => write!(f, "unknown({:08x})", cmd),
+        }
+    }
+}
+
+impl fmt::Display for IpcTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} {:08x} fd={} {}", self.id, self.addr, self.fd as i32, self.call)?;
+        if let Some(res) = self.result {
+            write!(f, " -> {}", res)?;
+            if !self.output.is_empty() {
+                let output: Vec<String> = self.output.iter().map(|b| hex(b)).collect();
+                write!(f, " out=[{}]", output.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IpcTransaction {
+    /// Format this transaction as a JSON object.
+    pub fn to_json(&self) -> String {
+        let args = match &self.call {
+            IpcCall::Open { path, mode } => {
+                format!("\"cmd\":\"open\",\"path\":{},\"mode\":{}", json_str(path), mode)
+            },
+            IpcCall::Close => "\"cmd\":\"close\"".to_string(),
+            IpcCall::Read { addr, len } => {
+                format!("\"cmd\":\"read\",\"addr\":{},\"len\":{}", addr, len)
+            },
+            IpcCall::Write { addr, len, data } => {
+                format!("\"cmd\":\"write\",\"addr\":{},\"len\":{},\"data\":{}",
+                    addr, len, json_str(&hex(data)))
+            },
+            IpcCall::Seek { offset, whence } => {
+                format!("\"cmd\":\"seek\",\"offset\":{},\"whence\":{}", offset, whence)
+            },
+            IpcCall::Ioctl { cmd, input, output_addr, output_len } => {
+                format!("\"cmd\":\"ioctl\",\"ioctl\":{},\"input\":{},\
+                    \"output_addr\":{},\"output_len\":{}",
+                    cmd, json_str(&hex(input)), output_addr, output_len)
+            },
+            IpcCall::Ioctlv { cmd, input, output } => {
+                let output: Vec<String> = output.iter()
+                    .map(|v| format!("[{},{}]", v.data, v.len)).collect();
+                format!("\"cmd\":\"ioctlv\",\"ioctl\":{},\"input\":{},\"output_vecs\":[{}]",
+                    cmd, json_list(input), output.join(","))
+            },
+            IpcCall::Unknown(cmd) => format!("\"cmd\":\"unknown\",\"raw_cmd\":{}", cmd),
+        };
+        let result = match self.result {
+            Some(res) => res.to_string(),
+            None => "null".to_string(),
+        };
+        format!("{{\"id\":{},\"addr\":{},\"fd\":{},{},\"result\":{},\"output\":{}}}",
+            self.id, self.addr, self.fd as i32, args, result, json_list(&self.output))
+    }
+}
+
+/// Observer which decodes and logs IPC transactions.
+#[derive(Default)]
+pub struct IpcTracer {
+    /// Sequence number for the next request.
+    next_id: u64,
+    /// Requests waiting for a reply, by address.
+    pending: BTreeMap<u32, IpcTransaction>,
+    /// Completed transactions.
+    log: Arc<Mutex<Vec<IpcTransaction>>>,
+    /// Receivers for the live stream.
+    subscribers: Vec<Sender<IpcEvent>>,
+    /// File where completed transactions are written.
+    file: Option<File>,
+    /// Print each request and reply to stdout.
+    pub verbose: bool,
+}
+impl IpcTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write completed transactions to some file.
+    pub fn set_log_file(&mut self, filename: &str) -> Result<(), String> {
+        let f = File::create(filename)
+            .map_err(|e| format!("Couldn't create {}: {}", filename, e))?;
+        self.file = Some(f);
+        Ok(())
+    }
+
+    /// Returns a handle to the log of completed transactions.
+    pub fn log(&self) -> Arc<Mutex<Vec<IpcTransaction>>> {
+        self.log.clone()
+    }
+
+    /// Returns a receiver for the live stream of requests and replies.
+    pub fn subscribe(&mut self) -> Receiver<IpcEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Send some event to all subscribers, dropping any which are gone.
+    fn publish(&mut self, ev: IpcEvent) {
+        self.subscribers.retain(|tx| tx.send(ev.clone()).is_ok());
+    }
+
+    fn handle_request(&mut self, bus: &mut Bus, addr: u32) {
+        let (fd, call) = match IpcCall::decode(bus, addr) {
+            Some(res) => res,
+            None => return,
+        };
+        let t = IpcTransaction {
+            id: self.next_id, addr, fd, call, result: None, output: Vec::new(),
+        };
+        self.next_id += 1;
+        if self.verbose {
+            println!("IPC request {}", t);
+        }
+        if let Some(old) = self.pending.insert(addr, t.clone()) {
+            println!("IPC request #{} at {:08x} was never replied to", old.id, addr);
+        }
+        self.publish(IpcEvent::Request(t));
+    }
+
+    fn handle_reply(&mut self, bus: &mut Bus, addr: u32) {
+        let mut t = match self.pending.remove(&addr) {
+            Some(t) => t,
+            None => {
+                println!("IPC reply at {:08x} doesn't match any request", addr);
+                return;
+            },
+        };
+        let result = bus.read32(addr + 4) as i32;
+        t.output = t.call.capture_output(bus, result);
+        t.result = Some(result);
+        if self.verbose {
+            println!("IPC reply   {}", t);
+        }
+        if let Some(f) = self.file.as_mut() {
+            if let Err(e) = writeln!(f, "{}", t.to_json()) {
+                println!("IPC couldn't write log: {}", e);
+                self.file = None;
+            }
+        }
+        self.log.lock().unwrap().push(t.clone());
+        self.publish(IpcEvent::Reply(t));
+    }
+}
+
+impl IpcObserver for IpcTracer {
+    fn observe(&mut self, bus: &mut Bus, msg: IpcMessage) {
+        match msg {
+            IpcMessage::Request(addr) => self.handle_request(bus, addr),
+            IpcMessage::Reply(addr) => self.handle_reply(bus, addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BAD_ADDR: u32 = 0x2000_0000;
+
+    fn write_req(bus: &mut Bus, addr: u32, words: [u32; 8]) {
+        let buf: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        bus.dma_write(addr, &buf);
+    }
+
+    #[test]
+    fn pointers_outside_of_memory() {
+        let mut bus = Bus::new_blank();
+        assert!(IpcCall::decode(&mut bus, BAD_ADDR).is_none());
+
+        write_req(&mut bus, 0x1000, [6, 0, 3, 1, BAD_ADDR, 0x20, BAD_ADDR, 0x20]);
+        match IpcCall::decode(&mut bus, 0x1000) {
+            Some((3, IpcCall::Ioctl { input, .. })) => assert!(input.is_empty()),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        // The number of vectors doesn't overflow, and the table isn't read
+        write_req(&mut bus, 0x1000, [7, 0, 3, 1, 0xffff_ffff, 1, BAD_ADDR, 0]);
+        match IpcCall::decode(&mut bus, 0x1000) {
+            Some((3, IpcCall::Ioctlv { input, output, .. })) => {
+                assert!(input.is_empty() && output.is_empty());
+            },
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -9,4 +9,6 @@ pub mod decode;
 pub mod interp;
 
 pub mod ipc;
+pub mod hle;
 pub mod ppc;
+pub mod vcd;
@@ -1,32 +1,41 @@
 //! Backend for handling PowerPC HLE.
 //!
-//! NOTE: The socket is blocking right now, but I guess ultimately we don't
-//! want that. 
+//! Clients connect to a UNIX socket (or some other [Transport]), and may use either the original
+//! protocol (used by `pyronic`) or the framed protocol described in
+//! [proto]. Sockets are non-blocking, so many clients can be connected at
+//! once. The server is a front end for [hle::PpcHle], which passes IPC
+//! messages from all clients to the ARM one at a time; replies are routed
+//! back to the client which sent the corresponding request.
+
+pub mod boot;
+pub mod hle;
+pub mod proto;
+pub mod transport;
 
 use ironic_core::bus::*;
-use ironic_core::dev::hlwd::irq::*;
+use ironic_core::dev::hlwd::gpio::Button;
+use ironic_core::dev::hlwd::ipc::PPC_CTRL_X2;
 use crate::back::*;
+use crate::ppc::hle::*;
+use crate::ppc::proto::*;
+use crate::ppc::transport::*;
 
 use std::thread;
 use std::sync::{Arc, RwLock};
-use std::os::unix::net::{UnixStream, UnixListener};
-use std::net::Shutdown;
-use std::io::{Read, Write};
+use std::io::ErrorKind;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 
-extern crate pretty_hex;
-use pretty_hex::*;
-
-/// A type of command sent over the socket.
+/// A type of command sent over the socket (in the original protocol).
 #[derive(Debug)]
 #[repr(u32)]
-pub enum Command { 
-    HostWrite, 
-    HostRead, 
-    Message, 
-    Ack, 
+pub enum Command {
+    HostWrite,
+    HostRead,
+    Message,
+    Ack,
     MessageNoReturn,
-    Unimpl 
+    Unimpl
 }
 impl Command {
     fn from_u32(x: u32) -> Self {
@@ -41,7 +50,7 @@ impl Command {
     }
 }
 
-/// A request packet from the socket.
+/// A request packet from the socket (in the original protocol).
 #[repr(C)]
 pub struct SocketReq {
     pub cmd: Command,
@@ -60,30 +69,101 @@ impl SocketReq {
 }
 
 pub const IPC_SOCK: &str = "/tmp/ironic.sock";
-pub const BUF_LEN: usize = 0x10000;
+
+/// How many times to check for the first ACK from IOS (every 10ms) after
+/// Broadway comes online.
+const ACK_TIMEOUT_POLLS: usize = 6000;
+
+/// The protocol spoken by some client.
+#[derive(Debug, PartialEq)]
+enum Protocol {
+    /// The original, unversioned protocol.
+    V1,
+    /// The framed protocol, before the handshake has completed.
+    V2Handshake,
+    /// The framed protocol.
+    V2,
+}
+
+/// Where the reply to some IPC message should be sent.
+#[derive(Debug, Clone, Copy)]
+enum ReplyTo {
+    /// A client using the original protocol.
+    V1(usize),
+    /// A client using the framed protocol, with the ID of the request.
+    V2(usize, u32),
+}
+
+/// A client connected to the socket.
+struct Client {
+    stream: Box<dyn ClientStream>,
+    /// Bytes received, but not handled yet.
+    ibuf: Vec<u8>,
+    /// Bytes waiting to be sent.
+    obuf: Vec<u8>,
+    /// The protocol spoken by this client, once it has been detected.
+    proto: Option<Protocol>,
+    /// Set when the connection should be closed.
+    closed: bool,
+}
+impl Client {
+    fn new(stream: Box<dyn ClientStream>) -> Self {
+        Client { stream, ibuf: Vec::new(), obuf: Vec::new(), proto: None, closed: false }
+    }
+
+    /// Read everything available from the socket, returning true if any
+    /// bytes were received.
+    fn fill(&mut self) -> bool {
+        let mut buf = [0u8; 0x10000];
+        let mut res = false;
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => { self.closed = true; break; },
+                Ok(len) => { self.ibuf.extend_from_slice(&buf[..len]); res = true; },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => { self.closed = true; break; },
+            }
+        }
+        res
+    }
+
+    /// Write as much of the output buffer as possible.
+    fn flush(&mut self) {
+        while !self.obuf.is_empty() {
+            match self.stream.write(&self.obuf) {
+                Ok(0) => { self.closed = true; break; },
+                Ok(len) => { self.obuf.drain(..len); },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => { self.closed = true; self.obuf.clear(); break; },
+            }
+        }
+    }
+
+    fn respond(&mut self, id: u32, status: u32, payload: Vec<u8>) {
+        let res = Response { id, status, flags: 0, payload };
+        self.obuf.extend_from_slice(&res.encode());
+    }
+}
 
 pub struct PpcBackend {
-    /// Reference to the system bus.
-    pub bus: Arc<RwLock<Bus>>,
-    /// Input buffer for the socket.
-    pub ibuf: [u8; BUF_LEN],
-    /// Output buffer for the socket.
-    pub obuf: [u8; BUF_LEN],
+    /// The PowerPC side of IPC.
+    hle: PpcHle<ReplyTo>,
+    /// Where the server listens for clients.
+    pub transport: Transport,
+    /// Connected clients.
+    clients: BTreeMap<usize, Client>,
+    /// ID for the next client.
+    next_client: usize,
 }
 impl PpcBackend {
     pub fn new(bus: Arc<RwLock<Bus>>) -> Self {
         PpcBackend {
-            bus,
-            ibuf: [0; BUF_LEN],
-            obuf: [0; BUF_LEN],
-        }
-    }
-
-    fn recv(&mut self, client: &mut UnixStream) -> Option<usize> {
-        let res = client.read(&mut self.ibuf);
-        match res {
-            Ok(len) => if len == 0 { None } else { Some(len) },
-            Err(_) => None
+            hle: PpcHle::new(bus),
+            transport: Transport::Unix(IPC_SOCK.to_string()),
+            clients: BTreeMap::new(),
+            next_client: 0,
         }
     }
 }
@@ -92,143 +172,264 @@ impl PpcBackend {
 impl PpcBackend {
 
     /// Handle clients connected to the socket.
-    pub fn server_loop(&mut self, sock: UnixListener) {
+    pub fn server_loop(&mut self, sock: Listener) {
+        if let Err(e) = sock.set_nonblocking(true) {
+            println!("[PPC] Couldn't make socket non-blocking, {:?}", e);
+            return;
+        }
         loop {
-            let res = sock.accept();
-            let mut client = match res {
-                Ok((stream, _)) => stream,
-                Err(e) => { 
-                    println!("[PPC] accept() error {:?}", e);
-                    break;
-                }
-            };
+            let mut busy = self.accept_clients(&sock);
+            let ids: Vec<usize> = self.clients.keys().copied().collect();
+            for id in ids {
+                busy |= self.service_client(id);
+            }
+            busy |= self.poll_mailbox();
 
-            'handle_client: loop {
-                println!("[PPC] waiting for command ...");
-
-                let res = self.wait_for_request(&mut client);
-                let req = if res.is_none() { break; } else { res.unwrap() };
-                match req.cmd {
-                    Command::Ack => self.handle_ack(req),
-                    Command::HostRead => self.handle_read(&mut client, req),
-                    Command::HostWrite => self.handle_write(&mut client, req),
-                    Command::Message => {
-                        self.handle_message(&mut client, req);
-                        let armmsg = self.wait_for_resp();
-                        client.write(&u32::to_le_bytes(armmsg)).unwrap();
-                    },
-                    Command::MessageNoReturn => {
-                        self.handle_message(&mut client, req);
-                    },
-                    Command::Unimpl => break,
-                }
+            // Flush any responses, and drop any disconnected clients
+            for c in self.clients.values_mut() {
+                c.flush();
+            }
+            let closed: Vec<usize> = self.clients.iter()
+                .filter(|(_, c)| c.closed).map(|(id, _)| *id).collect();
+            for id in closed {
+                println!("[PPC] client {} disconnected", id);
+                let c = self.clients.remove(&id).unwrap();
+                c.stream.shutdown().ok();
+            }
+
+            if !busy {
+                thread::sleep(std::time::Duration::from_millis(1));
             }
-            client.shutdown(Shutdown::Both).unwrap();
         }
     }
 
-    /// Block until we get a response from ARM-world.
-    fn wait_for_resp(&mut self) -> u32 {
-        println!("[PPC] waiting for response ...");
+    /// Accept any new clients, returning true if there were any.
+    fn accept_clients(&mut self, sock: &Listener) -> bool {
+        let mut res = false;
         loop {
-            if self.bus.read().unwrap().hlwd.irq.ppc_irq_output {
-                println!("[PPC] got irq");
-                let mut bus = self.bus.write().unwrap();
-
-                if bus.hlwd.ipc.state.ppc_ack {
-                    println!("[PPC] got extra ACK");
-                    bus.hlwd.ipc.state.ppc_ack = false;
-                    bus.hlwd.irq.ppc_irq_status.unset(HollywoodIrq::PpcIpc);
-                    continue
-                }
-
-                if bus.hlwd.ipc.state.ppc_req {
-                    let armmsg = bus.hlwd.ipc.arm_msg;
-                    println!("[PPC] Got message from ARM {:08x}", armmsg);
-                    bus.hlwd.ipc.state.ppc_req = false;
-                    bus.hlwd.ipc.state.arm_ack = true;
-                    bus.hlwd.irq.ppc_irq_status.unset(HollywoodIrq::PpcIpc);
-                    return armmsg;
-                }
-            } else {
-                thread::sleep(std::time::Duration::from_millis(10));
+            match sock.accept() {
+                Ok(stream) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    println!("[PPC] client {} connected", self.next_client);
+                    self.clients.insert(self.next_client, Client::new(stream));
+                    self.next_client += 1;
+                    res = true;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("[PPC] accept() error {:?}", e);
+                    break;
+                },
             }
         }
+        res
     }
 
-    /// Block until we get an ACK from ARM-world.
-    fn wait_for_ack(&mut self) {
-        println!("[PPC] waiting for ACK ...");
-        loop {
-            if self.bus.read().unwrap().hlwd.irq.ppc_irq_output {
-                println!("[PPC] got irq");
-                let mut bus = self.bus.write().unwrap();
-
-                if bus.hlwd.ipc.state.ppc_ack {
-                    bus.hlwd.ipc.state.ppc_ack = false;
-                    println!("[PPC] got ACK");
-                    bus.hlwd.irq.ppc_irq_status.unset(HollywoodIrq::PpcIpc);
-                    break;
-                }
-                if bus.hlwd.ipc.state.ppc_req {
-                    let armmsg = bus.hlwd.ipc.arm_msg;
-                    println!("[PPC] Got extra message from ARM {:08x}", armmsg);
-                    bus.hlwd.ipc.state.ppc_req = false;
-                    bus.hlwd.ipc.state.arm_ack = true;
-                    bus.hlwd.irq.ppc_irq_status.unset(HollywoodIrq::PpcIpc);
-                    continue;
-                }
+    /// Handle any requests from some client, returning true if there were
+    /// any.
+    fn service_client(&mut self, id: usize) -> bool {
+        let mut c = self.clients.remove(&id).unwrap();
+        let res = c.fill();
+
+        // Clients speaking the framed protocol start with the magic bytes
+        if c.proto.is_none() && c.ibuf.len() >= MAGIC.len() {
+            c.proto = Some(if c.ibuf[0..4] == MAGIC {
+                Protocol::V2Handshake
             } else {
-                thread::sleep(std::time::Duration::from_millis(10));
+                Protocol::V1
+            });
+        }
+        match c.proto {
+            Some(Protocol::V1) => self.handle_v1(id, &mut c),
+            Some(Protocol::V2Handshake) => {
+                if let Some(version) = parse_handshake(&c.ibuf) {
+                    c.ibuf.drain(..HANDSHAKE_LEN);
+                    self.handle_handshake(id, &mut c, version);
+                    self.handle_v2(id, &mut c);
+                }
+            },
+            Some(Protocol::V2) => self.handle_v2(id, &mut c),
+            None => {},
+        }
+        self.clients.insert(id, c);
+        res
+    }
+
+    /// Handle requests in the original protocol.
+    fn handle_v1(&mut self, id: usize, c: &mut Client) {
+        while c.ibuf.len() >= 0xc && !c.closed {
+            let req = SocketReq::from_buf(&c.ibuf[0..0xc].try_into().unwrap());
+            let total = match req.cmd {
+                Command::HostWrite => 0xc + req.len as usize,
+                _ => 0xc,
+            };
+            if total - 0xc > MAX_TRANSFER_LEN {
+                c.closed = true;
+                break;
+            }
+            if c.ibuf.len() < total {
+                break;
+            }
+            let data: Vec<u8> = c.ibuf.drain(..total).skip(0xc).collect();
+            match req.cmd {
+                Command::Ack => self.hle.ack(),
+                Command::HostRead => {
+                    match self.handle_read(req.addr, req.len as usize) {
+                        Some(buf) => c.obuf.extend_from_slice(&buf),
+                        None => c.closed = true,
+                    }
+                },
+                Command::HostWrite => {
+                    if self.handle_write(req.addr, &data) {
+                        c.obuf.extend_from_slice(b"OK");
+                    } else {
+                        c.closed = true;
+                    }
+                },
+                Command::Message => {
+                    self.hle.send(req.addr, ReplyTo::V1(id));
+                    c.obuf.extend_from_slice(b"OK");
+                },
+                Command::MessageNoReturn => {
+                    self.hle.send_no_reply(req.addr);
+                    c.obuf.extend_from_slice(b"OK");
+                },
+                Command::Unimpl => c.closed = true,
             }
         }
     }
 
-    /// Block until we receive some command message from a client.
-    fn wait_for_request(&mut self, client: &mut UnixStream) -> Option<SocketReq> {
-        let res = self.recv(client);
-        if res.is_none() {
-            return None;
+    /// Reply to the handshake from a client using the framed protocol.
+    fn handle_handshake(&mut self, id: usize, c: &mut Client, version: u32) {
+        match negotiate(version) {
+            Ok(version) => {
+                c.obuf.extend_from_slice(&handshake_reply(version, STATUS_OK));
+                c.proto = Some(Protocol::V2);
+            },
+            Err(status) => {
+                println!("[PPC] client {} wants unsupported version {}", id, version);
+                c.obuf.extend_from_slice(&handshake_reply(VERSION, status));
+                c.closed = true;
+            },
         }
-        let req = SocketReq::from_buf(
-            &self.ibuf[0..0xc].try_into().unwrap()
-        );
-        if req.len as usize > BUF_LEN - 0xc {
-            return None;
+    }
+
+    /// Handle requests in the framed protocol.
+    fn handle_v2(&mut self, id: usize, c: &mut Client) {
+        while !c.closed {
+            let (req, len) = match Request::decode(&c.ibuf) {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(e) => {
+                    // We can't find the next frame after this, so give up
+                    c.respond(0, e, Vec::new());
+                    c.closed = true;
+                    break;
+                },
+            };
+            c.ibuf.drain(..len);
+            self.handle_request(id, c, req);
         }
-        Some(req)
     }
 
-    /// Read from physical memory.
-    pub fn handle_read(&mut self, client: &mut UnixStream, req: SocketReq) {
-        println!("[PPC] read {:x} bytes at {:08x}", req.len, req.addr);
-        self.bus.write().unwrap().dma_read(req.addr, 
-            &mut self.obuf[0..req.len as usize]);
-        client.write(&self.obuf[0..req.len as usize]).unwrap();
+    fn handle_request(&mut self, id: usize, c: &mut Client, req: Request) {
+        let p = &req.payload;
+        let arg = |idx: usize| u32::from_le_bytes(p[idx * 4..idx * 4 + 4].try_into().unwrap());
+        match req.cmd {
+            CMD_READ if p.len() == 8 => {
+                let (addr, len) = (arg(0), arg(1) as usize);
+                let buf = match self.handle_read(addr, len) {
+                    Some(buf) => buf,
+                    None => return c.respond(req.id, ERR_BAD_ADDR, Vec::new()),
+                };
+                // Split large reads into chunks
+                let mut chunks = buf.chunks(CHUNK_LEN).peekable();
+                if chunks.peek().is_none() {
+                    c.respond(req.id, STATUS_OK, Vec::new());
+                }
+                while let Some(chunk) = chunks.next() {
+                    let flags = if chunks.peek().is_some() { FLAG_MORE } else { 0 };
+                    let res = Response {
+                        id: req.id, status: STATUS_OK, flags, payload: chunk.to_vec(),
+                    };
+                    c.obuf.extend_from_slice(&res.encode());
+                }
+            },
+            CMD_WRITE if p.len() >= 4 => {
+                let status = if self.handle_write(arg(0), &p[4..]) {
+                    STATUS_OK
+                } else {
+                    ERR_BAD_ADDR
+                };
+                c.respond(req.id, status, Vec::new());
+            },
+            CMD_MESSAGE if p.len() == 4 => {
+                self.hle.send(arg(0), ReplyTo::V2(id, req.id));
+            },
+            CMD_MESSAGE_NO_REPLY if p.len() == 4 => {
+                self.hle.send_no_reply(arg(0));
+                c.respond(req.id, STATUS_OK, Vec::new());
+            },
+            CMD_ACK if p.is_empty() => {
+                self.hle.ack();
+                c.respond(req.id, STATUS_OK, Vec::new());
+            },
+            CMD_BOOT_INFO if p.is_empty() => match self.hle.boot_info() {
+                Some((entry, bootstrap)) => {
+                    let mut payload = entry.unwrap_or(0xffff_ffff).to_le_bytes().to_vec();
+                    for word in bootstrap.iter() {
+                        payload.extend_from_slice(&word.to_le_bytes());
+                    }
+                    c.respond(req.id, STATUS_OK, payload);
+                },
+                None => c.respond(req.id, ERR_NOT_BOOTED, Vec::new()),
+            },
+            CMD_BUTTON if p.len() == 8 => match Button::from_u32(arg(0)) {
+                Some(button) => {
+                    self.hle.set_button(button, arg(1) != 0);
+                    c.respond(req.id, STATUS_OK, Vec::new());
+                },
+                None => c.respond(req.id, ERR_MALFORMED, Vec::new()),
+            },
+            CMD_READ | CMD_WRITE | CMD_MESSAGE | CMD_MESSAGE_NO_REPLY | CMD_ACK |
+            CMD_BOOT_INFO | CMD_BUTTON => {
+                c.respond(req.id, ERR_MALFORMED, Vec::new());
+            },
+            _ => c.respond(req.id, ERR_UNKNOWN_CMD, Vec::new()),
+        }
     }
 
-    /// Write to physical memory.
-    pub fn handle_write(&mut self, client: &mut UnixStream, req: SocketReq) {
-        println!("[PPC] write {:x} bytes at {:08x}", req.len, req.addr);
-        let data = &self.ibuf[0xc..(0xc + req.len as usize)];
-        self.bus.write().unwrap().dma_write(req.addr, data);
-        client.write("OK".as_bytes()).unwrap();
+    /// Pass queued messages to the ARM, and route any replies back to the
+    /// clients. Returns true if anything happened.
+    fn poll_mailbox(&mut self) -> bool {
+        let mut replies = Vec::new();
+        let res = self.hle.poll(&mut replies);
+        for (to, armmsg) in replies {
+            match to {
+                ReplyTo::V1(id) => if let Some(c) = self.clients.get_mut(&id) {
+                    c.obuf.extend_from_slice(&armmsg.to_le_bytes());
+                },
+                ReplyTo::V2(id, req_id) => if let Some(c) = self.clients.get_mut(&id) {
+                    c.respond(req_id, STATUS_OK, armmsg.to_le_bytes().to_vec());
+                },
+            }
+        }
+        res
     }
 
-    /// Tell ARM-world that an IPC request is ready at the location indicated
-    /// by the pointer in PPC_MSG.
-    pub fn handle_message(&mut self, client: &mut UnixStream, req: SocketReq) {
-        let mut bus = self.bus.write().unwrap();
-        bus.hlwd.ipc.ppc_msg = req.addr;
-        bus.hlwd.ipc.state.arm_req = true;
-        bus.hlwd.ipc.state.arm_ack = true;
-        client.write("OK".as_bytes()).unwrap();
+    /// Read from physical memory.
+    fn handle_read(&mut self, addr: u32, len: usize) -> Option<Vec<u8>> {
+        if len > MAX_TRANSFER_LEN {
+            return None;
+        }
+        self.hle.read(addr, len)
     }
 
-    pub fn handle_ack(&mut self, req: SocketReq) {
-        let mut bus = self.bus.write().unwrap();
-        let ppc_ctrl = bus.hlwd.ipc.read_handler(4) & 0x3c;
-        bus.hlwd.ipc.write_handler(4, ppc_ctrl | 0x8);
+    /// Write to physical memory.
+    fn handle_write(&mut self, addr: u32, data: &[u8]) -> bool {
+        data.len() <= MAX_TRANSFER_LEN && self.hle.write(addr, data)
     }
 
 }
@@ -237,11 +438,14 @@ impl PpcBackend {
 impl Backend for PpcBackend {
     fn run(&mut self) {
         println!("[PPC] PPC backend thread started");
-        self.bus.write().unwrap().hlwd.ipc.state.ppc_ctrl_write(0x36);
+        self.hle.enable_irqs();
 
-        'wait_for_broadway: loop { 
-            if self.bus.read().unwrap().hlwd.ppc_on {
+        'wait_for_broadway: loop {
+            if self.hle.is_online() {
                 println!("[PPC] Broadway came online");
+                if let Some((Some(entry), _)) = self.hle.boot_info() {
+                    println!("[PPC] Broadway entry point is {:08x}", entry);
+                }
                 break 'wait_for_broadway;
             } else {
                 thread::sleep(std::time::Duration::from_millis(500));
@@ -249,32 +453,26 @@ impl Backend for PpcBackend {
         }
 
         // Block until we get an IRQ with an ACK/MSG
-        self.wait_for_ack();
+        let res = self.hle.wait_for_ack(ACK_TIMEOUT_POLLS,
+            || thread::sleep(std::time::Duration::from_millis(10)));
+        if let Err(e) = res {
+            println!("[PPC] {}", e);
+            println!("[PPC] thread exited");
+            return;
+        }
 
         // Send an extra ACK
-        self.bus.write().unwrap().hlwd.ipc.state.arm_ack = true;
+        self.hle.bus.write().unwrap().hlwd.ipc.ppc_ctrl_set(PPC_CTRL_X2);
         thread::sleep(std::time::Duration::from_millis(100));
 
-        // Try binding to the socket
-        let res = std::fs::remove_file(IPC_SOCK);
-        match res {
-            Ok(_) => {},
-            Err(e) => {},
-        }
-        let res = UnixListener::bind(IPC_SOCK);
-        let sock = match res {
-            Ok(sock) => Some(sock),
-            Err(e) => {
-                println!("[PPC] Couldn't bind to {},\n{:?}", IPC_SOCK, e);
-                None
-            }
-        };
-
-        // If we successfully bind, run the server until it exits
-        if sock.is_some() {
-            self.server_loop(sock.unwrap());
+        // Try binding to the socket, and run the server until it exits
+        match Listener::bind(&self.transport) {
+            Ok(sock) => {
+                println!("[PPC] listening on {}", self.transport);
+                self.server_loop(sock);
+            },
+            Err(e) => println!("[PPC] Couldn't bind to {},\n{:?}", self.transport, e),
         }
         println!("[PPC] thread exited");
     }
 }
-
@@ -0,0 +1,109 @@
+//! Saving the state of Broadway when it boots, for offline analysis.
+
+use ironic_core::bus::*;
+use ironic_core::dev::hlwd::boot::*;
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Virtual address of the cached MEM1 mapping.
+const MEM1_VADDR: u32 = 0x8000_0000;
+
+const ELF_HEADER_LEN: usize = 0x34;
+const ELF_PHDR_LEN: usize = 0x20;
+const EM_PPC: u16 = 20;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const DOL_HEADER_LEN: usize = 0x100;
+
+fn put32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_be_bytes());
+}
+fn put16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+/// Build a (big-endian, 32-bit) ELF with the bootstrap code at the reset
+/// vector, and MEM1 at its cached virtual address.
+pub fn to_elf(event: &PpcBootEvent) -> Vec<u8> {
+    let mut code = Vec::new();
+    for word in event.bootstrap.iter() {
+        code.extend_from_slice(&word.to_be_bytes());
+    }
+    // (vaddr, paddr, flags, data)
+    let segments = [
+        (PPC_RESET_VECTOR, PPC_RESET_VECTOR, PF_R | PF_X, &code[..]),
+        (MEM1_VADDR, 0, PF_R | PF_W | PF_X, &event.mem1[..]),
+    ];
+
+    let mut res = vec![0u8; ELF_HEADER_LEN + ELF_PHDR_LEN * segments.len()];
+    res[0..4].copy_from_slice(b"\x7fELF");
+    res[4] = 1; // ELFCLASS32
+    res[5] = 2; // ELFDATA2MSB
+    res[6] = 1; // EV_CURRENT
+    put16(&mut res, 0x10, 2); // ET_EXEC
+    put16(&mut res, 0x12, EM_PPC);
+    put32(&mut res, 0x14, 1);
+    put32(&mut res, 0x18, event.entry_vaddr().unwrap_or(PPC_RESET_VECTOR));
+    put32(&mut res, 0x1c, ELF_HEADER_LEN as u32);
+    put16(&mut res, 0x28, ELF_HEADER_LEN as u16);
+    put16(&mut res, 0x2a, ELF_PHDR_LEN as u16);
+    put16(&mut res, 0x2c, segments.len() as u16);
+    put16(&mut res, 0x2e, 0x28);
+
+    for (idx, (vaddr, paddr, flags, data)) in segments.iter().enumerate() {
+        let phdr = ELF_HEADER_LEN + idx * ELF_PHDR_LEN;
+        let off = res.len() as u32;
+        put32(&mut res, phdr, PT_LOAD);
+        put32(&mut res, phdr + 0x04, off);
+        put32(&mut res, phdr + 0x08, *vaddr);
+        put32(&mut res, phdr + 0x0c, *paddr);
+        put32(&mut res, phdr + 0x10, data.len() as u32);
+        put32(&mut res, phdr + 0x14, data.len() as u32);
+        put32(&mut res, phdr + 0x18, *flags);
+        put32(&mut res, phdr + 0x1c, 4);
+        res.extend_from_slice(data);
+    }
+    res
+}
+
+/// Build a DOL with MEM1 in the first text section. DOLs can't describe
+/// the reset vector, so the bootstrap code is left out.
+pub fn to_dol(event: &PpcBootEvent) -> Vec<u8> {
+    let mut res = vec![0u8; DOL_HEADER_LEN];
+    put32(&mut res, 0x00, DOL_HEADER_LEN as u32);
+    put32(&mut res, 0x48, MEM1_VADDR);
+    put32(&mut res, 0x90, event.mem1.len() as u32);
+    put32(&mut res, 0xe0, event.entry_vaddr().unwrap_or(0));
+    res.extend_from_slice(&event.mem1);
+    res
+}
+
+/// Saves the state of Broadway each time it boots as an ELF and a DOL in
+/// some directory.
+pub struct PpcBootDumper {
+    dir: PathBuf,
+    /// Number of boots seen so far.
+    count: usize,
+}
+impl PpcBootDumper {
+    pub fn new(dir: &str) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Couldn't create {}: {}", dir, e))?;
+        Ok(PpcBootDumper { dir: PathBuf::from(dir), count: 0 })
+    }
+}
+impl PpcBootObserver for PpcBootDumper {
+    fn observe(&mut self, _bus: &mut Bus, event: &PpcBootEvent) {
+        for (ext, data) in [("elf", to_elf(event)), ("dol", to_dol(event))] {
+            let path = self.dir.join(format!("ppc-boot-{}.{}", self.count, ext));
+            match fs::write(&path, data) {
+                Ok(_) => println!("[PPC] saved boot state to {}", path.display()),
+                Err(e) => println!("[PPC] couldn't write {}: {}", path.display(), e),
+            }
+        }
+        self.count += 1;
+    }
+}
@@ -0,0 +1,277 @@
+//! The PowerPC side of IPC, independent of any transport.
+//!
+//! [PpcHle] does the work of the PowerPC: it reads and writes physical
+//! memory, passes IPC messages to the ARM one at a time, and matches replies
+//! to the messages which were sent. Replies are returned from [PpcHle::poll]
+//! along with a tag chosen by the caller, so that a front end (like the
+//! socket server) can route them back to whoever sent the message.
+
+use ironic_core::bus::*;
+use ironic_core::bus::prim::BusMaster;
+use ironic_core::dev::hlwd::irq::*;
+use ironic_core::dev::hlwd::ipc::*;
+use ironic_core::dev::hlwd::gpio::Button;
+
+use std::sync::{Arc, RwLock};
+use std::collections::VecDeque;
+
+pub struct PpcHle<T> {
+    /// Reference to the system bus.
+    pub bus: Arc<RwLock<Bus>>,
+    /// IPC messages waiting to be sent to the ARM.
+    outbox: VecDeque<(u32, Option<T>)>,
+    /// IPC messages sent to the ARM, waiting for a reply.
+    inflight: Vec<(u32, T)>,
+    /// Replies which arrived while waiting for something else, returned
+    /// from the next call to [PpcHle::poll].
+    unclaimed: Vec<(T, u32)>,
+}
+impl<T> PpcHle<T> {
+    pub fn new(bus: Arc<RwLock<Bus>>) -> Self {
+        PpcHle {
+            bus, outbox: VecDeque::new(), inflight: Vec::new(), unclaimed: Vec::new(),
+        }
+    }
+
+    /// Enable IPC interrupts, like the kernel on the PowerPC would.
+    pub fn enable_irqs(&mut self) {
+        self.bus.write().unwrap().hlwd.ipc.write_handler(0x04,
+            PPC_CTRL_Y2 | PPC_CTRL_Y1 | PPC_CTRL_IY1 | PPC_CTRL_IY2);
+    }
+
+    /// Returns true once the ARM has started the PowerPC.
+    pub fn is_online(&self) -> bool {
+        self.bus.read().unwrap().hlwd.ppc_on
+    }
+
+    /// Returns the entry point and bootstrap code from the last time the ARM
+    /// released Broadway from reset.
+    pub fn boot_info(&self) -> Option<(Option<u32>, [u32; 0x10])> {
+        let bus = self.bus.read().unwrap();
+        bus.ppc_boot.as_ref().map(|e| (e.entry, e.bootstrap))
+    }
+
+    /// Press or release a button on the console.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.bus.write().unwrap().set_button(button, pressed);
+    }
+
+    /// Returns true if some range is backed by memory.
+    pub fn is_valid_range(&self, addr: u32, len: usize) -> bool {
+        len == 0 || self.bus.read().unwrap().is_mem_range(addr, len as u32)
+    }
+
+    /// Read from physical memory, or from a single register. Fails when
+    /// the protection registers don't give Broadway access.
+    pub fn read(&mut self, addr: u32, len: usize) -> Option<Vec<u8>> {
+        println!("[PPC] read {:x} bytes at {:08x}", len, addr);
+        let mut buf = vec![0u8; len];
+        if !self.bus.write().unwrap().master_read(BusMaster::Ppc, addr, &mut buf) {
+            return None;
+        }
+        Some(buf)
+    }
+
+    /// Write to physical memory, or to a single register. Fails when the
+    /// protection registers don't give Broadway access.
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> bool {
+        println!("[PPC] write {:x} bytes at {:08x}", data.len(), addr);
+        self.bus.write().unwrap().master_write(BusMaster::Ppc, addr, data)
+    }
+
+    /// Acknowledge a message from the ARM.
+    pub fn ack(&mut self) {
+        self.bus.write().unwrap().hlwd.ipc.ppc_ctrl_set(PPC_CTRL_Y1 | PPC_CTRL_X2);
+    }
+
+    /// Queue an IPC message for the ARM. The reply is returned from
+    /// [PpcHle::poll] along with `tag`.
+    pub fn send(&mut self, addr: u32, tag: T) {
+        self.outbox.push_back((addr, Some(tag)));
+    }
+
+    /// Queue an IPC message for the ARM, ignoring the reply.
+    pub fn send_no_reply(&mut self, addr: u32) {
+        self.outbox.push_back((addr, None));
+    }
+
+    /// Returns true if some message is still waiting for a reply.
+    pub fn is_pending(&self) -> bool {
+        !self.outbox.is_empty() || !self.inflight.is_empty()
+    }
+
+    /// Match a message from the ARM to the message it replies to, adding it
+    /// to `replies` along with the tag.
+    fn match_reply(&mut self, armmsg: u32, replies: &mut Vec<(T, u32)>) {
+        match self.inflight.iter().position(|(addr, _)| *addr == armmsg) {
+            Some(idx) => {
+                println!("[PPC] Got message from ARM {:08x}", armmsg);
+                replies.push((self.inflight.remove(idx).1, armmsg));
+            },
+            None => println!("[PPC] Got extra message from ARM {:08x}", armmsg),
+        }
+    }
+
+    /// Pass queued messages to the ARM, and deal with any ACKs or messages
+    /// from the ARM. Replies are added to `replies` along with their tags.
+    /// Returns true if anything happened.
+    pub fn poll(&mut self, replies: &mut Vec<(T, u32)>) -> bool {
+        let mut res = !self.unclaimed.is_empty();
+        replies.append(&mut self.unclaimed);
+        let mut armmsgs = Vec::new();
+        {
+            let mut bus = self.bus.write().unwrap();
+
+            // Only send a message after the ARM has taken the previous one
+            if !bus.hlwd.ipc.state.arm_req {
+                if let Some((addr, tag)) = self.outbox.pop_front() {
+                    println!("[PPC] sending message {:08x}", addr);
+                    bus.hlwd.ipc.send_request(addr);
+                    bus.hlwd.ipc.ppc_ctrl_set(PPC_CTRL_X2);
+                    if let Some(tag) = tag {
+                        self.inflight.push((addr, tag));
+                    }
+                    res = true;
+                }
+            }
+
+            if bus.hlwd.ipc.take_ack() {
+                bus.hlwd.irq.clear_ppc(HollywoodIrq::PpcIpc);
+                res = true;
+            }
+            if let Some(armmsg) = bus.hlwd.ipc.take_reply() {
+                bus.hlwd.irq.clear_ppc(HollywoodIrq::PpcIpc);
+                armmsgs.push(armmsg);
+                res = true;
+            }
+        }
+
+        for armmsg in armmsgs {
+            self.match_reply(armmsg, replies);
+        }
+        res
+    }
+
+    /// Block until we get an ACK from ARM-world, calling `idle` while there
+    /// is nothing to do. Gives up after checking `max_steps` times.
+    ///
+    /// Replies which arrive while waiting are kept for [PpcHle::poll].
+    pub fn wait_for_ack(&mut self, max_steps: usize, mut idle: impl FnMut())
+        -> Result<(), String>
+    {
+        println!("[PPC] waiting for ACK ...");
+        for _ in 0..max_steps {
+            let armmsg = {
+                let mut bus = self.bus.write().unwrap();
+                if bus.hlwd.ipc.take_ack() {
+                    println!("[PPC] got ACK");
+                    bus.hlwd.irq.clear_ppc(HollywoodIrq::PpcIpc);
+                    return Ok(());
+                }
+                let armmsg = bus.hlwd.ipc.take_reply();
+                if armmsg.is_some() {
+                    bus.hlwd.irq.clear_ppc(HollywoodIrq::PpcIpc);
+                }
+                armmsg
+            };
+            match armmsg {
+                Some(armmsg) => {
+                    let mut replies = std::mem::take(&mut self.unclaimed);
+                    self.match_reply(armmsg, &mut replies);
+                    self.unclaimed = replies;
+                },
+                None => idle(),
+            }
+        }
+        Err(format!("No ACK from the ARM after {} steps", max_steps))
+    }
+}
+
+impl PpcHle<()> {
+    /// Send an IPC message and block until the reply arrives, calling `step`
+    /// while waiting (i.e. to run the ARM in the same thread). Returns the
+    /// pointer from the reply, or gives up after `max_steps` steps.
+    ///
+    /// Replies to any other messages are kept for [PpcHle::poll].
+    pub fn message_sync(&mut self, addr: u32, max_steps: usize, mut step: impl FnMut())
+        -> Result<u32, String>
+    {
+        self.send(addr, ());
+        let mut replies = Vec::new();
+        for _ in 0..max_steps {
+            self.poll(&mut replies);
+            if let Some(idx) = replies.iter().position(|(_, ptr)| *ptr == addr) {
+                let (_, ptr) = replies.remove(idx);
+                self.unclaimed.append(&mut replies);
+                return Ok(ptr);
+            }
+            step();
+        }
+        self.unclaimed.append(&mut replies);
+        Err(format!("No reply to message {:08x} after {} steps", addr, max_steps))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_hle() -> (PpcHle<()>, Arc<RwLock<Bus>>) {
+        let bus = Arc::new(RwLock::new(Bus::new_blank()));
+        (PpcHle::new(bus.clone()), bus)
+    }
+
+    #[test]
+    fn give_up_without_the_arm() {
+        let (mut hle, _) = new_hle();
+        assert!(hle.message_sync(0x100, 0x10, || ()).is_err());
+        assert!(hle.wait_for_ack(0x10, || ()).is_err());
+    }
+
+    #[test]
+    fn message_sync_keeps_other_replies() {
+        let (mut hle, bus) = new_hle();
+        hle.send(0x100, ());
+
+        // Reply to each request in the order they arrive
+        let mut reqs = Vec::new();
+        let res = hle.message_sync(0x200, 0x10, || {
+            let mut bus = bus.write().unwrap();
+            if let Some(req) = bus.hlwd.ipc.take_request() {
+                reqs.push(req);
+            }
+            if !reqs.is_empty() && !bus.hlwd.ipc.state.ppc_req {
+                bus.hlwd.ipc.send_reply(reqs.remove(0));
+            }
+        });
+        assert_eq!(res, Ok(0x200));
+
+        let mut replies = Vec::new();
+        assert!(hle.poll(&mut replies));
+        assert_eq!(replies, vec![((), 0x100)]);
+    }
+
+    #[test]
+    fn wait_for_ack_keeps_replies() {
+        let (mut hle, bus) = new_hle();
+        hle.send(0x100, ());
+        hle.poll(&mut Vec::new());
+        assert_eq!(bus.write().unwrap().hlwd.ipc.take_request(), Some(0x100));
+        hle.poll(&mut Vec::new());
+
+        let mut steps = 0;
+        let res = hle.wait_for_ack(0x10, || {
+            let mut bus = bus.write().unwrap();
+            match steps {
+                0 => bus.hlwd.ipc.send_reply(0x100),
+                _ => bus.hlwd.ipc.arm_ctrl_set(ARM_CTRL_Y2),
+            }
+            steps += 1;
+        });
+        assert_eq!(res, Ok(()));
+
+        let mut replies = Vec::new();
+        hle.poll(&mut replies);
+        assert_eq!(replies, vec![((), 0x100)]);
+    }
+}
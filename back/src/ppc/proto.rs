@@ -0,0 +1,297 @@
+//! Version 2 of the protocol used by PPC HLE clients.
+//!
+//! A client starts by sending [MAGIC] and the protocol version it speaks
+//! (as a little-endian u32). The server replies with [MAGIC], the version
+//! it will use, and a status code. All other fields are little-endian.
+//!
+//! After the handshake, all messages are length-prefixed frames. Requests
+//! carry an ID chosen by the client, which is echoed back in the response:
+//!
+//! ```text
+//! request:  len:u32 | id:u32 | cmd:u32 | payload
+//! response: len:u32 | id:u32 | status:u32 | flags:u32 | payload
+//! ```
+//!
+//! where `len` is the number of bytes following the length field. Large
+//! reads are split into chunks, where every chunk but the last one has
+//! [FLAG_MORE] set. Large writes may be split by the client into separate
+//! requests.
+//!
+//! Clients which don't start with [MAGIC] are assumed to speak the original
+//! (unversioned) protocol.
+
+use std::convert::TryInto;
+
+/// Magic bytes sent at the start of the handshake.
+pub const MAGIC: [u8; 4] = *b"IRNC";
+/// The newest protocol version supported by the server.
+pub const VERSION: u32 = 2;
+
+/// Length of the handshake sent by clients.
+pub const HANDSHAKE_LEN: usize = 8;
+/// Length of the handshake reply sent by the server.
+pub const HANDSHAKE_REPLY_LEN: usize = 12;
+
+/// Read `len` bytes of physical memory. Payload is `addr:u32 | len:u32`.
+pub const CMD_READ: u32 = 1;
+/// Write to physical memory. Payload is `addr:u32 | data`.
+pub const CMD_WRITE: u32 = 2;
+/// Send an IPC message to the ARM, and respond with the pointer from the
+/// reply (as a u32). Payload is `addr:u32`.
+pub const CMD_MESSAGE: u32 = 3;
+/// Acknowledge a message from the ARM. There is no payload.
+pub const CMD_ACK: u32 = 4;
+/// Send an IPC message to the ARM without waiting for the reply.
+/// Payload is `addr:u32`.
+pub const CMD_MESSAGE_NO_REPLY: u32 = 5;
+/// Get the state captured when Broadway was last released from reset. There
+/// is no payload. Responds with `entry:u32 | bootstrap:[u32; 16]`, where the
+/// entry point is `0xffffffff` if it couldn't be found.
+pub const CMD_BOOT_INFO: u32 = 6;
+/// Press or release a button on the console. Payload is
+/// `button:u32 | pressed:u32`, where the button is 0 (power), 1 (reset), or
+/// 2 (eject).
+pub const CMD_BUTTON: u32 = 7;
+
+pub const STATUS_OK: u32 = 0;
+/// The command is not recognized.
+pub const ERR_UNKNOWN_CMD: u32 = 1;
+/// The payload has the wrong length.
+pub const ERR_MALFORMED: u32 = 2;
+/// Some address range isn't backed by memory (or a single register), or
+/// Broadway isn't allowed to access it.
+pub const ERR_BAD_ADDR: u32 = 3;
+/// Some frame or transfer is too large.
+pub const ERR_TOO_LARGE: u32 = 4;
+/// The requested protocol version is not supported.
+pub const ERR_VERSION: u32 = 5;
+/// Broadway hasn't been released from reset yet.
+pub const ERR_NOT_BOOTED: u32 = 6;
+
+/// Set on every chunk of a response except the last one.
+pub const FLAG_MORE: u32 = 0x0000_0001;
+
+/// The largest frame accepted by the server (not including the length).
+pub const MAX_FRAME_LEN: usize = 0x0010_0000;
+/// The largest payload in a single chunk of a response.
+pub const CHUNK_LEN: usize = 0x0001_0000;
+/// The largest single transfer to or from memory.
+pub const MAX_TRANSFER_LEN: usize = 0x0400_0000;
+
+fn le32(x: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(x[off..off + 4].try_into().unwrap())
+}
+
+/// Build a handshake for some protocol version.
+pub fn handshake(version: u32) -> [u8; HANDSHAKE_LEN] {
+    let mut res = [0u8; HANDSHAKE_LEN];
+    res[0..4].copy_from_slice(&MAGIC);
+    res[4..8].copy_from_slice(&version.to_le_bytes());
+    res
+}
+
+/// Parse a handshake, returning the version.
+pub fn parse_handshake(buf: &[u8]) -> Option<u32> {
+    if buf.len() < HANDSHAKE_LEN || buf[0..4] != MAGIC {
+        return None;
+    }
+    Some(le32(buf, 4))
+}
+
+/// Choose the version used with a client which asked for some version.
+/// Newer clients are expected to fall back to [VERSION].
+pub fn negotiate(version: u32) -> Result<u32, u32> {
+    if version < VERSION {
+        return Err(ERR_VERSION);
+    }
+    Ok(VERSION)
+}
+
+/// Build the reply to a handshake.
+pub fn handshake_reply(version: u32, status: u32) -> [u8; HANDSHAKE_REPLY_LEN] {
+    let mut res = [0u8; HANDSHAKE_REPLY_LEN];
+    res[0..8].copy_from_slice(&handshake(version));
+    res[8..12].copy_from_slice(&status.to_le_bytes());
+    res
+}
+
+/// Parse the reply to a handshake, returning the version and status.
+pub fn parse_handshake_reply(buf: &[u8]) -> Option<(u32, u32)> {
+    let version = parse_handshake(buf)?;
+    if buf.len() < HANDSHAKE_REPLY_LEN {
+        return None;
+    }
+    Some((version, le32(buf, 8)))
+}
+
+/// Returns the total length of the frame at the start of some buffer, if
+/// the whole frame has been received.
+fn frame_len(buf: &[u8]) -> Result<Option<usize>, u32> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = le32(buf, 0) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ERR_TOO_LARGE);
+    }
+    if buf.len() < 4 + len {
+        return Ok(None);
+    }
+    Ok(Some(4 + len))
+}
+
+/// A request frame sent by a client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub id: u32,
+    pub cmd: u32,
+    pub payload: Vec<u8>,
+}
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(12 + self.payload.len());
+        res.extend_from_slice(&(8 + self.payload.len() as u32).to_le_bytes());
+        res.extend_from_slice(&self.id.to_le_bytes());
+        res.extend_from_slice(&self.cmd.to_le_bytes());
+        res.extend_from_slice(&self.payload);
+        res
+    }
+
+    /// Decode a request from the start of some buffer, returning the request
+    /// and the number of bytes used (or None if the frame is incomplete).
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, u32> {
+        let len = match frame_len(buf)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len < 12 {
+            return Err(ERR_MALFORMED);
+        }
+        let req = Request {
+            id: le32(buf, 4),
+            cmd: le32(buf, 8),
+            payload: buf[12..len].to_vec(),
+        };
+        Ok(Some((req, len)))
+    }
+}
+
+/// A response frame sent by the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub id: u32,
+    pub status: u32,
+    pub flags: u32,
+    pub payload: Vec<u8>,
+}
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(16 + self.payload.len());
+        res.extend_from_slice(&(12 + self.payload.len() as u32).to_le_bytes());
+        res.extend_from_slice(&self.id.to_le_bytes());
+        res.extend_from_slice(&self.status.to_le_bytes());
+        res.extend_from_slice(&self.flags.to_le_bytes());
+        res.extend_from_slice(&self.payload);
+        res
+    }
+
+    /// Decode a response from the start of some buffer, returning the
+    /// response and the number of bytes used (or None if the frame is
+    /// incomplete).
+    pub fn decode(buf: &[u8]) -> Result<Option<(Self, usize)>, u32> {
+        let len = match frame_len(buf)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        if len < 16 {
+            return Err(ERR_MALFORMED);
+        }
+        let res = Response {
+            id: le32(buf, 4),
+            status: le32(buf, 8),
+            flags: le32(buf, 12),
+            payload: buf[16..len].to_vec(),
+        };
+        Ok(Some((res, len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip() {
+        assert_eq!(parse_handshake(&handshake(VERSION)), Some(VERSION));
+        let reply = handshake_reply(VERSION, ERR_VERSION);
+        assert_eq!(parse_handshake_reply(&reply), Some((VERSION, ERR_VERSION)));
+
+        // Short buffers and the wrong magic are rejected
+        assert_eq!(parse_handshake(&handshake(VERSION)[..7]), None);
+        assert_eq!(parse_handshake_reply(&reply[..8]), None);
+        assert_eq!(parse_handshake(b"IRNX\x02\x00\x00\x00"), None);
+    }
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate(VERSION), Ok(VERSION));
+        assert_eq!(negotiate(VERSION + 1), Ok(VERSION));
+        assert_eq!(negotiate(1), Err(ERR_VERSION));
+        assert_eq!(negotiate(0), Err(ERR_VERSION));
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let req = Request { id: 7, cmd: CMD_READ, payload: vec![1, 2, 3, 4, 5, 6, 7, 8] };
+        let mut buf = req.encode();
+        assert_eq!(buf.len(), 20);
+        assert_eq!(le32(&buf, 0), 16);
+
+        // Trailing bytes from the next frame are left alone
+        buf.extend_from_slice(&[0xaa; 3]);
+        assert_eq!(Request::decode(&buf), Ok(Some((req, 20))));
+
+        let empty = Request { id: 1, cmd: CMD_ACK, payload: Vec::new() };
+        assert_eq!(Request::decode(&empty.encode()), Ok(Some((empty, 12))));
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let res = Response { id: 3, status: STATUS_OK, flags: FLAG_MORE, payload: vec![9; 0x10] };
+        let buf = res.encode();
+        assert_eq!(buf.len(), 0x20);
+        assert_eq!(Response::decode(&buf), Ok(Some((res, 0x20))));
+    }
+
+    #[test]
+    fn incomplete_frames() {
+        let buf = Request { id: 1, cmd: CMD_WRITE, payload: vec![0; 8] }.encode();
+        for len in 0..buf.len() {
+            assert_eq!(Request::decode(&buf[..len]), Ok(None), "len {}", len);
+        }
+        let buf = Response { id: 1, status: 0, flags: 0, payload: vec![0; 8] }.encode();
+        assert_eq!(Response::decode(&buf[..buf.len() - 1]), Ok(None));
+    }
+
+    #[test]
+    fn short_frames() {
+        // Frames which are too short for the header
+        let mut buf = 4u32.to_le_bytes().to_vec();
+        buf.extend_from_slice(&[0; 4]);
+        assert_eq!(Request::decode(&buf), Err(ERR_MALFORMED));
+        buf[0] = 8;
+        buf.extend_from_slice(&[0; 4]);
+        assert_eq!(Response::decode(&buf), Err(ERR_MALFORMED));
+    }
+
+    #[test]
+    fn oversized_frames() {
+        // Oversized frames are rejected as soon as the length is received
+        let buf = (MAX_FRAME_LEN as u32 + 1).to_le_bytes();
+        assert_eq!(Request::decode(&buf), Err(ERR_TOO_LARGE));
+        assert_eq!(Response::decode(&buf), Err(ERR_TOO_LARGE));
+
+        let buf = (MAX_FRAME_LEN as u32).to_le_bytes();
+        assert_eq!(Request::decode(&buf), Ok(None));
+    }
+}
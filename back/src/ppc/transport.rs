@@ -0,0 +1,92 @@
+//! Transports used to serve the PPC HLE protocol.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// An address where the server listens for clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// A path to a UNIX socket.
+    Unix(String),
+    /// A TCP address (i.e. `127.0.0.1:5000`).
+    Tcp(String),
+}
+impl Transport {
+    /// Parse a transport from a string like `unix:<path>` or `tcp:<addr>`.
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Some(Transport::Unix(path.to_string()))
+        } else {
+            s.strip_prefix("tcp:").map(|addr| Transport::Tcp(addr.to_string()))
+        }
+    }
+}
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Transport::Unix(path) => write!(f, "unix:{}", path),
+            Transport::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// A connection to some client.
+pub trait ClientStream: Read + Write + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+impl ClientStream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+impl ClientStream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// A socket listening for clients on some transport.
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+impl Listener {
+    /// Start listening on some transport.
+    pub fn bind(transport: &Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Unix(path) => {
+                // Remove a stale socket left behind by a previous instance
+                let _ = std::fs::remove_file(path);
+                UnixListener::bind(path).map(Listener::Unix)
+            },
+            Transport::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Unix(l) => l.set_nonblocking(nonblocking),
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Accept a new client.
+    pub fn accept(&self) -> io::Result<Box<dyn ClientStream>> {
+        match self {
+            Listener::Unix(l) => Ok(Box::new(l.accept()?.0)),
+            Listener::Tcp(l) => {
+                let (stream, _) = l.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            },
+        }
+    }
+}
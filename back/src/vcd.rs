@@ -0,0 +1,184 @@
+//! Writing the state of the GPIO pins and interrupt lines to a VCD file.
+//!
+//! [VcdTracer] samples Hollywood on every bus step and writes any changes
+//! as a Value Change Dump, which can be opened with GTKWave. Time is counted
+//! in bus cycles. Tracing can be limited to some window, which starts and
+//! stops on a particular cycle or boot stage.
+
+use ironic_core::dev::hlwd::trace::*;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// A signal in the trace: (scope, name, width, getter).
+type Signal = (&'static str, &'static str, u32, fn(&HlwdSample) -> u32);
+
+const SIGNALS: &[Signal] = &[
+    ("gpio", "arm_output", 32, |s| s.arm_gpio_output),
+    ("gpio", "arm_dir",    32, |s| s.arm_gpio_dir),
+    ("gpio", "arm_input",  32, |s| s.arm_gpio_input),
+    ("gpio", "ppc_output", 32, |s| s.ppc_gpio_output),
+    ("gpio", "ppc_dir",    32, |s| s.ppc_gpio_dir),
+    ("gpio", "ppc_input",  32, |s| s.ppc_gpio_input),
+    ("irq", "arm_status",  32, |s| s.arm_irq_status),
+    ("irq", "arm_enable",  32, |s| s.arm_irq_enable),
+    ("irq", "arm_fiq_enable", 32, |s| s.arm_fiq_enable),
+    ("irq", "ppc_status",  32, |s| s.ppc_irq_status),
+    ("irq", "ppc_enable",  32, |s| s.ppc_irq_enable),
+    ("irq", "arm_irq",     1,  |s| s.arm_irq_output as u32),
+    ("irq", "arm_fiq",     1,  |s| s.arm_fiq_output as u32),
+    ("irq", "ppc_irq",     1,  |s| s.ppc_irq_output as u32),
+    ("ppc", "ppc_on",      1,  |s| s.ppc_on as u32),
+];
+
+/// Some point in emulation where tracing starts or stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceTrigger {
+    /// Some bus cycle.
+    Cycle(usize),
+    /// Broadway is released from reset.
+    PpcBoot,
+    /// Some value is written to the GPIO debug port.
+    Debug(u8),
+}
+impl TraceTrigger {
+    /// Parse a trigger from a string: a cycle number, `ppc-boot`, or
+    /// `debug:<code>` (with the code in hex).
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "ppc-boot" {
+            return Some(Self::PpcBoot);
+        }
+        if let Some(code) = s.strip_prefix("debug:") {
+            return u8::from_str_radix(code.trim_start_matches("0x"), 16)
+                .ok().map(Self::Debug);
+        }
+        let cycle = s.strip_prefix("cycle:").unwrap_or(s);
+        match cycle.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => cycle.parse().ok(),
+        }.map(Self::Cycle)
+    }
+
+    fn is_hit(&self, cycle: usize, sample: &HlwdSample) -> bool {
+        match *self {
+            Self::Cycle(c) => cycle >= c,
+            Self::PpcBoot => sample.ppc_on,
+            Self::Debug(code) => ((sample.arm_gpio_output >> 16) & 0xff) as u8 == code,
+        }
+    }
+}
+
+/// Stages of tracing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TraceState {
+    Waiting,
+    Tracing,
+    Done,
+}
+
+/// Tracer which writes changes in the state of Hollywood to a VCD file.
+pub struct VcdTracer {
+    out: BufWriter<File>,
+    filename: String,
+    /// Tracing starts when this is hit (or immediately, if unset).
+    pub start: Option<TraceTrigger>,
+    /// Tracing stops when this is hit (or when emulation stops, if unset).
+    pub stop: Option<TraceTrigger>,
+    state: TraceState,
+    /// The last sample written to the file.
+    last: Option<HlwdSample>,
+}
+impl VcdTracer {
+    pub fn new(filename: &str) -> Result<Self, String> {
+        let f = File::create(filename)
+            .map_err(|e| format!("Couldn't create {}: {}", filename, e))?;
+        let mut res = VcdTracer {
+            out: BufWriter::new(f),
+            filename: filename.to_string(),
+            start: None,
+            stop: None,
+            state: TraceState::Waiting,
+            last: None,
+        };
+        res.write_header().map_err(|e| format!("Couldn't write {}: {}", filename, e))?;
+        Ok(res)
+    }
+
+    /// Identifier for the signal at some index.
+    fn id(idx: usize) -> char {
+        (b'!' + idx as u8) as char
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        writeln!(self.out, "$version ironic $end")?;
+        writeln!(self.out, "$comment time is in bus cycles $end")?;
+        writeln!(self.out, "$timescale 1ns $end")?;
+        writeln!(self.out, "$scope module hollywood $end")?;
+        let mut scope = "";
+        for (idx, (s, name, width, _)) in SIGNALS.iter().enumerate() {
+            if *s != scope {
+                if !scope.is_empty() {
+                    writeln!(self.out, "$upscope $end")?;
+                }
+                writeln!(self.out, "$scope module {} $end", s)?;
+                scope = s;
+            }
+            writeln!(self.out, "$var wire {} {} {} $end", width, Self::id(idx), name)?;
+        }
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$enddefinitions $end")
+    }
+
+    /// Write the signals which changed since the last sample.
+    fn write_changes(&mut self, cycle: usize, sample: &HlwdSample) -> std::io::Result<()> {
+        if self.last.as_ref() == Some(sample) {
+            return Ok(());
+        }
+        writeln!(self.out, "#{}", cycle)?;
+        for (idx, (_, _, width, get)) in SIGNALS.iter().enumerate() {
+            let val = get(sample);
+            if self.last.as_ref().is_some_and(|last| get(last) == val) {
+                continue;
+            }
+            if *width == 1 {
+                writeln!(self.out, "{}{}", val, Self::id(idx))?;
+            } else {
+                writeln!(self.out, "b{:b} {}", val, Self::id(idx))?;
+            }
+        }
+        self.last = Some(*sample);
+        Ok(())
+    }
+}
+
+impl HlwdTracer for VcdTracer {
+    fn sample(&mut self, cycle: usize, sample: &HlwdSample) {
+        if self.state == TraceState::Waiting {
+            if !self.start.is_none_or(|t| t.is_hit(cycle, sample)) {
+                return;
+            }
+            println!("VCD tracing started at cycle {}", cycle);
+            self.state = TraceState::Tracing;
+        }
+        if self.state != TraceState::Tracing {
+            return;
+        }
+
+        if let Err(e) = self.write_changes(cycle, sample) {
+            println!("VCD couldn't write {}: {}", self.filename, e);
+            self.state = TraceState::Done;
+        }
+        if self.stop.is_some_and(|t| t.is_hit(cycle, sample)) {
+            println!("VCD tracing stopped at cycle {}", cycle);
+            self.state = TraceState::Done;
+            self.finish();
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Err(e) = self.out.flush() {
+            println!("VCD couldn't write {}: {}", self.filename, e);
+        }
+    }
+}
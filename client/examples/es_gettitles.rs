@@ -0,0 +1,63 @@
+//! List the titles installed on the NAND, like `pyronic/es_gettitles.py`.
+//!
+//! Pass a transport (i.e. `tcp:127.0.0.1:5000`) to connect to the server,
+//! or `hle:<dir>` to run IOS HLE in this process with some host directory.
+
+use ironic_core::bus::Bus;
+use ironic_backend::hle::IosHleBackend;
+use ironic_backend::ppc::IPC_SOCK;
+use ironic_backend::ppc::transport::Transport;
+use ironic_client::alloc::Allocator;
+use ironic_client::conn::PpcConn;
+use ironic_client::ios::IosClient;
+use ironic_client::local::LocalConn;
+
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+
+fn list_titles<C: PpcConn>(ipc: &mut IosClient<C>) -> Result<(), String> {
+    let fd = ipc.open("/dev/es", 0)?;
+    println!("fd={}", fd);
+    if fd < 0 {
+        return Ok(());
+    }
+
+    let (res, out) = ipc.ioctlv(fd, 0x0e, &[], &[4])?;
+    if res < 0 {
+        println!("ES_GetTitleCount() returned {}", res);
+        ipc.close(fd)?;
+        return Ok(());
+    }
+    let num_titles = u32::from_be_bytes(out[0][..4].try_into().unwrap());
+    println!("num_titles={}", num_titles);
+
+    let (res, out) = ipc.ioctlv(fd, 0x0f, &[&num_titles.to_be_bytes()], &[8 * num_titles])?;
+    if res < 0 {
+        println!("ES_GetTitles() returned {}", res);
+        ipc.close(fd)?;
+        return Ok(());
+    }
+    println!("Found titles:");
+    for t in out[0].chunks(8) {
+        println!("\t{:016x}", u64::from_be_bytes(t.try_into().unwrap()));
+    }
+
+    ipc.close(fd)?;
+    Ok(())
+}
+
+fn main() -> Result<(), String> {
+    let arg = std::env::args().nth(1);
+    if let Some(dir) = arg.as_ref().and_then(|arg| arg.strip_prefix("hle:")) {
+        let bus = Arc::new(RwLock::new(Bus::new()));
+        let ios = IosHleBackend::with_host_dir(bus, dir)?;
+        let mut ipc = IosClient::new(LocalConn::with_ios_hle(ios)?, Allocator::default());
+        return list_titles(&mut ipc);
+    }
+
+    let transport = match arg {
+        Some(arg) => Transport::parse(&arg).ok_or(format!("Invalid transport '{}'", arg))?,
+        None => Transport::Unix(IPC_SOCK.to_string()),
+    };
+    list_titles(&mut IosClient::connect(&transport)?)
+}
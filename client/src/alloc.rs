@@ -0,0 +1,142 @@
+//! Allocating guest memory for buffers used in IPC requests.
+
+use std::collections::BTreeMap;
+
+/// Base address and length of MEM1.
+pub const MEM1: (u32, u32) = (0x0000_0000, 0x0180_0000);
+/// Base address and length of MEM2.
+pub const MEM2: (u32, u32) = (0x1000_0000, 0x0400_0000);
+/// The region used by default (the same one used by `pyronic`).
+pub const DEFAULT_HEAP: (u32, u32) = (0x0100_0000, 0x0078_0000);
+
+/// Alignment of all allocations (IPC requests must be 32-byte aligned).
+pub const ALIGN: u32 = 0x20;
+
+/// A first-fit, free-list allocator over some region of guest memory.
+pub struct Allocator {
+    /// Free blocks, by address.
+    free: BTreeMap<u32, u32>,
+    /// Allocated blocks, by address.
+    used: BTreeMap<u32, u32>,
+}
+impl Allocator {
+    pub fn new(base: u32, len: u32) -> Self {
+        // Trim the region so that every block is aligned
+        let start = (base + ALIGN - 1) & !(ALIGN - 1);
+        let end = (base + len) & !(ALIGN - 1);
+        assert!(start < end, "Heap region {:08x}+{:x} is too small", base, len);
+        let mut free = BTreeMap::new();
+        free.insert(start, end - start);
+        Allocator { free, used: BTreeMap::new() }
+    }
+
+    /// Allocate some block, returning the address.
+    pub fn alloc(&mut self, len: u32) -> Option<u32> {
+        let len = len.max(1).checked_add(ALIGN - 1)? & !(ALIGN - 1);
+        let (addr, free_len) = self.free.iter()
+            .find(|(_, &free_len)| free_len >= len)
+            .map(|(&a, &l)| (a, l))?;
+        self.free.remove(&addr);
+        if free_len > len {
+            self.free.insert(addr + len, free_len - len);
+        }
+        self.used.insert(addr, len);
+        Some(addr)
+    }
+
+    /// Free some block, returning false if it wasn't allocated.
+    pub fn free(&mut self, addr: u32) -> bool {
+        let mut len = match self.used.remove(&addr) {
+            Some(len) => len,
+            None => return false,
+        };
+        let mut addr = addr;
+
+        // Merge with the neighbouring free blocks
+        if let Some(next_len) = self.free.remove(&(addr + len)) {
+            len += next_len;
+        }
+        if let Some((&prev, &prev_len)) = self.free.range(..addr).next_back() {
+            if prev + prev_len == addr {
+                addr = prev;
+                len += prev_len;
+            }
+        }
+        self.free.insert(addr, len);
+        true
+    }
+
+    /// Returns the total number of free bytes.
+    pub fn free_bytes(&self) -> u32 {
+        self.free.values().sum()
+    }
+}
+impl Default for Allocator {
+    fn default() -> Self {
+        Allocator::new(DEFAULT_HEAP.0, DEFAULT_HEAP.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_and_free() {
+        let mut heap = Allocator::new(0x1000, 0x100);
+        let a = heap.alloc(0x10).unwrap();
+        let b = heap.alloc(0x21).unwrap();
+        assert_eq!(a, 0x1000);
+        assert_eq!(b, 0x1020);
+        assert_eq!(heap.free_bytes(), 0x100 - 0x20 - 0x40);
+
+        assert!(heap.free(a));
+        assert!(!heap.free(a));
+        assert!(!heap.free(0x1010));
+
+        // First fit reuses the freed block
+        assert_eq!(heap.alloc(0x20), Some(a));
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut heap = Allocator::new(0x1000, 0xc0);
+        let a = heap.alloc(0x40).unwrap();
+        let b = heap.alloc(0x40).unwrap();
+        let c = heap.alloc(0x40).unwrap();
+
+        // Freeing the blocks on either side, then the middle one, leaves
+        // a single block covering the whole region
+        assert!(heap.free(a));
+        assert!(heap.free(c));
+        assert_eq!(heap.alloc(0x80), None);
+        assert!(heap.free(b));
+        assert_eq!(heap.free.len(), 1);
+        assert_eq!(heap.alloc(0xc0), Some(0x1000));
+    }
+
+    #[test]
+    fn alignment() {
+        // The region is trimmed to 0x1020-0x1100
+        let mut heap = Allocator::new(0x1005, 0x100);
+        assert_eq!(heap.free_bytes(), 0xe0);
+        assert_eq!(heap.alloc(0), Some(0x1020));
+        assert_eq!(heap.alloc(1), Some(0x1040));
+        assert_eq!(heap.alloc(0x20), Some(0x1060));
+        for addr in heap.used.keys() {
+            assert_eq!(addr % ALIGN, 0);
+        }
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut heap = Allocator::new(0x1000, 0x100);
+        assert_eq!(heap.alloc(0x101), None);
+        assert_eq!(heap.alloc(u32::MAX), None);
+        assert_eq!(heap.alloc(0x100), Some(0x1000));
+        assert_eq!(heap.alloc(1), None);
+        assert_eq!(heap.free_bytes(), 0);
+        assert!(heap.free(0x1000));
+        assert_eq!(heap.free_bytes(), 0x100);
+    }
+}
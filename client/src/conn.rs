@@ -0,0 +1,201 @@
+//! A connection to the PPC HLE server.
+
+use ironic_backend::ppc::proto::*;
+use ironic_backend::ppc::transport::Transport;
+use ironic_core::dev::hlwd::gpio::Button;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::convert::TryInto;
+
+/// Some stream connected to the server.
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Describe a status code from the server.
+fn status_str(status: u32) -> &'static str {
+    match status {
+        ERR_UNKNOWN_CMD => "unknown command",
+        ERR_MALFORMED => "malformed request",
+        ERR_BAD_ADDR => "bad address",
+        ERR_TOO_LARGE => "request too large",
+        ERR_VERSION => "unsupported version",
+        ERR_NOT_BOOTED => "Broadway hasn't booted",
+        _ => "unknown error",
+    }
+}
+
+/// Something which can access guest memory and pass IPC messages to the
+/// ARM, on behalf of the PowerPC.
+pub trait PpcConn {
+    /// Read some guest physical memory.
+    fn host_read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, String>;
+
+    /// Write some guest physical memory.
+    fn host_write(&mut self, addr: u32, data: &[u8]) -> Result<(), String>;
+
+    /// Send an IPC message to the ARM, and wait for the reply. Returns the
+    /// pointer from the reply.
+    fn message(&mut self, addr: u32) -> Result<u32, String>;
+
+    /// Send an IPC message to the ARM without waiting for the reply.
+    fn message_no_reply(&mut self, addr: u32) -> Result<(), String>;
+
+    /// Acknowledge a message from the ARM.
+    fn ack(&mut self) -> Result<(), String>;
+}
+
+/// A client using the framed protocol.
+///
+/// Requests are sent one at a time, and each call blocks until the server
+/// has responded.
+pub struct PpcClient {
+    stream: Box<dyn Stream>,
+    /// ID for the next request.
+    next_id: u32,
+}
+impl PpcClient {
+    /// Connect to the server, and perform the handshake.
+    pub fn connect(transport: &Transport) -> Result<Self, String> {
+        let stream: Box<dyn Stream> = match transport {
+            Transport::Unix(path) => Box::new(UnixStream::connect(path)
+                .map_err(|e| format!("Couldn't connect to {}: {}", transport, e))?),
+            Transport::Tcp(addr) => {
+                let s = TcpStream::connect(addr)
+                    .map_err(|e| format!("Couldn't connect to {}: {}", transport, e))?;
+                s.set_nodelay(true).ok();
+                Box::new(s)
+            },
+        };
+        let mut res = PpcClient { stream, next_id: 0 };
+
+        res.send(&handshake(VERSION))?;
+        let mut buf = [0u8; HANDSHAKE_REPLY_LEN];
+        res.recv(&mut buf)?;
+        match parse_handshake_reply(&buf) {
+            Some((_, STATUS_OK)) => Ok(res),
+            Some((version, status)) => Err(format!(
+                "Handshake failed ({}, server speaks version {})",
+                status_str(status), version
+            )),
+            None => Err("Invalid handshake reply".to_string()),
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> Result<(), String> {
+        self.stream.write_all(buf).map_err(|e| format!("Couldn't send: {}", e))
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.stream.read_exact(buf).map_err(|e| format!("Couldn't receive: {}", e))
+    }
+
+    /// Receive a single response frame.
+    fn recv_response(&mut self) -> Result<Response, String> {
+        let mut len = [0u8; 4];
+        self.recv(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(format!("Response frame too large ({:x} bytes)", len));
+        }
+        let mut buf = vec![0u8; 4 + len];
+        buf[0..4].copy_from_slice(&(len as u32).to_le_bytes());
+        self.recv(&mut buf[4..])?;
+        match Response::decode(&buf) {
+            Ok(Some((res, _))) => Ok(res),
+            _ => Err("Malformed response".to_string()),
+        }
+    }
+
+    /// Send a request and return the payload of the response, joining any
+    /// chunks together.
+    fn request(&mut self, cmd: u32, payload: Vec<u8>) -> Result<Vec<u8>, String> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.send(&Request { id, cmd, payload }.encode())?;
+
+        let mut res = Vec::new();
+        loop {
+            let chunk = self.recv_response()?;
+            if chunk.id != id {
+                return Err(format!("Expected response {}, got {}", id, chunk.id));
+            }
+            if chunk.status != STATUS_OK {
+                return Err(format!("Request failed ({})", status_str(chunk.status)));
+            }
+            res.extend_from_slice(&chunk.payload);
+            if chunk.flags & FLAG_MORE == 0 {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// Get the entry point and bootstrap code from the last time Broadway
+    /// was released from reset.
+    pub fn boot_info(&mut self) -> Result<(Option<u32>, [u32; 0x10]), String> {
+        let res = self.request(CMD_BOOT_INFO, Vec::new())?;
+        if res.len() != 0x44 {
+            return Err("Malformed boot info".to_string());
+        }
+        let word = |idx: usize| u32::from_le_bytes(res[idx * 4..idx * 4 + 4].try_into().unwrap());
+        let entry = match word(0) {
+            0xffff_ffff => None,
+            x => Some(x),
+        };
+        let mut bootstrap = [0u32; 0x10];
+        for (idx, w) in bootstrap.iter_mut().enumerate() {
+            *w = word(idx + 1);
+        }
+        Ok((entry, bootstrap))
+    }
+
+    /// Press or release a button on the console.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> Result<(), String> {
+        let mut payload = (button as u32).to_le_bytes().to_vec();
+        payload.extend_from_slice(&(pressed as u32).to_le_bytes());
+        self.request(CMD_BUTTON, payload)?;
+        Ok(())
+    }
+}
+
+impl PpcConn for PpcClient {
+    fn host_read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, String> {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&addr.to_le_bytes());
+        payload.extend_from_slice(&(len as u32).to_le_bytes());
+        let res = self.request(CMD_READ, payload)?;
+        if res.len() != len {
+            return Err(format!("Expected {:x} bytes, got {:x}", len, res.len()));
+        }
+        Ok(res)
+    }
+
+    fn host_write(&mut self, addr: u32, data: &[u8]) -> Result<(), String> {
+        // Split large writes so they fit into a single frame
+        for (idx, chunk) in data.chunks(CHUNK_LEN).enumerate() {
+            let mut payload = Vec::with_capacity(4 + chunk.len());
+            payload.extend_from_slice(&(addr + (idx * CHUNK_LEN) as u32).to_le_bytes());
+            payload.extend_from_slice(chunk);
+            self.request(CMD_WRITE, payload)?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, addr: u32) -> Result<u32, String> {
+        let res = self.request(CMD_MESSAGE, addr.to_le_bytes().to_vec())?;
+        let ptr: [u8; 4] = res.as_slice().try_into()
+            .map_err(|_| "Malformed reply to message".to_string())?;
+        Ok(u32::from_le_bytes(ptr))
+    }
+
+    fn message_no_reply(&mut self, addr: u32) -> Result<(), String> {
+        self.request(CMD_MESSAGE_NO_REPLY, addr.to_le_bytes().to_vec())?;
+        Ok(())
+    }
+
+    fn ack(&mut self) -> Result<(), String> {
+        self.request(CMD_ACK, Vec::new())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Typed wrappers for IOS IPC requests.
+
+use ironic_backend::ipc::*;
+use ironic_backend::ppc::transport::Transport;
+use crate::conn::{PpcConn, PpcClient};
+use crate::alloc::Allocator;
+
+use std::convert::TryInto;
+
+/// A client used to send IPC requests to IOS.
+///
+/// Buffers for each request are allocated from `heap`, and are freed again
+/// once the request has completed.
+pub struct IosClient<C: PpcConn = PpcClient> {
+    pub conn: C,
+    pub heap: Allocator,
+}
+impl IosClient<PpcClient> {
+    /// Connect to the server, using the default heap.
+    pub fn connect(transport: &Transport) -> Result<Self, String> {
+        Ok(IosClient::new(PpcClient::connect(transport)?, Allocator::default()))
+    }
+}
+impl<C: PpcConn> IosClient<C> {
+    pub fn new(conn: C, heap: Allocator) -> Self {
+        IosClient { conn, heap }
+    }
+
+    /// Allocate a buffer in guest memory, initialized with some data.
+    pub fn alloc_buf(&mut self, data: &[u8]) -> Result<u32, String> {
+        let addr = self.alloc_raw(data.len() as u32)?;
+        if let Err(e) = self.conn.host_write(addr, data) {
+            self.heap.free(addr);
+            return Err(e);
+        }
+        Ok(addr)
+    }
+
+    /// Allocate an uninitialized buffer in guest memory.
+    pub fn alloc_raw(&mut self, len: u32) -> Result<u32, String> {
+        self.heap.alloc(len)
+            .ok_or_else(|| format!("Couldn't allocate {:x} bytes", len))
+    }
+
+    pub fn free(&mut self, addr: u32) {
+        self.heap.free(addr);
+    }
+
+    /// Run some function with a list of buffers, freeing all of them
+    /// afterwards (even if the function fails).
+    fn with_bufs<T>(&mut self,
+        f: impl FnOnce(&mut Self, &mut Vec<u32>) -> Result<T, String>) -> Result<T, String>
+    {
+        let mut bufs = Vec::new();
+        let res = f(self, &mut bufs);
+        for addr in bufs {
+            self.heap.free(addr);
+        }
+        res
+    }
+
+    /// Send an IPC request and wait for the reply, returning the result.
+    pub fn ipc(&mut self, req: &IosReq) -> Result<i32, String> {
+        self.with_bufs(|c, bufs| {
+            let addr = c.alloc_buf(&req.to_buf())?;
+            bufs.push(addr);
+            let reply = c.conn.message(addr)?;
+            let buf = c.conn.host_read(reply, IosReq::LEN)?;
+            Ok(i32::from_be_bytes(buf[4..8].try_into().unwrap()))
+        })
+    }
+
+    /// Build a request with some arguments.
+    fn req(cmd: IosCmd, fd: u32, args: [u32; 5]) -> IosReq {
+        let arg = IosArg {
+            ioctl: IoctlArg {
+                cmd: args[0], input_addr: args[1], input_len: args[2],
+                output_addr: args[3], output_len: args[4],
+            }
+        };
+        IosReq { cmd, res: 0, fd, arg }
+    }
+
+    pub fn open(&mut self, path: &str, mode: u32) -> Result<i32, String> {
+        self.with_bufs(|c, bufs| {
+            let mut name = path.as_bytes().to_vec();
+            name.push(0);
+            let name = c.alloc_buf(&name)?;
+            bufs.push(name);
+            c.ipc(&Self::req(IosCmd::Open, 0, [name, mode, 0, 0, 0]))
+        })
+    }
+
+    pub fn close(&mut self, fd: i32) -> Result<i32, String> {
+        self.ipc(&Self::req(IosCmd::Close, fd as u32, [0; 5]))
+    }
+
+    /// Read from some file. Returns the result and the data which was read.
+    pub fn read(&mut self, fd: i32, len: u32) -> Result<(i32, Vec<u8>), String> {
+        self.with_bufs(|c, bufs| {
+            let buf = c.alloc_raw(len)?;
+            bufs.push(buf);
+            let res = c.ipc(&Self::req(IosCmd::Read, fd as u32, [buf, len, 0, 0, 0]))?;
+            let data = if res > 0 {
+                c.conn.host_read(buf, (res as u32).min(len) as usize)?
+            } else {
+                Vec::new()
+            };
+            Ok((res, data))
+        })
+    }
+
+    pub fn write(&mut self, fd: i32, data: &[u8]) -> Result<i32, String> {
+        self.with_bufs(|c, bufs| {
+            let buf = c.alloc_buf(data)?;
+            bufs.push(buf);
+            let len = data.len() as u32;
+            c.ipc(&Self::req(IosCmd::Write, fd as u32, [buf, len, 0, 0, 0]))
+        })
+    }
+
+    pub fn seek(&mut self, fd: i32, offset: i32, whence: u32) -> Result<i32, String> {
+        self.ipc(&Self::req(IosCmd::Seek, fd as u32, [offset as u32, whence, 0, 0, 0]))
+    }
+
+    /// Send an ioctl. Returns the result and the contents of the output
+    /// buffer.
+    pub fn ioctl(&mut self, fd: i32, cmd: u32, input: &[u8], output_len: u32)
+        -> Result<(i32, Vec<u8>), String>
+    {
+        self.with_bufs(|c, bufs| {
+            let input_addr = c.alloc_buf(input)?;
+            bufs.push(input_addr);
+            let output_addr = c.alloc_raw(output_len)?;
+            bufs.push(output_addr);
+            let res = c.ipc(&Self::req(IosCmd::Ioctl, fd as u32, [
+                cmd, input_addr, input.len() as u32, output_addr, output_len
+            ]))?;
+            let output = c.conn.host_read(output_addr, output_len as usize)?;
+            Ok((res, output))
+        })
+    }
+
+    /// Send an ioctlv with some input buffers, and output buffers with some
+    /// lengths. Returns the result and the contents of the output buffers.
+    pub fn ioctlv(&mut self, fd: i32, cmd: u32, input: &[&[u8]], output_lens: &[u32])
+        -> Result<(i32, Vec<Vec<u8>>), String>
+    {
+        self.with_bufs(|c, bufs| {
+            let mut entries = Vec::new();
+            for data in input {
+                let addr = c.alloc_buf(data)?;
+                bufs.push(addr);
+                entries.push(IoctlvArgEntry { data: addr, len: data.len() as u32 });
+            }
+            for &len in output_lens {
+                let addr = c.alloc_raw(len)?;
+                bufs.push(addr);
+                entries.push(IoctlvArgEntry { data: addr, len });
+            }
+
+            let mut argv = Vec::new();
+            for e in entries.iter() {
+                argv.extend_from_slice(&e.data.to_be_bytes());
+                argv.extend_from_slice(&e.len.to_be_bytes());
+            }
+            let argv = c.alloc_buf(&argv)?;
+            bufs.push(argv);
+
+            let res = c.ipc(&Self::req(IosCmd::Ioctlv, fd as u32, [
+                cmd, input.len() as u32, output_lens.len() as u32, argv, 0
+            ]))?;
+            let mut output = Vec::new();
+            for e in &entries[input.len()..] {
+                output.push(c.conn.host_read(e.data, e.len as usize)?);
+            }
+            Ok((res, output))
+        })
+    }
+}
@@ -0,0 +1,11 @@
+//! Client library for the PPC HLE server.
+//!
+//! [conn::PpcClient] speaks the framed protocol to the server, and
+//! [local::LocalConn] drives the PowerPC side of IPC in the same process.
+//! [alloc::Allocator] manages some region of guest memory, and
+//! [ios::IosClient] uses both to send IPC requests to IOS.
+
+pub mod conn;
+pub mod local;
+pub mod alloc;
+pub mod ios;
@@ -0,0 +1,223 @@
+//! An in-process connection, without any socket.
+
+use ironic_backend::hle::IosHleBackend;
+use ironic_backend::ppc::hle::PpcHle;
+use crate::conn::PpcConn;
+
+use std::sync::{Arc, RwLock};
+use ironic_core::bus::Bus;
+use ironic_core::dev::hlwd::ipc::PPC_CTRL_X2;
+
+/// The default number of times the ARM side is stepped while waiting for
+/// a reply, before giving up.
+pub const DEFAULT_MAX_STEPS: usize = 0x0010_0000;
+
+/// A connection which drives [PpcHle] directly.
+///
+/// While waiting for a reply, `step` is called to let the ARM side make
+/// progress, so everything can run in a single thread.
+pub struct LocalConn {
+    pub hle: PpcHle<()>,
+    step: Box<dyn FnMut()>,
+    /// The most times `step` is called while waiting for a reply.
+    pub max_steps: usize,
+}
+impl LocalConn {
+    /// Create a connection for some bus, once the ARM side has already
+    /// started the PowerPC.
+    pub fn new(bus: Arc<RwLock<Bus>>, step: impl FnMut() + 'static) -> Self {
+        LocalConn { hle: PpcHle::new(bus), step: Box::new(step), max_steps: DEFAULT_MAX_STEPS }
+    }
+
+    /// Create a connection to IOS HLE, and wait for it to start the
+    /// PowerPC.
+    pub fn with_ios_hle(mut ios: IosHleBackend) -> Result<Self, String> {
+        let mut hle = PpcHle::new(ios.bus.clone());
+        hle.enable_irqs();
+        ios.boot_ppc();
+        hle.wait_for_ack(DEFAULT_MAX_STEPS, || ios.step())?;
+        hle.bus.write().unwrap().hlwd.ipc.ppc_ctrl_set(PPC_CTRL_X2);
+        Ok(LocalConn { hle, step: Box::new(move || ios.step()), max_steps: DEFAULT_MAX_STEPS })
+    }
+}
+
+impl PpcConn for LocalConn {
+    fn host_read(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, String> {
+        self.hle.read(addr, len)
+            .ok_or_else(|| format!("Couldn't read {:x} bytes at {:08x}", len, addr))
+    }
+
+    fn host_write(&mut self, addr: u32, data: &[u8]) -> Result<(), String> {
+        if self.hle.write(addr, data) {
+            Ok(())
+        } else {
+            Err(format!("Couldn't write {:x} bytes at {:08x}", data.len(), addr))
+        }
+    }
+
+    fn message(&mut self, addr: u32) -> Result<u32, String> {
+        let step = &mut self.step;
+        self.hle.message_sync(addr, self.max_steps, step)
+    }
+
+    fn message_no_reply(&mut self, addr: u32) -> Result<(), String> {
+        self.hle.send_no_reply(addr);
+        while self.hle.is_pending() {
+            self.hle.poll(&mut Vec::new());
+            (self.step)();
+        }
+        Ok(())
+    }
+
+    fn ack(&mut self) -> Result<(), String> {
+        self.hle.ack();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::Allocator;
+    use crate::ios::IosClient;
+    use ironic_backend::ipc::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Start IOS HLE with a new, empty host directory.
+    fn ios_hle(name: &str) -> (IosClient<LocalConn>, PathBuf) {
+        let dir = std::env::temp_dir()
+            .join(format!("ironic-client-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let bus = Arc::new(RwLock::new(Bus::new_blank()));
+        let ios = IosHleBackend::with_host_dir(bus, dir.to_str().unwrap()).unwrap();
+        let ipc = IosClient::new(LocalConn::with_ios_hle(ios).unwrap(), Allocator::default());
+        (ipc, dir)
+    }
+
+    #[test]
+    fn open_es_with_ios_hle() {
+        let (mut ipc, dir) = ios_hle("es");
+        let fd = ipc.open("/dev/es", 0).unwrap();
+        assert!(fd >= 0);
+        assert!(ipc.open("/dev/nonexistent", 0).unwrap() < 0);
+
+        // There are no titles in an empty directory
+        let (res, out) = ipc.ioctlv(fd, 0x0e, &[], &[4]).unwrap();
+        assert_eq!(res, 0);
+        assert_eq!(out[0], 0u32.to_be_bytes());
+        assert_eq!(ipc.close(fd).unwrap(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Build a request with some (ioctl-shaped) arguments.
+    fn raw_req(cmd: IosCmd, fd: i32, args: [u32; 5]) -> IosReq {
+        let arg = IosArg {
+            ioctl: IoctlArg {
+                cmd: args[0], input_addr: args[1], input_len: args[2],
+                output_addr: args[3], output_len: args[4],
+            }
+        };
+        IosReq { cmd, res: 0, fd: fd as u32, arg }
+    }
+
+    /// An address which isn't backed by any memory.
+    const BAD_ADDR: u32 = 0x2000_0000;
+
+    #[test]
+    fn buffers_outside_of_memory() {
+        let (mut ipc, dir) = ios_hle("buffers");
+        fs::write(dir.join("file"), [0x5a; 0x20]).unwrap();
+
+        let name = ipc.alloc_buf(b"/file\0").unwrap();
+        assert_eq!(ipc.ipc(&raw_req(IosCmd::Open, 0, [BAD_ADDR, 3, 0, 0, 0])).unwrap(), -4);
+        let fd = ipc.ipc(&raw_req(IosCmd::Open, 0, [name, 3, 0, 0, 0])).unwrap();
+        assert!(fd >= 0);
+
+        // Reads and writes are checked before the file is touched
+        let read = raw_req(IosCmd::Read, fd, [BAD_ADDR, 0x10, 0, 0, 0]);
+        assert_eq!(ipc.ipc(&read).unwrap(), -101);
+        let write = raw_req(IosCmd::Write, fd, [0x017f_fff0, 0x20, 0, 0, 0]);
+        assert_eq!(ipc.ipc(&write).unwrap(), -101);
+        let write = raw_req(IosCmd::Write, fd, [name, 0xffff_ffff, 0, 0, 0]);
+        assert_eq!(ipc.ipc(&write).unwrap(), -101);
+        assert_eq!(ipc.read(fd, 0x20).unwrap(), (0x20, vec![0x5a; 0x20]));
+
+        let es = ipc.open("/dev/es", 0).unwrap();
+        let entry = [BAD_ADDR.to_be_bytes(), 4u32.to_be_bytes()].concat();
+        let argv = ipc.alloc_buf(&entry).unwrap();
+        let req = IosReq {
+            cmd: IosCmd::Ioctlv, res: 0, fd: es as u32,
+            arg: IosArg { ioctlv: IoctlvArg { cmd: 0x0e, input: 0, output: 1, argv, pad4: 0 } },
+        };
+        assert_eq!(ipc.ipc(&req).unwrap(), -1017);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_file_transfers() {
+        let (mut ipc, dir) = ios_hle("large");
+        fs::write(dir.join("file"), []).unwrap();
+        let data: Vec<u8> = (0..0x1_8000u32).map(|x| (x % 251) as u8).collect();
+
+        // These are split into several chunks
+        let fd = ipc.open("/file", 3).unwrap();
+        assert_eq!(ipc.write(fd, &data).unwrap(), data.len() as i32);
+        assert_eq!(ipc.seek(fd, 0, 0).unwrap(), 0);
+        assert_eq!(ipc.read(fd, 0x2_0000).unwrap(), (data.len() as i32, data.clone()));
+        assert_eq!(fs::read(dir.join("file")).unwrap(), data);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Build the input for creating a file or directory.
+    fn create_args(path: &str) -> Vec<u8> {
+        let mut buf = vec![0u8; 0x4a];
+        buf[6..6 + path.len()].copy_from_slice(path.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn symlinks_out_of_the_root() {
+        let (mut ipc, dir) = ios_hle("symlinks");
+        let outside = dir.with_extension("outside");
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new"), dir.join("dangling")).unwrap();
+
+        let fd = ipc.open("/dev/fs", 0).unwrap();
+        for path in ["/link/file", "/dangling"] {
+            let (res, _) = ipc.ioctl(fd, 0x09, &create_args(path), 0).unwrap();
+            assert_eq!(res, -102, "{}", path);
+        }
+        let (res, _) = ipc.ioctl(fd, 0x03, &create_args("/link/dir"), 0).unwrap();
+        assert_eq!(res, -102);
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+
+        // Paths inside the root still work
+        let (res, _) = ipc.ioctl(fd, 0x09, &create_args("/file"), 0).unwrap();
+        assert_eq!(res, 0);
+        assert!(dir.join("file").is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn ioctlv_with_too_many_vectors() {
+        let (mut ipc, dir) = ios_hle("ioctlv");
+        let fd = ipc.open("/dev/es", 0).unwrap();
+        for (input, output) in [(0xffff_ffff, 1), (0x1000_0000, 0)] {
+            let arg = IosArg {
+                ioctlv: IoctlvArg { cmd: 0x0e, input, output, argv: 0, pad4: 0 }
+            };
+            let req = IosReq { cmd: IosCmd::Ioctlv, res: 0, fd: fd as u32, arg };
+            assert_eq!(ipc.ipc(&req).unwrap(), -4);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
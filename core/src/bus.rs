@@ -3,16 +3,22 @@ pub mod decode;
 pub mod dispatch;
 pub mod mmio;
 pub mod task;
+pub mod prot;
 use crate::bus::task::*;
 
 use crate::mem::*;
+use crate::mem::storage::*;
 use crate::dev::hlwd::*;
+use crate::dev::hlwd::ipc::IpcObserver;
+use crate::dev::hlwd::boot::{PpcBootEvent, PpcBootObserver};
+use crate::dev::hlwd::trace::{HlwdSample, HlwdTracer};
 use crate::dev::aes::*;
 use crate::dev::sha::*;
 use crate::dev::nand::*;
 use crate::dev::ehci::*;
 use crate::dev::ohci::*;
 use crate::dev::sdhc::*;
+use crate::cpu::cache::CacheModel;
 
 
 /// Implementation of an emulated bus.
@@ -43,20 +49,54 @@ pub struct Bus {
     pub mirror_enabled: bool,
 
     /// Queue for pending work on I/O devices.
-    pub tasks: Vec<Task>,
+    pub sched: Scheduler,
+    /// The current bus cycle.
     pub cycle: usize,
+    /// Set when some MMIO write may have side-effects that need to be handled
+    /// by the bus before the CPU can continue.
+    pub sync_req: bool,
+
+    /// Observer for messages passed through the IPC mailbox.
+    pub ipc_observer: Option<Box<dyn IpcObserver>>,
+    /// Observer for Broadway being released from reset.
+    pub ppc_boot_observer: Option<Box<dyn PpcBootObserver>>,
+    /// State captured the last time Broadway was released from reset
+    /// (without the contents of MEM1).
+    pub ppc_boot: Option<PpcBootEvent>,
+    /// Tracer for the state of the GPIO pins and interrupt lines.
+    pub hlwd_tracer: Option<Box<dyn HlwdTracer>>,
+    /// Model of the ARM caches. This lives on the bus so that device DMA
+    /// can be checked against it.
+    pub arm_cache: Option<CacheModel>,
 }
 impl Bus {
     pub fn new()-> Self {
+        Bus::new_with_storage(&StorageConfig::default())
+    }
+
+    /// Create a new bus, opening storage images with the given modes.
+    pub fn new_with_storage(storage: &StorageConfig) -> Self {
+        let mrom = BigEndianMemory::new(0x0000_2000, Some("./boot0.bin"));
+        Bus::new_with_mrom(mrom, storage)
+    }
+
+    /// Create a new bus which doesn't need any image files. The mask ROM is
+    /// empty, and every storage device is blank.
+    pub fn new_blank() -> Self {
+        let mrom = BigEndianMemory::new(0x0000_2000, None);
+        Bus::new_with_mrom(mrom, &StorageConfig::blank())
+    }
+
+    fn new_with_mrom(mrom: BigEndianMemory, storage: &StorageConfig) -> Self {
         Bus { 
-            mrom: BigEndianMemory::new(0x0000_2000, Some("./boot0.bin")),
+            mrom,
             sram0: BigEndianMemory::new(0x0001_0000, None),
             sram1: BigEndianMemory::new(0x0001_0000, None),
             mem1: BigEndianMemory::new(0x0180_0000, None),
             mem2: BigEndianMemory::new(0x0400_0000, None),
 
-            hlwd: Hollywood::new(),
-            nand: NandInterface::new("./nand.bin"),
+            hlwd: Hollywood::new(storage),
+            nand: NandInterface::new("./nand.bin", storage.nand.clone()),
             aes: AesInterface::new(),
             sha: ShaInterface::new(),
             ehci: EhcInterface::new(),
@@ -67,9 +107,46 @@ impl Bus {
 
             rom_disabled: false,
             mirror_enabled: false,
-            tasks: Vec::new(),
+            sched: Scheduler::new(),
             cycle: 0,
+            sync_req: false,
+            ipc_observer: None,
+            ppc_boot_observer: None,
+            ppc_boot: None,
+            hlwd_tracer: None,
+            arm_cache: None,
+        }
+    }
+
+    /// Start passing messages from the IPC mailbox to some observer.
+    pub fn set_ipc_observer(&mut self, obs: Box<dyn IpcObserver>) {
+        self.hlwd.ipc.trace = true;
+        self.ipc_observer = Some(obs);
+    }
+
+    /// Start passing the state captured when Broadway boots to some
+    /// observer.
+    pub fn set_ppc_boot_observer(&mut self, obs: Box<dyn PpcBootObserver>) {
+        self.ppc_boot_observer = Some(obs);
+    }
+
+    /// Start sampling the state of Hollywood on every bus step.
+    pub fn set_hlwd_tracer(&mut self, tracer: Box<dyn HlwdTracer>) {
+        self.hlwd_tracer = Some(tracer);
+    }
+
+    /// Pass the current state of Hollywood to the tracer.
+    pub fn trace_hlwd(&mut self) {
+        if let Some(tracer) = self.hlwd_tracer.as_mut() {
+            tracer.sample(self.cycle, &HlwdSample::capture(&self.hlwd));
         }
     }
+
+    /// Persist any changes to storage devices.
+    pub fn flush_storage(&mut self) {
+        self.nand.data.flush();
+        self.hlwd.gpio.flush();
+        self.hlwd.otp.flush();
+    }
 }
 
@@ -6,36 +6,61 @@ use crate::bus::*;
 use crate::bus::prim::*;
 
 /// Top-level read/write functions for performing physical memory accesses.
+/// Unless some master is given, accesses are made on behalf of the ARM.
 impl Bus {
     /// Perform a 32-bit physical memory read.
     pub fn read32(&mut self, addr: u32) -> u32 {
-        let msg = self.do_read(addr, BusWidth::W);
-        match msg { BusPacket::Word(res) => res, _ => unreachable!(), }
+        self.read32_as(BusMaster::Arm, addr)
     }
-
     /// Perform a 16-bit physical memory read.
     pub fn read16(&mut self, addr: u32) -> u16 {
-        let msg = self.do_read(addr, BusWidth::H);
-        match msg { BusPacket::Half(res) => res, _ => unreachable!(), }
+        self.read16_as(BusMaster::Arm, addr)
     }
-
     /// Perform an 8-bit physical memory read.
     pub fn read8(&mut self, addr: u32) -> u8 {
-        let msg = self.do_read(addr, BusWidth::B);
-        match msg { BusPacket::Byte(res) => res, _ => unreachable!(), }
+        self.read8_as(BusMaster::Arm, addr)
     }
 
     /// Perform a 32-bit physical memory write.
     pub fn write32(&mut self, addr: u32, val: u32) {
-        self.do_write(addr, BusPacket::Word(val));
+        self.write32_as(BusMaster::Arm, addr, val);
     }
     /// Perform a 16-bit physical memory write.
     pub fn write16(&mut self, addr: u32, val: u16) {
-        self.do_write(addr, BusPacket::Half(val));
+        self.write16_as(BusMaster::Arm, addr, val);
     }
     /// Perform an 8-bit physical memory write.
     pub fn write8(&mut self, addr: u32, val: u8) {
-        self.do_write(addr, BusPacket::Byte(val));
+        self.write8_as(BusMaster::Arm, addr, val);
+    }
+
+    /// Perform a 32-bit physical memory read on behalf of some master.
+    pub fn read32_as(&mut self, master: BusMaster, addr: u32) -> u32 {
+        let msg = self.do_read(master, addr, BusWidth::W);
+        match msg { BusPacket::Word(res) => res, _ => unreachable!(), }
+    }
+    /// Perform a 16-bit physical memory read on behalf of some master.
+    pub fn read16_as(&mut self, master: BusMaster, addr: u32) -> u16 {
+        let msg = self.do_read(master, addr, BusWidth::H);
+        match msg { BusPacket::Half(res) => res, _ => unreachable!(), }
+    }
+    /// Perform an 8-bit physical memory read on behalf of some master.
+    pub fn read8_as(&mut self, master: BusMaster, addr: u32) -> u8 {
+        let msg = self.do_read(master, addr, BusWidth::B);
+        match msg { BusPacket::Byte(res) => res, _ => unreachable!(), }
+    }
+
+    /// Perform a 32-bit physical memory write on behalf of some master.
+    pub fn write32_as(&mut self, master: BusMaster, addr: u32, val: u32) {
+        self.do_write(master, addr, BusPacket::Word(val));
+    }
+    /// Perform a 16-bit physical memory write on behalf of some master.
+    pub fn write16_as(&mut self, master: BusMaster, addr: u32, val: u16) {
+        self.do_write(master, addr, BusPacket::Half(val));
+    }
+    /// Perform an 8-bit physical memory write on behalf of some master.
+    pub fn write8_as(&mut self, master: BusMaster, addr: u32, val: u8) {
+        self.do_write(master, addr, BusPacket::Byte(val));
     }
 
     /// Perform a DMA write operation.
@@ -47,14 +72,40 @@ impl Bus {
         self.do_dma_read(addr, buf);
     }
 
+    /// Perform a DMA write on behalf of some device. Writes which are
+    /// denied are dropped.
+    pub fn device_dma_write(&mut self, dev: IoDevice, addr: u32, buf: &[u8]) {
+        if let Some(cache) = self.arm_cache.as_mut() {
+            cache.check_dma_write(dev, addr, buf.len());
+        }
+        self.master_write(BusMaster::Dma(dev), addr, buf);
+    }
+    /// Perform a DMA read on behalf of some device. Reads which are denied
+    /// leave the buffer untouched.
+    pub fn device_dma_read(&mut self, dev: IoDevice, addr: u32, buf: &mut [u8]) {
+        if let Some(cache) = self.arm_cache.as_mut() {
+            cache.check_dma_read(dev, addr, buf.len());
+        }
+        self.master_read(BusMaster::Dma(dev), addr, buf);
+    }
+
 }
 
 impl Bus {
     /// Dispatch a physical read access (to memory, or some I/O device).
-    fn do_read(&mut self, addr: u32, width: BusWidth) -> BusPacket {
+    /// Reads which are denied to the master return zero.
+    fn do_read(&mut self, master: BusMaster, addr: u32, width: BusWidth) -> BusPacket {
         let handle = self.decode_phys_addr(addr).unwrap_or_else(||
             panic!("Unresolved physical address {:08x}", addr)
         );
+        if let Err(reg) = self.check_prot(master, handle.dev) {
+            println!("AHB {:?} read {:08x} denied by {}", master, addr, reg);
+            return match width {
+                BusWidth::W => BusPacket::Word(0),
+                BusWidth::H => BusPacket::Half(0),
+                BusWidth::B => BusPacket::Byte(0),
+            };
+        }
 
         let off = (addr & handle.mask) as usize;
         let resp = match handle.dev {
@@ -65,10 +116,15 @@ impl Bus {
     }
 
     /// Dispatch a physical write access (to memory, or some I/O device).
-    fn do_write(&mut self, addr: u32, msg: BusPacket) {
+    /// Writes which are denied to the master are dropped.
+    fn do_write(&mut self, master: BusMaster, addr: u32, msg: BusPacket) {
         let handle = self.decode_phys_addr(addr).unwrap_or_else(||
             panic!("Unresolved physical address {:08x}", addr)
         );
+        if let Err(reg) = self.check_prot(master, handle.dev) {
+            println!("AHB {:?} write {:08x} denied by {}", master, addr, reg);
+            return;
+        }
 
         let off = (addr & handle.mask) as usize;
         let _resp = match handle.dev {
@@ -2,6 +2,10 @@
 use crate::bus::*;
 use crate::bus::prim::*;
 use crate::bus::task::*;
+use crate::dev::nand::NandCmd;
+use crate::dev::aes::AesCommand;
+use crate::dev::sha::ShaCommand;
+use crate::dev::hlwd::compat::di::DriveInterface;
 
 /// Interface used by the bus to perform some access on an I/O device.
 pub trait MmioDevice {
@@ -63,50 +67,81 @@ impl Bus {
             _ => panic!("Unsupported write {:?} for {:?} at {:x}", msg, dev, off),
         };
 
+        // Writes on I/O devices may have side-effects (i.e. on the state of
+        // interrupt lines), so the CPU needs to synchronize with the bus
+        self.sync_req = true;
+
         // If the device returned some task, schedule it
-        if task.is_some() {
-            let t = task.unwrap();
+        if let Some(t) = task {
             let c = match t {
-                BusTask::Nand(_) => 0,
-                BusTask::Aes(_) => 0,
-                BusTask::Sha(_) => 0,
+                BusTask::Nand(x) => NandCmd::new(x).latency(),
+                BusTask::Aes(x) => AesCommand::from(x).latency(),
+                BusTask::Sha(x) => ShaCommand::from(x).latency(),
+                BusTask::Di(_) => DriveInterface::CMD_LATENCY,
+                BusTask::TimerAlarm => {
+                    let target = self.hlwd.timer.alarm_target();
+                    self.hlwd.timer.alarm_cycle = Some(target);
+                    target - self.cycle
+                },
 
                 BusTask::Mi{..} => 0,
                 BusTask::SetRomDisabled(_) => 0,
                 BusTask::SetMirrorEnabled(_) => 0,
             };
-            self.tasks.push(Task { kind: t, target_cycle: self.cycle + c });
+            self.schedule(t, c);
         }
     }
 }
 
 
 impl Bus {
-    /// Emulate a slice of work on the system bus.
+    /// The maximum number of cycles the CPU may run before synchronizing
+    /// with the bus, even when no events are pending.
+    pub const MAX_BATCH_CYCLES: usize = 0x1000;
+
+    /// Schedule some task to complete after the given number of cycles.
+    pub fn schedule(&mut self, kind: BusTask, latency: usize) {
+        self.sched.push(kind, self.cycle + latency);
+    }
+
+    /// Advance the bus to some cycle, dispatching any pending work.
     pub fn step(&mut self, cpu_cycle: usize) {
-        self.handle_step_hlwd(cpu_cycle);
-        if !self.tasks.is_empty() {
-            self.drain_tasks();
+        self.cycle = cpu_cycle;
+        self.sync_req = false;
+        self.hlwd.timer.sync(self.cycle);
+        self.drain_tasks();
+        self.handle_step_hlwd();
+        self.trace_hlwd();
+    }
+
+    /// Returns the number of cycles the CPU can run before the bus needs to
+    /// be stepped again.
+    ///
+    /// Batches end at the next increment of the Hollywood timer. The last
+    /// instruction in a batch may take cycles past the end, but it has
+    /// already been issued, and the bus is stepped (bringing the timer
+    /// up-to-date) before the next one. The timer register is therefore
+    /// exact as of the cycle on which the reading instruction was issued.
+    pub fn cycles_until_next_event(&self) -> usize {
+        let mut target = self.hlwd.timer.next_tick();
+        if let Some(c) = self.sched.next_cycle() {
+            target = target.min(c);
         }
-        self.cycle += 1;
+        target.saturating_sub(self.cycle).clamp(1, Self::MAX_BATCH_CYCLES)
     }
 
     /// Dispatch all of the pending tasks on the Bus.
     fn drain_tasks(&mut self) {
-        let mut idx = 0;
-        while idx != self.tasks.len() {
-            if self.tasks[idx].target_cycle <= self.cycle {
-                let task = self.tasks.remove(idx);
-                match task.kind {
-                    BusTask::Nand(x) => self.handle_task_nand(x),
-                    BusTask::Aes(x) => self.handle_task_aes(x),
-                    BusTask::Sha(x) => self.handle_task_sha(x),
-                    BusTask::Mi{kind, data} => self.handle_task_mi(kind, data),
-                    BusTask::SetRomDisabled(x) => self.rom_disabled = x,
-                    BusTask::SetMirrorEnabled(x) => self.mirror_enabled = x,
-                }
-            } else {
-                idx += 1;
+        while let Some(task) = self.sched.pop_due(self.cycle) {
+            match task.kind {
+                BusTask::Nand(x) => self.handle_task_nand(x),
+                BusTask::Aes(x) => self.handle_task_aes(x),
+                BusTask::Sha(x) => self.handle_task_sha(x),
+                BusTask::Di(x) => self.handle_task_di(x),
+                BusTask::TimerAlarm => self.handle_task_alarm(task.target_cycle),
+                BusTask::Mi{kind, data} => self.handle_task_mi(kind, data),
+                BusTask::SetRomDisabled(x) => self.rom_disabled = x,
+                BusTask::SetMirrorEnabled(x) => self.mirror_enabled = x,
             }
         }
     }
@@ -144,6 +144,17 @@ pub enum IoDevice {
     Mi,
 }
 
+/// Masters which can perform accesses on the bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusMaster {
+    /// Starlet.
+    Arm,
+    /// Broadway.
+    Ppc,
+    /// The DMA engine in some device.
+    Dma(IoDevice),
+}
+
 /// A message on the bus containing some value.
 #[derive(Debug, Clone, Copy)]
 pub enum BusPacket { Byte(u8), Half(u16), Word(u32) }
@@ -0,0 +1,248 @@
+//! Enforcement of the bus protection registers.
+//!
+//! SRNPROT, AHBPROT and AIPPROT decide which bus masters may access which
+//! targets. In this model, the ARM can always access everything, and DMA
+//! engines can only access memory. Broadway can always access MEM1 and MEM2,
+//! and other targets only when some protection bit is set:
+//!
+//! - SRAM needs [SRNPROT_PPC_SRAM] in SRNPROT.
+//! - Devices on the AHB (NAND through SDHC) need the bit in AHBPROT which is
+//!   numbered by their slot in the 0x0d0n_0000 region.
+//! - Hollywood registers need [AIPPROT_PPC_HLWD] in AIPPROT.
+//!
+//! Homebrew usually checks for AHBPROT being 0xffffffff, which gives
+//! Broadway access to every device on the AHB.
+
+use crate::bus::*;
+use crate::bus::prim::*;
+
+use std::convert::TryInto;
+
+/// SRNPROT bit which gives Broadway access to SRAM.
+pub const SRNPROT_PPC_SRAM: u32 = 0x0000_0008;
+/// AIPPROT bit which gives Broadway access to the Hollywood registers.
+pub const AIPPROT_PPC_HLWD: u32 = 0x0000_0001;
+
+/// Part of an access by some master: an address, a length, and the target.
+type Piece = (u32, usize, Device);
+
+/// Returns the AHBPROT bit for some device on the AHB.
+fn ahbprot_bit(dev: IoDevice) -> Option<u32> {
+    use IoDevice::*;
+    let slot = match dev {
+        Nand  => 1, Aes   => 2, Sha   => 3, Ehci  => 4,
+        Ohci0 => 5, Ohci1 => 6, Sdhc0 => 7, Sdhc1 => 8,
+        _ => return None,
+    };
+    Some(1 << slot)
+}
+
+impl Bus {
+    /// Check if some master may access some target, returning the name of
+    /// the protection register which denies it otherwise.
+    pub fn check_prot(&self, master: BusMaster, dev: Device) -> Result<(), &'static str> {
+        use MemDevice::*;
+        let busctrl = &self.hlwd.busctrl;
+        let (reg, val, mask) = match (master, dev) {
+            (BusMaster::Arm, _) => return Ok(()),
+            (_, Device::Mem(Mem1 | Mem2)) => return Ok(()),
+            (BusMaster::Dma(_), Device::Mem(_)) => return Ok(()),
+            (BusMaster::Dma(_), Device::Io(_)) => return Err("DMA to I/O"),
+
+            (BusMaster::Ppc, Device::Mem(_)) => {
+                ("SRNPROT", busctrl.srnprot, SRNPROT_PPC_SRAM)
+            },
+            (BusMaster::Ppc, Device::Io(dev)) => match ahbprot_bit(dev) {
+                Some(bit) => ("AHBPROT", busctrl.ahbprot, bit),
+                None => ("AIPPROT", busctrl.aipprot, AIPPROT_PPC_HLWD),
+            },
+        };
+        if (val & mask) != 0 { Ok(()) } else { Err(reg) }
+    }
+
+    /// Resolve the targets of an access by some master. The access must
+    /// either be in memory (other than the mask ROM), or be a single
+    /// register.
+    fn resolve_master_access(&self, addr: u32, len: usize) -> Option<Vec<Piece>> {
+        let handle = self.decode_phys_addr(addr)?;
+        match handle.dev {
+            Device::Mem(_) => self.split_mem_range(addr, len),
+            Device::Io(IoDevice::Mi | IoDevice::Ddr) if len == 2 && (addr & 1) == 0 => {
+                Some(vec![(addr, len, handle.dev)])
+            },
+            Device::Io(_) if len == 4 && (addr & 3) == 0 => {
+                Some(vec![(addr, len, handle.dev)])
+            },
+            _ => None,
+        }
+    }
+
+    /// Split a range of memory into pieces which are each backed by a single
+    /// memory device (other than the mask ROM).
+    fn split_mem_range(&self, addr: u32, len: usize) -> Option<Vec<Piece>> {
+        let mut res = Vec::new();
+        let (mut addr, mut left) = (addr, len);
+        loop {
+            let handle = self.decode_phys_addr(addr)?;
+            if let Device::Io(_) | Device::Mem(MemDevice::MaskRom) = handle.dev {
+                return None;
+            }
+            let avail = (handle.mask - (addr & handle.mask)) as usize + 1;
+            let piece = left.min(avail);
+            res.push((addr, piece, handle.dev));
+            left -= piece;
+            if left == 0 {
+                return Some(res);
+            }
+            addr = addr.checked_add(piece as u32)?;
+        }
+    }
+
+    /// Check an access by some master, reporting it when it's denied.
+    fn check_master_access(&self, master: BusMaster, addr: u32, len: usize,
+        write: bool) -> Option<Vec<Piece>>
+    {
+        let kind = if write { "write" } else { "read" };
+        let pieces = match self.resolve_master_access(addr, len) {
+            Some(pieces) => pieces,
+            None => {
+                println!("AHB {:?} {} {:08x} ({:x} bytes) has no target", master,
+                    kind, addr, len);
+                return None;
+            },
+        };
+        for (_, _, dev) in pieces.iter() {
+            if let Err(reg) = self.check_prot(master, *dev) {
+                println!("AHB {:?} {} {:08x} ({:x} bytes) denied by {}", master,
+                    kind, addr, len, reg);
+                return None;
+            }
+        }
+        Some(pieces)
+    }
+
+    /// Perform a read on behalf of some master, returning false when the
+    /// range isn't valid or the access is denied.
+    pub fn master_read(&mut self, master: BusMaster, addr: u32, buf: &mut [u8]) -> bool {
+        let pieces = match self.check_master_access(master, addr, buf.len(), false) {
+            Some(pieces) => pieces,
+            None => return false,
+        };
+        let mut off = 0;
+        for (addr, len, dev) in pieces {
+            let dst = &mut buf[off..off + len];
+            match dev {
+                Device::Mem(_) => self.dma_read(addr, dst),
+                Device::Io(_) if len == 2 => {
+                    dst.copy_from_slice(&self.read16_as(master, addr).to_be_bytes());
+                },
+                Device::Io(_) => {
+                    dst.copy_from_slice(&self.read32_as(master, addr).to_be_bytes());
+                },
+            }
+            off += len;
+        }
+        true
+    }
+
+    /// Perform a write on behalf of some master, returning false when the
+    /// range isn't valid or the access is denied.
+    pub fn master_write(&mut self, master: BusMaster, addr: u32, buf: &[u8]) -> bool {
+        let pieces = match self.check_master_access(master, addr, buf.len(), true) {
+            Some(pieces) => pieces,
+            None => return false,
+        };
+        let mut off = 0;
+        for (addr, len, dev) in pieces {
+            let src = &buf[off..off + len];
+            match dev {
+                Device::Mem(_) => self.dma_write(addr, src),
+                Device::Io(_) if len == 2 => {
+                    self.write16_as(master, addr, u16::from_be_bytes([src[0], src[1]]));
+                },
+                Device::Io(_) => {
+                    self.write32_as(master, addr, u32::from_be_bytes(src.try_into().unwrap()));
+                },
+            }
+            off += len;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRAM: Device = Device::Mem(MemDevice::Sram0);
+    const AES: Device = Device::Io(IoDevice::Aes);
+    const HLWD: Device = Device::Io(IoDevice::Hlwd);
+
+    #[test]
+    fn arm_is_always_allowed() {
+        let bus = Bus::new_blank();
+        for dev in [SRAM, AES, HLWD, Device::Mem(MemDevice::Mem1)] {
+            assert_eq!(bus.check_prot(BusMaster::Arm, dev), Ok(()));
+        }
+    }
+
+    #[test]
+    fn ppc_needs_protection_bits() {
+        let mut bus = Bus::new_blank();
+        assert_eq!(bus.check_prot(BusMaster::Ppc, Device::Mem(MemDevice::Mem1)), Ok(()));
+        assert_eq!(bus.check_prot(BusMaster::Ppc, Device::Mem(MemDevice::Mem2)), Ok(()));
+        assert_eq!(bus.check_prot(BusMaster::Ppc, SRAM), Err("SRNPROT"));
+        assert_eq!(bus.check_prot(BusMaster::Ppc, AES), Err("AHBPROT"));
+        assert_eq!(bus.check_prot(BusMaster::Ppc, HLWD), Err("AIPPROT"));
+
+        bus.hlwd.busctrl.srnprot = SRNPROT_PPC_SRAM;
+        bus.hlwd.busctrl.ahbprot = 1 << 2;
+        bus.hlwd.busctrl.aipprot = AIPPROT_PPC_HLWD;
+        assert_eq!(bus.check_prot(BusMaster::Ppc, SRAM), Ok(()));
+        assert_eq!(bus.check_prot(BusMaster::Ppc, AES), Ok(()));
+        assert_eq!(bus.check_prot(BusMaster::Ppc, HLWD), Ok(()));
+
+        // Only the bit for the AES engine is set
+        let sha = Device::Io(IoDevice::Sha);
+        assert_eq!(bus.check_prot(BusMaster::Ppc, sha), Err("AHBPROT"));
+    }
+
+    #[test]
+    fn dma_is_limited_to_memory() {
+        let bus = Bus::new_blank();
+        let master = BusMaster::Dma(IoDevice::Nand);
+        assert_eq!(bus.check_prot(master, SRAM), Ok(()));
+        assert_eq!(bus.check_prot(master, Device::Mem(MemDevice::Mem2)), Ok(()));
+        assert_eq!(bus.check_prot(master, AES), Err("DMA to I/O"));
+    }
+
+    #[test]
+    fn denied_ppc_register_accesses() {
+        let mut bus = Bus::new_blank();
+        bus.hlwd.busctrl.ahbprot = 0xdead_beef;
+        assert_eq!(bus.read32_as(BusMaster::Ppc, 0x0d80_0064), 0);
+        bus.write32_as(BusMaster::Ppc, 0x0d80_0064, 0xffff_ffff);
+        assert_eq!(bus.read32(0x0d80_0064), 0xdead_beef);
+
+        bus.hlwd.busctrl.aipprot = AIPPROT_PPC_HLWD;
+        assert_eq!(bus.read32_as(BusMaster::Ppc, 0x0d80_0064), 0xdead_beef);
+    }
+
+    #[test]
+    fn dma_across_memory_devices() {
+        let mut bus = Bus::new_blank();
+        let master = BusMaster::Dma(IoDevice::Nand);
+        let buf: Vec<u8> = (0..0x20).collect();
+        assert!(bus.master_write(master, 0x0d40_fff0, &buf));
+        assert_eq!(bus.read8(0x0d40_ffff), 0x0f);
+        assert_eq!(bus.read8(0x0d41_0000), 0x10);
+
+        let mut res = [0; 0x20];
+        assert!(bus.master_read(master, 0x0d40_fff0, &mut res));
+        assert_eq!(&res[..], &buf[..]);
+
+        // Ranges which run off the end of SRAM have no target
+        assert!(!bus.master_write(master, 0x0d41_fff0, &buf));
+        assert!(!bus.master_write(master, 0x0d01_0000, &buf[..4]));
+    }
+}
@@ -1,4 +1,11 @@
 
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// The number of CPU/bus cycles in a microsecond, used for expressing device
+/// latencies in terms of wall-clock time on the real hardware.
+pub const CYCLES_PER_US: usize = 243;
+
 /// Some type of indirect access (from memory interface to the DDR interface).
 #[derive(Debug)]
 pub enum IndirAccess { Read, Write }
@@ -12,6 +19,11 @@ pub enum BusTask {
     Aes(u32),
     /// A SHA interface command.
     Sha(u32),
+    /// A disc drive interface command.
+    Di(u32),
+
+    /// The Hollywood timer has reached the value in the alarm register.
+    TimerAlarm,
 
     /// Change the state of the boot ROM mapping
     SetRomDisabled(bool),
@@ -23,8 +35,64 @@ pub enum BusTask {
 }
 
 /// An entry kept by the [Bus], representing some task to-be-completed.
+#[derive(Debug)]
 pub struct Task {
     pub kind: BusTask,
     pub target_cycle: usize,
+    /// Used to preserve the order of tasks which complete on the same cycle.
+    seq: usize,
+}
+impl PartialEq for Task {
+    fn eq(&self, other: &Self) -> bool {
+        (self.target_cycle, self.seq) == (other.target_cycle, other.seq)
+    }
+}
+impl Eq for Task {}
+impl PartialOrd for Task {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Task {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.target_cycle, self.seq).cmp(&(other.target_cycle, other.seq))
+    }
+}
+
+/// Priority queue of pending tasks, keyed on the cycle they complete on.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<Task>>,
+    next_seq: usize,
+}
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler { queue: BinaryHeap::new(), next_seq: 0 }
+    }
+
+    /// Schedule some task to complete on the given cycle.
+    pub fn push(&mut self, kind: BusTask, target_cycle: usize) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.queue.push(Reverse(Task { kind, target_cycle, seq }));
+    }
+
+    /// Remove and return the earliest task due on (or before) some cycle.
+    pub fn pop_due(&mut self, cycle: usize) -> Option<Task> {
+        match self.queue.peek() {
+            Some(Reverse(t)) if t.target_cycle <= cycle => {
+                self.queue.pop().map(|Reverse(t)| t)
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the cycle of the earliest pending task, if one exists.
+    pub fn next_cycle(&self) -> Option<usize> {
+        self.queue.peek().map(|Reverse(t)| t.target_cycle)
+    }
+
+    pub fn len(&self) -> usize { self.queue.len() }
+    pub fn is_empty(&self) -> bool { self.queue.is_empty() }
 }
 
@@ -5,6 +5,7 @@ pub mod reg;
 pub mod psr;
 pub mod mmu;
 pub mod alu;
+pub mod cache;
 
 use std::sync::{Arc,RwLock};
 
@@ -39,6 +40,13 @@ pub struct Cpu {
 
     /// Whether or not an interrupt request is currently asserted.
     pub irq_input: bool,
+    /// Whether or not a fast interrupt request is currently asserted.
+    pub fiq_input: bool,
+    /// Set when a write needs to be handled by the bus before the next
+    /// instruction is executed.
+    pub bus_sync: bool,
+    /// Cycles spent waiting on cache line fills since the last instruction.
+    pub stall_cycles: usize,
 }
 impl Cpu {
     pub fn new(bus: Arc<RwLock<Bus>>) -> Self { 
@@ -48,6 +56,9 @@ impl Cpu {
             p15: coproc::SystemControl::new(),
             scratch: 0,
             irq_input: false,
+            fiq_input: false,
+            bus_sync: false,
+            stall_cycles: 0,
             current_exception: None,
             dbg_on: false,
             dbg_steps: 1_000_000,
@@ -0,0 +1,312 @@
+//! A model of the ARM926 instruction and data caches.
+//!
+//! Only the tags are modelled: the CPU always reads and writes memory
+//! directly, and the model keeps track of which lines a real cache would be
+//! holding (and which of them would be dirty). This is enough to count hits
+//! and misses, and to catch guest code which forgets to clean or invalidate
+//! lines around device DMA.
+//!
+//! Lines are tagged with physical addresses. The real caches are virtually
+//! tagged, but IOS maps most memory flat.
+
+use crate::bus::prim::IoDevice;
+use crate::cpu::coproc::FlagRes;
+use crate::cpu::mmu::prim::{TLBReq, Access};
+use crate::cpu::Cpu;
+
+/// The size of a cache line in bytes.
+pub const LINE_LEN: u32 = 32;
+/// The number of ways in each set.
+pub const NUM_WAYS: usize = 4;
+/// The size of each cache in bytes.
+pub const CACHE_SIZE: usize = 0x4000;
+/// The number of sets in each cache.
+const NUM_SETS: usize = CACHE_SIZE / (LINE_LEN as usize * NUM_WAYS);
+
+/// Cycles spent filling a line after a miss.
+pub const LINE_FILL_CYCLES: usize = 8;
+
+/// How some access is handled by the caches (from the C and B bits in the
+/// page tables).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    Uncached,
+    WriteThrough,
+    WriteBack,
+}
+impl CachePolicy {
+    pub fn from_cb(cb: u32) -> Self {
+        match cb {
+            0b10 => CachePolicy::WriteThrough,
+            0b11 => CachePolicy::WriteBack,
+            _ => CachePolicy::Uncached,
+        }
+    }
+}
+
+/// A line in some cache.
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    /// The physical address of the line.
+    addr: u32,
+    valid: bool,
+    dirty: bool,
+    /// Set when some device wrote to memory backing this line with DMA
+    /// since the line was filled.
+    stale: Option<IoDevice>,
+}
+
+/// The tags for a 16KB, 4-way set-associative cache.
+pub struct Cache {
+    sets: Vec<[Line; NUM_WAYS]>,
+    /// The next way to be replaced in each set (round-robin).
+    victim: Vec<usize>,
+    pub hits: usize,
+    pub misses: usize,
+}
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            sets: vec![[Line::default(); NUM_WAYS]; NUM_SETS],
+            victim: vec![0; NUM_SETS],
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+impl Cache {
+
+    fn line_addr(addr: u32) -> u32 { addr & !(LINE_LEN - 1) }
+    fn set_idx(addr: u32) -> usize { (addr / LINE_LEN) as usize % NUM_SETS }
+
+    /// Convert the operand of a set/way operation into indices.
+    fn set_way(val: u32) -> (usize, usize) {
+        (Self::set_idx(val), (val >> 30) as usize)
+    }
+
+    fn find(&mut self, addr: u32) -> Option<&mut Line> {
+        let addr = Self::line_addr(addr);
+        self.sets[Self::set_idx(addr)].iter_mut()
+            .find(|l| l.valid && l.addr == addr)
+    }
+
+    /// Returns the valid lines which overlap some range of memory.
+    fn lines_in(&mut self, addr: u32, len: usize) -> impl Iterator<Item=&mut Line> {
+        let start = Self::line_addr(addr);
+        let end = addr.wrapping_add(len as u32);
+        self.sets.iter_mut().flatten()
+            .filter(move |l| l.valid && l.addr >= start && l.addr < end)
+    }
+
+    /// Look up some line, filling it on a miss when `allocate` is set.
+    /// Returns the line on a hit.
+    fn access(&mut self, addr: u32, allocate: bool) -> Option<&mut Line> {
+        let set = Self::set_idx(addr);
+        let line = Self::line_addr(addr);
+        match self.sets[set].iter().position(|l| l.valid && l.addr == line) {
+            Some(way) => {
+                self.hits += 1;
+                Some(&mut self.sets[set][way])
+            },
+            None => {
+                self.misses += 1;
+                if allocate {
+                    let way = self.victim[set];
+                    self.victim[set] = (way + 1) % NUM_WAYS;
+                    self.sets[set][way] = Line { addr: line, valid: true, ..Default::default() };
+                }
+                None
+            },
+        }
+    }
+
+    pub fn invalidate_all(&mut self) {
+        self.sets.iter_mut().flatten().for_each(|l| *l = Line::default());
+    }
+    pub fn invalidate_line(&mut self, addr: u32) {
+        if let Some(l) = self.find(addr) {
+            *l = Line::default();
+        }
+    }
+    pub fn invalidate_set_way(&mut self, val: u32) {
+        let (set, way) = Self::set_way(val);
+        self.sets[set][way] = Line::default();
+    }
+
+    pub fn clean_line(&mut self, addr: u32) {
+        if let Some(l) = self.find(addr) {
+            l.dirty = false;
+        }
+    }
+    pub fn clean_set_way(&mut self, val: u32) {
+        let (set, way) = Self::set_way(val);
+        self.sets[set][way].dirty = false;
+    }
+    /// Clean every line, returning the number of lines which were dirty.
+    pub fn clean_all(&mut self) -> usize {
+        let mut res = 0;
+        for l in self.sets.iter_mut().flatten().filter(|l| l.dirty) {
+            l.dirty = false;
+            res += 1;
+        }
+        res
+    }
+}
+
+/// The ARM926 instruction and data caches.
+pub struct CacheModel {
+    pub icache: Cache,
+    pub dcache: Cache,
+    /// When set, report device DMA which isn't coherent with the data cache.
+    pub check_dma: bool,
+    /// Number of coherency problems which were reported.
+    pub problems: usize,
+}
+impl CacheModel {
+    pub fn new(check_dma: bool) -> Self {
+        CacheModel {
+            icache: Cache::default(),
+            dcache: Cache::default(),
+            check_dma,
+            problems: 0,
+        }
+    }
+
+    /// Handle an instruction fetch, returning true on a hit.
+    pub fn fetch(&mut self, paddr: u32) -> bool {
+        self.icache.access(paddr, true).is_some()
+    }
+
+    /// Handle a data read, returning false when a line had to be filled.
+    pub fn read(&mut self, paddr: u32, policy: CachePolicy) -> bool {
+        if policy == CachePolicy::Uncached {
+            return true;
+        }
+        let stale = match self.dcache.access(paddr, true) {
+            Some(line) => line.stale.take(),
+            None => return false,
+        };
+        if let Some(dev) = stale.filter(|_| self.check_dma) {
+            self.problems += 1;
+            println!("CACHE CPU read {:08x} hit a line which {:?} overwrote with DMA (missing invalidate?)",
+                paddr, dev);
+        }
+        true
+    }
+
+    /// Handle a data write. Lines are only allocated on reads.
+    pub fn write(&mut self, paddr: u32, policy: CachePolicy) {
+        if policy == CachePolicy::Uncached {
+            return;
+        }
+        if let Some(line) = self.dcache.access(paddr, false) {
+            line.dirty |= policy == CachePolicy::WriteBack;
+        }
+    }
+
+    /// Handle an MCR to p15 c7, with the operand already translated to a
+    /// physical address for the operations which take an address.
+    pub fn maintain(&mut self, val: u32, crm: u32, opcd2: u32) {
+        match (crm, opcd2) {
+            (5, 0) => self.icache.invalidate_all(),
+            (5, 1) => self.icache.invalidate_line(val),
+            (5, 2) => self.icache.invalidate_set_way(val),
+            (6, 0) => self.dcache.invalidate_all(),
+            (6, 1) => self.dcache.invalidate_line(val),
+            (6, 2) => self.dcache.invalidate_set_way(val),
+            (7, 0) => {
+                self.icache.invalidate_all();
+                self.dcache.invalidate_all();
+            },
+            (13, 1) => { self.icache.access(val, true); },
+            (10, 1) => self.dcache.clean_line(val),
+            (10, 2) => self.dcache.clean_set_way(val),
+            (14, 1) => self.dcache.invalidate_line(val),
+            (14, 2) => self.dcache.invalidate_set_way(val),
+            _ => {},
+        }
+    }
+
+    /// Handle a "test and clean" operation (optionally invalidating the data
+    /// cache too). Returns true when no dirty lines are left.
+    pub fn test_clean(&mut self, invalidate: bool) -> bool {
+        self.dcache.clean_all();
+        if invalidate {
+            self.dcache.invalidate_all();
+        }
+        true
+    }
+
+    /// Check a DMA read by some device: memory is stale when the data cache
+    /// is still holding dirty lines for it.
+    pub fn check_dma_read(&mut self, dev: IoDevice, addr: u32, len: usize) {
+        if !self.check_dma {
+            return;
+        }
+        let mut dirty = self.dcache.lines_in(addr, len).filter(|l| l.dirty);
+        if let Some(first) = dirty.next() {
+            let first = first.addr;
+            let count = 1 + dirty.count();
+            self.problems += 1;
+            println!("CACHE {:?} DMA read {:08x}-{:08x} with {} dirty line(s) from {:08x} (missing flush?)",
+                dev, addr, addr.wrapping_add(len as u32), count, first);
+        }
+    }
+
+    /// Check a DMA write by some device. Dirty lines would be written back
+    /// over the new data, and clean lines would hide it from the CPU until
+    /// they are invalidated.
+    pub fn check_dma_write(&mut self, dev: IoDevice, addr: u32, len: usize) {
+        if !self.check_dma {
+            return;
+        }
+        let mut dirty = None;
+        for line in self.dcache.lines_in(addr, len) {
+            if line.dirty && dirty.is_none() {
+                dirty = Some(line.addr);
+            }
+            line.stale = Some(dev);
+        }
+        if let Some(first) = dirty {
+            self.problems += 1;
+            println!("CACHE {:?} DMA write {:08x}-{:08x} under a dirty line at {:08x} (missing invalidate?)",
+                dev, addr, addr.wrapping_add(len as u32), first);
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!("Cache: icache {} hits/{} misses, dcache {} hits/{} misses, {} coherency problems",
+            self.icache.hits, self.icache.misses,
+            self.dcache.hits, self.dcache.misses, self.problems)
+    }
+}
+
+/// Cache maintenance operations on p15, which are passed to the cache model
+/// (if there is one) before they reach the coprocessor.
+impl Cpu {
+    pub fn p15_write(&mut self, val: u32, reg: u32, crm: u32, opcd2: u32) {
+        if reg == 7 && self.bus.read().unwrap().arm_cache.is_some() {
+            // Line operations take a virtual address
+            let addr = match (crm, opcd2) {
+                (5, 1) | (6, 1) | (10, 1) | (13, 1) | (14, 1) => {
+                    self.translate(TLBReq::new(val, Access::Debug))
+                },
+                _ => val,
+            };
+            if let Some(cache) = self.bus.write().unwrap().arm_cache.as_mut() {
+                cache.maintain(addr, crm, opcd2);
+            }
+        }
+        self.p15.write(val, reg, crm, opcd2);
+    }
+
+    pub fn p15_read_alt(&mut self, reg: u32, crm: u32, opcd2: u32) -> FlagRes {
+        if let (7, 10 | 14, 3) = (reg, crm, opcd2) {
+            if let Some(cache) = self.bus.write().unwrap().arm_cache.as_mut() {
+                let clean = cache.test_clean(crm == 14);
+                return FlagRes { n: None, z: Some(clean), c: None, v: None };
+            }
+        }
+        self.p15.read_alt(reg, crm, opcd2)
+    }
+}
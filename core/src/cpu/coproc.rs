@@ -118,7 +118,10 @@ impl SystemControl {
         use SystemControlReg::*;
         match SystemControlReg::from(reg) {
             CacheControl => match (crm, opcd2) {
+                // Test and clean dcache
                 (10, 3) => FlagRes { n: None, z: Some(true), c: None, v: None },
+                // Test, clean and invalidate dcache
+                (14, 3) => FlagRes { n: None, z: Some(true), c: None, v: None },
                 _ => panic!(""),
             },
             _ => panic!("Unimpl p15 read_alt {:?} crm={} opcd2={}", 
@@ -176,10 +179,18 @@ impl SystemControl {
 
             CacheControl => match (crm, opcd2) {
                 (5, 0) => {}, // Invalidate entire icache
+                (5, 1) => {}, // Invalidate icache line
+                (5, 2) => {}, // Invalidate icache line (set/way)
                 (6, 0) => {}, // Invalidate entire dcache
                 (6, 1) => {}, // Invalidate dcache line
+                (6, 2) => {}, // Invalidate dcache line (set/way)
+                (7, 0) => {}, // Invalidate entire icache and dcache
                 (10, 1) => {}, // Clean dcache line
+                (10, 2) => {}, // Clean dcache line (set/way)
                 (10, 4) => {}, // Drain write buffer
+                (13, 1) => {}, // Prefetch icache line
+                (14, 1) => {}, // Clean and invalidate dcache line
+                (14, 2) => {}, // Clean and invalidate dcache line (set/way)
                 _ => panic!("Unimpl P15 write {:08x} {:?} crm={} opcd2={}",
                     val, SystemControlReg::from(reg), crm, opcd2),
             },
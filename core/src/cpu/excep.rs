@@ -73,7 +73,6 @@ impl Cpu {
 
     /// Change CPU state to reflect the fact that we've entered an exception.
     pub fn generate_exception(&mut self, e: ExceptionType) {
-        assert_ne!(e, ExceptionType::Swi);
         let current_pc = self.read_fetch_pc();
 
         let old_cpsr = self.reg.cpsr;
@@ -2,7 +2,11 @@
 
 pub mod prim;
 
+use crate::bus::Bus;
+use crate::bus::prim::BusMaster;
 use crate::cpu::mmu::prim::*;
+use crate::cpu::cache::*;
+use crate::cpu::coproc::ControlRegister;
 use crate::cpu::Cpu;
 
 /// These are the top-level "public" functions providing read/write accesses.
@@ -11,56 +15,114 @@ use crate::cpu::Cpu;
 /// mutable reference to the bus. This is expensive.
 impl Cpu {
     pub fn read32(&mut self, addr: u32) -> u32 {
-        let paddr = self.translate(TLBReq::new(addr, Access::Read));
-        let res = self.bus.write().unwrap().read32(paddr);
-        res
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Read));
+        let mut bus = self.bus.write().unwrap();
+        self.stall_cycles += dcache_read(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.read32_as(BusMaster::Arm, paddr)
     }
     pub fn read16(&mut self, addr: u32) -> u16 {
-        let paddr = self.translate(TLBReq::new(addr, Access::Read));
-        let res = self.bus.write().unwrap().read16(paddr);
-        res
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Read));
+        let mut bus = self.bus.write().unwrap();
+        self.stall_cycles += dcache_read(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.read16_as(BusMaster::Arm, paddr)
     }
     pub fn read8(&mut self, addr: u32) -> u8 {
-        let paddr = self.translate(TLBReq::new(addr, Access::Read));
-        let res = self.bus.write().unwrap().read8(paddr);
-        res
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Read));
+        let mut bus = self.bus.write().unwrap();
+        self.stall_cycles += dcache_read(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.read8_as(BusMaster::Arm, paddr)
     }
 
     pub fn write32(&mut self, addr: u32, val: u32) {
-        let paddr = self.translate(TLBReq::new(addr, Access::Write));
-        self.bus.write().unwrap().write32(paddr, val);
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Write));
+        let mut bus = self.bus.write().unwrap();
+        dcache_write(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.write32_as(BusMaster::Arm, paddr, val);
+        self.bus_sync |= bus.sync_req;
     }
     pub fn write16(&mut self, addr: u32, val: u32) {
-        let paddr = self.translate(TLBReq::new(addr, Access::Write));
-        self.bus.write().unwrap().write16(paddr, val as u16);
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Write));
+        let mut bus = self.bus.write().unwrap();
+        dcache_write(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.write16_as(BusMaster::Arm, paddr, val as u16);
+        self.bus_sync |= bus.sync_req;
     }
     pub fn write8(&mut self, addr: u32, val: u32) {
-        let paddr = self.translate(TLBReq::new(addr, Access::Write));
-        self.bus.write().unwrap().write8(paddr, val as u8);
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Write));
+        let mut bus = self.bus.write().unwrap();
+        dcache_write(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.write8_as(BusMaster::Arm, paddr, val as u8);
+        self.bus_sync |= bus.sync_req;
+    }
+
+    /// Fetch an ARM instruction.
+    pub fn fetch32(&mut self, addr: u32) -> u32 {
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Read));
+        let mut bus = self.bus.write().unwrap();
+        self.stall_cycles += icache_fetch(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.read32_as(BusMaster::Arm, paddr)
+    }
+    /// Fetch a Thumb instruction.
+    pub fn fetch16(&mut self, addr: u32) -> u16 {
+        let (paddr, policy) = self.translate_policy(TLBReq::new(addr, Access::Read));
+        let mut bus = self.bus.write().unwrap();
+        self.stall_cycles += icache_fetch(&mut bus, self.p15.c1_ctrl, paddr, policy);
+        bus.read16_as(BusMaster::Arm, paddr)
+    }
+}
+
+/// Pass a data read to the cache model (if there is one), returning the
+/// number of cycles spent filling a line.
+fn dcache_read(bus: &mut Bus, ctrl: ControlRegister, paddr: u32, policy: CachePolicy) -> usize {
+    match bus.arm_cache.as_mut().filter(|_| ctrl.dcache_enabled()) {
+        Some(cache) => if cache.read(paddr, policy) { 0 } else { LINE_FILL_CYCLES },
+        None => 0,
+    }
+}
+
+/// Pass a data write to the cache model (if there is one).
+fn dcache_write(bus: &mut Bus, ctrl: ControlRegister, paddr: u32, policy: CachePolicy) {
+    if let Some(cache) = bus.arm_cache.as_mut().filter(|_| ctrl.dcache_enabled()) {
+        cache.write(paddr, policy);
+    }
+}
+
+/// Pass an instruction fetch to the cache model (if there is one), returning
+/// the number of cycles spent filling a line.
+///
+/// When the MMU is disabled, all instruction fetches are cacheable.
+fn icache_fetch(bus: &mut Bus, ctrl: ControlRegister, paddr: u32, policy: CachePolicy) -> usize {
+    let cacheable = !ctrl.mmu_enabled() || policy != CachePolicy::Uncached;
+    match bus.arm_cache.as_mut().filter(|_| ctrl.icache_enabled() && cacheable) {
+        Some(cache) => if cache.fetch(paddr) { 0 } else { LINE_FILL_CYCLES },
+        None => 0,
     }
 }
 
 /// These are the functions used to perform virtual-to-physical translation.
 impl Cpu {
-    /// Resolve a section descriptor, returning a physical address.
-    fn resolve_section(&self, req: TLBReq, d: SectionDescriptor) -> u32 {
+    /// Resolve a section descriptor, returning a physical address and the
+    /// cache policy.
+    fn resolve_section(&self, req: TLBReq, d: SectionDescriptor) -> (u32, CachePolicy) {
         let ctx = self.get_ctx(d.domain());
         if ctx.validate(&req, d.ap()) {
-            d.base_addr() | req.vaddr.section_idx() 
+            (d.base_addr() | req.vaddr.section_idx(), CachePolicy::from_cb(d.cb()))
         } else {
             panic!("Domain access faults are unimplemented, vaddr={:08x}",
                 req.vaddr.0);
         }
     }
 
-    /// Resolve a coarse descriptor, returning a physical address.
-    fn resolve_coarse(&self, req: TLBReq, d: CoarseDescriptor) -> u32 {
+    /// Resolve a coarse descriptor, returning a physical address and the
+    /// cache policy.
+    fn resolve_coarse(&self, req: TLBReq, d: CoarseDescriptor) -> (u32, CachePolicy) {
         let desc = self.l2_fetch(req.vaddr, L1Descriptor::Coarse(d));
         match desc {
             L2Descriptor::SmallPage(entry) => {
                 let ctx = self.get_ctx(d.domain());
                 if ctx.validate(&req, entry.get_ap(req.vaddr)) {
-                    entry.base_addr() | req.vaddr.small_page_idx()
+                    (entry.base_addr() | req.vaddr.small_page_idx(),
+                        CachePolicy::from_cb(entry.cb()))
                 } else {
                     panic!("Domain access faults are unimplemented, vaddr={:08x}",
                         req.vaddr.0);
@@ -111,6 +173,13 @@ impl Cpu {
 
     /// Translate a virtual address into a physical address.
     pub fn translate(&self, req: TLBReq) -> u32 {
+        self.translate_policy(req).0
+    }
+
+    /// Translate a virtual address into a physical address, also returning
+    /// how the access is handled by the caches. Data accesses are never
+    /// cached while the MMU is disabled.
+    pub fn translate_policy(&self, req: TLBReq) -> (u32, CachePolicy) {
         if self.p15.c1_ctrl.mmu_enabled() {
             let desc = self.l1_fetch(req.vaddr);
             match desc {
@@ -119,7 +188,7 @@ impl Cpu {
                 _ => panic!("TLB first-level descriptor {:?} unimplemented", desc),
             }
         } else {
-            req.vaddr.0
+            (req.vaddr.0, CachePolicy::Uncached)
         }
     }
 }
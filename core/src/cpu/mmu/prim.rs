@@ -128,10 +128,12 @@ impl SectionDescriptor {
     const ADDR_MASK: u32 = 0b111111111111_00000000_00_0_0000_000_00;
     const AP_MASK: u32   = 0b000000000000_00000000_11_0_0000_000_00;
     const DOM_MASK: u32  = 0b000000000000_00000000_00_0_1111_000_00;
+    const CB_MASK: u32   = 0x0000_000c;
 
     pub fn base_addr(&self) -> u32 { self.0 & Self::ADDR_MASK }
     pub fn ap(&self) -> u32 { (self.0 & Self::AP_MASK) >> 10 }
     pub fn domain(&self) -> u32 { (self.0 & Self::DOM_MASK) >> 5 }
+    pub fn cb(&self) -> u32 { (self.0 & Self::CB_MASK) >> 2 }
 }
 
 /// A coarse page table descriptor in the first-level page table.
@@ -176,6 +178,7 @@ impl SmallPageDescriptor {
     const AP2_MASK: u32  = 0b00000000000000000000_00_11_00_00_0_0_00;
     const AP1_MASK: u32  = 0b00000000000000000000_00_00_11_00_0_0_00;
     const AP0_MASK: u32  = 0b00000000000000000000_00_00_00_11_0_0_00;
+    const CB_MASK: u32   = 0x0000_000c;
 
     pub fn get_ap(&self, vaddr: VirtAddr) -> u32 {
         ((self.0 >> 4) >> ((vaddr.0 >> 9) & 0b0110)) & 0b11
@@ -186,6 +189,7 @@ impl SmallPageDescriptor {
     pub fn ap2(&self) -> u32 { (self.0 & Self::AP2_MASK) >> 8 }
     pub fn ap1(&self) -> u32 { (self.0 & Self::AP1_MASK) >> 6 }
     pub fn ap0(&self) -> u32 { (self.0 & Self::AP0_MASK) >> 4 }
+    pub fn cb(&self) -> u32 { (self.0 & Self::CB_MASK) >> 2 }
 }
 
 
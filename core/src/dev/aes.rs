@@ -42,6 +42,15 @@ impl From<u32> for AesCommand {
         }
     }
 }
+impl AesCommand {
+    /// Approximate number of bus cycles spent on each 16-byte block.
+    const CYCLES_PER_BLOCK: usize = 20;
+
+    /// The number of bus cycles it takes for this command to complete.
+    pub fn latency(&self) -> usize {
+        (self.len / 0x10) * Self::CYCLES_PER_BLOCK
+    }
+}
 
 
 pub struct AesInterface {
@@ -79,8 +88,8 @@ impl MmioDevice for AesInterface {
 
     fn read(&mut self, off: usize) -> BusPacket {
         match off {
-            //0x00 => BusPacket::Word(self.ctrl),
-            0x00 => BusPacket::Word(0),
+            // The busy bit is held until the command has completed
+            0x00 => BusPacket::Word(self.ctrl & 0x8000_0000),
             _ => panic!("Unhandled AES interface read {:x}", off),
         }
     }
@@ -131,7 +140,7 @@ impl Bus {
 
         // Read data from the source address
         let mut aes_inbuf = vec![0u8; cmd.len];
-        self.dma_read(self.aes.src, &mut aes_inbuf);
+        self.device_dma_read(IoDevice::Aes, self.aes.src, &mut aes_inbuf);
 
         if cmd.use_aes {
             // Build the right AES cipher for this request
@@ -156,12 +165,12 @@ impl Bus {
             } else {
                 cipher_enc.encrypt_padded_vec_mut::<NoPadding>(&aes_inbuf)
             };
-            self.dma_write(self.aes.dst, &aes_outbuf);
+            self.device_dma_write(IoDevice::Aes, self.aes.dst, &aes_outbuf);
 
             // Update IV buffer with the last 16 bytes of data
             self.aes.iv_buffer.copy_from_slice(&aes_inbuf[(cmd.len - 0x10)..]);
         } else {
-            self.dma_write(self.aes.dst, &aes_inbuf);
+            self.device_dma_write(IoDevice::Aes, self.aes.dst, &aes_inbuf);
         }
 
         // Update the source/destination registers exposed over MMIO
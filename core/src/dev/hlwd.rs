@@ -3,6 +3,7 @@ use crate::bus::*;
 use crate::bus::prim::*;
 use crate::bus::mmio::*;
 use crate::bus::task::*;
+use crate::mem::storage::*;
 
 /// One-time programmable [fused] memory.
 pub mod otp;
@@ -16,37 +17,69 @@ pub mod ddr;
 pub mod irq;
 /// Inter-processor communication.
 pub mod ipc;
+/// Broadway (PowerPC) boot handoff.
+pub mod boot;
+/// Sampling of pin and interrupt state for tracing.
+pub mod trace;
 
 /// The timer/alarm interface.
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct TimerInterface {
     pub timer: u32,
     pub alarm: u32,
 
-    pub cpu_cycle_prev: usize,
+    /// The number of bus cycles between increments of the timer register.
+    pub period: usize,
+    /// The bus cycle on which the timer register was last incremented.
+    pub tick_cycle: usize,
+    /// The bus cycle on which the currently-pending alarm event will fire.
+    pub alarm_cycle: Option<usize>,
+}
+impl Default for TimerInterface {
+    fn default() -> Self {
+        TimerInterface {
+            timer: 0, alarm: 0,
+            period: Self::CPU_CLK_DIV,
+            tick_cycle: 0,
+            alarm_cycle: None,
+        }
+    }
 }
 impl TimerInterface {
-    /// Timer period (some fraction of the CPU clock).
+    /// Timer period (some fraction of the CPU clock) at the usual clock rate.
     pub const CPU_CLK_DIV: usize = 128;
 
-    pub fn step(&mut self, current_cpu_cycle: usize) -> bool {
-        // Fine as long as bus steps are interleaved with CPU steps I guess?
-        if current_cpu_cycle - self.cpu_cycle_prev >= Self::CPU_CLK_DIV {
-            self.timer += 1;
-            self.cpu_cycle_prev = current_cpu_cycle;
-            if self.timer == self.alarm {
-                println!("HLWD alarm IRQ {:08x}", self.timer);
-                return true;
-            } else {
-                return false;
-            }
-        }
-        false
+    /// Keep the timer running at its usual rate (~1.898MHz) when the
+    /// Starlet is clocked at some rate.
+    pub fn set_clock(&mut self, hz: u64) {
+        let period = (hz * Self::CPU_CLK_DIV as u64 / ClockInterface::STARLET_HZ) as usize;
+        self.period = period.max(1);
+    }
+
+    /// Bring the timer register up-to-date with the given bus cycle.
+    pub fn sync(&mut self, cycle: usize) {
+        let ticks = (cycle - self.tick_cycle) / self.period;
+        self.timer = self.timer.wrapping_add(ticks as u32);
+        self.tick_cycle += ticks * self.period;
+    }
+
+    /// Returns the bus cycle on which the timer register is next incremented.
+    pub fn next_tick(&self) -> usize {
+        self.tick_cycle + self.period
+    }
+
+    /// Returns the bus cycle on which the timer will next match the alarm.
+    pub fn alarm_target(&self) -> usize {
+        let ticks = match self.alarm.wrapping_sub(self.timer) {
+            0 => 1 << 32,
+            x => x as usize,
+        };
+        self.tick_cycle + ticks * self.period
     }
 }
 
 /// Various clocking registers.
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct ClockInterface {
     pub sys: u32,       // 0x1b0
     pub sys_ext: u32,   // 0x1b4
@@ -56,6 +89,43 @@ pub struct ClockInterface {
     pub ai: u32,        // 0x1cc
     pub ai_ext: u32,    // 0x1d0
     pub usb_ext: u32,   // 0x1d8
+
+    /// Clock rate used instead of the one configured by the system PLL.
+    pub starlet_hz_override: Option<u64>,
+}
+impl Default for ClockInterface {
+    fn default() -> Self {
+        ClockInterface {
+            sys: Self::SYS_PLL_243MHZ.0,
+            sys_ext: Self::SYS_PLL_243MHZ.1,
+            ddr: 0, ddr_ext: 0, vi_ext: 0, ai: 0, ai_ext: 0, usb_ext: 0,
+            starlet_hz_override: None,
+        }
+    }
+}
+impl ClockInterface {
+    /// The usual Starlet clock rate.
+    pub const STARLET_HZ: u64 = 243_000_000;
+
+    /// Values of the system PLL registers on a retail console, where the
+    /// Starlet runs at 243MHz.
+    pub const SYS_PLL_243MHZ: (u32, u32) = (0x0040_11c0, 0x1800_0018);
+
+    /// Returns the Starlet clock rate configured by the system PLL
+    /// registers. The layout of these registers isn't known, so only the
+    /// retail configuration is recognized.
+    pub fn sys_pll_hz(&self) -> Option<u64> {
+        match (self.sys, self.sys_ext) {
+            Self::SYS_PLL_243MHZ => Some(Self::STARLET_HZ),
+            _ => None,
+        }
+    }
+
+    /// Returns the current Starlet clock rate.
+    pub fn starlet_hz(&self) -> u64 {
+        self.starlet_hz_override.or_else(|| self.sys_pll_hz())
+            .unwrap_or(Self::STARLET_HZ)
+    }
 }
 
 /// Various bus control registers (?)
@@ -185,9 +255,12 @@ pub struct Hollywood {
 
     pub usb_frc_rst: u32,
     pub ppc_on: bool,
+    /// Set when Broadway has been released from reset, until the bus has
+    /// captured the boot state.
+    pub ppc_boot_pending: bool,
 }
 impl Hollywood {
-    pub fn new() -> Self {
+    pub fn new(storage: &StorageConfig) -> Self {
         // TODO: Where do the initial values for these registers matter?
         let mut res = Hollywood {
             task: None,
@@ -195,8 +268,8 @@ impl Hollywood {
             busctrl: BusCtrlInterface::default(),
             timer: TimerInterface::default(),
             irq: irq::IrqInterface::default(),
-            otp: otp::OtpInterface::new(),
-            gpio: gpio::GpioInterface::new(),
+            otp: otp::OtpInterface::new(storage.otp.clone()),
+            gpio: gpio::GpioInterface::new(storage.seeprom.clone()),
             pll: ClockInterface::default(),
 
             ahb: AhbInterface::default(),
@@ -215,9 +288,39 @@ impl Hollywood {
             io_str_ctrl0: 0,
             io_str_ctrl1: 0,
             ppc_on: false,
+            ppc_boot_pending: false,
         };
         res
     }
+
+    /// Use some Starlet clock rate instead of the one configured by the
+    /// system PLL.
+    pub fn set_clock_override(&mut self, hz: u64) {
+        self.pll.starlet_hz_override = Some(hz);
+        self.timer.set_clock(hz);
+    }
+
+    /// Update the timer after a change to the system PLL, returning a task
+    /// to reschedule any pending alarm.
+    fn update_clock(&mut self) -> Option<BusTask> {
+        if self.pll.starlet_hz_override.is_none() && self.pll.sys_pll_hz().is_none() {
+            println!("HLWD unknown system PLL config {:08x} {:08x}",
+                self.pll.sys, self.pll.sys_ext);
+        }
+        self.timer.set_clock(self.pll.starlet_hz());
+        self.timer.alarm_cycle.map(|_| BusTask::TimerAlarm)
+    }
+
+    /// Assert the IPC interrupts for any flags which are set in the mailbox
+    /// (and enabled in the control registers).
+    pub fn update_ipc_irqs(&mut self) {
+        if self.ipc.assert_ppc_irq() {
+            self.irq.assert(irq::HollywoodIrq::PpcIpc);
+        }
+        if self.ipc.assert_arm_irq() {
+            self.irq.assert(irq::HollywoodIrq::ArmIpc);
+        }
+    }
 }
 
 
@@ -240,8 +343,8 @@ impl MmioDevice for Hollywood {
             0x18c           => self.spare1,
             0x190           => self.clocks,
             0x194           => self.resets,
-            0x1b0           => 0x0040_11c0, //self.pll.sys,
-            0x1b4           => 0x1800_0018, //self.pll.sys_ext,
+            0x1b0           => self.pll.sys,
+            0x1b4           => self.pll.sys_ext,
             0x1bc           => self.pll.ddr,
             0x1c0           => self.pll.ddr_ext,
             0x1c8           => self.pll.vi_ext,
@@ -260,10 +363,14 @@ impl MmioDevice for Hollywood {
 
     fn write(&mut self, off: usize, val: u32) -> Option<BusTask> {
         match off {
-            0x000..=0x00c => self.ipc.write_handler(off, val),
+            0x000..=0x00c => {
+                self.ipc.write_handler(off, val);
+                self.update_ipc_irqs();
+            },
             0x014 => {
                 println!("HLWD alarm={:08x} (timer={:08x})", val, self.timer.timer);
                 self.timer.alarm = val;
+                return Some(BusTask::TimerAlarm);
             },
             0x030..=0x05c => self.irq.write_handler(off - 0x30, val),
             0x060 => {
@@ -314,6 +421,7 @@ impl MmioDevice for Hollywood {
                     if (val & 0x0000_0020 != 0) && (val & 0x0000_0010 != 0) {
                         println!("HLWD Broadway power on");
                         self.ppc_on = true;
+                        self.ppc_boot_pending = true;
                     } else {
                         println!("HLWD Broadway power off");
                         self.ppc_on = false;
@@ -323,8 +431,10 @@ impl MmioDevice for Hollywood {
                 println!("HLWD resets={:08x}", val);
                 self.resets = val;
             },
-            0x1b0 => self.pll.sys = val,
-            0x1b4 => self.pll.sys_ext = val,
+            0x1b0 | 0x1b4 => {
+                if off == 0x1b0 { self.pll.sys = val } else { self.pll.sys_ext = val }
+                return self.update_clock();
+            },
             0x1bc => self.pll.ddr = val,
             0x1c0 => self.pll.ddr_ext = val,
             0x1c8 => self.pll.vi_ext = val,
@@ -343,31 +453,90 @@ impl MmioDevice for Hollywood {
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum HlwdTask { 
-    GpioOutput(u32) 
+    GpioOutput(u32),
+    GpioDir(u32),
 }
 
 impl Bus {
-    pub fn handle_step_hlwd(&mut self, cpu_cycle: usize) {
+    pub fn handle_step_hlwd(&mut self) {
+        self.handle_ipc_messages();
+        if self.hlwd.ppc_boot_pending {
+            self.handle_ppc_boot();
+        }
 
         // Potentially assert an IRQ
-        let timer_irq = self.hlwd.timer.step(cpu_cycle);
-        if timer_irq {
-            self.hlwd.irq.assert(irq::HollywoodIrq::Timer);
-        }
-        if self.hlwd.ipc.assert_ppc_irq() {
-            self.hlwd.irq.assert(irq::HollywoodIrq::PpcIpc);
-        }
-        if self.hlwd.ipc.assert_arm_irq() {
-            self.hlwd.irq.assert(irq::HollywoodIrq::ArmIpc);
-        }
+        self.hlwd.update_ipc_irqs();
 
         if self.hlwd.task.is_some() {
             match self.hlwd.task.unwrap() {
-                HlwdTask::GpioOutput(val) => self.hlwd.gpio.handle_output(val),
+                HlwdTask::GpioOutput(val) => self.hlwd.gpio.handle_output(self.cycle, val),
+                HlwdTask::GpioDir(val) => self.hlwd.gpio.handle_dir(self.cycle, val),
             }
             self.hlwd.task = None;
         }
     }
-}
 
+    /// Pass any recorded IPC messages to the observer.
+    fn handle_ipc_messages(&mut self) {
+        if self.hlwd.ipc.messages.is_empty() {
+            return;
+        }
+        let msgs = std::mem::take(&mut self.hlwd.ipc.messages);
+        if let Some(mut obs) = self.ipc_observer.take() {
+            for msg in msgs {
+                obs.observe(self, msg);
+            }
+            self.ipc_observer = Some(obs);
+        }
+    }
+
+    /// Press or release one of the buttons on the console.
+    pub fn set_button(&mut self, button: gpio::Button, pressed: bool) {
+        println!("HLWD {:?} button {}", button,
+            if pressed { "pressed" } else { "released" });
+        let irq = match button {
+            gpio::Button::Reset => {
+                // The reset button has a dedicated IRQ instead of a GPIO pin
+                self.hlwd.gpio.reset_pressed = pressed;
+                if pressed { Some(irq::HollywoodIrq::RstBtn) } else { None }
+            },
+            gpio::Button::Power => self.hlwd.gpio.set_input(gpio::GpioPin::Power, pressed),
+            gpio::Button::Eject => self.hlwd.gpio.set_input(gpio::GpioPin::EjectButton, pressed),
+        };
+        if let Some(irq) = irq {
+            self.hlwd.irq.assert(irq);
+        }
+    }
+
+    /// Capture the state left behind for Broadway when it was released
+    /// from reset, and pass it to the observer. MEM1 is only copied when
+    /// there's an observer, and isn't kept afterwards.
+    fn handle_ppc_boot(&mut self) {
+        self.hlwd.ppc_boot_pending = false;
+        let mut event = boot::PpcBootEvent::capture(self);
+        match event.entry {
+            Some(entry) => println!("HLWD Broadway boot, entry={:08x}", entry),
+            None => println!("HLWD Broadway boot, unknown entry"),
+        }
+        if let Some(mut obs) = self.ppc_boot_observer.take() {
+            event.capture_mem1(self);
+            obs.observe(self, &event);
+            self.ppc_boot_observer = Some(obs);
+            event.mem1 = Vec::new();
+        }
+        self.ppc_boot = Some(event);
+    }
+
+    /// Handle an alarm event scheduled by a write to the alarm register.
+    pub fn handle_task_alarm(&mut self, target_cycle: usize) {
+        // The alarm register may have been re-written since this event was
+        // scheduled, in which case this event is stale
+        if self.hlwd.timer.alarm_cycle != Some(target_cycle) {
+            return;
+        }
+        self.hlwd.timer.alarm_cycle = None;
+        println!("HLWD alarm IRQ {:08x}", self.hlwd.timer.timer);
+        self.hlwd.irq.assert(irq::HollywoodIrq::Timer);
+    }
+}
 
@@ -0,0 +1,89 @@
+use crate::bus::*;
+
+/// Broadway starts executing here after reset. Reads from the first 0x40
+/// bytes are served from the bootstrap buffer in the EXI interface.
+pub const PPC_RESET_VECTOR: u32 = 0xfff0_0100;
+
+/// Length of MEM1.
+pub const MEM1_LEN: usize = 0x0180_0000;
+
+/// State captured when the ARM releases Broadway from reset.
+#[derive(Debug, Clone)]
+pub struct PpcBootEvent {
+    /// The bus cycle on which Broadway was released.
+    pub cycle: usize,
+    /// Bootstrap instructions in the EXI buffer.
+    pub bootstrap: [u32; 0x10],
+    /// The entry point branched to by the bootstrap code (if it was found).
+    pub entry: Option<u32>,
+    /// The contents of MEM1, with any trailing zeroes removed. This is
+    /// empty unless [PpcBootEvent::capture_mem1] was called.
+    pub mem1: Vec<u8>,
+}
+impl PpcBootEvent {
+    /// Capture the current state of the bootstrap buffer.
+    pub fn capture(bus: &Bus) -> Self {
+        let bootstrap = bus.hlwd.exi.ppc_bootstrap;
+        PpcBootEvent {
+            cycle: bus.cycle,
+            bootstrap,
+            entry: find_bootstrap_entry(&bootstrap),
+            mem1: Vec::new(),
+        }
+    }
+
+    /// Capture the current contents of MEM1.
+    pub fn capture_mem1(&mut self, bus: &mut Bus) {
+        let mut mem1 = vec![0u8; MEM1_LEN];
+        bus.dma_read(0, &mut mem1);
+        let len = mem1.iter().rposition(|&b| b != 0).map_or(0, |x| (x + 4) & !3);
+        mem1.truncate(len);
+        self.mem1 = mem1;
+    }
+
+    /// Returns the entry point as an address in the cached MEM1 mapping,
+    /// converting it if the bootstrap code jumps there in real mode.
+    pub fn entry_vaddr(&self) -> Option<u32> {
+        self.entry.map(|x| if (x as usize) < MEM1_LEN { x | 0x8000_0000 } else { x })
+    }
+}
+
+/// Try to find the address that some PowerPC bootstrap code branches to.
+///
+/// IOS writes a short stub which loads an address into a register with
+/// `lis`/`ori`, moves it into SRR0 (or LR/CTR), and then branches with
+/// `rfi` (or `blr`/`bctr`). Only those instructions are simulated here.
+pub fn find_bootstrap_entry(code: &[u32]) -> Option<u32> {
+    let mut gpr = [None; 32];
+    let mut res = None;
+    for &op in code {
+        let rd = ((op >> 21) & 0x1f) as usize;
+        let ra = ((op >> 16) & 0x1f) as usize;
+        let imm = op & 0xffff;
+        match op >> 26 {
+            // addi/addis (li/lis when rA is zero)
+            14 | 15 => {
+                let base = if ra == 0 { Some(0) } else { gpr[ra] };
+                let val = if op >> 26 == 15 { imm << 16 } else { imm as i16 as u32 };
+                gpr[rd] = base.map(|b: u32| b.wrapping_add(val));
+            },
+            // ori/oris (rS is in the rD field)
+            24 => gpr[ra] = gpr[rd].map(|x| x | imm),
+            25 => gpr[ra] = gpr[rd].map(|x| x | (imm << 16)),
+            // mtspr to SRR0, LR, or CTR
+            31 if (op >> 1) & 0x3ff == 467 => {
+                let spr = ((op >> 16) & 0x1f) | (((op >> 11) & 0x1f) << 5);
+                if matches!(spr, 26 | 8 | 9) {
+                    res = gpr[rd];
+                }
+            },
+            _ => {},
+        }
+    }
+    res
+}
+
+/// Something which watches for Broadway being released from reset.
+pub trait PpcBootObserver: Send + Sync {
+    fn observe(&mut self, bus: &mut Bus, event: &PpcBootEvent);
+}
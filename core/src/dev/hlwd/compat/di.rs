@@ -1,6 +1,9 @@
+
+use crate::bus::*;
 use crate::bus::mmio::*;
 use crate::bus::prim::*;
 use crate::bus::task::*;
+use crate::dev::hlwd::irq::*;
 
 /// Legacy disc drive interface.
 #[derive(Default, Debug, Clone)]
@@ -14,12 +17,23 @@ pub struct DriveInterface {
     diimmbuf: u32,
     dicfg: u32,
 }
+impl DriveInterface {
+    /// Approximate number of bus cycles it takes for a command to complete.
+    pub const CMD_LATENCY: usize = 10 * CYCLES_PER_US;
+}
 impl MmioDevice for DriveInterface {
     type Width = u32;
     fn read(&mut self, off: usize) -> BusPacket {
         let val = match off {
             0x00 => self.disr,
             0x04 => self.dicvr,
+            0x08 => self.dicmdbuf[0],
+            0x0c => self.dicmdbuf[1],
+            0x10 => self.dicmdbuf[2],
+            0x14 => self.dimar,
+            0x18 => self.dilength,
+            0x1c => self.dicr,
+            0x20 => self.diimmbuf,
             0x24 => self.dicfg,
             _ => panic!("DI read to undefined offset {:x}", off),
         };
@@ -29,10 +43,37 @@ impl MmioDevice for DriveInterface {
         match off {
             0x00 => self.disr = val,
             0x04 => self.dicvr = val,
+            0x08 => self.dicmdbuf[0] = val,
+            0x0c => self.dicmdbuf[1] = val,
+            0x10 => self.dicmdbuf[2] = val,
+            0x14 => self.dimar = val,
+            0x18 => self.dilength = val,
+            0x1c => {
+                self.dicr = val;
+                // Start a transfer when TSTART is set
+                if val & 0x0000_0001 != 0 {
+                    return Some(BusTask::Di(val));
+                }
+            },
+            0x20 => self.diimmbuf = val,
             _ => panic!("DI write {:08x?} to undefined offset {:x}", val, off),
         }
         None
     }
 }
 
+impl Bus {
+    /// Complete a disc drive command.
+    ///
+    /// There's no drive attached, so commands just complete immediately and
+    /// raise the transfer-complete interrupt.
+    pub fn handle_task_di(&mut self, val: u32) {
+        println!("DI command {:08x?} (cr={:08x})", self.hlwd.di.dicmdbuf, val);
+        self.hlwd.di.dicr &= !0x0000_0001;
+        self.hlwd.di.disr |= 0x0000_0010;
+        if self.hlwd.di.disr & 0x0000_0008 != 0 {
+            self.hlwd.irq.assert(HollywoodIrq::Di);
+        }
+    }
+}
 
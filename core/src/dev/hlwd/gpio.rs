@@ -1,8 +1,18 @@
 
 pub mod seeprom;
+pub mod ave;
+pub mod debug;
+pub mod misc;
 use crate::dev::hlwd::gpio::seeprom::*;
+use crate::dev::hlwd::gpio::ave::*;
+use crate::dev::hlwd::gpio::debug::*;
+use crate::dev::hlwd::gpio::misc::*;
 use crate::dev::hlwd::*;
+use crate::dev::hlwd::irq::HollywoodIrq;
 
+use std::any::Any;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u32)]
 pub enum GpioPin {
     Power       = 0x0000_0001,
@@ -23,35 +33,223 @@ pub enum GpioPin {
     AveSda      = 0x0000_8000,
 }
 
+/// A button on the front of the console.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    Power,
+    Reset,
+    Eject,
+}
+impl Button {
+    pub fn from_u32(x: u32) -> Option<Self> {
+        match x {
+            0 => Some(Self::Power),
+            1 => Some(Self::Reset),
+            2 => Some(Self::Eject),
+            _ => None,
+        }
+    }
+}
+
+
+/// A change on the ARM GPIO output pins.
+#[derive(Debug, Clone, Copy)]
+pub struct GpioEdge {
+    /// The bus cycle on which the change happened.
+    pub cycle: usize,
+    /// Output levels before the change.
+    pub prev: u32,
+    /// Output levels after the change.
+    pub output: u32,
+    /// Pin directions before the change.
+    pub prev_dir: u32,
+    /// Pin directions after the change (set bits are driven by the ARM).
+    pub dir: u32,
+}
+impl GpioEdge {
+    /// Returns true if any of the pins in `mask` changed.
+    pub fn changed(&self, mask: u32) -> bool {
+        ((self.prev ^ self.output) & mask) != 0
+    }
+    /// Returns the output level of some pin.
+    pub fn level(&self, pin: GpioPin) -> bool {
+        (self.output & pin as u32) != 0
+    }
+    /// Returns true if some pin went from low to high.
+    pub fn rising(&self, pin: GpioPin) -> bool {
+        (self.prev & pin as u32) == 0 && self.level(pin)
+    }
+    /// Returns true if some pin went from high to low.
+    pub fn falling(&self, pin: GpioPin) -> bool {
+        (self.prev & pin as u32) != 0 && !self.level(pin)
+    }
+    /// Returns the level of an open-drain line, which is pulled up unless
+    /// the ARM drives it low.
+    pub fn line(&self, pin: GpioPin) -> bool {
+        (self.dir & pin as u32) == 0 || self.level(pin)
+    }
+    /// Returns true if any of the open-drain lines in `mask` changed.
+    pub fn line_changed(&self, mask: u32) -> bool {
+        let prev = self.prev | !self.prev_dir;
+        let cur = self.output | !self.dir;
+        ((prev ^ cur) & mask) != 0
+    }
+}
+
+/// A device attached to some set of GPIO pins.
+pub trait GpioDevice: Any + Send + Sync {
+    /// Mask of the pins which the device is connected to.
+    fn pins(&self) -> u32;
+    /// Mask of the pins which are open-drain lines. Changing the direction
+    /// of these pins also counts as an edge.
+    fn open_drain(&self) -> u32 { 0 }
+    /// Handle a change on some of the device's pins. Devices drive input
+    /// pins by changing bits in `input`.
+    fn handle_edge(&mut self, edge: &GpioEdge, input: &mut u32);
+    /// Persist any changes to storage.
+    fn flush(&mut self) {}
+}
+
+/// Something which watches changes on the GPIO pins.
+pub trait GpioObserver: Send + Sync {
+    /// Called after the attached devices have handled some change, with the
+    /// resulting input levels.
+    fn observe(&mut self, edge: &GpioEdge, input: u32);
+}
+
 
 /// Top-level container for GPIO pin state.
 pub struct GpioInterface {
     pub arm: ArmGpio,
     pub ppc: PpcGpio,
 
-    pub seeprom: SeepromState,
+    /// Devices attached to the pins, in the order they are stepped.
+    devices: Vec<Box<dyn GpioDevice>>,
+    /// Observers for changes on the pins.
+    observers: Vec<Box<dyn GpioObserver>>,
+
+    /// True while the reset button is held down.
+    pub reset_pressed: bool,
 }
 impl GpioInterface {
-    pub fn new() -> Self {
-        GpioInterface {
+    pub fn new(seeprom_mode: StorageMode) -> Self {
+        let mut res = GpioInterface {
             arm: ArmGpio::default(),
             ppc: PpcGpio::default(),
-            seeprom: SeepromState::new(),
+            devices: Vec::new(),
+            observers: Vec::new(),
+            reset_pressed: false,
+        };
+        res.attach(Box::new(SeepromState::new(seeprom_mode)));
+        res.attach(Box::new(AveState::new()));
+        res.attach(Box::new(DebugPort::default()));
+        res.attach(Box::new(SensorBar::default()));
+        res.attach(Box::new(PowerControl::default()));
+        res
+    }
+
+    /// Attach a device to the pins.
+    pub fn attach(&mut self, dev: Box<dyn GpioDevice>) {
+        self.devices.push(dev);
+    }
+
+    /// Start passing changes on the pins to some observer.
+    pub fn add_observer(&mut self, obs: Box<dyn GpioObserver>) {
+        self.observers.push(obs);
+    }
+
+    /// Get a reference to the first attached device of some type.
+    pub fn device<T: GpioDevice>(&self) -> Option<&T> {
+        self.devices.iter().find_map(|d| (d.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    /// Get a mutable reference to the first attached device of some type.
+    pub fn device_mut<T: GpioDevice>(&mut self) -> Option<&mut T> {
+        self.devices.iter_mut().find_map(|d| (d.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// Returns true if the guest has asserted the Shutdown pin.
+    pub fn shutdown(&self) -> bool {
+        self.device::<PowerControl>().is_some_and(|p| p.shutdown)
+    }
+
+    /// Persist any changes to storage in the attached devices.
+    pub fn flush(&mut self) {
+        for dev in self.devices.iter_mut() {
+            dev.flush();
         }
     }
 }
 
 impl GpioInterface {
-    pub fn handle_output(&mut self, val: u32) {
-        let diff = self.arm.output ^ val;
-        if (diff & 0x0000_1c00) != 0 {
-            self.handle_seeprom(val);
-        } else if (diff & 0x00ff_0000) != 0 {
-            println!("GPIO DEBUG pins [{:02x}]", (val & 0x00ff_0000) >> 16);
-        } else if (diff & 0x0000_000c) != 0 {
-            println!("GPIO Fan/DCDC output {:08x}", diff);
+    pub fn handle_output(&mut self, cycle: usize, val: u32) {
+        let edge = GpioEdge {
+            cycle, prev: self.arm.output, output: val,
+            prev_dir: self.arm.dir, dir: self.arm.dir,
+        };
+
+        let mut connected = 0;
+        for dev in self.devices.iter_mut() {
+            connected |= dev.pins();
+            if edge.changed(dev.pins()) {
+                dev.handle_edge(&edge, &mut self.arm.input);
+            }
+        }
+        if edge.changed(!connected) {
+            println!("GPIO unhandled output diff={:08x} output={:08x}",
+                (edge.prev ^ val) & !connected, val);
+        }
+        self.arm.output = val;
+
+        for obs in self.observers.iter_mut() {
+            obs.observe(&edge, self.arm.input);
+        }
+    }
+
+    /// Handle a write to the pin directions. Only devices with open-drain
+    /// lines see these changes: they release a line by making it an input.
+    pub fn handle_dir(&mut self, cycle: usize, val: u32) {
+        let edge = GpioEdge {
+            cycle, prev: self.arm.output, output: self.arm.output,
+            prev_dir: self.arm.dir, dir: val,
+        };
+        for dev in self.devices.iter_mut() {
+            if edge.line_changed(dev.open_drain()) {
+                dev.handle_edge(&edge, &mut self.arm.input);
+            }
+        }
+        self.arm.dir = val;
+
+        for obs in self.observers.iter_mut() {
+            obs.observe(&edge, self.arm.input);
+        }
+    }
+
+    /// Drive some input pin, returning the IRQ which should be asserted
+    /// (if any). Pins owned by the PowerPC interrupt the PowerPC.
+    pub fn set_input(&mut self, pin: GpioPin, level: bool) -> Option<HollywoodIrq> {
+        let mask = pin as u32;
+        if level {
+            self.arm.input |= mask;
         } else {
-            panic!("Unhandled GPIO output diff={:08x}", diff);
+            self.arm.input &= !mask;
+        }
+        self.ppc.input = self.arm.input & self.arm.owner;
+
+        // Interrupts are level-triggered: a pin is flagged when the input
+        // matches the level in the INTLVL register
+        if (self.arm.owner & mask) != 0 {
+            if (!(self.ppc.input ^ self.ppc.intlvl) & mask) == 0 {
+                return None;
+            }
+            self.ppc.intflag |= mask;
+            ((self.ppc.intmask & mask) != 0).then_some(HollywoodIrq::PpcGpio)
+        } else {
+            if (!(self.arm.input ^ self.arm.intlvl) & mask) == 0 {
+                return None;
+            }
+            self.arm.intflag |= mask;
+            ((self.arm.intmask & mask) != 0).then_some(HollywoodIrq::ArmGpio)
         }
     }
 }
@@ -83,10 +281,17 @@ impl ArmGpio {
                 };
                 return task;
             },
-            0x08 => self.dir = data,
+            0x08 => {
+                let task = if (self.dir ^ data) != 0 {
+                    Some(HlwdTask::GpioDir(data))
+                } else {
+                    None
+                };
+                return task;
+            },
             0x0c => panic!("CPU wrote to GPIO inputs!?"),
             0x10 => self.intlvl = data,
-            0x14 => self.intflag = data,
+            0x14 => self.intflag &= !data,
             0x18 => self.intmask = data,
             0x1c => self.straps = data,
             0x20 => self.owner = data,
@@ -101,7 +306,7 @@ impl ArmGpio {
             0x08 => self.dir,
             0x0c => self.input,
             0x10 => self.intlvl,
-            0x14 => 0x0000_0000, //self.intflag,
+            0x14 => self.intflag,
             0x18 => self.intmask,
             0x1c => self.straps,
             0x20 => self.owner,
@@ -126,6 +331,11 @@ impl PpcGpio {
         match off {
             0x00 => self.output = data,
             0x04 => self.dir = data,
+            0x08 => panic!("CPU wrote to GPIO inputs!?"),
+            0x0c => self.intlvl = data,
+            0x10 => self.intflag &= !data,
+            0x14 => self.intmask = data,
+            0x18 => self.straps = data,
             _ => panic!("unimplemented PpcGpio write {:08x}", off),
         }
     }
@@ -133,6 +343,11 @@ impl PpcGpio {
         match off {
             0x00 => self.output,
             0x04 => self.dir,
+            0x08 => self.input,
+            0x0c => self.intlvl,
+            0x10 => self.intflag,
+            0x14 => self.intmask,
+            0x18 => self.straps,
             _ => panic!("unimplemented PpcGpio read {:08x}", off),
         }
     }
@@ -0,0 +1,370 @@
+use crate::dev::hlwd::gpio::*;
+
+/// The (7-bit) I2C address of the audio/video encoder.
+pub const AVE_ADDR: u8 = 0x70;
+
+/// States of the I2C bit-bang decoder.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum I2cState {
+    /// Waiting for a start condition.
+    Idle,
+    /// Shifting in a byte from the master.
+    Recv,
+    /// Driving the ACK for a byte from the master.
+    Ack,
+    /// Shifting out a byte to the master.
+    Send,
+    /// Waiting for the master to ACK a byte we sent.
+    MasterAck,
+}
+
+/// Container for the state of the emulated AVE (and the I2C bus to it).
+#[derive(Debug)]
+pub struct AveState {
+    /// The register file.
+    pub regs: [u8; 0x100],
+
+    /// Levels of SCL/SDA on the last step.
+    scl: bool,
+    sda: bool,
+    /// The level driven onto SDA by the device (low to pull it down).
+    pub sda_out: bool,
+
+    pub state: I2cState,
+    /// Bits shifted in (or out) of the current byte.
+    shift: u8,
+    num_bits: u32,
+    /// Set when the master ACKed the last byte we sent.
+    master_ack: bool,
+
+    /// True if the current transaction is addressed to the AVE.
+    selected: bool,
+    /// True if the current transaction is a read.
+    read: bool,
+    /// Set once the register index has been written in this transaction.
+    have_idx: bool,
+    /// The register used for the next access.
+    pub idx: u8,
+    /// Bytes in the current transaction, for logging.
+    bytes: Vec<u8>,
+}
+impl AveState {
+    pub fn new() -> Self {
+        AveState {
+            regs: [0; 0x100],
+            scl: true, sda: true, sda_out: true,
+            state: I2cState::Idle,
+            shift: 0, num_bits: 0, master_ack: false,
+            selected: false, read: false, have_idx: false, idx: 0,
+            bytes: Vec::new(),
+        }
+    }
+}
+impl Default for AveState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AveState {
+    /// Log the bytes in the last transaction.
+    fn finish(&mut self) {
+        if self.bytes.is_empty() {
+            return;
+        }
+        let dir = if self.read { "read" } else { "write" };
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("AVE I2C {} [{}]", dir, bytes.join(" "));
+        self.bytes.clear();
+    }
+
+    /// Handle a byte sent by the master, returning true if it should be
+    /// ACKed.
+    fn handle_byte(&mut self, byte: u8) -> bool {
+        self.bytes.push(byte);
+
+        // The first byte after a start condition is the address
+        if self.bytes.len() == 1 {
+            self.read = (byte & 1) != 0;
+            self.selected = (byte >> 1) == AVE_ADDR;
+            if !self.selected {
+                println!("AVE I2C no device at address {:02x}", byte >> 1);
+            }
+            return self.selected;
+        }
+
+        // Writes start with the register index, followed by data
+        if !self.have_idx {
+            self.idx = byte;
+            self.have_idx = true;
+        } else {
+            println!("AVE write reg[{:02x}] = {:02x}", self.idx, byte);
+            self.regs[self.idx as usize] = byte;
+            self.idx = self.idx.wrapping_add(1);
+        }
+        true
+    }
+
+    /// Start shifting out the next register to the master.
+    fn load_byte(&mut self) {
+        self.shift = self.regs[self.idx as usize];
+        println!("AVE read reg[{:02x}] = {:02x}", self.idx, self.shift);
+        self.bytes.push(self.shift);
+        self.idx = self.idx.wrapping_add(1);
+        self.num_bits = 0;
+        self.sda_out = (self.shift & 0x80) != 0;
+        self.state = I2cState::Send;
+    }
+
+    /// Step the decoder with the current levels of SCL and SDA, returning
+    /// the level driven onto SDA by the device.
+    pub fn step(&mut self, scl: bool, sda: bool) -> bool {
+        use I2cState::*;
+
+        // While SCL is high, SDA only changes for start/stop conditions
+        if self.scl && scl && self.sda != sda {
+            if !sda {
+                self.finish();
+                self.state = Recv;
+                self.shift = 0;
+                self.num_bits = 0;
+                self.selected = false;
+                self.have_idx = false;
+            } else {
+                self.finish();
+                self.state = Idle;
+            }
+            self.sda_out = true;
+        }
+        // Bits are sampled on the rising edge of SCL
+        else if !self.scl && scl {
+            match self.state {
+                Recv => {
+                    self.shift = (self.shift << 1) | sda as u8;
+                    self.num_bits += 1;
+                },
+                MasterAck => self.master_ack = !sda,
+                _ => {},
+            }
+        }
+        // SDA is changed after the falling edge of SCL
+        else if self.scl && !scl {
+            match self.state {
+                Recv if self.num_bits == 8 => {
+                    let ack = self.handle_byte(self.shift);
+                    self.sda_out = !ack;
+                    self.state = if ack { Ack } else { Idle };
+                },
+                Ack => {
+                    self.sda_out = true;
+                    if self.read {
+                        self.load_byte();
+                    } else {
+                        self.shift = 0;
+                        self.num_bits = 0;
+                        self.state = Recv;
+                    }
+                },
+                Send => {
+                    self.num_bits += 1;
+                    if self.num_bits == 8 {
+                        self.sda_out = true;
+                        self.state = MasterAck;
+                    } else {
+                        self.sda_out = (self.shift & (0x80 >> self.num_bits)) != 0;
+                    }
+                },
+                MasterAck => {
+                    if self.master_ack {
+                        self.load_byte();
+                    } else {
+                        self.sda_out = true;
+                        self.state = Idle;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        self.scl = scl;
+        self.sda = sda;
+        self.sda_out
+    }
+}
+
+impl GpioDevice for AveState {
+    fn pins(&self) -> u32 {
+        GpioPin::AveScl as u32 | GpioPin::AveSda as u32
+    }
+
+    fn open_drain(&self) -> u32 {
+        self.pins()
+    }
+
+    fn handle_edge(&mut self, edge: &GpioEdge, input: &mut u32) {
+        let sda_mask = GpioPin::AveSda as u32;
+
+        // The lines are pulled up unless something drives them low
+        let scl = edge.line(GpioPin::AveScl);
+        let master_sda = edge.line(GpioPin::AveSda);
+        let sda = master_sda && self.sda_out;
+
+        let device_sda = self.step(scl, sda);
+        if master_sda && device_sda {
+            *input |= sda_mask;
+        } else {
+            *input &= !sda_mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::storage::StorageMode;
+
+    const SCL: u32 = GpioPin::AveScl as u32;
+    const SDA: u32 = GpioPin::AveSda as u32;
+
+    /// Something which an I2C master can drive the lines of.
+    trait I2cLines {
+        /// Drive SCL and SDA, returning the level of the SDA line.
+        fn set(&mut self, scl: bool, sda: bool) -> bool;
+
+        /// Clock out a bit with SDA at some level, returning the level of
+        /// the SDA line while SCL is high.
+        fn clock(&mut self, sda: bool) -> bool {
+            self.set(false, sda);
+            let res = self.set(true, sda);
+            self.set(false, sda);
+            res
+        }
+        /// Send a (repeated) start condition.
+        fn start(&mut self) {
+            self.set(false, true);
+            self.set(true, true);
+            self.set(true, false);
+            self.set(false, false);
+        }
+        /// Send a stop condition.
+        fn stop(&mut self) {
+            self.set(false, false);
+            self.set(true, false);
+            self.set(true, true);
+        }
+        /// Send a byte, returning true if it was ACKed.
+        fn write_byte(&mut self, byte: u8) -> bool {
+            for i in (0..8).rev() {
+                self.clock((byte >> i) & 1 != 0);
+            }
+            !self.clock(true)
+        }
+        /// Receive a byte, then ACK or NAK it.
+        fn read_byte(&mut self, ack: bool) -> u8 {
+            let mut res = 0;
+            for _ in 0..8 {
+                res = (res << 1) | self.clock(true) as u8;
+            }
+            self.clock(!ack);
+            res
+        }
+    }
+
+    impl I2cLines for AveState {
+        fn set(&mut self, scl: bool, sda: bool) -> bool {
+            let line = sda && self.sda_out;
+            let device_sda = self.step(scl, line);
+            sda && device_sda
+        }
+    }
+
+    /// Drives the lines as open-drain outputs through the ARM GPIO
+    /// registers: SDA is only driven low, and is released by making it an
+    /// input.
+    impl I2cLines for GpioInterface {
+        fn set(&mut self, scl: bool, sda: bool) -> bool {
+            let output = if scl { SCL } else { 0 };
+            let dir = if sda { SCL } else { SCL | SDA };
+            if self.arm.read_handler(0x04) != output {
+                self.handle_output(0, output);
+            }
+            if self.arm.read_handler(0x08) != dir {
+                self.handle_dir(0, dir);
+            }
+            (self.arm.read_handler(0x0c) & SDA) != 0
+        }
+    }
+
+    const WRITE: u8 = AVE_ADDR << 1;
+    const READ: u8 = AVE_ADDR << 1 | 1;
+
+    #[test]
+    fn write() {
+        let mut ave = AveState::new();
+        ave.start();
+        assert!(ave.write_byte(WRITE));
+        assert!(ave.write_byte(0x10));
+        assert!(ave.write_byte(0xaa));
+        assert!(ave.write_byte(0xbb));
+        ave.stop();
+        assert_eq!(ave.regs[0x10..0x12], [0xaa, 0xbb]);
+        assert_eq!(ave.idx, 0x12);
+        assert_eq!(ave.state, I2cState::Idle);
+    }
+
+    #[test]
+    fn repeated_start_read() {
+        let mut ave = AveState::new();
+        ave.regs[0x20..0x23].copy_from_slice(&[0x12, 0x34, 0x56]);
+        ave.start();
+        assert!(ave.write_byte(WRITE));
+        assert!(ave.write_byte(0x20));
+        ave.start();
+        assert!(ave.write_byte(READ));
+        assert_eq!(ave.read_byte(true), 0x12);
+        assert_eq!(ave.read_byte(true), 0x34);
+        assert_eq!(ave.read_byte(false), 0x56);
+        ave.stop();
+        assert_eq!(ave.idx, 0x23);
+    }
+
+    #[test]
+    fn nack_other_address() {
+        let mut ave = AveState::new();
+        ave.start();
+        assert!(!ave.write_byte(0x50 << 1));
+        assert!(!ave.write_byte(0x00));
+        assert!(!ave.write_byte(0xff));
+        ave.stop();
+        assert_eq!(ave.regs, [0; 0x100]);
+    }
+
+    #[test]
+    fn master_nak_ends_read() {
+        let mut ave = AveState::new();
+        ave.start();
+        assert!(ave.write_byte(READ));
+        assert_eq!(ave.read_byte(true), 0x00);
+        assert_eq!(ave.state, I2cState::Send);
+
+        // After the NAK, the device stops driving SDA
+        assert_eq!(ave.read_byte(false), 0x00);
+        assert_eq!(ave.state, I2cState::Idle);
+        assert!(ave.sda_out);
+        assert_eq!(ave.read_byte(true), 0xff);
+        assert_eq!(ave.idx, 2);
+    }
+
+    #[test]
+    fn open_drain_sda() {
+        let mut gpio = GpioInterface::new(StorageMode::Blank);
+        gpio.device_mut::<AveState>().unwrap().regs[0x05] = 0xa5;
+        gpio.start();
+        assert!(gpio.write_byte(WRITE));
+        assert!(gpio.write_byte(0x05));
+        gpio.start();
+        assert!(gpio.write_byte(READ));
+        assert_eq!(gpio.read_byte(false), 0xa5);
+        gpio.stop();
+    }
+}
@@ -0,0 +1,109 @@
+use crate::dev::hlwd::gpio::*;
+
+use std::collections::HashMap;
+
+/// Mask of the pins used for the 8-bit debug port.
+pub const DEBUG_PORT_PINS: u32 = 0x00ff_0000;
+
+/// The number of entries printed in [DebugPort::summary].
+const SUMMARY_LEN: usize = 16;
+
+/// Table of messages for the codes which boot code writes to the debug port.
+#[derive(Debug, Default, Clone)]
+pub struct DebugCodeTable {
+    codes: HashMap<u8, String>,
+}
+impl DebugCodeTable {
+    pub fn insert(&mut self, code: u8, msg: &str) {
+        self.codes.insert(code, msg.to_string());
+    }
+
+    pub fn get(&self, code: u8) -> Option<&str> {
+        self.codes.get(&code).map(|s| s.as_str())
+    }
+
+    /// Parse a table with one `<code> <message>` per line, where the code is
+    /// in hex. Empty lines and lines starting with '#' are ignored.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut res = DebugCodeTable::default();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, msg) = line.split_once(char::is_whitespace)
+                .unwrap_or((line, ""));
+            let code = u8::from_str_radix(code.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("Invalid debug code on line {}: '{}'", num + 1, code))?;
+            res.insert(code, msg.trim());
+        }
+        Ok(res)
+    }
+
+    pub fn from_file(filename: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(filename)
+            .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+        Self::parse(&text)
+    }
+}
+
+/// The debug port on GPIO pins 16-23, which boot code uses to report its
+/// progress.
+#[derive(Debug, Default)]
+pub struct DebugPort {
+    /// The last value written to the port.
+    pub value: u8,
+    /// Each value written to the port, with the bus cycle it was written on.
+    pub history: Vec<(usize, u8)>,
+    /// Messages for known codes.
+    pub table: DebugCodeTable,
+}
+impl DebugPort {
+    /// Returns the last value written to the port and the cycle it was
+    /// written on.
+    pub fn last(&self) -> Option<(usize, u8)> {
+        self.history.last().copied()
+    }
+
+    /// Returns the message for some code (if it's known).
+    pub fn describe(&self, code: u8) -> Option<&str> {
+        self.table.get(code)
+    }
+
+    fn format_entry(&self, cycle: usize, code: u8) -> String {
+        match self.describe(code) {
+            Some(msg) => format!("{:02x} at cycle {} ({})", code, cycle, msg),
+            None => format!("{:02x} at cycle {}", code, cycle),
+        }
+    }
+
+    /// Describe the last few values written to the port.
+    pub fn summary(&self) -> String {
+        if self.history.is_empty() {
+            return "Debug port: no codes written".to_string();
+        }
+        let skip = self.history.len().saturating_sub(SUMMARY_LEN);
+        let mut res = format!("Debug port: {} codes written", self.history.len());
+        if skip != 0 {
+            res += &format!(", last {}", SUMMARY_LEN);
+        }
+        for &(cycle, code) in &self.history[skip..] {
+            res += &format!("\n  {}", self.format_entry(cycle, code));
+        }
+        res
+    }
+}
+impl GpioDevice for DebugPort {
+    fn pins(&self) -> u32 {
+        DEBUG_PORT_PINS
+    }
+
+    fn handle_edge(&mut self, edge: &GpioEdge, _input: &mut u32) {
+        self.value = ((edge.output & DEBUG_PORT_PINS) >> 16) as u8;
+        self.history.push((edge.cycle, self.value));
+        match self.describe(self.value) {
+            Some(msg) => println!("GPIO DEBUG pins [{:02x}] {}", self.value, msg),
+            None => println!("GPIO DEBUG pins [{:02x}]", self.value),
+        }
+    }
+}
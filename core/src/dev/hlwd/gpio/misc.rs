@@ -0,0 +1,39 @@
+use crate::dev::hlwd::gpio::*;
+
+/// The pins which control power to the system.
+#[derive(Debug, Default)]
+pub struct PowerControl {
+    /// Set when the guest has asserted the Shutdown pin.
+    pub shutdown: bool,
+}
+impl GpioDevice for PowerControl {
+    fn pins(&self) -> u32 {
+        GpioPin::Shutdown as u32 | GpioPin::Fan as u32 | GpioPin::Dcdc as u32
+    }
+
+    fn handle_edge(&mut self, edge: &GpioEdge, _input: &mut u32) {
+        if edge.changed(GpioPin::Fan as u32 | GpioPin::Dcdc as u32) {
+            println!("GPIO Fan/DCDC output {:08x}", edge.prev ^ edge.output);
+        }
+        if edge.rising(GpioPin::Shutdown) {
+            println!("GPIO Shutdown asserted");
+            self.shutdown = true;
+        }
+    }
+}
+
+/// The sensor bar.
+#[derive(Debug, Default)]
+pub struct SensorBar {
+    pub enabled: bool,
+}
+impl GpioDevice for SensorBar {
+    fn pins(&self) -> u32 {
+        GpioPin::SensorBar as u32
+    }
+
+    fn handle_edge(&mut self, edge: &GpioEdge, _input: &mut u32) {
+        self.enabled = edge.level(GpioPin::SensorBar);
+        println!("GPIO sensor bar {}", if self.enabled { "on" } else { "off" });
+    }
+}
@@ -1,8 +1,6 @@
 
-use std::io::Read;
-use std::fs::File;
 use crate::dev::hlwd::gpio::*;
-use crate::mem::*;
+use crate::mem::storage::*;
 
 /// Set of commands to/states of the SEEPROM state machine.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -38,7 +36,7 @@ impl SeepromOp {
 #[derive(Debug)]
 pub struct SeepromState {
     /// Data on the SEEPROM device.
-    data: BigEndianMemory,
+    data: BackedMemory,
 
     /// Input buffer (of some set of bits).
     pub in_buf: u32,
@@ -55,13 +53,13 @@ pub struct SeepromState {
     pub write_buffer: Option<u16>,
 }
 impl SeepromState {
-    pub fn new() -> Self {
+    pub fn new(mode: StorageMode) -> Self {
         SeepromState {
             in_buf: 0,
             num_bits: 0,
             out_buf: None,
             opcd: SeepromOp::Init,
-            data: BigEndianMemory::new(0x100, Some("seeprom.bin")),
+            data: BackedMemory::open("seeprom.bin", 0x100, 2, mode),
             wren: false,
             addr: None,
             write_buffer: None,
@@ -70,6 +68,11 @@ impl SeepromState {
 }
 
 impl SeepromState {
+    /// Persist any changes to the SEEPROM.
+    pub fn flush(&mut self) {
+        self.data.flush();
+    }
+
     pub fn reset(&mut self) {
         self.in_buf = 0;
         self.out_buf = None;
@@ -172,33 +175,35 @@ impl SeepromState {
     }
 }
 
-impl GpioInterface {
-    pub fn handle_seeprom(&mut self, val: u32) {
-        let mosi = ((val & GpioPin::SeepromMosi as u32) != 0) as u32;
-        let cs = (val & GpioPin::SeepromCs as u32) != 0;
-        let clk_rise = (self.arm.output & GpioPin::SeepromClk as u32) == 0 
-            && (val & GpioPin::SeepromClk as u32) != 0;
+impl GpioDevice for SeepromState {
+    fn pins(&self) -> u32 {
+        GpioPin::SeepromCs as u32 | GpioPin::SeepromClk as u32 |
+            GpioPin::SeepromMosi as u32 | GpioPin::SeepromMiso as u32
+    }
+
+    fn handle_edge(&mut self, edge: &GpioEdge, input: &mut u32) {
+        let mosi = edge.level(GpioPin::SeepromMosi) as u32;
+        let cs = edge.level(GpioPin::SeepromCs);
 
         // When CS is deasserted, the state of the SEEPROM is irrelevant.
         if !cs {
-            //if self.seeprom.num_bits > 0 {
+            //if self.num_bits > 0 {
             //    println!("SEEPROM CS deasserted after {} input bits, {:b}", 
-            //        self.seeprom.num_bits, self.seeprom.in_buf);
+            //        self.num_bits, self.in_buf);
             //}
-            self.seeprom.reset();
+            self.reset();
         } 
 
         // If CS is asserted and we're at the rising edge of the clock,
         // compute the next step of the serial/SPI state machine.
-        if cs && clk_rise {
-            let new_input = self.seeprom.step(mosi, self.arm.input);
-            if new_input.is_some() {
-                self.arm.input = new_input.unwrap();
+        if cs && edge.rising(GpioPin::SeepromClk) {
+            if let Some(new_input) = self.step(mosi, *input) {
+                *input = new_input;
             }
         }
+    }
 
-        // Commit the value to the output register
-        self.arm.output = val;
+    fn flush(&mut self) {
+        SeepromState::flush(self);
     }
 }
-
@@ -1,14 +1,65 @@
 use crate::bus::task::*;
+use crate::bus::*;
 use crate::dev::hlwd::irq::*;
 
+/// A message passed between the PowerPC and ARM through the mailbox.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpcMessage {
+    /// A pointer to some request, sent from the PowerPC to the ARM.
+    Request(u32),
+    /// A pointer to some completed request, sent from the ARM to the PowerPC.
+    Reply(u32),
+}
+
+/// Something which watches the messages passed through the mailbox.
+pub trait IpcObserver: Send + Sync {
+    fn observe(&mut self, bus: &mut Bus, msg: IpcMessage);
+}
+
+// Bits in PPC_CTRL.
+/// X1: the PowerPC has sent a message (set-only).
+pub const PPC_CTRL_X1: u32  = 0x0000_0001;
+/// Y2: the ARM acknowledged a message (write-1-to-clear).
+pub const PPC_CTRL_Y2: u32  = 0x0000_0002;
+/// Y1: the ARM has sent a message (write-1-to-clear).
+pub const PPC_CTRL_Y1: u32  = 0x0000_0004;
+/// X2: the PowerPC acknowledged a message (set-only).
+pub const PPC_CTRL_X2: u32  = 0x0000_0008;
+/// IY1: interrupt the PowerPC when Y1 is set.
+pub const PPC_CTRL_IY1: u32 = 0x0000_0010;
+/// IY2: interrupt the PowerPC when Y2 is set.
+pub const PPC_CTRL_IY2: u32 = 0x0000_0020;
+
+// Bits in ARM_CTRL.
+/// Y1: the ARM has sent a message (set-only).
+pub const ARM_CTRL_Y1: u32  = 0x0000_0001;
+/// X2: the PowerPC acknowledged a message (write-1-to-clear).
+pub const ARM_CTRL_X2: u32  = 0x0000_0002;
+/// X1: the PowerPC has sent a message (write-1-to-clear).
+pub const ARM_CTRL_X1: u32  = 0x0000_0004;
+/// Y2: the ARM acknowledged a message (set-only).
+pub const ARM_CTRL_Y2: u32  = 0x0000_0008;
+/// IX1: interrupt the ARM when X1 is set.
+pub const ARM_CTRL_IX1: u32 = 0x0000_0010;
+/// IX2: interrupt the ARM when X2 is set.
+pub const ARM_CTRL_IX2: u32 = 0x0000_0020;
+
+/// Mask of the interrupt enable bits in either control register.
+const CTRL_INT_MASK: u32 = 0x0000_0030;
+
+/// State of the flags shared between both control registers.
 #[derive(Clone, Default, Debug)]
 pub struct MailboxState {
+    /// Y1: message from the ARM is waiting.
     pub ppc_req: bool,
+    /// Y2: the ARM acknowledged a message from the PowerPC.
     pub ppc_ack: bool,
     pub ppc_req_int: bool,
     pub ppc_ack_int: bool,
 
+    /// X1: message from the PowerPC is waiting.
     pub arm_req: bool,
+    /// X2: the PowerPC acknowledged a message from the ARM.
     pub arm_ack: bool,
     pub arm_req_int: bool,
     pub arm_ack_int: bool,
@@ -17,48 +68,45 @@ pub struct MailboxState {
 impl MailboxState {
     /// Write handler for PPC_CTRL
     pub fn ppc_ctrl_write(&mut self, x: u32) {
-        self.arm_req = x & 0x0000_0001 != 0;
-        self.arm_ack = x & 0x0000_0008 != 0;
-        if x & 0x0000_0002 != 0 { self.ppc_ack = false; }
-        if x & 0x0000_0004 != 0 { self.ppc_req = false; }
-        self.ppc_req_int = x & 0x0000_0010 != 0;
-        self.ppc_ack_int = x & 0x0000_0020 != 0;
-        println!("{:?}", self);
+        if x & PPC_CTRL_X1 != 0 { self.arm_req = true; }
+        if x & PPC_CTRL_Y2 != 0 { self.ppc_ack = false; }
+        if x & PPC_CTRL_Y1 != 0 { self.ppc_req = false; }
+        if x & PPC_CTRL_X2 != 0 { self.arm_ack = true; }
+        self.ppc_req_int = x & PPC_CTRL_IY1 != 0;
+        self.ppc_ack_int = x & PPC_CTRL_IY2 != 0;
     }
 
     /// Write handler for ARM_CTRL
     pub fn arm_ctrl_write(&mut self, x: u32) {
-        self.ppc_req = x & 0x0000_0001 != 0;
-        self.ppc_ack = x & 0x0000_0008 != 0;
-        if x & 0x0000_0002 != 0 { self.arm_ack = false; }
-        if x & 0x0000_0004 != 0 { self.arm_req = false; }
-        self.arm_req_int = x & 0x0000_0010 != 0;
-        self.arm_ack_int = x & 0x0000_0020 != 0;
-        println!("{:?}", self);
-
+        if x & ARM_CTRL_Y1 != 0 { self.ppc_req = true; }
+        if x & ARM_CTRL_X2 != 0 { self.arm_ack = false; }
+        if x & ARM_CTRL_X1 != 0 { self.arm_req = false; }
+        if x & ARM_CTRL_Y2 != 0 { self.ppc_ack = true; }
+        self.arm_req_int = x & ARM_CTRL_IX1 != 0;
+        self.arm_ack_int = x & ARM_CTRL_IX2 != 0;
     }
 
     /// Read handler for PPC_CTRL.
     pub fn ppc_ctrl_read(&self) -> u32 {
         let mut res = 0;
-        res |= (self.arm_req as u32) << 0;
-        res |= (self.ppc_ack as u32) << 1;
-        res |= (self.ppc_req as u32) << 2;
-        res |= (self.arm_ack as u32) << 3;
-        res |= (self.ppc_req_int as u32) << 4;
-        res |= (self.ppc_ack_int as u32) << 5;
+        if self.arm_req { res |= PPC_CTRL_X1; }
+        if self.ppc_ack { res |= PPC_CTRL_Y2; }
+        if self.ppc_req { res |= PPC_CTRL_Y1; }
+        if self.arm_ack { res |= PPC_CTRL_X2; }
+        if self.ppc_req_int { res |= PPC_CTRL_IY1; }
+        if self.ppc_ack_int { res |= PPC_CTRL_IY2; }
         res
     }
 
     /// Read handler for ARM_CTRL.
     pub fn arm_ctrl_read(&self) -> u32 {
         let mut res = 0;
-        res |= (self.ppc_req as u32) << 0;
-        res |= (self.arm_ack as u32) << 1;
-        res |= (self.arm_req as u32) << 2;
-        res |= (self.ppc_ack as u32) << 3;
-        res |= (self.arm_req_int as u32) << 4;
-        res |= (self.arm_ack_int as u32) << 5;
+        if self.ppc_req { res |= ARM_CTRL_Y1; }
+        if self.arm_ack { res |= ARM_CTRL_X2; }
+        if self.arm_req { res |= ARM_CTRL_X1; }
+        if self.ppc_ack { res |= ARM_CTRL_Y2; }
+        if self.arm_req_int { res |= ARM_CTRL_IX1; }
+        if self.arm_ack_int { res |= ARM_CTRL_IX2; }
         res
     }
 
@@ -71,13 +119,82 @@ pub struct IpcInterface {
     pub arm_msg: u32,
     pub state: MailboxState,
 
+    /// When set, messages passed through the mailbox are recorded.
+    pub trace: bool,
+    /// Messages waiting to be passed to an observer.
+    pub messages: Vec<IpcMessage>,
 }
 impl IpcInterface {
     pub fn new() -> Self {
         IpcInterface {
             ppc_msg: 0, arm_msg: 0,
             state: MailboxState::default(),
+            trace: false,
+            messages: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, msg: IpcMessage) {
+        if self.trace {
+            self.messages.push(msg);
+        }
+    }
+
+    /// Set some bits in PPC_CTRL, preserving the interrupt enables.
+    pub fn ppc_ctrl_set(&mut self, bits: u32) {
+        let ctrl = self.state.ppc_ctrl_read() & CTRL_INT_MASK;
+        self.write_handler(0x04, ctrl | bits);
+    }
+
+    /// Set some bits in ARM_CTRL, preserving the interrupt enables.
+    pub fn arm_ctrl_set(&mut self, bits: u32) {
+        let ctrl = self.state.arm_ctrl_read() & CTRL_INT_MASK;
+        self.write_handler(0x0c, ctrl | bits);
+    }
+
+    /// Send a request from the PowerPC to the ARM.
+    pub fn send_request(&mut self, msg: u32) {
+        self.write_handler(0x00, msg);
+        self.ppc_ctrl_set(PPC_CTRL_X1);
+    }
+
+    /// Take a reply on the PowerPC side (if there is one), and acknowledge
+    /// it so that the ARM can send another.
+    pub fn take_reply(&mut self) -> Option<u32> {
+        if !self.state.ppc_req {
+            return None;
+        }
+        let msg = self.arm_msg;
+        self.ppc_ctrl_set(PPC_CTRL_Y1);
+        self.ppc_ctrl_set(PPC_CTRL_X2);
+        Some(msg)
+    }
+
+    /// Take an acknowledgement on the PowerPC side, returning true if there
+    /// was one.
+    pub fn take_ack(&mut self) -> bool {
+        if !self.state.ppc_ack {
+            return false;
+        }
+        self.ppc_ctrl_set(PPC_CTRL_Y2);
+        true
+    }
+
+    /// Send a reply from the ARM to the PowerPC.
+    pub fn send_reply(&mut self, msg: u32) {
+        self.write_handler(0x08, msg);
+        self.arm_ctrl_set(ARM_CTRL_Y1);
+    }
+
+    /// Take a request on the ARM side (if there is one), and acknowledge it.
+    pub fn take_request(&mut self) -> Option<u32> {
+        if !self.state.arm_req {
+            return None;
         }
+        let msg = self.ppc_msg;
+        self.arm_ctrl_set(ARM_CTRL_X1);
+        self.arm_ctrl_set(ARM_CTRL_Y2);
+        Some(msg)
     }
 
     /// Returns true if a PPC IPC interrupt is currently asserted.
@@ -104,24 +221,134 @@ impl IpcInterface {
     }
     pub fn write_handler(&mut self, off: usize, val: u32) {
         match off {
-            0x00 => {
-                println!("IPC PPC MSG write {:08x}", val);
-                self.ppc_msg = val;
-            }
+            0x00 => self.ppc_msg = val,
             0x04 => {
-                println!("IPC PPC CTRL write {:08x}", val);
                 self.state.ppc_ctrl_write(val);
+                if val & PPC_CTRL_X1 != 0 {
+                    self.record(IpcMessage::Request(self.ppc_msg));
+                }
             },
-            0x08 => {
-                println!("IPC ARM MSG write {:08x}", val);
-                self.arm_msg = val;
-            },
+            0x08 => self.arm_msg = val,
             0x0c => {
-                println!("IPC ARM CTRL write {:08x}", val);
                 self.state.arm_ctrl_write(val);
+                if val & ARM_CTRL_Y1 != 0 {
+                    self.record(IpcMessage::Reply(self.arm_msg));
+                }
             },
             _ => unreachable!(),
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mailbox with interrupts enabled on both sides.
+    fn mailbox() -> IpcInterface {
+        let mut ipc = IpcInterface::new();
+        ipc.write_handler(0x04, PPC_CTRL_IY1 | PPC_CTRL_IY2);
+        ipc.write_handler(0x0c, ARM_CTRL_IX1 | ARM_CTRL_IX2);
+        ipc
+    }
+
+    #[test]
+    fn request_from_ppc() {
+        let mut ipc = mailbox();
+        assert!(!ipc.assert_arm_irq());
+
+        ipc.write_handler(0x00, 0x0123_4560);
+        ipc.write_handler(0x04, PPC_CTRL_IY1 | PPC_CTRL_IY2 | PPC_CTRL_X1);
+        assert!(ipc.assert_arm_irq());
+        assert!(!ipc.assert_ppc_irq());
+        assert_eq!(ipc.read_handler(0x0c) & ARM_CTRL_X1, ARM_CTRL_X1);
+        assert_eq!(ipc.read_handler(0x00), 0x0123_4560);
+
+        // The ARM clears X1 and acknowledges with Y2
+        ipc.write_handler(0x0c, ARM_CTRL_IX1 | ARM_CTRL_IX2 | ARM_CTRL_X1);
+        assert!(!ipc.assert_arm_irq());
+        ipc.write_handler(0x0c, ARM_CTRL_IX1 | ARM_CTRL_IX2 | ARM_CTRL_Y2);
+        assert!(ipc.assert_ppc_irq());
+        assert_eq!(ipc.read_handler(0x04) & PPC_CTRL_Y2, PPC_CTRL_Y2);
+
+        // The PowerPC clears Y2
+        ipc.write_handler(0x04, PPC_CTRL_IY1 | PPC_CTRL_IY2 | PPC_CTRL_Y2);
+        assert!(!ipc.assert_ppc_irq());
+        assert_eq!(ipc.read_handler(0x04), PPC_CTRL_IY1 | PPC_CTRL_IY2);
+    }
+
+    #[test]
+    fn reply_from_arm() {
+        let mut ipc = mailbox();
+        ipc.write_handler(0x08, 0x0123_4560);
+        ipc.write_handler(0x0c, ARM_CTRL_IX1 | ARM_CTRL_IX2 | ARM_CTRL_Y1);
+        assert!(ipc.assert_ppc_irq());
+        assert!(!ipc.assert_arm_irq());
+        assert_eq!(ipc.read_handler(0x04) & PPC_CTRL_Y1, PPC_CTRL_Y1);
+
+        // The PowerPC clears Y1, then relaunches the ARM with X2
+        ipc.write_handler(0x04, PPC_CTRL_IY1 | PPC_CTRL_IY2 | PPC_CTRL_Y1);
+        assert!(!ipc.assert_ppc_irq());
+        ipc.write_handler(0x04, PPC_CTRL_IY1 | PPC_CTRL_IY2 | PPC_CTRL_X2);
+        assert!(ipc.assert_arm_irq());
+        assert_eq!(ipc.read_handler(0x0c) & ARM_CTRL_X2, ARM_CTRL_X2);
+
+        // The ARM clears X2
+        ipc.write_handler(0x0c, ARM_CTRL_IX1 | ARM_CTRL_IX2 | ARM_CTRL_X2);
+        assert!(!ipc.assert_arm_irq());
+    }
+
+    #[test]
+    fn set_only_bits_ignore_zero_writes() {
+        let mut ipc = mailbox();
+        ipc.send_request(0x1000);
+        ipc.send_reply(0x2000);
+
+        // Changing the interrupt enables doesn't retract either message
+        ipc.write_handler(0x04, 0);
+        ipc.write_handler(0x0c, 0);
+        assert!(ipc.state.arm_req);
+        assert!(ipc.state.ppc_req);
+        assert_eq!(ipc.read_handler(0x04), PPC_CTRL_X1 | PPC_CTRL_Y1);
+        assert_eq!(ipc.read_handler(0x0c), ARM_CTRL_X1 | ARM_CTRL_Y1);
+    }
+
+    #[test]
+    fn interrupts_need_enables() {
+        let mut ipc = IpcInterface::new();
+        ipc.send_request(0x1000);
+        ipc.send_reply(0x2000);
+        assert!(!ipc.assert_arm_irq());
+        assert!(!ipc.assert_ppc_irq());
+
+        ipc.write_handler(0x0c, ARM_CTRL_IX1);
+        assert!(ipc.assert_arm_irq());
+        ipc.write_handler(0x04, PPC_CTRL_IY2);
+        assert!(!ipc.assert_ppc_irq());
+        ipc.write_handler(0x04, PPC_CTRL_IY1);
+        assert!(ipc.assert_ppc_irq());
+    }
+
+    #[test]
+    fn full_transaction() {
+        let mut ipc = mailbox();
+        ipc.trace = true;
+
+        ipc.send_request(0x1000);
+        assert!(ipc.assert_arm_irq());
+        assert_eq!(ipc.take_request(), Some(0x1000));
+        assert_eq!(ipc.take_request(), None);
+        assert!(ipc.take_ack());
+        assert!(!ipc.take_ack());
+
+        ipc.send_reply(0x1000);
+        assert!(ipc.assert_ppc_irq());
+        assert_eq!(ipc.take_reply(), Some(0x1000));
+        assert_eq!(ipc.take_reply(), None);
+        assert!(!ipc.assert_ppc_irq());
+
+        // Taking the reply relaunches the ARM
+        assert!(ipc.state.arm_ack);
+        assert_eq!(ipc.messages, vec![IpcMessage::Request(0x1000), IpcMessage::Reply(0x1000)]);
+    }
+}
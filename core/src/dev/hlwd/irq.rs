@@ -1,4 +1,6 @@
 
+/// Hollywood IRQ sources. Every source bit which has a known device is
+/// here; the rest are in [UNKNOWN_IRQ_BITS].
 #[derive(Debug, Copy, Clone)]
 #[repr(u32)]
 pub enum HollywoodIrq {
@@ -12,18 +14,28 @@ pub enum HollywoodIrq {
     Ohci1   = 0x0000_0040,
     Sdhc    = 0x0000_0080,
 
+    /// The second SDHC controller, which the WLAN module is attached to.
     Wifi    = 0x0000_0100,
 
+    /// GPIO pins owned by Broadway (sometimes called "GPIO-B").
     PpcGpio = 0x0000_0400,
+    /// GPIO pins owned by the ARM.
     ArmGpio = 0x0000_0800,
 
     RstBtn  = 0x0002_0000,
+    /// The drive interface. There's only one DI source bit.
     Di      = 0x0004_0000,
 
+    /// The IPC mailbox, as seen by Broadway.
     PpcIpc  = 0x4000_0000,
+    /// The IPC mailbox, as seen by the ARM.
     ArmIpc  = 0x8000_0000,
 }
 
+/// IRQ source bits with no known device (9, 12-16 and 19-29). These can
+/// still be enabled and asserted with [IrqInterface::assert_bits].
+pub const UNKNOWN_IRQ_BITS: u32 = 0x3ff8_0000 | 0x0001_f000 | 0x0000_0200;
+
 #[derive(Debug, Default, Clone)]
 #[repr(transparent)]
 pub struct IrqBits(pub u32);
@@ -60,10 +72,36 @@ impl IrqBits {
     pub fn armipc(&self) -> bool    { (self.0 & 0x8000_0000) != 0 }
 }
 
+/// Returns the name of some IRQ source bit, or None for the bits in
+/// [UNKNOWN_IRQ_BITS].
+pub fn irq_name(bit: u32) -> Option<&'static str> {
+    Some(match bit {
+        0  => "timer",
+        1  => "nand",
+        2  => "aes",
+        3  => "sha",
+        4  => "ehci",
+        5  => "ohci0",
+        6  => "ohci1",
+        7  => "sdhc",
+        8  => "wifi",
+        10 => "ppcgpio",
+        11 => "armgpio",
+        17 => "rstbtn",
+        18 => "di",
+        30 => "ppcipc",
+        31 => "armipc",
+        _ => return None,
+    })
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct IrqInterface {
     /// Output IRQ line to the ARM side; set true when any IRQ is asserted
     pub arm_irq_output: bool,
+    /// Output FIQ line to the ARM side; set true when any IRQ routed to the
+    /// FIQ is asserted.
+    pub arm_fiq_output: bool,
     /// Output IRQ line to the PPC side; set true when any IRQ is asserted.
     pub ppc_irq_output: bool,
 
@@ -79,14 +117,21 @@ impl IrqInterface {
 
     pub fn read_handler(&self, off: usize) -> u32 {
         match off {
+            0x00 => self.ppc_irq_status.0,
+            0x04 => self.ppc_irq_enable.0,
             0x08 => self.arm_irq_status.0,
             0x0c => self.arm_irq_enable.0,
+            0x10 => self.arm_fiq_enable.0,
             _ => panic!("Unhandled read on HLWD IRQ interface {:02x}", off),
         }
     }
 
     pub fn write_handler(&mut self, off: usize, val: u32) {
         match off {
+            0x00 => {
+                self.ppc_irq_status.0 &= !val;
+            },
+
             0x04 => {
                 self.ppc_irq_enable.0 = val;
                 println!("IRQ PPC enable={:08x}", val);
@@ -105,6 +150,7 @@ impl IrqInterface {
 
             0x10 => {
                 self.arm_fiq_enable.0 = val;
+                println!("IRQ ARM FIQ enable={:08x}", val);
             },
             _ => panic!("Unhandled write {:08x} on HLWD IRQ interface {:02x}", 
                 val, off),
@@ -116,37 +162,136 @@ impl IrqInterface {
 impl IrqInterface {
     /// Update the state of the output IRQ signal to both CPUs.
     fn update_irq_lines(&mut self) {
-        if (self.arm_irq_status.0 & self.arm_irq_enable.0) == 0 {
-            self.arm_irq_output = false;
-        } else {
-            self.arm_irq_output = true;
-        }
-
-        if (self.ppc_irq_status.0 & self.ppc_irq_enable.0) == 0 {
-            self.ppc_irq_output = false;
-        } else {
-            self.ppc_irq_output = true;
-        }
+        self.arm_irq_output = (self.arm_irq_status.0 & self.arm_irq_enable.0) != 0;
+        self.arm_fiq_output = (self.arm_irq_status.0 & self.arm_fiq_enable.0) != 0;
+        self.ppc_irq_output = (self.ppc_irq_status.0 & self.ppc_irq_enable.0) != 0;
     }
 
     /// Returns true if the given IRQ is asserted on the ARM-side.
     pub fn arm_irq_pending(&self, irq: HollywoodIrq) -> bool {
-        (self.arm_irq_status.0 & self.arm_irq_enable.0 == 0) &&
-            (self.arm_irq_status.0 & irq as u32) == 0
+        (self.arm_irq_status.0 & self.arm_irq_enable.0 & irq as u32) != 0
+    }
+
+    /// Returns true if the given IRQ is asserted on the ARM-side FIQ.
+    pub fn arm_fiq_pending(&self, irq: HollywoodIrq) -> bool {
+        (self.arm_irq_status.0 & self.arm_fiq_enable.0 & irq as u32) != 0
     }
 
     /// Returns true if the given IRQ is asserted on the PPC-side.
     pub fn ppc_irq_pending(&self, irq: HollywoodIrq) -> bool {
-        (self.ppc_irq_status.0 & self.ppc_irq_enable.0 == 0) &&
-            (self.ppc_irq_status.0 & irq as u32) == 0
+        (self.ppc_irq_status.0 & self.ppc_irq_enable.0 & irq as u32) != 0
     }
 
     /// Assert a Hollywood IRQ.
     pub fn assert(&mut self, irq: HollywoodIrq) {
-        if self.arm_irq_enable.is_set(irq) { self.arm_irq_status.set(irq); }
-        if self.ppc_irq_enable.is_set(irq) { self.ppc_irq_status.set(irq); }
+        self.assert_bits(irq as u32);
+    }
+
+    /// Assert some set of IRQ source bits. A bit is only latched on the side
+    /// which has it enabled (on the ARM, either as an IRQ or FIQ).
+    pub fn assert_bits(&mut self, bits: u32) {
+        self.arm_irq_status.0 |= bits & (self.arm_irq_enable.0 | self.arm_fiq_enable.0);
+        self.ppc_irq_status.0 |= bits & self.ppc_irq_enable.0;
+        self.update_irq_lines();
+    }
+
+    /// Clear a Hollywood IRQ on the PPC-side, like the PPC would by writing
+    /// to the status register.
+    pub fn clear_ppc(&mut self, irq: HollywoodIrq) {
+        self.ppc_irq_status.unset(irq);
         self.update_irq_lines();
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_irq_is_not_latched() {
+        let mut irq = IrqInterface::default();
+        irq.assert(HollywoodIrq::Nand);
+        assert_eq!(irq.arm_irq_status.0, 0);
+        assert!(!irq.arm_irq_output);
+        assert!(!irq.ppc_irq_output);
+    }
+
+    #[test]
+    fn irq_and_fiq_routing() {
+        let mut irq = IrqInterface::default();
+        irq.write_handler(0x0c, HollywoodIrq::Timer as u32);
+        irq.write_handler(0x10, HollywoodIrq::Aes as u32);
+
+        irq.assert(HollywoodIrq::Timer);
+        assert!(irq.arm_irq_output);
+        assert!(!irq.arm_fiq_output);
+        assert!(irq.arm_irq_pending(HollywoodIrq::Timer));
+
+        irq.assert(HollywoodIrq::Aes);
+        assert!(irq.arm_fiq_output);
+        assert!(irq.arm_fiq_pending(HollywoodIrq::Aes));
+        assert!(!irq.arm_irq_pending(HollywoodIrq::Aes));
+
+        // Status bits are write-1-to-clear
+        irq.write_handler(0x08, HollywoodIrq::Timer as u32);
+        assert!(!irq.arm_irq_output);
+        assert!(irq.arm_fiq_output);
+        irq.write_handler(0x08, HollywoodIrq::Aes as u32);
+        assert!(!irq.arm_fiq_output);
+        assert_eq!(irq.read_handler(0x08), 0);
+    }
+
+    #[test]
+    fn masking_latched_irq() {
+        let mut irq = IrqInterface::default();
+        irq.write_handler(0x0c, HollywoodIrq::Sha as u32);
+        irq.assert(HollywoodIrq::Sha);
+        assert!(irq.arm_irq_output);
+
+        // Masking the IRQ drops the output, but the status stays latched
+        irq.write_handler(0x0c, 0);
+        assert!(!irq.arm_irq_output);
+        assert_eq!(irq.read_handler(0x08), HollywoodIrq::Sha as u32);
+        irq.write_handler(0x0c, HollywoodIrq::Sha as u32);
+        assert!(irq.arm_irq_output);
+    }
+
+    #[test]
+    fn ppc_side() {
+        let mut irq = IrqInterface::default();
+        irq.write_handler(0x04, HollywoodIrq::PpcIpc as u32);
+        irq.assert(HollywoodIrq::PpcIpc);
+        assert!(irq.ppc_irq_output);
+        assert!(!irq.arm_irq_output);
+        assert!(irq.ppc_irq_pending(HollywoodIrq::PpcIpc));
+        assert_eq!(irq.read_handler(0x00), HollywoodIrq::PpcIpc as u32);
 
+        irq.write_handler(0x00, HollywoodIrq::PpcIpc as u32);
+        assert!(!irq.ppc_irq_output);
+
+        irq.assert(HollywoodIrq::PpcIpc);
+        irq.clear_ppc(HollywoodIrq::PpcIpc);
+        assert!(!irq.ppc_irq_output);
+    }
+
+    #[test]
+    fn raw_source_bits() {
+        let mut irq = IrqInterface::default();
+        irq.write_handler(0x0c, 0x0000_0200);
+        irq.assert_bits(0x0000_0201);
+        assert_eq!(irq.read_handler(0x08), 0x0000_0200);
+        assert!(irq.arm_irq_output);
+        assert_eq!(irq_name(9), None);
+        assert_eq!(irq_name(31), Some("armipc"));
+    }
+
+    #[test]
+    fn every_source_bit_is_named_or_unknown() {
+        for bit in 0..32 {
+            let unknown = (UNKNOWN_IRQ_BITS & (1 << bit)) != 0;
+            assert_eq!(irq_name(bit).is_none(), unknown, "bit {}", bit);
+        }
+        assert_eq!(irq_name(10), Some("ppcgpio"));
+        assert_eq!(irq_name(18), Some("di"));
+    }
+}
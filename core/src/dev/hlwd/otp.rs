@@ -1,37 +1,43 @@
 
-use std::io::Read;
-use std::fs::File;
-use crate::bus::prim::AccessWidth;
+use crate::mem::storage::*;
 
 /// One-time programmable memory device/interface.
 pub struct OtpInterface {
     /// Bits fused to the device.
-    data: [u8; 0x80],
+    data: BackedMemory,
     /// Command register.
     pub cmd: u32,
     /// Command output register.
     pub out: u32,
 }
 impl OtpInterface {
-    pub fn new() -> Self {
-        let mut f = File::open("otp.bin")
-            .expect("Couldn't initialize OTP memory");
-        let mut otp = OtpInterface { data: [0; 0x80], cmd: 0, out: 0 };
-        f.read(&mut otp.data).unwrap();
-        otp
+    pub fn new(mode: StorageMode) -> Self {
+        OtpInterface {
+            data: BackedMemory::open("otp.bin", 0x80, 4, mode),
+            cmd: 0,
+            out: 0,
+        }
+    }
+
+    /// Persist any changes to OTP memory.
+    pub fn flush(&mut self) {
+        self.data.flush();
     }
 }
 
 impl OtpInterface {
     /// Read a word from OTP memory.
     fn read(&self, word_idx: usize) -> u32 {
-        let off = word_idx * 4;
-        assert!(off + 4 <= self.data.len());
-        let res = AccessWidth::from_be_bytes(&self.data[off..off+4]);
+        let res = self.data.read::<u32>(word_idx * 4);
         //println!("OTP read {:08x} @ idx={:x}", res, word_idx);
         res
     }
 
+    /// Read some bytes from OTP memory.
+    pub fn read_bytes(&self, off: usize, dst: &mut [u8]) {
+        self.data.read_buf(off, dst);
+    }
+
     /// Handle a command request.
     pub fn write_handler(&mut self, cmd: u32) {
         if cmd & 0x8000_0000 == 0 {
@@ -0,0 +1,54 @@
+use crate::dev::hlwd::*;
+
+/// The state of the GPIO pins and interrupt lines at some bus cycle.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct HlwdSample {
+    pub arm_gpio_output: u32,
+    pub arm_gpio_dir: u32,
+    pub arm_gpio_input: u32,
+    pub ppc_gpio_output: u32,
+    pub ppc_gpio_dir: u32,
+    pub ppc_gpio_input: u32,
+
+    pub arm_irq_status: u32,
+    pub arm_irq_enable: u32,
+    pub arm_fiq_enable: u32,
+    pub ppc_irq_status: u32,
+    pub ppc_irq_enable: u32,
+    pub arm_irq_output: bool,
+    pub arm_fiq_output: bool,
+    pub ppc_irq_output: bool,
+
+    /// True once Broadway has been released from reset.
+    pub ppc_on: bool,
+}
+impl HlwdSample {
+    pub fn capture(hlwd: &Hollywood) -> Self {
+        HlwdSample {
+            arm_gpio_output: hlwd.gpio.arm.read_handler(0x04),
+            arm_gpio_dir: hlwd.gpio.arm.read_handler(0x08),
+            arm_gpio_input: hlwd.gpio.arm.read_handler(0x0c),
+            ppc_gpio_output: hlwd.gpio.ppc.read_handler(0x00),
+            ppc_gpio_dir: hlwd.gpio.ppc.read_handler(0x04),
+            ppc_gpio_input: hlwd.gpio.ppc.read_handler(0x08),
+
+            arm_irq_status: hlwd.irq.arm_irq_status.0,
+            arm_irq_enable: hlwd.irq.arm_irq_enable.0,
+            arm_fiq_enable: hlwd.irq.arm_fiq_enable.0,
+            ppc_irq_status: hlwd.irq.ppc_irq_status.0,
+            ppc_irq_enable: hlwd.irq.ppc_irq_enable.0,
+            arm_irq_output: hlwd.irq.arm_irq_output,
+            arm_fiq_output: hlwd.irq.arm_fiq_output,
+            ppc_irq_output: hlwd.irq.ppc_irq_output,
+
+            ppc_on: hlwd.ppc_on,
+        }
+    }
+}
+
+/// Something which samples the state of Hollywood on every bus step.
+pub trait HlwdTracer: Send + Sync {
+    fn sample(&mut self, cycle: usize, sample: &HlwdSample);
+    /// Called when emulation stops, and periodically while it's running.
+    fn finish(&mut self) {}
+}
@@ -3,9 +3,14 @@ extern crate pretty_hex;
 use pretty_hex::*;
 
 pub mod util;
+pub mod fault;
+pub mod sffs;
 use crate::dev::nand::util::*;
+use crate::dev::nand::fault::*;
 
-use crate::mem::*;
+use std::collections::BTreeSet;
+
+use crate::mem::storage::*;
 use crate::bus::*;
 use crate::bus::prim::*;
 use crate::bus::mmio::*;
@@ -15,18 +20,52 @@ use crate::dev::hlwd::irq::*;
 /// The length of each page in the NAND flash, in bytes.
 const NAND_PAGE_LEN: usize = 0x0000_0840;
 
+/// The number of pages in each block of the NAND flash.
+const NAND_PAGES_PER_BLOCK: usize = 64;
+
 /// The length of each block in the NAND flash, in bytes.
-const NAND_BLOCK_LEN: usize = NAND_PAGE_LEN * 64;
+const NAND_BLOCK_LEN: usize = NAND_PAGE_LEN * NAND_PAGES_PER_BLOCK;
 
 /// The number of pages in the NAND flash.
 const NUM_NAND_PAGES: usize = 0x0040_000;
 
+/// The number of blocks in the NAND flash.
+const NUM_NAND_BLOCKS: usize = NUM_NAND_PAGES / NAND_PAGES_PER_BLOCK;
+
+/// The offset of the spare area in each page.
+const NAND_SPARE_OFF: usize = 0x800;
+
 /// The total length of the NAND flash, in bytes.
 const NAND_SIZE: usize = NAND_PAGE_LEN * NUM_NAND_PAGES;
 
 /// NAND device ID.
 const NAND_ID: [u8; 4] = [ 0xad, 0xdc, 0x80, 0x95 ]; // HY27UF084G2M
 
+// Approximate timing parameters for the NAND flash, in bus cycles.
+
+/// Latency for latching a command/address.
+const NAND_T_CMD: usize = CYCLES_PER_US / 4;
+/// Latency for transferring a single byte to/from the page register.
+const NAND_T_RC: usize = 6;
+/// Latency for loading a page into the page register.
+const NAND_T_R: usize = 25 * CYCLES_PER_US;
+/// Latency for programming a page.
+const NAND_T_PROG: usize = 200 * CYCLES_PER_US;
+/// Latency for erasing a block.
+const NAND_T_BERS: usize = 2000 * CYCLES_PER_US;
+/// Latency for a device reset.
+const NAND_T_RST: usize = 5 * CYCLES_PER_US;
+
+/// Set by the interface in the control register when a command fails.
+const NAND_CTRL_ERR: u32 = 0x2000_0000;
+
+/// Status register bit indicating that the last program/erase failed.
+const NAND_STATUS_FAIL: u8  = 0x01;
+/// Status register bits indicating that the device is ready.
+const NAND_STATUS_READY: u8 = 0x60;
+/// Status register bit indicating that the device is not write-protected.
+const NAND_STATUS_NWP: u8   = 0x80;
+
 
 /// NAND command opcodes.
 #[derive(Debug)]
@@ -59,15 +98,18 @@ pub enum NandOpcd {
 pub struct NandCmd {
     /// Set when an IRQ should be asserted on command completion.
     pub irq: bool,
+    /// Error flag (set by the interface when a command fails).
     pub err: bool,
     pub addr: u32,
     /// The type of command.
     pub opcd: NandOpcd,
+    /// Wait for the flash to become ready before completing the command.
     pub wait: bool,
     /// Write flag.
     pub wr: bool,
     /// Read flag.
     pub rd: bool,
+    /// Compute ECC bytes for the data being transferred.
     pub ecc: bool,
     /// Length of the associated read or write.
     pub len: u32,
@@ -98,6 +140,28 @@ impl NandCmd {
         let len  =  x & 0x0000_0fff;
         NandCmd { irq, err, addr, opcd, wait, wr, rd, ecc, len }
     }
+
+    /// The number of bus cycles the flash is busy after this command.
+    pub fn busy_time(&self) -> usize {
+        use NandOpcd::*;
+        match self.opcd {
+            Read    => NAND_T_R,
+            Program => NAND_T_PROG,
+            Erase   => NAND_T_BERS,
+            Reset   => NAND_T_RST,
+            _ => 0,
+        }
+    }
+
+    /// The number of bus cycles it takes for this command to complete.
+    ///
+    /// Unless the wait flag is set, the interface doesn't wait for the flash
+    /// to become ready before completing the command.
+    pub fn latency(&self) -> usize {
+        let xfer = self.len as usize * NAND_T_RC;
+        let busy = if self.wait { self.busy_time() } else { 0 };
+        NAND_T_CMD + busy + xfer
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -126,13 +190,22 @@ pub struct NandRegisters {
 /// Representing the state of the NAND interface.
 pub struct NandInterface {
     /// Actual backing data for the NAND flash.
-    pub data: Box<BigEndianMemory>,
+    pub data: Box<BackedMemory>,
     /// Set of registers associated with this interface.
     pub reg: NandRegisters,
+
+    /// Status register bits reported by the flash.
+    pub status: u8,
+    /// The bus cycle on which the flash will become ready.
+    pub busy_until: usize,
+    /// The set of bad blocks.
+    pub bad_blocks: BTreeSet<usize>,
+    /// Faults injected into the flash.
+    pub faults: NandFaults,
 }
 impl NandInterface {
     /// Create a new instance of the NAND interface.
-    pub fn new(filename: &str) -> Self {
+    pub fn new(filename: &str, mode: StorageMode) -> Self {
         let reg = NandRegisters {
             ctrl: 0,
             cfg: 0,
@@ -145,11 +218,68 @@ impl NandInterface {
             current_page: 0,
             current_poff: 0,
         };
-        NandInterface {
-            data: Box::new(BigEndianMemory::new(NAND_SIZE, Some(filename))),
+        let mut res = NandInterface {
+            data: Box::new(BackedMemory::open(filename, NAND_SIZE, NAND_PAGE_LEN, mode)),
             reg,
+            status: 0,
+            busy_until: 0,
+            bad_blocks: BTreeSet::new(),
+            faults: NandFaults::default(),
+        };
+        res.scan_bad_blocks();
+        res
+    }
+
+    /// Build the bad block table from the factory markers in the spare area
+    /// of the first two pages in each block.
+    fn scan_bad_blocks(&mut self) {
+        for block in 0..NUM_NAND_BLOCKS {
+            let off = block * NAND_BLOCK_LEN + NAND_SPARE_OFF;
+            let m0 = self.data.read::<u8>(off);
+            let m1 = self.data.read::<u8>(off + NAND_PAGE_LEN);
+            if m0 != 0xff || m1 != 0xff {
+                println!("NND block {:x} is marked as bad", block);
+                self.bad_blocks.insert(block);
+            }
         }
     }
+
+    /// Returns true if the given block is in the bad block table.
+    pub fn is_bad_block(&self, block: usize) -> bool {
+        self.bad_blocks.contains(&block)
+    }
+
+    /// Add a block to the bad block table.
+    pub fn mark_bad_block(&mut self, block: usize) {
+        assert!(block < NUM_NAND_BLOCKS);
+        self.bad_blocks.insert(block);
+    }
+
+    /// Inject a set of faults into the flash.
+    pub fn set_faults(&mut self, faults: NandFaults) {
+        for block in faults.bad_blocks.iter() {
+            self.mark_bad_block(*block);
+        }
+        self.faults = faults;
+    }
+
+    /// Returns the value of the status register on some bus cycle.
+    pub fn status_reg(&self, cycle: usize) -> u8 {
+        let ready = if cycle >= self.busy_until { NAND_STATUS_READY } else { 0 };
+        self.status | ready | NAND_STATUS_NWP
+    }
+
+    /// Returns true if programming the given page should succeed.
+    fn can_program(&self, page: usize) -> bool {
+        !self.is_bad_block(page / NAND_PAGES_PER_BLOCK) &&
+            !self.faults.program_fail.contains(&page)
+    }
+
+    /// Returns true if erasing the given block should succeed.
+    fn can_erase(&self, block: usize) -> bool {
+        !self.is_bad_block(block) && !self.faults.erase_fail.contains(&block)
+    }
+
     /// Read data from the specified offset in the NAND flash into some buffer
     pub fn read_data(&self, off: usize, dst: &mut [u8]) {
         self.data.read_buf(off, dst);
@@ -199,8 +329,8 @@ impl MmioDevice for NandInterface {
     type Width = u32;
     fn read(&mut self, off: usize) -> BusPacket {
         let val = match off { 
-            //0x00 => self.reg.ctrl,
-            0x00 => 0x0000_0001,
+            // The busy bit is held until the command has completed
+            0x00 => (self.reg.ctrl & (0x8000_0000 | NAND_CTRL_ERR)) | 0x0000_0001,
             0x04 => self.reg.cfg,
             0x08 => self.reg.addr1,
             0x0c => self.reg.addr2,
@@ -220,7 +350,7 @@ impl MmioDevice for NandInterface {
             0x00 => {
                 // When this bit is set, emit command to NAND flash
                 if val & 0x8000_0000 != 0 {
-                    self.reg.ctrl = val;
+                    self.reg.ctrl = val & !NAND_CTRL_ERR;
                     self.send_addr(val);
                     return Some(BusTask::Nand(val));
                 } 
@@ -245,12 +375,17 @@ impl Bus {
         self.nand.reg
     }
 
-    fn nand_erase_page(&mut self, cmd: &NandCmd, reg: &NandRegisters) {
+    /// Erase a NAND block, returning true on success.
+    fn nand_erase_page(&mut self, cmd: &NandCmd, reg: &NandRegisters) -> bool {
         assert_ne!(cmd.ecc, true);
         assert_ne!(cmd.rd, true);
-        let off = reg.addr2 as usize * NAND_PAGE_LEN;
-        self.nand.clear_data(off, NAND_BLOCK_LEN);
-        //panic!("nand erase unimpl");
+        let block = reg.addr2 as usize / NAND_PAGES_PER_BLOCK;
+        if !self.nand.can_erase(block) {
+            println!("NND erase failed on block {:x}", block);
+            return false;
+        }
+        self.nand.clear_data(block * NAND_BLOCK_LEN, NAND_BLOCK_LEN);
+        true
     }
 
     /// Perform a NAND read into memory
@@ -258,22 +393,27 @@ impl Bus {
         // Read the source data from the NAND
         let mut local_buf = vec![0; cmd.len as usize];
 
-        let off = reg.addr2 as usize * NAND_PAGE_LEN;
-        self.nand.read_data(off, &mut local_buf);
+        let page = reg.addr2 as usize;
+        self.nand.read_data(page * NAND_PAGE_LEN, &mut local_buf);
+        self.nand.faults.apply_bitflips(page, &mut local_buf);
 
         //println!("{:?}", local_buf.hex_dump());
 
-        // Do the DMA writes to memory
-        self.dma_write(reg.databuf, &local_buf[..0x800]);
-        self.dma_write(reg.eccbuf, &local_buf[0x800..]);
-
-        // Compute and write the ECC bytes for the data
-        for i in 0..4 {
-            let addr = (reg.eccbuf ^ 0x40) + (i as u32 * 4);
-            let new_ecc = calc_ecc(&mut local_buf[(i * 0x200)..]);
-            let old_ecc = self.read32(addr);
-            //println!("NND old_ecc={:08x} new_ecc={:08x}", old_ecc, new_ecc);
-            self.write32(addr, new_ecc);
+        // Do the DMA writes to memory. Anything past the page data is from
+        // the spare area, and goes into the ECC buffer
+        let data_len = local_buf.len().min(NAND_SPARE_OFF);
+        self.device_dma_write(IoDevice::Nand, reg.databuf, &local_buf[..data_len]);
+        if local_buf.len() > data_len {
+            self.device_dma_write(IoDevice::Nand, reg.eccbuf, &local_buf[data_len..]);
+        }
+
+        // Compute and write the ECC bytes for each 0x200-byte subpage
+        if cmd.ecc {
+            for i in 0..(data_len / 0x200) {
+                let addr = (reg.eccbuf ^ 0x40) + (i as u32 * 4);
+                let new_ecc = calc_ecc(&mut local_buf[(i * 0x200)..]);
+                self.device_dma_write(IoDevice::Nand, addr, &new_ecc.to_be_bytes());
+            }
         }
     }
 
@@ -281,22 +421,37 @@ impl Bus {
     fn nand_write_page(&mut self, cmd: &NandCmd, reg: &NandRegisters) {
         // Read from memory
         let mut local_buf = vec![0; cmd.len as usize];
-        self.dma_read(reg.databuf, &mut local_buf);
+        self.device_dma_read(IoDevice::Nand, reg.databuf, &mut local_buf);
 
-        let off = (reg.current_page as usize * NAND_PAGE_LEN) + 
-            reg.current_poff as usize;
-        self.nand.write_data(off, &local_buf);
+        // If programming this page is going to fail, leave the flash alone
+        let page = reg.current_page as usize;
+        if self.nand.can_program(page) {
+            let off = (page * NAND_PAGE_LEN) + reg.current_poff as usize;
+            self.nand.write_data(off, &local_buf);
+        }
 
         if cmd.ecc {
             assert!(cmd.len == 0x800);
             for i in 0..4 {
                 let addr = (reg.eccbuf ^ 0x40) + (i as u32 * 4);
                 let new_ecc = calc_ecc(&mut local_buf[(i * 0x200)..]);
-                self.write32(addr, new_ecc);
+                self.device_dma_write(IoDevice::Nand, addr, &new_ecc.to_be_bytes());
             }
         }
+    }
 
-
+    /// Update the status register after a program/erase command.
+    fn nand_report_result(&mut self, cmd: &NandCmd, ok: bool) {
+        if ok {
+            self.nand.status &= !NAND_STATUS_FAIL;
+        } else {
+            self.nand.status |= NAND_STATUS_FAIL;
+            // The interface only notices the failure if it waited for the
+            // flash to become ready
+            if cmd.wait {
+                self.nand.reg.ctrl |= NAND_CTRL_ERR;
+            }
+        }
     }
 
     /// Handle a NAND command
@@ -322,12 +477,12 @@ impl Bus {
                     },
                     PrefixRead  => next_cycle = reg._cycle + 1,
                     PrefixErase => next_cycle = reg._cycle + 1,
-                    ReadId      => self.dma_write(reg.databuf, &NAND_ID),
+                    ReadId      => self.device_dma_write(IoDevice::Nand, reg.databuf, &NAND_ID),
                     ReadStatus  => {
-                        let status_register: [u8;1] = [0xe0];
-                        self.dma_write(reg.databuf, &status_register);
+                        let status_register = [self.nand.status_reg(self.cycle)];
+                        self.device_dma_write(IoDevice::Nand, reg.databuf, &status_register);
                     },
-                    Reset       => {},
+                    Reset       => self.nand.status = 0,
                     _ => panic!("NAND unknown cycle 0 opcd {:?}", cmd.opcd),
                 }
             },
@@ -337,7 +492,10 @@ impl Bus {
                     self.nand_write_page(&cmd, &reg);
                 },
                 Read    => self.nand_read_page(&cmd, &reg),
-                Erase   => self.nand_erase_page(&cmd, &reg),
+                Erase   => {
+                    let ok = self.nand_erase_page(&cmd, &reg);
+                    self.nand_report_result(&cmd, ok);
+                },
                 _ => panic!("NAND unknown cycle 1 opcd {:?}", cmd.opcd),
             },
             2 => match cmd.opcd {
@@ -345,6 +503,11 @@ impl Bus {
                 // when the Serial/Random input commands arrive, and just not
                 // do anything when we see the actual program command
                 Program => {
+                    let ok = self.nand.can_program(reg.current_page as usize);
+                    if !ok {
+                        println!("NND program failed on page {:x}", reg.current_page);
+                    }
+                    self.nand_report_result(&cmd, ok);
                 },
                 _ => panic!("NAND unknown cycle 2 opcd {:?}", cmd.opcd),
             },
@@ -365,6 +528,10 @@ impl Bus {
 
             // Mark this command as completed
             self.nand.reg.ctrl &= 0x7fff_ffff;
+            // If we didn't wait for the flash, it may still be busy
+            if !cmd.wait {
+                self.nand.busy_until = self.cycle + cmd.busy_time();
+            }
             // Increment cycle counter for NAND state machine
             self.nand.reg._cycle = next_cycle;
         }
@@ -0,0 +1,117 @@
+//! Error injection for the NAND flash.
+//!
+//! Faults are described by a simple text file, with one directive per line:
+//!
+//! ```text
+//! # Flip bit 3 of byte 0x10 whenever page 0x1000 is read
+//! flip 0x1000 0x10 0x08
+//! # Erasing block 0x100 always fails
+//! erase-fail 0x100
+//! # Programming page 0x4000 always fails
+//! program-fail 0x4000
+//! # Treat block 0x200 as a bad block
+//! bad 0x200
+//! ```
+//!
+//! Numbers may be written in decimal, or in hexadecimal with a `0x` prefix.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+use super::{NUM_NAND_PAGES, NUM_NAND_BLOCKS, NAND_PAGE_LEN};
+
+/// A set of faults injected into the NAND flash.
+#[derive(Debug, Default, Clone)]
+pub struct NandFaults {
+    /// Bits flipped when reading a page, keyed by page index. Each entry is
+    /// an offset into the page and a mask of the bits to flip.
+    pub bitflips: HashMap<usize, Vec<(usize, u8)>>,
+    /// Blocks where erase commands always fail.
+    pub erase_fail: HashSet<usize>,
+    /// Pages where program commands always fail.
+    pub program_fail: HashSet<usize>,
+    /// Blocks which are added to the bad block table.
+    pub bad_blocks: HashSet<usize>,
+}
+
+impl NandFaults {
+    /// Parse a set of faults from some file.
+    pub fn from_file(filename: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(filename)
+            .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+        Self::parse(&text)
+    }
+
+    /// Parse a set of faults from a string.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut res = NandFaults::default();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let args: Vec<usize> = fields[1..].iter()
+                .map(|f| parse_num(f))
+                .collect::<Option<Vec<usize>>>()
+                .ok_or(format!("line {}: invalid number in '{}'", idx + 1, line))?;
+
+            let check = |what: &str, val: usize, limit: usize| {
+                if val < limit {
+                    Ok(())
+                } else {
+                    Err(format!("line {}: {} {:x} is out of range (limit {:x})",
+                        idx + 1, what, val, limit))
+                }
+            };
+            match (fields[0], args.as_slice()) {
+                ("flip", [page, off, mask]) => {
+                    check("page", *page, NUM_NAND_PAGES)?;
+                    check("offset", *off, NAND_PAGE_LEN)?;
+                    if *mask > 0xff {
+                        return Err(format!("line {}: mask {:x} is wider than a byte",
+                            idx + 1, mask));
+                    }
+                    res.bitflips.entry(*page).or_default().push((*off, *mask as u8));
+                },
+                ("erase-fail", [block]) => {
+                    check("block", *block, NUM_NAND_BLOCKS)?;
+                    res.erase_fail.insert(*block);
+                },
+                ("program-fail", [page]) => {
+                    check("page", *page, NUM_NAND_PAGES)?;
+                    res.program_fail.insert(*page);
+                },
+                ("bad", [block]) => {
+                    check("block", *block, NUM_NAND_BLOCKS)?;
+                    res.bad_blocks.insert(*block);
+                },
+                _ => return Err(format!("line {}: invalid directive '{}'",
+                    idx + 1, line)),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Flip any bits associated with the given page.
+    pub fn apply_bitflips(&self, page: usize, buf: &mut [u8]) {
+        if let Some(flips) = self.bitflips.get(&page) {
+            for (off, mask) in flips {
+                if *off < buf.len() {
+                    println!("NND injecting bitflip page={:x} off={:x} mask={:02x}",
+                        page, off, mask);
+                    buf[*off] ^= mask;
+                }
+            }
+        }
+    }
+}
+
+/// Parse a decimal or hexadecimal number.
+fn parse_num(s: &str) -> Option<usize> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse::<usize>().ok()
+    }
+}
@@ -0,0 +1,602 @@
+//! Support for inspecting the filesystem (SFFS) on the NAND flash.
+//!
+//! The flash is divided into 0x8000 clusters of eight pages. The last 0x100
+//! clusters hold sixteen copies of the superblock, each of which contains a
+//! generation number, the cluster allocation table (FAT), and the file
+//! system table (FST). The copy with the highest generation is current.
+//!
+//! File data is encrypted with the NAND key (AES-128-CBC, with a zero IV for
+//! each cluster). Each cluster is authenticated by an HMAC-SHA1 over the
+//! decrypted data, which lives in the spare area of its last two pages.
+//!
+//! These functions operate on a [NandInterface], so they can be used on a
+//! live [Bus] or on a NAND image opened offline. Note that IOS keeps its own
+//! copy of the superblock in memory: injecting files while IOS is running
+//! will not be visible to IOS, and may be undone by IOS.
+
+extern crate aes;
+extern crate cbc;
+
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+use std::collections::BTreeSet;
+use std::fs;
+
+use crate::dev::nand::{NandInterface, NAND_PAGE_LEN, NAND_SPARE_OFF};
+use crate::dev::nand::{NUM_NAND_PAGES, NAND_PAGES_PER_BLOCK};
+use crate::dev::nand::util::calc_ecc;
+use crate::bus::*;
+use crate::dev::hlwd::otp::*;
+use crate::dev::sha::util::hmac_sha1;
+
+/// The number of pages in each cluster.
+const PAGES_PER_CLUSTER: usize = 8;
+/// The number of data bytes in each cluster.
+const CLUSTER_LEN: usize = NAND_SPARE_OFF * PAGES_PER_CLUSTER;
+/// The number of clusters in the NAND flash.
+const NUM_CLUSTERS: usize = NUM_NAND_PAGES / PAGES_PER_CLUSTER;
+
+/// The first cluster used to store superblocks.
+const SUPERBLOCK_BASE: usize = 0x7f00;
+/// The number of clusters in each superblock.
+const SUPERBLOCK_CLUSTERS: usize = 16;
+/// The number of superblocks.
+const NUM_SUPERBLOCKS: usize = 16;
+/// The length of each superblock, in bytes.
+const SUPERBLOCK_LEN: usize = CLUSTER_LEN * SUPERBLOCK_CLUSTERS;
+
+/// The offset of the FAT in the superblock.
+const FAT_OFF: usize = 0x0c;
+/// The offset of the FST in the superblock.
+const FST_OFF: usize = FAT_OFF + NUM_CLUSTERS * 2;
+/// The number of entries in the FST.
+const NUM_FST_ENTRIES: usize = 0x17ff;
+/// The length of each FST entry, in bytes.
+const FST_ENTRY_LEN: usize = 0x20;
+
+/// FAT entry marking the last cluster in a chain.
+pub const FAT_LAST: u16     = 0xfffb;
+/// FAT entry marking a reserved cluster.
+pub const FAT_RESERVED: u16 = 0xfffc;
+/// FAT entry marking a bad cluster.
+pub const FAT_BAD: u16      = 0xfffd;
+/// FAT entry marking a free cluster.
+pub const FAT_FREE: u16     = 0xfffe;
+
+/// Value of [FstEntry::sub] for files without any clusters.
+const FST_NONE: u16 = 0xffff;
+
+/// Keys used to decrypt and authenticate the filesystem.
+#[derive(Debug, Clone, Copy)]
+pub struct SffsKeys {
+    pub aes: [u8; 0x10],
+    pub hmac: [u8; 0x14],
+}
+impl SffsKeys {
+    /// The offset of the NAND HMAC key in OTP memory.
+    const OTP_HMAC_OFF: usize = 0x44;
+    /// The offset of the NAND AES key in OTP memory.
+    const OTP_AES_OFF: usize = 0x58;
+
+    /// Get the keys fused into OTP memory.
+    pub fn from_otp(otp: &OtpInterface) -> Self {
+        let mut res = SffsKeys { aes: [0; 0x10], hmac: [0; 0x14] };
+        otp.read_bytes(Self::OTP_AES_OFF, &mut res.aes);
+        otp.read_bytes(Self::OTP_HMAC_OFF, &mut res.hmac);
+        res
+    }
+
+    /// Get the keys from some OTP dump.
+    pub fn from_otp_file(filename: &str) -> Result<Self, String> {
+        let data = fs::read(filename)
+            .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+        if data.len() < 0x80 {
+            return Err(format!("{} is too short to be an OTP dump", filename));
+        }
+        let mut res = SffsKeys { aes: [0; 0x10], hmac: [0; 0x14] };
+        res.aes.copy_from_slice(&data[Self::OTP_AES_OFF..Self::OTP_AES_OFF + 0x10]);
+        res.hmac.copy_from_slice(&data[Self::OTP_HMAC_OFF..Self::OTP_HMAC_OFF + 0x14]);
+        Ok(res)
+    }
+}
+
+/// An entry in the file system table.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FstEntry {
+    pub name: [u8; 12],
+    /// Entry type (low 2 bits) and permissions.
+    pub mode: u8,
+    pub attr: u8,
+    /// The first child (for directories) or first cluster (for files).
+    pub sub: u16,
+    /// The next sibling in the parent directory.
+    pub sib: u16,
+    pub size: u32,
+    pub uid: u32,
+    pub gid: u16,
+    pub x3: u32,
+}
+impl FstEntry {
+    pub fn from_bytes(x: &[u8]) -> Self {
+        let mut name = [0u8; 12];
+        name.copy_from_slice(&x[0x00..0x0c]);
+        FstEntry {
+            name,
+            mode: x[0x0c],
+            attr: x[0x0d],
+            sub: u16::from_be_bytes([x[0x0e], x[0x0f]]),
+            sib: u16::from_be_bytes([x[0x10], x[0x11]]),
+            size: u32::from_be_bytes([x[0x12], x[0x13], x[0x14], x[0x15]]),
+            uid: u32::from_be_bytes([x[0x16], x[0x17], x[0x18], x[0x19]]),
+            gid: u16::from_be_bytes([x[0x1a], x[0x1b]]),
+            x3: u32::from_be_bytes([x[0x1c], x[0x1d], x[0x1e], x[0x1f]]),
+        }
+    }
+
+    pub fn to_bytes(&self, x: &mut [u8]) {
+        x[0x00..0x0c].copy_from_slice(&self.name);
+        x[0x0c] = self.mode;
+        x[0x0d] = self.attr;
+        x[0x0e..0x10].copy_from_slice(&self.sub.to_be_bytes());
+        x[0x10..0x12].copy_from_slice(&self.sib.to_be_bytes());
+        x[0x12..0x16].copy_from_slice(&self.size.to_be_bytes());
+        x[0x16..0x1a].copy_from_slice(&self.uid.to_be_bytes());
+        x[0x1a..0x1c].copy_from_slice(&self.gid.to_be_bytes());
+        x[0x1c..0x20].copy_from_slice(&self.x3.to_be_bytes());
+    }
+
+    /// Returns the name of this entry.
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(12);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+
+    pub fn is_file(&self) -> bool { self.mode & 3 == 1 }
+    pub fn is_dir(&self) -> bool { self.mode & 3 == 2 }
+}
+
+/// The state of the filesystem, read from the current superblock.
+pub struct Sffs {
+    pub keys: SffsKeys,
+    /// Index of the current superblock.
+    pub superblock: usize,
+    /// Generation number of the current superblock.
+    pub generation: u32,
+    /// Unknown superblock field, preserved when writing a new superblock.
+    unk: u32,
+    /// The cluster allocation table.
+    pub fat: Vec<u16>,
+    /// The file system table.
+    pub fst: Vec<FstEntry>,
+    /// Clusters which were freed since the last commit. The superblock on
+    /// the flash may still refer to them, so they aren't reused until the
+    /// next commit.
+    released: BTreeSet<usize>,
+}
+
+/// Functions for accessing clusters in the NAND flash.
+impl Sffs {
+    /// Read the data and spare area of each page in some cluster.
+    fn read_cluster_raw(nand: &NandInterface, cluster: usize) -> (Vec<u8>, Vec<[u8; 0x40]>) {
+        let mut data = vec![0u8; CLUSTER_LEN];
+        let mut spare = vec![[0u8; 0x40]; PAGES_PER_CLUSTER];
+        for page in 0..PAGES_PER_CLUSTER {
+            let off = (cluster * PAGES_PER_CLUSTER + page) * NAND_PAGE_LEN;
+            let dst = &mut data[page * NAND_SPARE_OFF..(page + 1) * NAND_SPARE_OFF];
+            nand.read_data(off, dst);
+            nand.read_data(off + NAND_SPARE_OFF, &mut spare[page]);
+        }
+        (data, spare)
+    }
+
+    /// Write the data for some cluster, building the spare area for each
+    /// page (including the ECC bytes and the HMAC).
+    fn write_cluster_raw(nand: &mut NandInterface, cluster: usize, data: &[u8],
+        hmac: &[u8; 0x14])
+    {
+        assert!(data.len() == CLUSTER_LEN);
+        for page in 0..PAGES_PER_CLUSTER {
+            let mut buf = [0u8; NAND_PAGE_LEN];
+            buf[..NAND_SPARE_OFF].copy_from_slice(
+                &data[page * NAND_SPARE_OFF..(page + 1) * NAND_SPARE_OFF]);
+
+            let spare = &mut buf[NAND_SPARE_OFF..];
+            spare[0] = 0xff;
+            match page {
+                6 => {
+                    spare[0x01..0x15].copy_from_slice(hmac);
+                    spare[0x15..0x21].copy_from_slice(&hmac[..0x0c]);
+                },
+                7 => spare[0x01..0x09].copy_from_slice(&hmac[0x0c..]),
+                _ => {},
+            }
+            for i in 0..4 {
+                let mut subpage = [0u8; 0x200];
+                subpage.copy_from_slice(&buf[i * 0x200..(i + 1) * 0x200]);
+                let ecc = calc_ecc(&mut subpage);
+                buf[NAND_SPARE_OFF + 0x30 + i * 4..NAND_SPARE_OFF + 0x34 + i * 4]
+                    .copy_from_slice(&ecc.to_be_bytes());
+            }
+
+            let off = (cluster * PAGES_PER_CLUSTER + page) * NAND_PAGE_LEN;
+            nand.write_data(off, &buf);
+        }
+    }
+
+    /// Get the HMAC stored in the spare area of some cluster.
+    fn stored_hmac(spare: &[[u8; 0x40]]) -> [u8; 0x14] {
+        let mut res = [0u8; 0x14];
+        res.copy_from_slice(&spare[6][0x01..0x15]);
+        res
+    }
+
+    /// Compute the HMAC for some superblock.
+    fn superblock_hmac(&self, cluster: usize, data: &[u8]) -> [u8; 0x14] {
+        let mut msg = vec![0u8; 0x40];
+        msg[0x12..0x14].copy_from_slice(&(cluster as u16).to_be_bytes());
+        msg.extend_from_slice(data);
+        hmac_sha1(&self.keys.hmac, &msg)
+    }
+
+    /// Compute the HMAC for some cluster of (decrypted) file data.
+    fn data_hmac(&self, entry: usize, chain_idx: usize, data: &[u8]) -> [u8; 0x14] {
+        let e = &self.fst[entry];
+        let mut msg = vec![0u8; 0x40];
+        msg[0x00..0x04].copy_from_slice(&e.uid.to_be_bytes());
+        msg[0x04..0x10].copy_from_slice(&e.name);
+        msg[0x10..0x14].copy_from_slice(&(entry as u32).to_be_bytes());
+        msg[0x14..0x18].copy_from_slice(&e.x3.to_be_bytes());
+        msg[0x18..0x1c].copy_from_slice(&(chain_idx as u32).to_be_bytes());
+        msg.extend_from_slice(data);
+        hmac_sha1(&self.keys.hmac, &msg)
+    }
+}
+
+/// Reading and writing superblocks.
+impl Sffs {
+    /// Load the filesystem from the most recent valid superblock.
+    pub fn load(nand: &NandInterface, keys: SffsKeys) -> Result<Self, String> {
+        let mut res = Sffs {
+            keys,
+            superblock: 0,
+            generation: 0,
+            unk: 0,
+            fat: Vec::new(),
+            fst: Vec::new(),
+            released: BTreeSet::new(),
+        };
+
+        // Find the superblocks, newest first
+        let mut candidates = Vec::new();
+        for idx in 0..NUM_SUPERBLOCKS {
+            let cluster = SUPERBLOCK_BASE + idx * SUPERBLOCK_CLUSTERS;
+            let mut hdr = [0u8; 8];
+            nand.read_data(cluster * PAGES_PER_CLUSTER * NAND_PAGE_LEN, &mut hdr);
+            if &hdr[0..4] == b"SFFS" {
+                let generation = u32::from_be_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]);
+                candidates.push((generation, idx));
+            }
+        }
+        candidates.sort_by(|a, b| b.cmp(a));
+
+        for (generation, idx) in candidates {
+            let base = SUPERBLOCK_BASE + idx * SUPERBLOCK_CLUSTERS;
+            let mut data = Vec::with_capacity(SUPERBLOCK_LEN);
+            let mut last_spare = Vec::new();
+            for cluster in base..base + SUPERBLOCK_CLUSTERS {
+                let (buf, spare) = Self::read_cluster_raw(nand, cluster);
+                data.extend_from_slice(&buf);
+                last_spare = spare;
+            }
+
+            if res.superblock_hmac(base, &data) != Self::stored_hmac(&last_spare) {
+                println!("SFFS superblock {} (gen {:x}) has a bad HMAC", idx, generation);
+                continue;
+            }
+
+            res.superblock = idx;
+            res.generation = generation;
+            res.unk = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+            res.fat = data[FAT_OFF..FST_OFF].chunks(2)
+                .map(|x| u16::from_be_bytes([x[0], x[1]]))
+                .collect();
+            res.fst = data[FST_OFF..FST_OFF + NUM_FST_ENTRIES * FST_ENTRY_LEN]
+                .chunks(FST_ENTRY_LEN)
+                .map(FstEntry::from_bytes)
+                .collect();
+            return Ok(res);
+        }
+        Err("Couldn't find a valid superblock".to_string())
+    }
+
+    /// Write the current state of the filesystem to the next superblock.
+    pub fn commit(&mut self, nand: &mut NandInterface) {
+        let mut data = vec![0u8; SUPERBLOCK_LEN];
+        data[0..4].copy_from_slice(b"SFFS");
+        data[4..8].copy_from_slice(&(self.generation + 1).to_be_bytes());
+        data[8..12].copy_from_slice(&self.unk.to_be_bytes());
+        for (idx, ent) in self.fat.iter().enumerate() {
+            data[FAT_OFF + idx * 2..FAT_OFF + idx * 2 + 2]
+                .copy_from_slice(&ent.to_be_bytes());
+        }
+        for (idx, ent) in self.fst.iter().enumerate() {
+            ent.to_bytes(&mut data[FST_OFF + idx * FST_ENTRY_LEN..]);
+        }
+
+        let idx = (self.superblock + 1) % NUM_SUPERBLOCKS;
+        let base = SUPERBLOCK_BASE + idx * SUPERBLOCK_CLUSTERS;
+        let hmac = self.superblock_hmac(base, &data);
+        for (i, buf) in data.chunks(CLUSTER_LEN).enumerate() {
+            // Only the last cluster carries the HMAC for the superblock
+            let cluster_hmac = if i == SUPERBLOCK_CLUSTERS - 1 { hmac } else { [0; 0x14] };
+            Self::write_cluster_raw(nand, base + i, buf, &cluster_hmac);
+        }
+
+        self.superblock = idx;
+        self.generation += 1;
+        self.released.clear();
+        println!("SFFS wrote superblock {} (gen {:x})", idx, self.generation);
+    }
+}
+
+/// Looking up and listing files.
+impl Sffs {
+    /// Find the FST entry for some absolute path. Fails if the FST has a
+    /// loop in it.
+    pub fn lookup(&self, path: &str) -> Result<Option<usize>, String> {
+        let mut cur = 0;
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            cur = match self.find_child(cur, name)? {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(cur))
+    }
+
+    /// Find a child of some directory by name.
+    fn find_child(&self, dir: usize, name: &str) -> Result<Option<usize>, String> {
+        if !self.fst[dir].is_dir() {
+            return Ok(None);
+        }
+        // A directory can't have more children than there are entries
+        let mut cur = self.fst[dir].sub as usize;
+        for _ in 0..self.fst.len() {
+            if cur >= self.fst.len() {
+                return Ok(None);
+            }
+            if self.fst[cur].name() == name {
+                return Ok(Some(cur));
+            }
+            cur = self.fst[cur].sib as usize;
+        }
+        Err(format!("Loop in the FST below entry {:x}", dir))
+    }
+
+    /// Returns the path and FST entry index of everything below some
+    /// directory.
+    pub fn list(&self, path: &str) -> Result<Vec<(String, usize)>, String> {
+        let dir = self.lookup(path)?.ok_or(format!("{} not found", path))?;
+        let mut res = Vec::new();
+        let prefix = path.trim_end_matches('/').to_string();
+        if self.fst[dir].is_dir() {
+            let mut visited = vec![false; self.fst.len()];
+            visited[dir] = true;
+            self.list_dir(dir, &prefix, &mut visited, &mut res)?;
+        } else {
+            res.push((prefix, dir));
+        }
+        Ok(res)
+    }
+
+    /// Add everything below some directory to `res`. Each entry can only be
+    /// visited once, so that loops in the FST are caught.
+    fn list_dir(&self, dir: usize, prefix: &str, visited: &mut [bool],
+        res: &mut Vec<(String, usize)>) -> Result<(), String>
+    {
+        let mut cur = self.fst[dir].sub as usize;
+        while cur < self.fst.len() {
+            if std::mem::replace(&mut visited[cur], true) {
+                return Err(format!("Loop in the FST at entry {:x}", cur));
+            }
+            let path = format!("{}/{}", prefix, self.fst[cur].name());
+            res.push((path.clone(), cur));
+            if self.fst[cur].is_dir() {
+                self.list_dir(cur, &path, visited, res)?;
+            }
+            cur = self.fst[cur].sib as usize;
+        }
+        Ok(())
+    }
+
+    /// Returns the chain of clusters for some file.
+    fn chain(&self, entry: usize) -> Result<Vec<usize>, String> {
+        let mut res = Vec::new();
+        let mut cur = self.fst[entry].sub;
+        while cur != FAT_LAST && cur != FST_NONE {
+            if cur as usize >= NUM_CLUSTERS || res.len() > NUM_CLUSTERS {
+                return Err(format!("Bad cluster chain for entry {:x}", entry));
+            }
+            res.push(cur as usize);
+            cur = self.fat[cur as usize];
+        }
+        Ok(res)
+    }
+}
+
+/// Reading and writing files.
+impl Sffs {
+    /// Read and decrypt the contents of some file.
+    pub fn read_file(&self, nand: &NandInterface, path: &str) -> Result<Vec<u8>, String> {
+        let entry = self.lookup(path)?.ok_or(format!("{} not found", path))?;
+        if !self.fst[entry].is_file() {
+            return Err(format!("{} is not a file", path));
+        }
+
+        let mut res = Vec::new();
+        for (chain_idx, cluster) in self.chain(entry)?.iter().enumerate() {
+            let (mut data, spare) = Self::read_cluster_raw(nand, *cluster);
+            Aes128CbcDec::new_from_slices(&self.keys.aes, &[0; 0x10]).unwrap()
+                .decrypt_padded_mut::<NoPadding>(&mut data).unwrap();
+            if self.data_hmac(entry, chain_idx, &data) != Self::stored_hmac(&spare) {
+                return Err(format!("{}: bad HMAC on cluster {:x}", path, cluster));
+            }
+            res.extend_from_slice(&data);
+        }
+        res.truncate(self.fst[entry].size as usize);
+        Ok(res)
+    }
+
+    /// Replace the contents of some file, creating it if necessary. Changes
+    /// aren't visible on the flash until [Sffs::commit] is called.
+    pub fn write_file(&mut self, nand: &mut NandInterface, path: &str, data: &[u8])
+        -> Result<(), String>
+    {
+        let existing = match self.lookup(path)? {
+            Some(e) if self.fst[e].is_file() => Some(e),
+            Some(_) => return Err(format!("{} is not a file", path)),
+            None => None,
+        };
+        let old_chain = match existing {
+            Some(e) => self.chain(e)?,
+            None => Vec::new(),
+        };
+
+        // Allocate clusters for the new contents. The old contents are kept
+        // until the new ones are written, and clusters which the current
+        // superblock may still refer to are left alone.
+        let num_clusters = data.len().div_ceil(CLUSTER_LEN);
+        let clusters: Vec<usize> = (0..NUM_CLUSTERS)
+            .filter(|c| self.fat[*c] == FAT_FREE && !self.released.contains(c))
+            .filter(|c| !nand.is_bad_block(c * PAGES_PER_CLUSTER / NAND_PAGES_PER_BLOCK))
+            .take(num_clusters)
+            .collect();
+        if clusters.len() < num_clusters {
+            return Err(format!("Not enough free clusters for {}", path));
+        }
+
+        // Nothing has been changed yet, so there's nothing to undo if the
+        // file can't be created
+        let entry = match existing {
+            Some(e) => e,
+            None => self.create_file(path)?,
+        };
+
+        // Encrypt and write each cluster
+        for (chain_idx, (cluster, chunk)) in clusters.iter()
+            .zip(data.chunks(CLUSTER_LEN)).enumerate()
+        {
+            let mut buf = vec![0u8; CLUSTER_LEN];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            let hmac = self.data_hmac(entry, chain_idx, &buf);
+            Aes128CbcEnc::new_from_slices(&self.keys.aes, &[0; 0x10]).unwrap()
+                .encrypt_padded_mut::<NoPadding>(&mut buf, CLUSTER_LEN).unwrap();
+            Self::write_cluster_raw(nand, *cluster, &buf, &hmac);
+        }
+
+        // Link the new chain into the file, then release the old one
+        for (idx, cluster) in clusters.iter().enumerate() {
+            self.fat[*cluster] = clusters.get(idx + 1)
+                .map(|c| *c as u16).unwrap_or(FAT_LAST);
+        }
+        self.fst[entry].sub = clusters.first().map(|c| *c as u16).unwrap_or(FST_NONE);
+        self.fst[entry].size = data.len() as u32;
+        for cluster in old_chain {
+            self.fat[cluster] = FAT_FREE;
+            self.released.insert(cluster);
+        }
+        Ok(())
+    }
+
+    /// Create a new (empty) file in an existing directory.
+    fn create_file(&mut self, path: &str) -> Result<usize, String> {
+        let (dir_path, name) = path.rsplit_once('/')
+            .ok_or(format!("{} is not an absolute path", path))?;
+        if name.is_empty() || name.len() > 12 {
+            return Err(format!("Invalid file name '{}'", name));
+        }
+        let dir = self.lookup(dir_path)?
+            .filter(|d| self.fst[*d].is_dir())
+            .ok_or(format!("{} is not a directory", dir_path))?;
+        let entry = self.fst.iter().position(|e| e.mode == 0)
+            .ok_or("The FST is full".to_string())?;
+
+        // Inherit the owner and permissions from the parent directory
+        let parent = self.fst[dir];
+        let mut new = FstEntry {
+            mode: (parent.mode & !3) | 1,
+            sub: FST_NONE,
+            sib: parent.sub,
+            uid: parent.uid,
+            gid: parent.gid,
+            ..Default::default()
+        };
+        new.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.fst[entry] = new;
+        self.fst[dir].sub = entry as u16;
+        Ok(entry)
+    }
+}
+
+impl Bus {
+    /// Load the filesystem from the NAND flash, using the keys in OTP memory.
+    pub fn load_sffs(&self) -> Result<Sffs, String> {
+        Sffs::load(&self.nand, SffsKeys::from_otp(&self.hlwd.otp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a filesystem with no clusters, and some FST entries (where
+    /// each entry is a name, a mode, and the sub and sib links).
+    fn sffs(entries: &[(&str, u8, u16, u16)]) -> Sffs {
+        let fst = entries.iter().map(|&(name, mode, sub, sib)| {
+            let mut e = FstEntry { mode, sub, sib, ..Default::default() };
+            e.name[..name.len()].copy_from_slice(name.as_bytes());
+            e
+        }).collect();
+        Sffs {
+            keys: SffsKeys { aes: [0; 0x10], hmac: [0; 0x14] },
+            superblock: 0, generation: 0, unk: 0,
+            fat: Vec::new(), fst, released: BTreeSet::new(),
+        }
+    }
+
+    #[test]
+    fn lookup_and_list() {
+        let fs = sffs(&[
+            ("/", 2, 1, FST_NONE),
+            ("sys", 2, 3, 2),
+            ("tmp", 2, FST_NONE, FST_NONE),
+            ("uid.sys", 1, FAT_LAST, FST_NONE),
+        ]);
+        assert_eq!(fs.lookup("/sys/uid.sys"), Ok(Some(3)));
+        assert_eq!(fs.lookup("/sys/cert.sys"), Ok(None));
+        let paths: Vec<String> = fs.list("/").unwrap().into_iter().map(|(p, _)| p).collect();
+        assert_eq!(paths, ["/sys", "/sys/uid.sys", "/tmp"]);
+    }
+
+    #[test]
+    fn loops_in_the_fst() {
+        // The siblings of /sys loop back around to it
+        let fs = sffs(&[
+            ("/", 2, 1, FST_NONE),
+            ("sys", 2, FST_NONE, 2),
+            ("tmp", 2, FST_NONE, 1),
+        ]);
+        assert!(fs.lookup("/title").is_err());
+        assert!(fs.list("/").is_err());
+
+        // /sys is its own child
+        let fs = sffs(&[
+            ("/", 2, 1, FST_NONE),
+            ("sys", 2, 1, FST_NONE),
+        ]);
+        assert!(fs.list("/").is_err());
+        assert_eq!(fs.lookup("/sys/sys/sys/title"), Ok(None));
+    }
+}
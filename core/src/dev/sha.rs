@@ -21,6 +21,15 @@ impl From<u32> for ShaCommand {
         }
     }
 }
+impl ShaCommand {
+    /// Approximate number of bus cycles spent on each 64-byte block.
+    const CYCLES_PER_BLOCK: usize = 100;
+
+    /// The number of bus cycles it takes for this command to complete.
+    pub fn latency(&self) -> usize {
+        (self.len as usize / 0x40) * Self::CYCLES_PER_BLOCK
+    }
+}
 
 /// Representing the SHA interface.
 pub struct ShaInterface {
@@ -55,7 +64,8 @@ impl MmioDevice for ShaInterface {
 
     fn read(&mut self, off: usize) -> BusPacket {
         let val = match off {
-            0x00 => 0, //self.ctrl,
+            // The busy bit is held until the command has completed
+            0x00 => self.ctrl & 0x8000_0000,
             0x08 => self.state.digest[0],
             0x0c => self.state.digest[1],
             0x10 => self.state.digest[2],
@@ -92,7 +102,7 @@ impl Bus {
         let cmd = ShaCommand::from(val);
 
         let mut sha_buf = vec![0u8; cmd.len as usize];
-        self.dma_read(self.sha.src, &mut sha_buf);
+        self.device_dma_read(IoDevice::Sha, self.sha.src, &mut sha_buf);
         //println!("{:?}", sha_buf.hex_dump());
 
         self.sha.state.update(&sha_buf);
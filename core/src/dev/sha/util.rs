@@ -130,3 +130,43 @@ impl Sha1State {
 
     }
 }
+
+/// The initial state of the digest for a new message.
+pub const SHA1_IV: [u32; 5] = [
+    0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476, 0xc3d2_e1f0,
+];
+
+/// Compute the SHA-1 digest of some message, including the final padding.
+pub fn sha1(msg: &[u8]) -> [u8; 20] {
+    let mut buf = msg.to_vec();
+    buf.push(0x80);
+    while buf.len() % 64 != 56 {
+        buf.push(0);
+    }
+    buf.extend_from_slice(&((msg.len() as u64) * 8).to_be_bytes());
+
+    let mut state = Sha1State::new();
+    state.digest = SHA1_IV;
+    state.update(&buf);
+
+    let mut res = [0u8; 20];
+    for (dst, word) in res.chunks_mut(4).zip(state.digest.iter()) {
+        dst.copy_from_slice(&word.to_be_bytes());
+    }
+    res
+}
+
+/// Compute the HMAC-SHA1 of some message.
+pub fn hmac_sha1(key: &[u8], msg: &[u8]) -> [u8; 20] {
+    assert!(key.len() <= 64);
+    let mut ipad = vec![0x36u8; 64];
+    let mut opad = vec![0x5cu8; 64];
+    for (idx, k) in key.iter().enumerate() {
+        ipad[idx] ^= k;
+        opad[idx] ^= k;
+    }
+    ipad.extend_from_slice(msg);
+    let inner = sha1(&ipad);
+    opad.extend_from_slice(&inner);
+    sha1(&opad)
+}
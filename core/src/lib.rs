@@ -13,4 +13,6 @@ pub mod dev;
 pub mod bus;
 /// Implementation of runtime debugging features.
 pub mod dbg;
+/// Loading code directly into memory.
+pub mod loader;
 
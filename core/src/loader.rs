@@ -0,0 +1,191 @@
+//! Loading code directly into memory, bypassing the boot ROM.
+
+pub mod elf;
+pub mod title;
+
+use crate::bus::*;
+use crate::bus::prim::*;
+use crate::cpu::*;
+use crate::cpu::reg::*;
+use crate::loader::elf::*;
+
+/// SPARE1 bit which disables the boot ROM mapping.
+const SPARE1_ROM_DISABLE: u32 = 0x0000_1000;
+/// SRNPROT bit which enables the SRAM mirror.
+const SRNPROT_MIRROR_ENABLE: u32 = 0x0000_0020;
+
+impl Bus {
+    /// Unmap the boot ROM (as boot1 would), exposing SRAM at 0xffff0000.
+    pub fn disable_boot_rom(&mut self) {
+        self.hlwd.spare1 |= SPARE1_ROM_DISABLE;
+        self.rom_disabled = true;
+    }
+
+    /// Put the bus into the state left behind by boot2 before it jumps into
+    /// the IOS kernel: the boot ROM is unmapped, the SRAM mirror is enabled,
+    /// and all AHB masters have access to all devices.
+    pub fn set_post_boot2_state(&mut self) {
+        self.disable_boot_rom();
+        self.hlwd.busctrl.srnprot |= SRNPROT_MIRROR_ENABLE;
+        self.mirror_enabled = true;
+        self.hlwd.busctrl.ahbprot = 0xffff_ffff;
+    }
+
+    /// Returns true if the range [addr, addr+len) is backed by a single
+    /// memory device.
+    pub fn is_mem_range(&self, addr: u32, len: u32) -> bool {
+        let end = match addr.checked_add(len.saturating_sub(1)) {
+            Some(end) => end,
+            None => return false,
+        };
+        match (self.decode_phys_addr(addr), self.decode_phys_addr(end)) {
+            (Some(s), Some(e)) => match (s.dev, e.dev) {
+                (Device::Mem(MemDevice::MaskRom), _) => false,
+                (Device::Mem(sdev), Device::Mem(edev)) => {
+                    sdev == edev && (end & e.mask) >= (addr & s.mask)
+                },
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Load the segments from some ELF file into memory (at their physical
+    /// addresses), returning the entrypoint. Nothing is written unless every
+    /// segment is in memory.
+    pub fn load_elf(&mut self, data: &[u8]) -> Result<u32, String> {
+        let elf = Elf::parse(data)?;
+        self.check_segments(&elf)?;
+        self.write_segments(&elf);
+        Ok(elf.entry)
+    }
+
+    /// Check that every segment in some ELF file is backed by memory.
+    fn check_segments(&self, elf: &Elf) -> Result<(), String> {
+        for seg in elf.segments.iter().filter(|seg| seg.memsz != 0) {
+            if !self.is_mem_range(seg.paddr, seg.memsz) {
+                return Err(format!("ELF segment at {:08x} (len {:x}) isn't in memory",
+                    seg.paddr, seg.memsz));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the segments from some (checked) ELF file into memory.
+    fn write_segments(&mut self, elf: &Elf) {
+        for seg in elf.segments.iter().filter(|seg| seg.memsz != 0) {
+            println!("LDR segment paddr={:08x} vaddr={:08x} filesz={:x} memsz={:x}",
+                seg.paddr, seg.vaddr, seg.data.len(), seg.memsz);
+            let mut buf = seg.data.to_vec();
+            buf.resize(seg.memsz as usize, 0);
+            self.dma_write(seg.paddr, &buf);
+        }
+    }
+
+    /// Copy a raw binary into memory at some physical address.
+    pub fn load_raw(&mut self, addr: u32, data: &[u8]) -> Result<(), String> {
+        if !self.is_mem_range(addr, data.len() as u32) {
+            return Err(format!("Binary at {:08x} (len {:x}) isn't in memory",
+                addr, data.len()));
+        }
+        println!("LDR raw binary paddr={:08x} len={:x}", addr, data.len());
+        self.dma_write(addr, data);
+        Ok(())
+    }
+
+    /// Load an IOS kernel into memory, returning the entrypoint.
+    ///
+    /// The boot content for IOS is usually an ELF file prefixed with a small
+    /// ELF loader stub, which is skipped here. A bare ELF file is also
+    /// accepted. The bus is put into the post-boot2 state beforehand.
+    ///
+    /// The segments are checked against the memory map left by boot2, so
+    /// the bus is only changed if the whole image is valid.
+    pub fn load_ios(&mut self, data: &[u8]) -> Result<u32, String> {
+        let elf = Elf::parse(find_ios_elf(data)?)?;
+
+        let prev_spare1 = self.hlwd.spare1;
+        let prev_busctrl = self.hlwd.busctrl.clone();
+        let prev_map = (self.rom_disabled, self.mirror_enabled);
+        self.set_post_boot2_state();
+        if let Err(e) = self.check_segments(&elf) {
+            self.hlwd.spare1 = prev_spare1;
+            self.hlwd.busctrl = prev_busctrl;
+            (self.rom_disabled, self.mirror_enabled) = prev_map;
+            return Err(e);
+        }
+        self.write_segments(&elf);
+        Ok(elf.entry)
+    }
+}
+
+/// Find the ELF file in some IOS image, skipping the loader stub (if any).
+fn find_ios_elf(data: &[u8]) -> Result<&[u8], String> {
+    if Elf::is_elf(data) {
+        return Ok(data);
+    }
+
+    // The loader header: header length, loader length, and ELF length
+    if data.len() < 0x10 {
+        return Err("IOS image is truncated".to_string());
+    }
+    let hdr_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let ldr_len = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    match hdr_len.checked_add(ldr_len) {
+        Some(off) if off < data.len() && Elf::is_elf(&data[off..]) => Ok(&data[off..]),
+        _ => Err("Couldn't find an ELF file in the IOS image".to_string()),
+    }
+}
+
+impl Cpu {
+    /// Reset the CPU and start executing at some entrypoint in supervisor
+    /// mode, with interrupts disabled and the MMU off.
+    pub fn reset_to_entry(&mut self, entry: u32) {
+        self.reg = RegisterFile::new();
+        self.p15 = coproc::SystemControl::new();
+        self.reg.cpsr.set_thumb(entry & 1 != 0);
+        self.write_exec_pc(entry & !1);
+        println!("LDR CPU entry at {:08x}", entry);
+    }
+
+    /// Switch to some operating mode, and set the stack pointer for it.
+    pub fn set_mode_and_stack(&mut self, mode: CpuMode, sp: u32) {
+        let mut cpsr = self.reg.cpsr;
+        cpsr.set_mode(mode);
+        self.reg.write_cpsr(cpsr);
+        self.reg.r[13] = sp;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::elf::tests::build;
+
+    #[test]
+    fn invalid_ios_leaves_bus_alone() {
+        let mut bus = Bus::new_blank();
+        let data = build(0xffff_0000, &[(0xffff_0000, &[1; 4]), (0x2000_0000, &[2; 4])]);
+        assert!(bus.load_ios(&data).is_err());
+        assert!(!bus.rom_disabled);
+        assert!(!bus.mirror_enabled);
+        assert_eq!(bus.hlwd.busctrl.ahbprot, 0);
+        assert_eq!(bus.read32(0xfff0_0000), 0);
+        assert_eq!(bus.read32(0xfff1_0000), 0);
+    }
+
+    #[test]
+    fn load_ios_after_loader_stub() {
+        let mut bus = Bus::new_blank();
+        let elf = build(0xffff_0000, &[(0xffff_0000, &[1; 4]), (0x0001_0000, &[2; 4])]);
+        let mut data = vec![0u8; 0x20];
+        data[0..4].copy_from_slice(&0x10u32.to_be_bytes());
+        data[4..8].copy_from_slice(&0x10u32.to_be_bytes());
+        data.extend_from_slice(&elf);
+
+        assert_eq!(bus.load_ios(&data), Ok(0xffff_0000));
+        assert!(bus.rom_disabled);
+        assert_eq!(bus.read32(0xffff_0000), 0x0101_0101);
+        assert_eq!(bus.read32(0x0001_0000), 0x0202_0202);
+    }
+}
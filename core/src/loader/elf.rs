@@ -0,0 +1,156 @@
+//! Minimal parser for 32-bit big-endian ARM ELF files.
+
+/// Program header type for loadable segments.
+const PT_LOAD: u32 = 1;
+/// Machine type for ARM.
+const EM_ARM: u16 = 40;
+/// Length of a (32-bit) program header.
+const PHDR_LEN: usize = 0x20;
+
+/// A loadable segment in some ELF file.
+#[derive(Debug)]
+pub struct Segment<'a> {
+    /// Virtual address of the segment.
+    pub vaddr: u32,
+    /// Physical address of the segment.
+    pub paddr: u32,
+    /// Length of the segment in memory (the remainder is zero-filled).
+    pub memsz: u32,
+    /// The contents of the segment in the file.
+    pub data: &'a [u8],
+}
+
+/// A parsed ELF file.
+#[derive(Debug)]
+pub struct Elf<'a> {
+    pub entry: u32,
+    pub segments: Vec<Segment<'a>>,
+}
+
+fn be16(x: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([x[off], x[off + 1]])
+}
+fn be32(x: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([x[off], x[off + 1], x[off + 2], x[off + 3]])
+}
+
+impl<'a> Elf<'a> {
+    /// Returns true if some buffer starts with the ELF magic bytes.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.len() >= 4 && &data[0..4] == b"\x7fELF"
+    }
+
+    /// Parse the header and loadable segments of some ELF file.
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if !Self::is_elf(data) || data.len() < 0x34 {
+            return Err("Not an ELF file".to_string());
+        }
+        if data[4] != 1 || data[5] != 2 {
+            return Err("Only 32-bit big-endian ELF files are supported".to_string());
+        }
+        if be16(data, 0x12) != EM_ARM {
+            return Err(format!("Unsupported ELF machine type {}", be16(data, 0x12)));
+        }
+
+        let entry = be32(data, 0x18);
+        let phoff = be32(data, 0x1c) as usize;
+        let phentsize = be16(data, 0x2a) as usize;
+        let phnum = be16(data, 0x2c) as usize;
+        if phnum != 0 && phentsize < PHDR_LEN {
+            return Err(format!("ELF program header size {:x} is too small", phentsize));
+        }
+
+        let mut segments = Vec::new();
+        for idx in 0..phnum {
+            let ph = idx.checked_mul(phentsize)
+                .and_then(|off| off.checked_add(phoff))
+                .and_then(|start| data.get(start..start.checked_add(PHDR_LEN)?))
+                .ok_or("ELF program headers are truncated")?;
+            if be32(ph, 0x00) != PT_LOAD {
+                continue;
+            }
+            let offset = be32(ph, 0x04) as usize;
+            let filesz = be32(ph, 0x10) as usize;
+            let memsz = be32(ph, 0x14);
+            let in_file = offset.checked_add(filesz).is_some_and(|end| end <= data.len());
+            if !in_file || filesz > memsz as usize {
+                return Err(format!("ELF segment {} is malformed", idx));
+            }
+            segments.push(Segment {
+                vaddr: be32(ph, 0x08),
+                paddr: be32(ph, 0x0c),
+                memsz,
+                data: &data[offset..offset + filesz],
+            });
+        }
+        Ok(Elf { entry, segments })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Build an ELF file with one loadable segment for each (paddr, data).
+    pub(crate) fn build(entry: u32, segments: &[(u32, &[u8])]) -> Vec<u8> {
+        let (phoff, phentsize) = (0x34, PHDR_LEN);
+        let mut res = vec![0u8; phoff + phentsize * segments.len()];
+        res[0..4].copy_from_slice(b"\x7fELF");
+        res[4] = 1;
+        res[5] = 2;
+        res[0x12..0x14].copy_from_slice(&EM_ARM.to_be_bytes());
+        res[0x18..0x1c].copy_from_slice(&entry.to_be_bytes());
+        res[0x1c..0x20].copy_from_slice(&(phoff as u32).to_be_bytes());
+        res[0x2a..0x2c].copy_from_slice(&(phentsize as u16).to_be_bytes());
+        res[0x2c..0x2e].copy_from_slice(&(segments.len() as u16).to_be_bytes());
+        for (idx, (paddr, data)) in segments.iter().enumerate() {
+            let ph = phoff + idx * phentsize;
+            let fields = [PT_LOAD, res.len() as u32, *paddr, *paddr,
+                data.len() as u32, data.len() as u32];
+            for (i, x) in fields.iter().enumerate() {
+                res[ph + i * 4..ph + i * 4 + 4].copy_from_slice(&x.to_be_bytes());
+            }
+            res.extend_from_slice(data);
+        }
+        res
+    }
+
+    #[test]
+    fn parse_segments() {
+        let data = build(0x1234, &[(0x100, &[1, 2, 3]), (0x200, &[4])]);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry, 0x1234);
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!(elf.segments[0].paddr, 0x100);
+        assert_eq!(elf.segments[0].data, &[1, 2, 3]);
+        assert_eq!(elf.segments[1].data, &[4]);
+    }
+
+    #[test]
+    fn small_program_headers() {
+        for phentsize in [0u16, 4, PHDR_LEN as u16 - 1] {
+            let mut data = build(0, &[(0x100, &[1])]);
+            data[0x2a..0x2c].copy_from_slice(&phentsize.to_be_bytes());
+            assert!(Elf::parse(&data).is_err(), "phentsize {}", phentsize);
+        }
+    }
+
+    #[test]
+    fn truncated_program_headers() {
+        let data = build(0, &[(0x100, &[]), (0x200, &[])]);
+        assert!(Elf::parse(&data[..data.len() - 1]).is_err());
+
+        // The last header runs off the end of the file
+        let mut data = build(0, &[(0x100, &[])]);
+        data[0x1c..0x20].copy_from_slice(&0xffff_fff0u32.to_be_bytes());
+        assert!(Elf::parse(&data).is_err());
+    }
+
+    #[test]
+    fn segment_outside_of_file() {
+        let mut data = build(0, &[(0x100, &[1, 2, 3, 4])]);
+        // Push the segment's offset to the end of the address space
+        data[0x38..0x3c].copy_from_slice(&0xffff_fffeu32.to_be_bytes());
+        assert!(Elf::parse(&data).is_err());
+    }
+}
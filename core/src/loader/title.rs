@@ -0,0 +1,302 @@
+//! Parsing and decryption for titles (WAD files, tickets, TMDs, and content).
+//!
+//! Title keys are stored in the ticket, encrypted with the common key (using
+//! the title ID as the IV). Each piece of content is encrypted with the title
+//! key (using the content index as the IV), and the TMD records the SHA-1
+//! digest of the decrypted content.
+
+extern crate aes;
+extern crate cbc;
+
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut, KeyIvInit};
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+use std::fs;
+use std::convert::TryFrom;
+
+use crate::dev::hlwd::otp::*;
+use crate::dev::sha::util::sha1;
+
+/// The offset of the common key in OTP memory.
+const OTP_COMMON_KEY_OFF: usize = 0x14;
+
+/// Get the common key fused into OTP memory.
+pub fn common_key_from_otp(otp: &OtpInterface) -> [u8; 0x10] {
+    let mut res = [0u8; 0x10];
+    otp.read_bytes(OTP_COMMON_KEY_OFF, &mut res);
+    res
+}
+
+/// Get the common key from some OTP dump.
+pub fn common_key_from_otp_file(filename: &str) -> Result<[u8; 0x10], String> {
+    let data = fs::read(filename)
+        .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+    if data.len() < OTP_COMMON_KEY_OFF + 0x10 {
+        return Err(format!("{} is too short to be an OTP dump", filename));
+    }
+    let mut res = [0u8; 0x10];
+    res.copy_from_slice(&data[OTP_COMMON_KEY_OFF..OTP_COMMON_KEY_OFF + 0x10]);
+    Ok(res)
+}
+
+fn be16(x: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([x[off], x[off + 1]])
+}
+fn be32(x: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([x[off], x[off + 1], x[off + 2], x[off + 3]])
+}
+fn be64(x: &[u8], off: usize) -> u64 {
+    ((be32(x, off) as u64) << 32) | be32(x, off + 4) as u64
+}
+
+/// Round some length up to a multiple of the given alignment, returning
+/// [None] if the result doesn't fit.
+fn align_up(x: usize, align: usize) -> Option<usize> {
+    Some(x.checked_add(align - 1)? & !(align - 1))
+}
+
+/// Returns the length of some content, padded to the AES block size.
+fn padded_content_len(rec: &ContentRecord) -> Result<usize, String> {
+    usize::try_from(rec.size).ok()
+        .and_then(|size| align_up(size, 0x10))
+        .ok_or_else(|| format!("Content {:08x} is truncated", rec.cid))
+}
+
+/// Decrypt some buffer in-place with AES-128-CBC.
+fn decrypt(key: &[u8; 0x10], iv: &[u8; 0x10], buf: &mut [u8]) {
+    Aes128CbcDec::new_from_slices(key, iv).unwrap()
+        .decrypt_padded_mut::<NoPadding>(buf).unwrap();
+}
+
+/// A ticket, which carries the (encrypted) title key.
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub title_id: u64,
+    pub enc_title_key: [u8; 0x10],
+    /// Index of the common key used to encrypt the title key.
+    pub common_key_idx: u8,
+}
+impl Ticket {
+    /// The length of a ticket, in bytes.
+    pub const LEN: usize = 0x2a4;
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < Self::LEN {
+            return Err("Ticket is truncated".to_string());
+        }
+        let mut enc_title_key = [0u8; 0x10];
+        enc_title_key.copy_from_slice(&data[0x1bf..0x1cf]);
+        Ok(Ticket {
+            title_id: be64(data, 0x1dc),
+            enc_title_key,
+            common_key_idx: data[0x1f1],
+        })
+    }
+
+    /// Decrypt the title key with the common key.
+    pub fn title_key(&self, common_key: &[u8; 0x10]) -> Result<[u8; 0x10], String> {
+        if self.common_key_idx != 0 {
+            return Err(format!("Unsupported common key index {}", self.common_key_idx));
+        }
+        let mut iv = [0u8; 0x10];
+        iv[..8].copy_from_slice(&self.title_id.to_be_bytes());
+        let mut key = self.enc_title_key;
+        decrypt(common_key, &iv, &mut key);
+        Ok(key)
+    }
+}
+
+/// An entry in the TMD describing some piece of content.
+#[derive(Debug, Clone)]
+pub struct ContentRecord {
+    pub cid: u32,
+    pub index: u16,
+    pub kind: u16,
+    pub size: u64,
+    /// SHA-1 digest of the decrypted content.
+    pub hash: [u8; 20],
+}
+
+/// Title metadata, describing the set of content in a title.
+#[derive(Debug, Clone)]
+pub struct Tmd {
+    pub ios_version: u64,
+    pub title_id: u64,
+    pub title_version: u16,
+    /// Index of the content used to boot the title.
+    pub boot_index: u16,
+    pub contents: Vec<ContentRecord>,
+}
+impl Tmd {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 0x1e4 {
+            return Err("TMD is truncated".to_string());
+        }
+        let num_contents = be16(data, 0x1de) as usize;
+        if data.len() < 0x1e4 + num_contents * 0x24 {
+            return Err("TMD content records are truncated".to_string());
+        }
+        let contents = (0..num_contents).map(|idx| {
+            let rec = &data[0x1e4 + idx * 0x24..];
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(&rec[0x10..0x24]);
+            ContentRecord {
+                cid: be32(rec, 0x00),
+                index: be16(rec, 0x04),
+                kind: be16(rec, 0x06),
+                size: be64(rec, 0x08),
+                hash,
+            }
+        }).collect();
+
+        Ok(Tmd {
+            ios_version: be64(data, 0x184),
+            title_id: be64(data, 0x18c),
+            title_version: be16(data, 0x1dc),
+            boot_index: be16(data, 0x1e0),
+            contents,
+        })
+    }
+
+    /// Returns the record for the boot content.
+    pub fn boot_content(&self) -> Option<&ContentRecord> {
+        self.contents.iter().find(|c| c.index == self.boot_index)
+    }
+}
+
+/// Decrypt some content with the title key, and verify it against its TMD
+/// record. The input may include padding to the AES block size.
+pub fn decrypt_content(rec: &ContentRecord, title_key: &[u8; 0x10], enc: &[u8])
+    -> Result<Vec<u8>, String>
+{
+    let padded_len = padded_content_len(rec)?;
+    if enc.len() < padded_len {
+        return Err(format!("Content {:08x} is truncated", rec.cid));
+    }
+    let size = rec.size as usize;
+    let mut iv = [0u8; 0x10];
+    iv[..2].copy_from_slice(&rec.index.to_be_bytes());
+    let mut buf = enc[..padded_len].to_vec();
+    decrypt(title_key, &iv, &mut buf);
+    buf.truncate(size);
+
+    if sha1(&buf) != rec.hash {
+        return Err(format!("Content {:08x} has a bad hash", rec.cid));
+    }
+    Ok(buf)
+}
+
+/// An installable WAD file.
+#[derive(Debug)]
+pub struct Wad<'a> {
+    pub ticket: Ticket,
+    pub tmd: Tmd,
+    /// The raw ticket.
+    pub ticket_data: &'a [u8],
+    /// The raw TMD.
+    pub tmd_data: &'a [u8],
+    /// The (encrypted) content data, in TMD order.
+    data: &'a [u8],
+}
+impl<'a> Wad<'a> {
+    /// Returns true if some buffer looks like a WAD file.
+    pub fn is_wad(data: &[u8]) -> bool {
+        data.len() >= 0x20 && be32(data, 0) == 0x20 &&
+            (&data[4..6] == b"Is" || &data[4..6] == b"ib")
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if !Self::is_wad(data) {
+            return Err("Not a WAD file".to_string());
+        }
+        let cert_len = be32(data, 0x08) as usize;
+        let tik_len = be32(data, 0x10) as usize;
+        let tmd_len = be32(data, 0x14) as usize;
+        let data_len = be32(data, 0x18) as usize;
+
+        // Each section is aligned to 0x40 bytes
+        let next = |off: usize, len: usize| off.checked_add(align_up(len, 0x40)?);
+        let truncated = || "WAD is truncated".to_string();
+        let cert_off = 0x40;
+        let tik_off = next(cert_off, cert_len).ok_or_else(truncated)?;
+        let tmd_off = next(tik_off, tik_len).ok_or_else(truncated)?;
+        let data_off = next(tmd_off, tmd_len).ok_or_else(truncated)?;
+        if data_off.checked_add(data_len).is_none_or(|end| end > data.len()) {
+            return Err(truncated());
+        }
+
+        let ticket_data = &data[tik_off..tik_off + tik_len];
+        let tmd_data = &data[tmd_off..tmd_off + tmd_len];
+        Ok(Wad {
+            ticket: Ticket::parse(ticket_data)?,
+            tmd: Tmd::parse(tmd_data)?,
+            ticket_data,
+            tmd_data,
+            data: &data[data_off..data_off + data_len],
+        })
+    }
+
+    /// Returns the encrypted data for each piece of content, in TMD order.
+    pub fn encrypted_contents(&self) -> Result<Vec<&'a [u8]>, String> {
+        let mut res = Vec::new();
+        let mut off = 0usize;
+        for rec in self.tmd.contents.iter() {
+            let truncated = || format!("Content {:08x} is truncated", rec.cid);
+            let end = off.checked_add(padded_content_len(rec)?)
+                .filter(|&end| end <= self.data.len())
+                .ok_or_else(truncated)?;
+            res.push(&self.data[off..end]);
+            // The next content starts at a multiple of 0x40 bytes
+            off = align_up(end, 0x40).ok_or_else(truncated)?;
+        }
+        Ok(res)
+    }
+
+    /// Decrypt and verify all of the content in this WAD.
+    pub fn decrypt_contents(&self, common_key: &[u8; 0x10])
+        -> Result<Vec<(ContentRecord, Vec<u8>)>, String>
+    {
+        let title_key = self.ticket.title_key(common_key)?;
+        self.tmd.contents.iter().zip(self.encrypted_contents()?)
+            .map(|(rec, enc)| {
+                decrypt_content(rec, &title_key, enc).map(|dec| (rec.clone(), dec))
+            }).collect()
+    }
+
+    /// Decrypt and verify the boot content in this WAD.
+    pub fn decrypt_boot_content(&self, common_key: &[u8; 0x10]) -> Result<Vec<u8>, String> {
+        let title_key = self.ticket.title_key(common_key)?;
+        let idx = self.tmd.contents.iter()
+            .position(|c| c.index == self.tmd.boot_index)
+            .ok_or("TMD has no boot content".to_string())?;
+        let enc = self.encrypted_contents()?[idx];
+        decrypt_content(&self.tmd.contents[idx], &title_key, enc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cid: u32, size: u64) -> ContentRecord {
+        ContentRecord { cid, index: 0, kind: 1, size, hash: [0; 20] }
+    }
+
+    #[test]
+    fn content_sizes_which_overflow() {
+        let key = [0u8; 0x10];
+        let enc = [0u8; 0x40];
+        for size in [u64::MAX, u64::MAX - 0xf, 0x41] {
+            assert_eq!(decrypt_content(&record(1, size), &key, &enc),
+                Err("Content 00000001 is truncated".to_string()));
+        }
+
+        let ticket = Ticket { title_id: 0, enc_title_key: key, common_key_idx: 0 };
+        let tmd = Tmd {
+            ios_version: 0, title_id: 0, title_version: 0, boot_index: 0,
+            contents: vec![record(1, 0x10), record(2, u64::MAX)],
+        };
+        let wad = Wad { ticket, tmd, ticket_data: &[], tmd_data: &[], data: &enc };
+        assert_eq!(wad.encrypted_contents(), Err("Content 00000002 is truncated".to_string()));
+    }
+}
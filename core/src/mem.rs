@@ -1,4 +1,6 @@
 
+pub mod storage;
+
 use std::fmt;
 use std::fs::File;
 use std::io::Read;
@@ -0,0 +1,292 @@
+//! Persistent storage for devices backed by some image file on the host.
+//!
+//! Each image can be opened in one of three modes:
+//!
+//! - Read-only: changes are kept in memory and discarded on exit.
+//! - Read-write: changes are written back to the image file.
+//! - Overlay: the image file is never modified. Changed chunks of the image
+//!   are recorded in a separate copy-on-write overlay file, which is applied
+//!   on top of the image the next time it is opened.
+//!
+//! Devices can also be blank (i.e. for tests), in which case there is no
+//! image file at all.
+//!
+//! An overlay file consists of a header (the magic `IOVL`, the chunk length,
+//! and the number of chunks), followed by a list of chunk entries. Each entry
+//! is a chunk index followed by the contents of the chunk. All integers are
+//! 32-bit big-endian.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::mem::*;
+use crate::bus::prim::AccessWidth;
+
+/// Magic bytes at the start of an overlay file.
+const OVERLAY_MAGIC: &[u8; 4] = b"IOVL";
+
+/// The way a storage image is opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Changes are discarded on exit.
+    #[default]
+    ReadOnly,
+    /// Changes are written back to the image file.
+    ReadWrite,
+    /// Changes are written to the given overlay file.
+    Overlay(String),
+    /// There is no image file: the device starts out erased, and changes
+    /// are discarded.
+    Blank,
+}
+impl StorageMode {
+    /// Parse a mode from a string (`ro`, `rw`, or `overlay:<file>`).
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ro" => Some(StorageMode::ReadOnly),
+            "rw" => Some(StorageMode::ReadWrite),
+            _ => s.strip_prefix("overlay:")
+                .filter(|f| !f.is_empty())
+                .map(|f| StorageMode::Overlay(f.to_string())),
+        }
+    }
+}
+
+/// The modes used to open each of the storage images on the system.
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfig {
+    pub nand: StorageMode,
+    pub seeprom: StorageMode,
+    pub otp: StorageMode,
+}
+impl StorageConfig {
+    /// A configuration where every storage device is blank.
+    pub fn blank() -> Self {
+        StorageConfig {
+            nand: StorageMode::Blank,
+            seeprom: StorageMode::Blank,
+            otp: StorageMode::Blank,
+        }
+    }
+}
+
+/// A memory device backed by some image file on the host.
+///
+/// Writes are tracked with the granularity of some chunk length (i.e. a NAND
+/// page, or a SEEPROM word), so that only modified chunks are persisted.
+pub struct BackedMemory {
+    /// The contents of this memory device.
+    pub mem: BigEndianMemory,
+    /// Path to the base image.
+    path: String,
+    /// The mode used to open the base image.
+    mode: StorageMode,
+    /// The granularity used to track changes, in bytes.
+    chunk_len: usize,
+    /// Set of chunks which need to be persisted.
+    dirty: BTreeSet<usize>,
+    /// Set when some chunk was modified since the last flush.
+    modified: bool,
+}
+impl BackedMemory {
+    /// Open some image file with the given length, chunk length, and mode.
+    pub fn open(path: &str, len: usize, chunk_len: usize, mode: StorageMode)
+        -> Self
+    {
+        assert!(len.is_multiple_of(chunk_len));
+        let data = if mode == StorageMode::Blank {
+            vec![0xff; len]
+        } else {
+            Self::read_image(path, len)
+        };
+
+        let mut res = BackedMemory {
+            mem: BigEndianMemory { data },
+            path: path.to_string(),
+            mode,
+            chunk_len,
+            dirty: BTreeSet::new(),
+            modified: false,
+        };
+
+        // Apply an existing overlay. These chunks are already different from
+        // the base image, so we need to keep them when writing the overlay.
+        if let StorageMode::Overlay(ovl) = &res.mode {
+            if Path::new(ovl).exists() {
+                let ovl = ovl.clone();
+                let chunks = read_overlay(&ovl, chunk_len, len)
+                    .unwrap_or_else(|e| panic!("{}", e));
+                println!("STORAGE applying {} chunks from {} to {}",
+                    chunks.len(), ovl, path);
+                for (idx, buf) in chunks {
+                    res.mem.write_buf(idx * chunk_len, &buf);
+                    res.dirty.insert(idx);
+                }
+            }
+        }
+        res
+    }
+
+    /// Read some image file, padding it out to the given length.
+    fn read_image(path: &str, len: usize) -> Vec<u8> {
+        let mut f = File::open(path)
+            .unwrap_or_else(|e| panic!("Couldn't open {}: {}", path, e));
+        let mut data = Vec::with_capacity(len);
+        Read::by_ref(&mut f).take(len as u64).read_to_end(&mut data).unwrap();
+        if data.len() < len {
+            println!("STORAGE {} is only {:x} bytes, expected {:x}",
+                path, data.len(), len);
+            data.resize(len, 0);
+        }
+        data
+    }
+
+    /// Mark the chunks covering some region as modified.
+    fn mark_dirty(&mut self, off: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = off / self.chunk_len;
+        let last = (off + len - 1) / self.chunk_len;
+        self.dirty.extend(first..=last);
+        self.modified = true;
+    }
+
+    /// Persist any changes, according to the mode used to open the image.
+    /// This does nothing when there were no changes since the last flush,
+    /// so it can be called periodically.
+    pub fn flush(&mut self) {
+        if !std::mem::take(&mut self.modified) {
+            return;
+        }
+        match &self.mode {
+            StorageMode::Blank => {},
+            StorageMode::ReadOnly => {
+                if !self.dirty.is_empty() {
+                    println!("STORAGE discarding {} modified chunks of {}",
+                        self.dirty.len(), self.path);
+                }
+            },
+            StorageMode::ReadWrite => {
+                let mut f = OpenOptions::new().write(true).open(&self.path)
+                    .unwrap_or_else(|e| panic!("Couldn't open {}: {}", self.path, e));
+                for idx in self.dirty.iter() {
+                    let off = idx * self.chunk_len;
+                    f.seek(SeekFrom::Start(off as u64)).unwrap();
+                    f.write_all(&self.mem.data[off..off + self.chunk_len]).unwrap();
+                }
+                println!("STORAGE wrote {} modified chunks to {}",
+                    self.dirty.len(), self.path);
+                self.dirty.clear();
+            },
+            StorageMode::Overlay(ovl) => {
+                let mut buf = Vec::new();
+                buf.extend_from_slice(OVERLAY_MAGIC);
+                buf.extend_from_slice(&(self.chunk_len as u32).to_be_bytes());
+                buf.extend_from_slice(&(self.dirty.len() as u32).to_be_bytes());
+                for idx in self.dirty.iter() {
+                    let off = idx * self.chunk_len;
+                    buf.extend_from_slice(&(*idx as u32).to_be_bytes());
+                    buf.extend_from_slice(&self.mem.data[off..off + self.chunk_len]);
+                }
+                // Replace the overlay in one step, so that it isn't left
+                // truncated if we're killed while writing it
+                let tmp = format!("{}.tmp", ovl);
+                fs::write(&tmp, &buf).and_then(|_| fs::rename(&tmp, ovl))
+                    .unwrap_or_else(|e| panic!("Couldn't write {}: {}", ovl, e));
+                println!("STORAGE wrote {} modified chunks of {} to {}",
+                    self.dirty.len(), self.path, ovl);
+            },
+        }
+    }
+}
+
+impl fmt::Debug for BackedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackedMemory")
+            .field("path", &self.path)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+/// Reads and writes which track the modified chunks.
+impl BackedMemory {
+    pub fn read<T: AccessWidth>(&self, off: usize) -> T {
+        self.mem.read(off)
+    }
+    pub fn write<T: AccessWidth>(&mut self, off: usize, val: T) {
+        self.mem.write(off, val);
+        self.mark_dirty(off, std::mem::size_of::<T>());
+    }
+    pub fn read_buf(&self, off: usize, dst: &mut [u8]) {
+        self.mem.read_buf(off, dst);
+    }
+    pub fn write_buf(&mut self, off: usize, src: &[u8]) {
+        self.mem.write_buf(off, src);
+        self.mark_dirty(off, src.len());
+    }
+    pub fn memset(&mut self, off: usize, len: usize, val: u8) {
+        self.mem.memset(off, len, val);
+        self.mark_dirty(off, len);
+    }
+}
+
+/// Read a big-endian 32-bit integer from some slice.
+fn be32(x: &[u8]) -> u32 {
+    <u32 as AccessWidth>::from_be_bytes(x)
+}
+
+/// Read the list of chunks from some overlay file, which applies to an image
+/// with the given length.
+fn read_overlay(filename: &str, chunk_len: usize, image_len: usize)
+    -> Result<Vec<(usize, Vec<u8>)>, String>
+{
+    let data = fs::read(filename)
+        .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+    if data.len() < 12 || &data[0..4] != OVERLAY_MAGIC {
+        return Err(format!("{} is not an overlay file", filename));
+    }
+    let ovl_chunk_len = be32(&data[4..8]) as usize;
+    let num_chunks = be32(&data[8..12]) as usize;
+    if chunk_len != 0 && ovl_chunk_len != chunk_len {
+        return Err(format!("{} has chunk length {:x}, expected {:x}",
+            filename, ovl_chunk_len, chunk_len));
+    }
+
+    let entry_len = 4 + ovl_chunk_len;
+    if data.len() != 12 + num_chunks * entry_len {
+        return Err(format!("{} is truncated", filename));
+    }
+    let mut res = Vec::with_capacity(num_chunks);
+    for entry in data[12..].chunks_exact(entry_len) {
+        let idx = be32(&entry[0..4]) as usize;
+        if (idx + 1) * ovl_chunk_len > image_len {
+            return Err(format!("{} has chunk {:x}, which is outside of the image",
+                filename, idx));
+        }
+        res.push((idx, entry[4..].to_vec()));
+    }
+    Ok(res)
+}
+
+/// Merge the chunks in some overlay file back into a base image, returning
+/// the number of chunks written.
+pub fn merge_overlay(base: &str, overlay: &str) -> Result<usize, String> {
+    let mut f = OpenOptions::new().write(true).open(base)
+        .map_err(|e| format!("Couldn't open {}: {}", base, e))?;
+    let base_len = f.metadata().map_err(|e| e.to_string())?.len() as usize;
+
+    // Chunks are checked against the base image up front, so that it isn't
+    // left half-merged if the overlay doesn't fit
+    let chunks = read_overlay(overlay, 0, base_len)?;
+    for (idx, buf) in chunks.iter() {
+        let off = idx * buf.len();
+        f.seek(SeekFrom::Start(off as u64)).map_err(|e| e.to_string())?;
+        f.write_all(buf).map_err(|e| e.to_string())?;
+    }
+    Ok(chunks.len())
+}
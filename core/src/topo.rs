@@ -1,5 +1,6 @@
 
 use crate::mem::*;
+use crate::mem::storage::*;
 use crate::dev::hlwd::*;
 use crate::dev::aes::*;
 use crate::dev::sha::*;
@@ -45,10 +46,10 @@ pub struct SystemDevice {
     pub sd1: WLANInterface,
 }
 impl SystemDevice {
-    pub fn new() -> Self {
+    pub fn new(storage: &StorageConfig) -> Self {
         SystemDevice {
-            hlwd: Hollywood::new(),
-            nand: NandInterface::new("./nand.bin"),
+            hlwd: Hollywood::new(storage),
+            nand: NandInterface::new("./nand.bin", storage.nand.clone()),
             aes: AesInterface::new(),
             sha: ShaInterface::new(),
             ehci: EhcInterface::new(),
@@ -1,12 +1,29 @@
 
+mod nandtool;
+mod titletool;
+
 use ironic_core::bus::*;
+use ironic_core::dev::nand::fault::*;
+use ironic_core::mem::storage::*;
+use ironic_core::dev::hlwd::gpio::debug::*;
+use ironic_core::loader::title::*;
 use ironic_backend::interp::*;
+use ironic_backend::interp::semihosting::*;
 use ironic_backend::back::*;
 use ironic_backend::ppc::*;
+use ironic_backend::ppc::transport::*;
+use ironic_backend::ppc::boot::PpcBootDumper;
+use ironic_backend::hle::*;
+use ironic_backend::ipc::trace::*;
+use ironic_backend::vcd::*;
+use ironic_core::cpu::reg::CpuMode;
+use ironic_core::cpu::cache::CacheModel;
 
-use std::sync::{Arc, RwLock};
-use std::thread::Builder;
+use std::sync::{Arc, RwLock, PoisonError};
+use std::thread::{self, Builder};
+use std::time::{Duration, Instant};
 use std::env;
+use std::fs;
 
 /// User-specified backend type.
 pub enum BackendType {
@@ -23,6 +40,125 @@ fn parse_backend(s: &str) -> Option<BackendType> {
     }
 }
 
+const USAGE: &str = "{interp|jit} [--nand-faults <file>] \
+[--nand <mode>] [--seeprom <mode>] [--otp <mode>] [--ios <wad|elf>] \
+[--elf <file> | --raw <file> --load-addr <addr>] [--entry <addr>] \
+[--sp <addr>] [--mode <usr|fiq|irq|svc|abt|und|sys>] [--no-rom] \
+[--semihosting <dir>] [--cmdline <args>] [--ios-hle <dir>] \
+[--ipc-trace] [--ipc-log <file>] \
+[--listen <unix:path|tcp:addr>] [--ppc-boot-dump <dir>] \
+[--vcd <file>] [--vcd-start <trigger>] [--vcd-stop <trigger>] \
+[--debug-codes <file>] [--clock <MHz>] [--realtime] \
+[--cache | --cache-check]
+       merge-overlay <base image> <overlay file>";
+
+fn usage(prog: &str) {
+    println!("usage: {} {}", prog, USAGE);
+    println!("       {} {}", prog, nandtool::USAGE);
+    println!("       {} {}", prog, titletool::USAGE);
+    println!();
+    println!("Storage modes are 'ro' (the default), 'rw', or 'overlay:<file>'.");
+    println!("VCD triggers are a cycle number, 'ppc-boot', or 'debug:<code>'.");
+}
+
+/// Merge a copy-on-write overlay back into its base image.
+fn merge(base: &str, overlay: &str) {
+    match merge_overlay(base, overlay) {
+        Ok(n) => println!("Merged {} chunks from {} into {}", n, overlay, base),
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// How to start executing on the emulated CPU.
+enum BootMode {
+    /// Start in the boot ROM.
+    Rom,
+    /// Start in an IOS kernel loaded directly into memory.
+    Ios(u32),
+    /// Start in a bare-metal program loaded directly into memory.
+    Program { entry: u32, mode: CpuMode, sp: u32 },
+}
+
+/// User-specified options for loading a bare-metal program.
+struct ProgramOpts {
+    elf: Option<String>,
+    raw: Option<String>,
+    load_addr: Option<u32>,
+    entry: Option<u32>,
+    sp: u32,
+    mode: CpuMode,
+    no_rom: bool,
+}
+
+/// Parse an address (in hexadecimal, with an optional `0x` prefix).
+fn parse_addr(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// Map from input string to a CPU mode.
+fn parse_mode(s: &str) -> Option<CpuMode> {
+    match s {
+        "usr" => Some(CpuMode::Usr), "fiq" => Some(CpuMode::Fiq),
+        "irq" => Some(CpuMode::Irq), "svc" => Some(CpuMode::Svc),
+        "abt" => Some(CpuMode::Abt), "und" => Some(CpuMode::Und),
+        "sys" => Some(CpuMode::Sys),
+        _ => None,
+    }
+}
+
+/// Load a bare-metal program into memory, returning the entrypoint.
+fn load_program(bus: &mut Bus, opts: &ProgramOpts) -> Result<u32, String> {
+    if opts.no_rom {
+        bus.disable_boot_rom();
+    }
+    let entry = match (&opts.elf, &opts.raw) {
+        (Some(filename), None) => {
+            let data = fs::read(filename)
+                .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+            bus.load_elf(&data)?
+        },
+        (None, Some(filename)) => {
+            let addr = opts.load_addr
+                .ok_or("--raw requires --load-addr".to_string())?;
+            let data = fs::read(filename)
+                .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+            bus.load_raw(addr, &data)?;
+            addr
+        },
+        _ => return Err("Expected exactly one of --elf or --raw".to_string()),
+    };
+    Ok(opts.entry.unwrap_or(entry))
+}
+
+/// Load an IOS kernel (from a WAD, or from the boot content itself) into
+/// memory, returning the entrypoint.
+fn load_ios(bus: &mut Bus, filename: &str) -> Result<u32, String> {
+    let data = fs::read(filename)
+        .map_err(|e| format!("Couldn't read {}: {}", filename, e))?;
+    if Wad::is_wad(&data) {
+        let wad = Wad::parse(&data)?;
+        let common_key = common_key_from_otp(&bus.hlwd.otp);
+        let content = wad.decrypt_boot_content(&common_key)?;
+        println!("Loading IOS from title {:016x} v{}",
+            wad.tmd.title_id, wad.tmd.title_version);
+        bus.load_ios(&content)
+    } else {
+        bus.load_ios(&data)
+    }
+}
+
+/// How often changes to storage are persisted while the emulator runs.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Persist changes to storage and to the trace file.
+fn flush_bus(bus: &RwLock<Bus>) {
+    let mut bus = bus.write().unwrap_or_else(PoisonError::into_inner);
+    bus.flush_storage();
+    if let Some(tracer) = bus.hlwd_tracer.as_mut() {
+        tracer.finish();
+    }
+}
+
 fn dump_memory(bus: &Bus) {
     bus.sram0.dump("/tmp/sram0.bin");
     bus.sram1.dump("/tmp/sram1.bin");
@@ -33,27 +169,266 @@ fn dump_memory(bus: &Bus) {
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        println!("usage: {} {{interp|jit}}", args[0]);
+        usage(&args[0]);
+        return;
+    }
+
+    if args[1] == "nand" {
+        if let Err(e) = nandtool::run(&args[2..]) {
+            println!("{}", e);
+        }
+        return;
+    }
+
+    if args[1] == "title" {
+        if let Err(e) = titletool::run(&args[2..]) {
+            println!("{}", e);
+        }
+        return;
+    }
+
+    if args[1] == "merge-overlay" {
+        if args.len() != 4 {
+            usage(&args[0]);
+        } else {
+            merge(&args[2], &args[3]);
+        }
         return;
     }
 
     // Let the user specify the backend
     let backend = parse_backend(args[1].as_str());
     if backend.is_none() {
-        println!("usage: {} {{interp|jit}}", args[0]);
+        usage(&args[0]);
         return;
     }
 
+    // Optionally inject faults into the NAND flash, and pick the modes used
+    // to open the storage images
+    let mut nand_faults = None;
+    let mut storage = StorageConfig::default();
+    let mut ios_file = None;
+    let mut semihosting_dir = None;
+    let mut hle_dir = None;
+    let mut ipc_trace = false;
+    let mut ipc_log = None;
+    let mut ppc_boot_dir = None;
+    let mut vcd_file = None;
+    let mut vcd_start = None;
+    let mut vcd_stop = None;
+    let mut debug_codes = None;
+    let mut clock_mhz = None;
+    let mut realtime = false;
+    let mut cache_check = None;
+    let mut transport = Transport::Unix(IPC_SOCK.to_string());
+    let mut cmdline = String::new();
+    let mut prog = ProgramOpts {
+        elf: None, raw: None, load_addr: None, entry: None, sp: 0,
+        mode: CpuMode::Svc, no_rom: false,
+    };
+    let mut opts = args[2..].iter();
+    while let Some(opt) = opts.next() {
+        if opt == "--no-rom" {
+            prog.no_rom = true;
+            continue;
+        }
+        if opt == "--ipc-trace" {
+            ipc_trace = true;
+            continue;
+        }
+        if opt == "--realtime" {
+            realtime = true;
+            continue;
+        }
+        if opt == "--cache" || opt == "--cache-check" {
+            cache_check = Some(opt == "--cache-check");
+            continue;
+        }
+        match (opt.as_str(), opts.next()) {
+            ("--nand-faults", Some(filename)) => {
+                match NandFaults::from_file(filename) {
+                    Ok(f) => nand_faults = Some(f),
+                    Err(e) => { println!("{}", e); return; },
+                }
+            },
+            ("--ios", Some(filename)) => ios_file = Some(filename.clone()),
+            ("--semihosting", Some(dir)) => semihosting_dir = Some(dir.clone()),
+            ("--cmdline", Some(val)) => cmdline = val.clone(),
+            ("--ios-hle", Some(dir)) => hle_dir = Some(dir.clone()),
+            ("--ipc-log", Some(filename)) => ipc_log = Some(filename.clone()),
+            ("--ppc-boot-dump", Some(dir)) => ppc_boot_dir = Some(dir.clone()),
+            ("--vcd", Some(filename)) => vcd_file = Some(filename.clone()),
+            ("--clock", Some(val)) => match val.parse::<u64>() {
+                Ok(mhz) if mhz != 0 => clock_mhz = Some(mhz),
+                _ => { println!("Invalid clock rate '{}'", val); return; },
+            },
+            ("--debug-codes", Some(filename)) => match DebugCodeTable::from_file(filename) {
+                Ok(t) => debug_codes = Some(t),
+                Err(e) => { println!("{}", e); return; },
+            },
+            ("--vcd-start", Some(val)) | ("--vcd-stop", Some(val)) => {
+                let trigger = match TraceTrigger::parse(val) {
+                    Some(t) => t,
+                    None => { println!("Invalid trigger '{}'", val); return; },
+                };
+                match opt.as_str() {
+                    "--vcd-start" => vcd_start = Some(trigger),
+                    _ => vcd_stop = Some(trigger),
+                }
+            },
+            ("--listen", Some(addr)) => match Transport::parse(addr) {
+                Some(t) => transport = t,
+                None => { println!("Invalid transport '{}'", addr); return; },
+            },
+            ("--elf", Some(filename)) => prog.elf = Some(filename.clone()),
+            ("--raw", Some(filename)) => prog.raw = Some(filename.clone()),
+            ("--mode", Some(mode)) => match parse_mode(mode) {
+                Some(m) => prog.mode = m,
+                None => { println!("Invalid CPU mode '{}'", mode); return; },
+            },
+            ("--load-addr", Some(val)) | ("--entry", Some(val)) |
+            ("--sp", Some(val)) => {
+                let addr = match parse_addr(val) {
+                    Some(a) => a,
+                    None => { println!("Invalid address '{}'", val); return; },
+                };
+                match opt.as_str() {
+                    "--load-addr" => prog.load_addr = Some(addr),
+                    "--entry" => prog.entry = Some(addr),
+                    _ => prog.sp = addr,
+                }
+            },
+            ("--nand", Some(mode)) | ("--seeprom", Some(mode)) |
+            ("--otp", Some(mode)) => {
+                let mode = match StorageMode::parse(mode) {
+                    Some(m) => m,
+                    None => { println!("Invalid storage mode '{}'", mode); return; },
+                };
+                match opt.as_str() {
+                    "--nand" => storage.nand = mode,
+                    "--seeprom" => storage.seeprom = mode,
+                    _ => storage.otp = mode,
+                }
+            },
+            _ => {
+                usage(&args[0]);
+                return;
+            },
+        }
+    }
+
     // The bus is shared between any threads we spin up
-    let bus = Arc::new(RwLock::new(Bus::new()));
+    let bus = Arc::new(RwLock::new(Bus::new_with_storage(&storage)));
+    if let Some(faults) = nand_faults {
+        bus.write().unwrap().nand.set_faults(faults);
+    }
+
+    // Optionally decode and log requests passed through the IPC mailbox
+    if ipc_trace || ipc_log.is_some() {
+        let mut tracer = IpcTracer::new();
+        tracer.verbose = ipc_trace;
+        if let Some(filename) = ipc_log {
+            if let Err(e) = tracer.set_log_file(&filename) {
+                println!("{}", e);
+                return;
+            }
+        }
+        bus.write().unwrap().set_ipc_observer(Box::new(tracer));
+    }
+
+    // Optionally save the state of Broadway each time it boots
+    if let Some(dir) = ppc_boot_dir {
+        match PpcBootDumper::new(&dir) {
+            Ok(dumper) => bus.write().unwrap().set_ppc_boot_observer(Box::new(dumper)),
+            Err(e) => { println!("{}", e); return; },
+        }
+    }
+
+    // Optionally run the Starlet at some other clock rate
+    if let Some(mhz) = clock_mhz {
+        bus.write().unwrap().hlwd.set_clock_override(mhz * 1_000_000);
+    }
+
+    // Optionally model the ARM caches, and check device DMA against them
+    if let Some(check_dma) = cache_check {
+        bus.write().unwrap().arm_cache = Some(CacheModel::new(check_dma));
+    }
 
-    // Fork off the backend thread
+    // Optionally use a table of messages for codes on the GPIO debug port
+    if let Some(table) = debug_codes {
+        if let Some(port) = bus.write().unwrap().hlwd.gpio.device_mut::<DebugPort>() {
+            port.table = table;
+        }
+    }
+
+    // Optionally write the state of the GPIO pins and interrupt lines to a
+    // VCD file
+    if let Some(filename) = vcd_file {
+        match VcdTracer::new(&filename) {
+            Ok(mut tracer) => {
+                tracer.start = vcd_start;
+                tracer.stop = vcd_stop;
+                bus.write().unwrap().set_hlwd_tracer(Box::new(tracer));
+            },
+            Err(e) => { println!("{}", e); return; },
+        }
+    }
+
+    // Optionally skip the boot ROM and load IOS (or some other program)
+    // directly into memory
+    let boot = {
+        let mut bus = bus.write().unwrap();
+        let res = if let Some(filename) = ios_file {
+            load_ios(&mut bus, &filename).map(BootMode::Ios)
+        } else if prog.elf.is_some() || prog.raw.is_some() {
+            load_program(&mut bus, &prog).map(|entry| {
+                BootMode::Program { entry, mode: prog.mode, sp: prog.sp }
+            })
+        } else {
+            Ok(BootMode::Rom)
+        };
+        match res {
+            Ok(boot) => boot,
+            Err(e) => { println!("{}", e); return; },
+        }
+    };
+
+    // Optionally let the guest make semihosting calls, with access to files
+    // in some host directory
+    let semihosting = match semihosting_dir.map(|d| Semihosting::new(&d, &cmdline)) {
+        Some(Ok(sh)) => Some(sh),
+        Some(Err(e)) => { println!("{}", e); return; },
+        None => None,
+    };
+
+    // Fork off the backend thread. Optionally, IOS is emulated at a high
+    // level instead of running on the emulated ARM core.
     let emu_bus = bus.clone();
     let emu_thread = match backend.unwrap() {
+        _ if hle_dir.is_some() => {
+            let mut back = match IosHleBackend::with_host_dir(emu_bus, &hle_dir.unwrap()) {
+                Ok(back) => back,
+                Err(e) => { println!("{}", e); return; },
+            };
+            Builder::new().name("HleThread".to_owned()).spawn(move || {
+                back.run();
+                None
+            }).unwrap()
+        },
         BackendType::Interpreter => {
             Builder::new().name("EmuThread".to_owned()).spawn(move || {
                 let mut back = InterpBackend::new(emu_bus);
+                back.semihosting = semihosting;
+                back.realtime = realtime;
+                match boot {
+                    BootMode::Rom => {},
+                    BootMode::Ios(entry) => back.boot_kernel(entry),
+                    BootMode::Program { entry, mode, sp } => {
+                        back.boot_program(entry, mode, sp);
+                    },
+                }
                 back.run();
+                back.exit_status
             }).unwrap()
         },
         _ => panic!("unimplemented backend"),
@@ -63,13 +438,41 @@ fn main() {
     let ppc_bus = bus.clone();
     let ppc_thread = Builder::new().name("IpcThread".to_owned()).spawn(move || {
         let mut back = PpcBackend::new(ppc_bus);
+        back.transport = transport;
         back.run();
     }).unwrap();
 
+    // Periodically persist changes to storage while the backend is running.
+    // The HLE backend never returns, and the emulator may be killed instead.
+    let mut last_flush = Instant::now();
+    while !emu_thread.is_finished() {
+        thread::sleep(Duration::from_millis(100));
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            flush_bus(&bus);
+            last_flush = Instant::now();
+        }
+    }
+
+    // Storage is flushed even if the backend panicked, in which case the
+    // lock on the bus may be poisoned
     //ppc_thread.join().unwrap();
-    emu_thread.join().unwrap();
+    let exit_status = emu_thread.join();
+    flush_bus(&bus);
 
-    let bus_ref = bus.write().unwrap();
+    let bus_ref = bus.write().unwrap_or_else(PoisonError::into_inner);
+    if let Some(port) = bus_ref.hlwd.gpio.device::<DebugPort>() {
+        println!("{}", port.summary());
+    }
+    if let Some(cache) = bus_ref.arm_cache.as_ref() {
+        println!("{}", cache.summary());
+    }
     dump_memory(&bus_ref);
+
+    // Report the exit status from the guest, if there was one
+    match exit_status {
+        Ok(Some(status)) => std::process::exit(status),
+        Ok(None) => {},
+        Err(_) => std::process::exit(101),
+    }
 }
 
@@ -0,0 +1,68 @@
+//! Subcommand for inspecting the filesystem on a NAND image.
+
+use ironic_core::dev::nand::*;
+use ironic_core::dev::nand::sffs::*;
+use ironic_core::mem::storage::*;
+
+use std::fs;
+
+pub const USAGE: &str = "nand [--image <file>] [--otp-file <file>] [--nand <mode>] \
+{ls [path] | extract <path> <file> | inject <file> <path>}";
+
+/// Run the `nand` subcommand with the given arguments.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut image = "./nand.bin".to_string();
+    let mut otp_file = "./otp.bin".to_string();
+    let mut mode = StorageMode::ReadOnly;
+
+    // Options come before the command
+    let mut idx = 0;
+    while idx + 1 < args.len() && args[idx].starts_with("--") {
+        let val = args[idx + 1].clone();
+        match args[idx].as_str() {
+            "--image" => image = val,
+            "--otp-file" => otp_file = val,
+            "--nand" => mode = StorageMode::parse(&val)
+                .ok_or(format!("Invalid storage mode '{}'", val))?,
+            opt => return Err(format!("Unknown option '{}'", opt)),
+        }
+        idx += 2;
+    }
+    let cmd: Vec<&str> = args[idx..].iter().map(|s| s.as_str()).collect();
+
+    let keys = SffsKeys::from_otp_file(&otp_file)?;
+    let mut nand = NandInterface::new(&image, mode.clone());
+    let mut fs = Sffs::load(&nand, keys)?;
+    println!("Using superblock {} (gen {:x})", fs.superblock, fs.generation);
+
+    match cmd.as_slice() {
+        ["ls"] | ["ls", _] => {
+            let path = cmd.get(1).copied().unwrap_or("/");
+            for (path, entry) in fs.list(path)? {
+                let e = &fs.fst[entry];
+                let kind = if e.is_dir() { 'd' } else { '-' };
+                println!("{}{:02x} {:08x} {:04x} {:8x} {}",
+                    kind, e.mode >> 2, e.uid, e.gid, e.size, path);
+            }
+        },
+        ["extract", path, file] => {
+            let data = fs.read_file(&nand, path)?;
+            fs::write(file, &data)
+                .map_err(|e| format!("Couldn't write {}: {}", file, e))?;
+            println!("Extracted {} ({:x} bytes) to {}", path, data.len(), file);
+        },
+        ["inject", file, path] => {
+            if mode == StorageMode::ReadOnly {
+                println!("NAND image is read-only, changes will be discarded");
+            }
+            let data = fs::read(file)
+                .map_err(|e| format!("Couldn't read {}: {}", file, e))?;
+            fs.write_file(&mut nand, path, &data)?;
+            fs.commit(&mut nand);
+            nand.data.flush();
+            println!("Injected {} ({:x} bytes) as {}", file, data.len(), path);
+        },
+        _ => return Err(format!("usage: {}", USAGE)),
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! Subcommand for decrypting the contents of a WAD file.
+
+use ironic_core::loader::title::*;
+
+use std::fs;
+use std::path::Path;
+
+pub const USAGE: &str = "title [--otp-file <file>] <wad> <output directory>";
+
+/// Run the `title` subcommand with the given arguments.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let (otp_file, args) = match args {
+        [opt, file, rest @ ..] if opt == "--otp-file" => (file.as_str(), rest),
+        _ => ("./otp.bin", args),
+    };
+    let (wad_file, out_dir) = match args {
+        [wad, out] => (wad, Path::new(out)),
+        _ => return Err(format!("usage: {}", USAGE)),
+    };
+
+    let common_key = common_key_from_otp_file(otp_file)?;
+    let data = fs::read(wad_file)
+        .map_err(|e| format!("Couldn't read {}: {}", wad_file, e))?;
+    let wad = Wad::parse(&data)?;
+    println!("Title {:016x} v{} (IOS {:016x}), {} contents, boot index {}",
+        wad.tmd.title_id, wad.tmd.title_version, wad.tmd.ios_version,
+        wad.tmd.contents.len(), wad.tmd.boot_index);
+
+    fs::create_dir_all(out_dir)
+        .map_err(|e| format!("Couldn't create {}: {}", out_dir.display(), e))?;
+    let write = |name: String, data: &[u8]| {
+        let path = out_dir.join(name);
+        fs::write(&path, data)
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+    };
+    write("title.tmd".to_string(), wad.tmd_data)?;
+    write("title.tik".to_string(), wad.ticket_data)?;
+    for (rec, dec) in wad.decrypt_contents(&common_key)? {
+        println!("  {:08x} index={:04x} type={:04x} size={:x}",
+            rec.cid, rec.index, rec.kind, rec.size);
+        write(format!("{:08x}.app", rec.cid), &dec)?;
+    }
+    Ok(())
+}